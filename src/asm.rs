@@ -0,0 +1,915 @@
+// Assembler for the mnemonic syntax the disassembler prints
+//
+// One statement per line, ; starts a comment. Mnemonics, registers and directives are
+// case insensitive, labels and constants are not.
+//
+//     SPEED EQU 2            ; constant, "SPEED = 2" works too
+//     loop:                  ; label, may be followed by a statement on the same line
+//         LD I, sprite
+//         DRW V0, V1, 5
+//         ADD V0, SPEED
+//         JP loop
+//     sprite:
+//         DB 0xF0, 0b10010000, 144, "text"
+//         DW 0x1234, sprite + 2
+//     INCLUDE "other.asm"    ; relative to the including file
+//     ORG 0x300              ; pads with zeros up to the address
+//
+// Numbers are decimal, 0x hex or 0b binary and can be combined with + and -.
+// Constants can only use symbols defined above them, instructions can use any label.
+
+use cpu::Opcode;
+use cpu::Opcode::*;
+use disasm::ROM_START;
+use error::AsmError;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Code and data can fill the whole XO-CHIP address space
+const MEMORY_END: usize = 0x10000;
+
+const MNEMONICS: &[&str] = &[
+    "SYS", "CLS", "RET", "JP", "CALL", "SE", "SNE", "LD", "ADD", "OR", "AND", "XOR", "SUB", "SUBN",
+    "SHR", "SHL", "RND", "DRW", "SKP", "SKNP", "SCD", "SCR", "SCL", "EXIT", "LOW", "HIGH", "SAVE",
+    "LOAD", "PLANE", "AUDIO", "PITCH",
+];
+
+// Assembles source without a file, includes are relative to the working directory
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut assembler = Assembler::new();
+    assembler.parse_source(source, "<source>", Path::new(""))?;
+    assembler.finish()
+}
+
+pub fn assemble_file(path: &Path) -> Result<Vec<u8>, AsmError> {
+    let mut assembler = Assembler::new();
+    assembler.include(path, None)?;
+    assembler.finish()
+}
+
+// The word CPU::decode maps to the instruction
+pub fn encode(opcode: Opcode) -> u16 {
+    let x = |x: u16| x << 8;
+    let xy = |x: u16, y: u16| x << 8 | y << 4;
+    match opcode {
+        Ignore => 0x0000,
+        ClearScreen => 0x00E0,
+        Return => 0x00EE,
+        Jump(nnn) => 0x1000 | nnn,
+        CallSubroutine(nnn) => 0x2000 | nnn,
+        SkipIfEqualAddress(vx, nn) => 0x3000 | x(vx) | nn,
+        SkipIfNotEqualAddress(vx, nn) => 0x4000 | x(vx) | nn,
+        SkipIfEqualRegister(vx, vy) => 0x5000 | xy(vx, vy),
+        SetRegister(vx, nn) => 0x6000 | x(vx) | nn,
+        AddAddressToRegister(vx, nn) => 0x7000 | x(vx) | nn,
+        Assign(vx, vy) => 0x8000 | xy(vx, vy),
+        AssignOr(vx, vy) => 0x8001 | xy(vx, vy),
+        AssignAnd(vx, vy) => 0x8002 | xy(vx, vy),
+        AssignXor(vx, vy) => 0x8003 | xy(vx, vy),
+        Add(vx, vy) => 0x8004 | xy(vx, vy),
+        Subtract(vx, vy) => 0x8005 | xy(vx, vy),
+        LeastSigStoreAndShift(vx, vy) => 0x8006 | xy(vx, vy),
+        SetSubtract(vx, vy) => 0x8007 | xy(vx, vy),
+        MostSigStoreAndShift(vx, vy) => 0x800E | xy(vx, vy),
+        SkipIfUnequalRegisters(vx, vy) => 0x9000 | xy(vx, vy),
+        SetIndexRegister(nnn) => 0xA000 | nnn,
+        Flow(nnn) => 0xB000 | nnn,
+        Rand(vx, nn) => 0xC000 | x(vx) | nn,
+        Display(vx, vy, n) => 0xD000 | xy(vx, vy) | n,
+        SkipIfKeyPressed(vx) => 0xE09E | x(vx),
+        SkipIfNotKeyPressed(vx) => 0xE0A1 | x(vx),
+        GetDelayTimer(vx) => 0xF007 | x(vx),
+        AwaitKeyPress(vx) => 0xF00A | x(vx),
+        SetDelayTimer(vx) => 0xF015 | x(vx),
+        SetSoundTimer(vx) => 0xF018 | x(vx),
+        AddToIndexRegister(vx) => 0xF01E | x(vx),
+        SetIndexRegisterToSpriteLocation(vx) => 0xF029 | x(vx),
+        StoreBinaryCodedDecimal(vx) => 0xF033 | x(vx),
+        RegisterDump(vx) => 0xF055 | x(vx),
+        RegisterLoad(vx) => 0xF065 | x(vx),
+        ScrollDown(n) => 0x00C0 | n,
+        ScrollRight => 0x00FB,
+        ScrollLeft => 0x00FC,
+        Exit => 0x00FD,
+        LowResolution => 0x00FE,
+        HighResolution => 0x00FF,
+        SetIndexRegisterToLargeSpriteLocation(vx) => 0xF030 | x(vx),
+        StoreFlags(vx) => 0xF075 | x(vx),
+        LoadFlags(vx) => 0xF085 | x(vx),
+        SetIndexRegisterLong => 0xF000,
+        RegisterRangeDump(vx, vy) => 0x5002 | xy(vx, vy),
+        RegisterRangeLoad(vx, vy) => 0x5003 | xy(vx, vy),
+        SelectPlanes(n) => 0xF001 | x(n),
+        LoadAudioPattern => 0xF002,
+        SetPitch(vx) => 0xF03A | x(vx),
+        Unknown(opcode) => opcode,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(i64),
+    Text(String),
+    Comma,
+    Colon,
+    Equals,
+    Plus,
+    Minus,
+    LeftBracket,
+    RightBracket,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Token::Ident(ref name) => write!(f, "'{}'", name),
+            Token::Number(value) => write!(f, "{}", value),
+            Token::Text(ref text) => write!(f, "\"{}\"", text),
+            Token::Comma => write!(f, "','"),
+            Token::Colon => write!(f, "':'"),
+            Token::Equals => write!(f, "'='"),
+            Token::Plus => write!(f, "'+'"),
+            Token::Minus => write!(f, "'-'"),
+            Token::LeftBracket => write!(f, "'['"),
+            Token::RightBracket => write!(f, "']'"),
+        }
+    }
+}
+
+struct Lexeme {
+    token: Token,
+    column: usize,
+}
+
+// The line a statement came from, for error messages
+#[derive(Clone)]
+struct Location {
+    file: String,
+    line: usize,
+}
+
+impl Location {
+    fn error<M: Into<String>>(&self, column: usize, message: M) -> AsmError {
+        AsmError {
+            file: self.file.clone(),
+            line: self.line,
+            column,
+            message: message.into(),
+        }
+    }
+}
+
+// A sum of numbers and symbols, evaluated once all labels are known
+struct Expr {
+    // Whether the term is subtracted, and the term
+    terms: Vec<(bool, Term)>,
+    column: usize,
+}
+
+enum Term {
+    Number(i64),
+    Symbol(String, usize),
+}
+
+enum Operand {
+    Register(u16),
+    // Vx - Vy of SAVE and LOAD
+    Range(u16, u16),
+    Index,
+    // [I]
+    IndexedMemory,
+    DelayTimer,
+    SoundTimer,
+    Key,
+    Font,
+    LargeFont,
+    Bcd,
+    Flags,
+    // LONG nnnn of LD I, LONG
+    Long(Expr),
+    Value(Expr),
+}
+
+enum Item {
+    Instruction(String, Vec<Operand>),
+    Bytes(Vec<Expr>),
+    Words(Vec<Expr>),
+}
+
+struct Statement {
+    address: usize,
+    location: Location,
+    column: usize,
+    item: Item,
+}
+
+struct Assembler {
+    statements: Vec<Statement>,
+    // Labels and constants
+    symbols: HashMap<String, i64>,
+    address: usize,
+    // Files currently being assembled, to catch include cycles
+    includes: Vec<PathBuf>,
+}
+
+impl Assembler {
+    fn new() -> Assembler {
+        Assembler {
+            statements: Vec::new(),
+            symbols: HashMap::new(),
+            address: ROM_START,
+            includes: Vec::new(),
+        }
+    }
+
+    // Assembles a file in place, from is the INCLUDE statement that asked for it
+    fn include(&mut self, path: &Path, from: Option<(&Location, usize)>) -> Result<(), AsmError> {
+        let error = |message: String| match from {
+            Some((location, column)) => location.error(column, message),
+            None => AsmError {
+                file: path.display().to_string(),
+                line: 0,
+                column: 0,
+                message,
+            },
+        };
+        let source = fs::read_to_string(path)
+            .map_err(|err| error(format!("Can't read {}: {}", path.display(), err)))?;
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        if self.includes.contains(&canonical) {
+            return Err(error(format!("{} includes itself", path.display())));
+        }
+
+        self.includes.push(canonical);
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let result = self.parse_source(&source, &path.display().to_string(), dir);
+        self.includes.pop();
+        result
+    }
+
+    // First pass, collects statements and assigns addresses to labels
+    fn parse_source(&mut self, source: &str, file: &str, dir: &Path) -> Result<(), AsmError> {
+        for (index, text) in source.lines().enumerate() {
+            let location = Location {
+                file: file.to_string(),
+                line: index + 1,
+            };
+            self.parse_line(text, &location, dir)?;
+        }
+        Ok(())
+    }
+
+    fn parse_line(&mut self, text: &str, location: &Location, dir: &Path) -> Result<(), AsmError> {
+        let lexemes = lex(text, location)?;
+        let mut rest = &lexemes[..];
+
+        if let [Token::Ident(name), Token::Colon, ..] = tokens(rest).as_slice() {
+            let address = self.address as i64;
+            self.define(name, address, location, rest[0].column)?;
+            rest = &rest[2..];
+        }
+
+        let (name, column) = match rest.first() {
+            None => return Ok(()),
+            Some(Lexeme {
+                token: Token::Ident(ref name),
+                column,
+            }) => (name.clone(), *column),
+            Some(other) => {
+                return Err(location.error(other.column, format!("Unexpected {}", other.token)))
+            }
+        };
+
+        let is_constant = match rest.get(1).map(|lexeme| &lexeme.token) {
+            Some(Token::Equals) => true,
+            Some(Token::Ident(ref word)) => word.eq_ignore_ascii_case("EQU"),
+            _ => false,
+        };
+        if is_constant {
+            let expr = parse_expr(&rest[2..], rest[1].column + 1, location)?;
+            let value = self.eval(&expr, location)?;
+            return self.define(&name, value, location, column);
+        }
+
+        let groups = split_operands(&rest[1..], column + name.len() + 1);
+        let mnemonic = name.to_ascii_uppercase();
+        let (item, size) = match mnemonic.as_str() {
+            "DB" | "DW" => {
+                if groups.is_empty() {
+                    return Err(location.error(column, format!("{} needs a value", mnemonic)));
+                }
+                let mut values = Vec::new();
+                for (group, column) in groups {
+                    match tokens(group).as_slice() {
+                        [Token::Text(text)] if mnemonic == "DB" => {
+                            values.extend(text.bytes().map(|byte| Expr {
+                                terms: vec![(false, Term::Number(byte as i64))],
+                                column,
+                            }))
+                        }
+                        _ => values.push(parse_expr(group, column, location)?),
+                    }
+                }
+                if mnemonic == "DB" {
+                    let size = values.len();
+                    (Item::Bytes(values), size)
+                } else {
+                    let size = values.len() * 2;
+                    (Item::Words(values), size)
+                }
+            }
+            "ORG" => {
+                let (group, column) = single_operand(&groups, column, location)?;
+                let address = self.eval(&parse_expr(group, column, location)?, location)?;
+                if address < self.address as i64 {
+                    return Err(location.error(
+                        column,
+                        format!(
+                            "ORG 0x{:X} is before the current address 0x{:X}",
+                            address, self.address
+                        ),
+                    ));
+                }
+                if address > MEMORY_END as i64 {
+                    return Err(location.error(column, "ORG is past the end of memory"));
+                }
+                self.address = address as usize;
+                return Ok(());
+            }
+            "INCLUDE" => {
+                let (group, column) = single_operand(&groups, column, location)?;
+                match tokens(group).as_slice() {
+                    [Token::Text(path)] => {
+                        return self.include(&dir.join(path), Some((location, column)))
+                    }
+                    _ => return Err(location.error(column, "INCLUDE needs a quoted path")),
+                }
+            }
+            _ => {
+                let mut operands = Vec::new();
+                for (group, column) in groups {
+                    operands.push(parse_operand(group, column, location)?);
+                }
+                let size = match (mnemonic.as_str(), &operands[..]) {
+                    ("LD", [Operand::Index, Operand::Long(_)]) => 4,
+                    _ => 2,
+                };
+                (Item::Instruction(mnemonic, operands), size)
+            }
+        };
+
+        self.statements.push(Statement {
+            address: self.address,
+            location: location.clone(),
+            column,
+            item,
+        });
+        self.address += size;
+        if self.address > MEMORY_END {
+            return Err(location.error(column, "The program doesn't fit into memory"));
+        }
+        Ok(())
+    }
+
+    fn define(
+        &mut self,
+        name: &str,
+        value: i64,
+        location: &Location,
+        column: usize,
+    ) -> Result<(), AsmError> {
+        if keyword_operand(name).is_some() || name.eq_ignore_ascii_case("LONG") {
+            return Err(location.error(column, format!("'{}' is a reserved name", name)));
+        }
+        if self.symbols.contains_key(name) {
+            return Err(location.error(column, format!("'{}' is already defined", name)));
+        }
+        self.symbols.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    fn eval(&self, expr: &Expr, location: &Location) -> Result<i64, AsmError> {
+        let mut value = 0i64;
+        for (negative, term) in &expr.terms {
+            let term = match *term {
+                Term::Number(number) => number,
+                Term::Symbol(ref name, column) => match self.symbols.get(name) {
+                    Some(value) => *value,
+                    None => {
+                        return Err(location.error(column, format!("Unknown symbol '{}'", name)))
+                    }
+                },
+            };
+            value = if *negative {
+                value.wrapping_sub(term)
+            } else {
+                value.wrapping_add(term)
+            };
+        }
+        Ok(value)
+    }
+
+    // Evaluates an expression that has to fit into min..=max
+    fn ranged(
+        &self,
+        expr: &Expr,
+        location: &Location,
+        min: i64,
+        max: i64,
+        what: &str,
+    ) -> Result<u16, AsmError> {
+        let value = self.eval(expr, location)?;
+        if value < min || value > max {
+            return Err(location.error(expr.column, format!("{} doesn't fit into {}", value, what)));
+        }
+        Ok(value as u16)
+    }
+
+    fn byte(&self, expr: &Expr, location: &Location) -> Result<u16, AsmError> {
+        // Negative bytes are stored as two's complement
+        Ok(self.ranged(expr, location, -128, 255, "a byte")? & 0xFF)
+    }
+
+    fn address(&self, expr: &Expr, location: &Location) -> Result<u16, AsmError> {
+        self.ranged(expr, location, 0, 0xFFF, "a 12-bit address")
+    }
+
+    fn nibble(&self, expr: &Expr, location: &Location) -> Result<u16, AsmError> {
+        self.ranged(expr, location, 0, 15, "a nibble")
+    }
+
+    // Second pass, encodes every statement now that all labels are known
+    fn finish(self) -> Result<Vec<u8>, AsmError> {
+        let mut rom = Vec::new();
+        for statement in &self.statements {
+            // ORG can only move forward, so the gap is all there is to fill
+            rom.resize(statement.address - ROM_START, 0);
+            let location = &statement.location;
+            match statement.item {
+                Item::Instruction(ref mnemonic, ref operands) => {
+                    let (opcode, long) = self.instruction(mnemonic, operands, statement)?;
+                    push_word(&mut rom, encode(opcode));
+                    if let Some(long) = long {
+                        push_word(&mut rom, long);
+                    }
+                }
+                Item::Bytes(ref values) => {
+                    for value in values {
+                        rom.push(self.byte(value, location)? as u8);
+                    }
+                }
+                Item::Words(ref values) => {
+                    for value in values {
+                        let word = self.ranged(value, location, -0x8000, 0xFFFF, "a word")?;
+                        push_word(&mut rom, word);
+                    }
+                }
+            }
+        }
+        Ok(rom)
+    }
+
+    // The opcode for a mnemonic, and the address after it for LD I, LONG
+    fn instruction(
+        &self,
+        mnemonic: &str,
+        operands: &[Operand],
+        statement: &Statement,
+    ) -> Result<(Opcode, Option<u16>), AsmError> {
+        use self::Operand::*;
+        let location = &statement.location;
+        let opcode = match (mnemonic, operands) {
+            ("SYS", [Value(nnn)]) => match self.address(nnn, location)? {
+                0 => Ignore,
+                // Machine code routines are never executed, but can still be assembled
+                nnn => Unknown(nnn),
+            },
+            ("CLS", []) => ClearScreen,
+            ("RET", []) => Return,
+            ("JP", [Value(nnn)]) => Jump(self.address(nnn, location)?),
+            ("JP", [Register(0), Value(nnn)]) => Flow(self.address(nnn, location)?),
+            ("CALL", [Value(nnn)]) => CallSubroutine(self.address(nnn, location)?),
+            ("SE", [Register(x), Value(nn)]) => SkipIfEqualAddress(*x, self.byte(nn, location)?),
+            ("SE", [Register(x), Register(y)]) => SkipIfEqualRegister(*x, *y),
+            ("SNE", [Register(x), Value(nn)]) => {
+                SkipIfNotEqualAddress(*x, self.byte(nn, location)?)
+            }
+            ("SNE", [Register(x), Register(y)]) => SkipIfUnequalRegisters(*x, *y),
+            ("LD", [Register(x), Value(nn)]) => SetRegister(*x, self.byte(nn, location)?),
+            ("LD", [Register(x), Register(y)]) => Assign(*x, *y),
+            ("LD", [Index, Value(nnn)]) => SetIndexRegister(self.address(nnn, location)?),
+            ("LD", [Index, Long(nnnn)]) => {
+                let nnnn = self.ranged(nnnn, location, 0, 0xFFFF, "a 16-bit address")?;
+                return Ok((SetIndexRegisterLong, Some(nnnn)));
+            }
+            ("LD", [Register(x), DelayTimer]) => GetDelayTimer(*x),
+            ("LD", [Register(x), Key]) => AwaitKeyPress(*x),
+            ("LD", [DelayTimer, Register(x)]) => SetDelayTimer(*x),
+            ("LD", [SoundTimer, Register(x)]) => SetSoundTimer(*x),
+            ("LD", [Font, Register(x)]) => SetIndexRegisterToSpriteLocation(*x),
+            ("LD", [LargeFont, Register(x)]) => SetIndexRegisterToLargeSpriteLocation(*x),
+            ("LD", [Bcd, Register(x)]) => StoreBinaryCodedDecimal(*x),
+            ("LD", [IndexedMemory, Register(x)]) => RegisterDump(*x),
+            ("LD", [Register(x), IndexedMemory]) => RegisterLoad(*x),
+            ("LD", [Flags, Register(x)]) => StoreFlags(*x),
+            ("LD", [Register(x), Flags]) => LoadFlags(*x),
+            ("ADD", [Register(x), Value(nn)]) => AddAddressToRegister(*x, self.byte(nn, location)?),
+            ("ADD", [Register(x), Register(y)]) => Add(*x, *y),
+            ("ADD", [Index, Register(x)]) => AddToIndexRegister(*x),
+            ("OR", [Register(x), Register(y)]) => AssignOr(*x, *y),
+            ("AND", [Register(x), Register(y)]) => AssignAnd(*x, *y),
+            ("XOR", [Register(x), Register(y)]) => AssignXor(*x, *y),
+            ("SUB", [Register(x), Register(y)]) => Subtract(*x, *y),
+            ("SUBN", [Register(x), Register(y)]) => SetSubtract(*x, *y),
+            // Without VY the register shifts itself, which works with either shift quirk
+            ("SHR", [Register(x)]) => LeastSigStoreAndShift(*x, *x),
+            ("SHR", [Register(x), Register(y)]) => LeastSigStoreAndShift(*x, *y),
+            ("SHL", [Register(x)]) => MostSigStoreAndShift(*x, *x),
+            ("SHL", [Register(x), Register(y)]) => MostSigStoreAndShift(*x, *y),
+            ("RND", [Register(x), Value(nn)]) => Rand(*x, self.byte(nn, location)?),
+            ("DRW", [Register(x), Register(y), Value(n)]) => {
+                Display(*x, *y, self.nibble(n, location)?)
+            }
+            ("SKP", [Register(x)]) => SkipIfKeyPressed(*x),
+            ("SKNP", [Register(x)]) => SkipIfNotKeyPressed(*x),
+            ("SCD", [Value(n)]) => ScrollDown(self.nibble(n, location)?),
+            ("SCR", []) => ScrollRight,
+            ("SCL", []) => ScrollLeft,
+            ("EXIT", []) => Exit,
+            ("LOW", []) => LowResolution,
+            ("HIGH", []) => HighResolution,
+            ("SAVE", [Range(x, y)]) => RegisterRangeDump(*x, *y),
+            ("LOAD", [Range(x, y)]) => RegisterRangeLoad(*x, *y),
+            ("PLANE", [Value(n)]) => SelectPlanes(self.nibble(n, location)?),
+            ("AUDIO", []) => LoadAudioPattern,
+            ("PITCH", [Register(x)]) => SetPitch(*x),
+            _ if MNEMONICS.contains(&mnemonic) => {
+                return Err(location.error(
+                    statement.column,
+                    format!("Invalid operands for {}", mnemonic),
+                ))
+            }
+            _ => {
+                return Err(location.error(
+                    statement.column,
+                    format!("Unknown instruction {}", mnemonic),
+                ))
+            }
+        };
+        Ok((opcode, None))
+    }
+}
+
+fn push_word(rom: &mut Vec<u8>, word: u16) {
+    rom.push((word >> 8) as u8);
+    rom.push(word as u8);
+}
+
+fn lex(text: &str, location: &Location) -> Result<Vec<Lexeme>, AsmError> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut lexemes = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let column = i + 1;
+        let token = match chars[i] {
+            ';' => break,
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            ',' => Token::Comma,
+            ':' => Token::Colon,
+            '=' => Token::Equals,
+            '+' => Token::Plus,
+            '-' => Token::Minus,
+            '[' => Token::LeftBracket,
+            ']' => Token::RightBracket,
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    i += 1;
+                    match chars.get(i) {
+                        Some('"') => break,
+                        Some(c) => text.push(*c),
+                        None => return Err(location.error(column, "Unterminated string")),
+                    }
+                }
+                Token::Text(text)
+            }
+            c if c.is_ascii_alphanumeric() || c == '_' || c == '.' => {
+                let start = i;
+                while i + 1 < chars.len()
+                    && (chars[i + 1].is_ascii_alphanumeric()
+                        || chars[i + 1] == '_'
+                        || chars[i + 1] == '.')
+                {
+                    i += 1;
+                }
+                let word: String = chars[start..=i].iter().collect();
+                if c.is_ascii_digit() {
+                    match parse_number(&word) {
+                        Some(number) => Token::Number(number),
+                        None => {
+                            return Err(location.error(column, format!("Invalid number '{}'", word)))
+                        }
+                    }
+                } else {
+                    Token::Ident(word)
+                }
+            }
+            c => return Err(location.error(column, format!("Unexpected character '{}'", c))),
+        };
+        lexemes.push(Lexeme { token, column });
+        i += 1;
+    }
+    Ok(lexemes)
+}
+
+fn tokens(lexemes: &[Lexeme]) -> Vec<&Token> {
+    lexemes.iter().map(|lexeme| &lexeme.token).collect()
+}
+
+fn parse_number(word: &str) -> Option<i64> {
+    let lower = word.to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+// Splits operands at commas, each with the column it starts at
+fn split_operands(lexemes: &[Lexeme], column: usize) -> Vec<(&[Lexeme], usize)> {
+    if lexemes.is_empty() {
+        return Vec::new();
+    }
+    let mut groups = Vec::new();
+    let mut start = 0;
+    let mut group_column = column;
+    for (i, lexeme) in lexemes.iter().enumerate() {
+        if lexeme.token == Token::Comma {
+            groups.push((
+                &lexemes[start..i],
+                first_column(&lexemes[start..i], group_column),
+            ));
+            start = i + 1;
+            group_column = lexeme.column + 1;
+        }
+    }
+    groups.push((
+        &lexemes[start..],
+        first_column(&lexemes[start..], group_column),
+    ));
+    groups
+}
+
+fn first_column(lexemes: &[Lexeme], default: usize) -> usize {
+    lexemes.first().map_or(default, |lexeme| lexeme.column)
+}
+
+fn single_operand<'a>(
+    groups: &[(&'a [Lexeme], usize)],
+    column: usize,
+    location: &Location,
+) -> Result<(&'a [Lexeme], usize), AsmError> {
+    match groups {
+        [group] => Ok(*group),
+        _ => Err(location.error(column, "Expected a single operand")),
+    }
+}
+
+fn parse_operand(
+    lexemes: &[Lexeme],
+    column: usize,
+    location: &Location,
+) -> Result<Operand, AsmError> {
+    match tokens(lexemes).as_slice() {
+        [Token::Ident(name)] => {
+            if let Some(operand) = keyword_operand(name) {
+                return Ok(operand);
+            }
+        }
+        [Token::LeftBracket, Token::Ident(name), Token::RightBracket]
+            if name.eq_ignore_ascii_case("I") =>
+        {
+            return Ok(Operand::IndexedMemory)
+        }
+        [Token::Ident(first), Token::Minus, Token::Ident(last)] => {
+            if let (Some(x), Some(y)) = (register(first), register(last)) {
+                return Ok(Operand::Range(x, y));
+            }
+        }
+        [Token::Ident(long), ..] if long.eq_ignore_ascii_case("LONG") => {
+            let column = lexemes[0].column + long.len() + 1;
+            return Ok(Operand::Long(parse_expr(&lexemes[1..], column, location)?));
+        }
+        _ => (),
+    }
+    Ok(Operand::Value(parse_expr(lexemes, column, location)?))
+}
+
+// Registers and the special operands of LD and ADD
+fn keyword_operand(name: &str) -> Option<Operand> {
+    if let Some(x) = register(name) {
+        return Some(Operand::Register(x));
+    }
+    match name.to_ascii_uppercase().as_str() {
+        "I" => Some(Operand::Index),
+        "DT" => Some(Operand::DelayTimer),
+        "ST" => Some(Operand::SoundTimer),
+        "K" => Some(Operand::Key),
+        "F" => Some(Operand::Font),
+        "HF" => Some(Operand::LargeFont),
+        "B" => Some(Operand::Bcd),
+        "R" => Some(Operand::Flags),
+        _ => None,
+    }
+}
+
+fn register(name: &str) -> Option<u16> {
+    let mut chars = name.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('V'), Some(digit), None) | (Some('v'), Some(digit), None) => {
+            digit.to_digit(16).map(|x| x as u16)
+        }
+        _ => None,
+    }
+}
+
+// [-] term {(+|-) term}
+fn parse_expr(lexemes: &[Lexeme], column: usize, location: &Location) -> Result<Expr, AsmError> {
+    let mut terms = Vec::new();
+    let mut negative = false;
+    let mut i = 0;
+    if let Some(Lexeme {
+        token: Token::Minus,
+        ..
+    }) = lexemes.first()
+    {
+        negative = true;
+        i = 1;
+    }
+    loop {
+        let term = match lexemes.get(i) {
+            Some(Lexeme {
+                token: Token::Number(number),
+                ..
+            }) => Term::Number(*number),
+            Some(Lexeme {
+                token: Token::Ident(ref name),
+                column,
+            }) => Term::Symbol(name.clone(), *column),
+            Some(other) => {
+                return Err(location.error(other.column, format!("Unexpected {}", other.token)))
+            }
+            None => {
+                let column = lexemes.last().map_or(column, |lexeme| lexeme.column + 1);
+                return Err(location.error(column, "Expected a value"));
+            }
+        };
+        terms.push((negative, term));
+        i += 1;
+        match lexemes.get(i) {
+            None => break,
+            Some(Lexeme {
+                token: Token::Plus, ..
+            }) => negative = false,
+            Some(Lexeme {
+                token: Token::Minus,
+                ..
+            }) => negative = true,
+            Some(other) => {
+                return Err(location.error(other.column, format!("Unexpected {}", other.token)))
+            }
+        }
+        i += 1;
+    }
+    Ok(Expr { terms, column })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::CPU;
+    use disasm::{disassemble, Mode};
+    use std::env;
+
+    fn error(source: &str) -> (usize, usize, String) {
+        let err = assemble(source).unwrap_err();
+        (err.line, err.column, err.message)
+    }
+
+    #[test]
+    fn encode_inverts_decode() {
+        for word in 0..=0xFFFF {
+            assert_eq!(encode(CPU::decode(word)), word);
+        }
+    }
+
+    #[test]
+    fn assembles_instructions_labels_and_constants() {
+        let source = "
+            ; Draws a digit and waits
+            DIGIT = 3
+            start:  LD V1, DIGIT + 1
+                    LD F, V1
+                    DRW V0, v1, 5
+            wait:   JP wait
+                    LD I, LONG data
+                    SAVE V0 - V3
+            data:   DB 0xFF, -1, 0b101, \"hi\"
+                    DW start
+        ";
+        assert_eq!(
+            assemble(source).unwrap(),
+            [
+                0x61, 0x04, 0xF1, 0x29, 0xD0, 0x15, 0x12, 0x06, 0xF0, 0x00, 0x02, 0x0E, 0x50, 0x32,
+                0xFF, 0xFF, 0x05, b'h', b'i', 0x02, 0x00
+            ]
+        );
+    }
+
+    #[test]
+    fn org_pads_with_zeros() {
+        assert_eq!(
+            assemble("CLS\nORG 0x206\nRET").unwrap(),
+            [0x00, 0xE0, 0x00, 0x00, 0x00, 0x00, 0x00, 0xEE]
+        );
+        assert_eq!(
+            error("CLS\nORG 0x200"),
+            (
+                2,
+                5,
+                "ORG 0x200 is before the current address 0x202".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn errors_point_at_the_problem() {
+        assert_eq!(
+            error("  FOO V1"),
+            (1, 3, "Unknown instruction FOO".to_string())
+        );
+        assert_eq!(
+            error("\nLD V1, 256"),
+            (2, 8, "256 doesn't fit into a byte".to_string())
+        );
+        assert_eq!(
+            error("JP nowhere"),
+            (1, 4, "Unknown symbol 'nowhere'".to_string())
+        );
+        assert_eq!(
+            error("DRW V0, V1"),
+            (1, 1, "Invalid operands for DRW".to_string())
+        );
+        assert_eq!(
+            error("a: CLS\na: CLS"),
+            (2, 1, "'a' is already defined".to_string())
+        );
+        assert_eq!(
+            error("DB \"open"),
+            (1, 4, "Unterminated string".to_string())
+        );
+        assert_eq!(error("LD V1,"), (1, 7, "Expected a value".to_string()));
+    }
+
+    #[test]
+    fn includes_are_relative_to_the_including_file() {
+        let dir = env::temp_dir().join(format!("rusty_chip_asm_{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("main.asm"), "INCLUDE \"lib/sub.asm\"\nCALL sub").unwrap();
+        fs::write(dir.join("lib/sub.asm"), "sub: RET").unwrap();
+        fs::write(dir.join("loop.asm"), "INCLUDE \"loop.asm\"").unwrap();
+
+        let rom = assemble_file(&dir.join("main.asm"));
+        let cycle = assemble_file(&dir.join("loop.asm"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(rom.unwrap(), [0x00, 0xEE, 0x22, 0x00]);
+        let cycle = cycle.unwrap_err();
+        assert_eq!((cycle.line, cycle.column), (1, 9));
+        assert!(cycle.message.ends_with("includes itself"));
+    }
+
+    #[test]
+    fn disassembly_assembles_back_into_the_rom() {
+        // Arbitrary bytes, decoded both as code and data
+        let mut seed = 0x1234_5678u32;
+        let rom: Vec<u8> = (0..1001)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        for mode in [Mode::Linear, Mode::Recursive].iter() {
+            let source = disassemble(&rom, *mode).source();
+            assert_eq!(assemble(&source).unwrap(), rom);
+        }
+    }
+}
@@ -0,0 +1,359 @@
+// The beeper the sound timer drives, synthesized into samples for sinks that want them
+// See AudioSink::sample_rate and Emulator::record_audio
+
+use cpu::CPU;
+use emulator::AudioSink;
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+// How long the beeper takes to fade in and out, starting and stopping at full volume clicks
+const ENVELOPE_SECONDS: f64 = 0.005;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Waveform {
+    Square,
+    Sine,
+}
+
+impl Waveform {
+    pub fn from_name(name: &str) -> Option<Waveform> {
+        match name {
+            "square" => Some(Waveform::Square),
+            "sine" => Some(Waveform::Sine),
+            _ => None,
+        }
+    }
+}
+
+// What the beeper sounds like
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tone {
+    pub waveform: Waveform,
+    // In Hz
+    pub frequency: f64,
+    // From 0.0 for silence to 1.0 for full scale
+    pub volume: f32,
+}
+
+// A quiet A4 square wave, close to the buzzers of the original machines
+impl Default for Tone {
+    fn default() -> Tone {
+        Tone {
+            waveform: Waveform::Square,
+            frequency: 440.0,
+            volume: 0.25,
+        }
+    }
+}
+
+// The XO-CHIP audio pattern, 128 1-bit samples played in a loop instead of the tone
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pattern {
+    // Most significant bit first, see CPU::audio_pattern
+    pub bits: [u8; 16],
+    // In samples per second, see CPU::playback_rate
+    pub rate: f64,
+}
+
+impl Pattern {
+    // The pattern the ROM loaded with F002, None until it loads one
+    // ROMs that never do get the tone, a pattern of zeros loaded on purpose stays silent
+    pub fn from_cpu(cpu: &CPU) -> Option<Pattern> {
+        if !cpu.has_audio_pattern() {
+            return None;
+        }
+        let mut bits = [0; 16];
+        bits.copy_from_slice(cpu.audio_pattern());
+        Some(Pattern {
+            bits,
+            rate: cpu.playback_rate(),
+        })
+    }
+
+    fn bit(&self, index: usize) -> bool {
+        self.bits[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+}
+
+// Renders the tone or the audio pattern frame by frame while the sound timer runs
+pub struct Beeper {
+    tone: Tone,
+    pattern: Option<Pattern>,
+    sample_rate: u32,
+    // Position in the current period of the tone, or in the pattern, from 0.0 to 1.0
+    phase: f64,
+    // The envelope, ramps towards 1.0 while beeping and towards 0.0 otherwise
+    level: f64,
+    // Frames rendered so far, to spread sample rates that aren't a multiple of 60 evenly
+    frames: u64,
+}
+
+impl Beeper {
+    pub fn new(tone: Tone, sample_rate: u32) -> Beeper {
+        Beeper {
+            tone,
+            pattern: None,
+            sample_rate,
+            phase: 0.0,
+            level: 0.0,
+            frames: 0,
+        }
+    }
+
+    pub fn tone(&self) -> Tone {
+        self.tone
+    }
+
+    pub fn set_tone(&mut self, tone: Tone) {
+        self.tone = tone;
+    }
+
+    // Plays the pattern instead of the tone from the next frame on, None goes back to the tone
+    pub fn set_pattern(&mut self, pattern: Option<Pattern>) {
+        if pattern.is_some() != self.pattern.is_some() {
+            self.phase = 0.0;
+        }
+        self.pattern = pattern;
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Number of samples the next frame has, every 60 frames are exactly a second
+    pub fn frame_len(&self) -> usize {
+        let rate = self.sample_rate as u64;
+        ((self.frames + 1) * rate / 60 - self.frames * rate / 60) as usize
+    }
+
+    // Appends the samples of the next 60 Hz frame, from -1.0 to 1.0
+    pub fn render_frame(&mut self, beeping: bool, samples: &mut Vec<f32>) {
+        let len = self.frame_len();
+        let step = match self.pattern {
+            Some(ref pattern) => pattern.rate / 128.0 / self.sample_rate as f64,
+            None => self.tone.frequency / self.sample_rate as f64,
+        };
+        let ramp = 1.0 / (ENVELOPE_SECONDS * self.sample_rate as f64);
+        let target = if beeping { 1.0 } else { 0.0 };
+        samples.reserve(len);
+        for _ in 0..len {
+            if self.level == 0.0 && !beeping {
+                // Every beep starts at the beginning of a period
+                self.phase = 0.0;
+                samples.push(0.0);
+                continue;
+            }
+            self.level += (target - self.level).clamp(-ramp, ramp);
+            let wave = match (self.pattern, self.tone.waveform) {
+                (Some(ref pattern), _) if pattern.bit((self.phase * 128.0) as usize) => 1.0,
+                (Some(_), _) => -1.0,
+                (None, Waveform::Square) if self.phase < 0.5 => 1.0,
+                (None, Waveform::Square) => -1.0,
+                (None, Waveform::Sine) => (self.phase * 2.0 * PI).sin(),
+            };
+            samples.push((wave * self.level) as f32 * self.tone.volume);
+            self.phase = (self.phase + step).fract();
+        }
+        self.frames += 1;
+    }
+}
+
+// Writes everything it receives into a mono 16-bit PCM WAV file
+pub struct WavSink<W: Write + Seek = BufWriter<File>> {
+    out: W,
+    sample_rate: u32,
+    samples: u32,
+    // The first write error, writing stops there and finish reports it
+    error: Option<io::Error>,
+}
+
+impl WavSink {
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32) -> io::Result<WavSink> {
+        WavSink::new(BufWriter::new(File::create(path)?), sample_rate)
+    }
+}
+
+impl<W: Write + Seek> WavSink<W> {
+    pub fn new(mut out: W, sample_rate: u32) -> io::Result<WavSink<W>> {
+        write_wav_header(&mut out, sample_rate, 0)?;
+        Ok(WavSink {
+            out,
+            sample_rate,
+            samples: 0,
+            error: None,
+        })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Number of samples written so far
+    pub fn len(&self) -> usize {
+        self.samples as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples == 0
+    }
+
+    // Fills in the sizes in the header and returns the writer
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        self.out.seek(SeekFrom::Start(0))?;
+        write_wav_header(&mut self.out, self.sample_rate, self.samples)?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(samples.len() * 2);
+        for &sample in samples {
+            bytes.extend_from_slice(&to_i16(sample).to_le_bytes());
+        }
+        self.out.write_all(&bytes)?;
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> AudioSink for WavSink<W> {
+    fn sample_rate(&self) -> Option<u32> {
+        Some(self.sample_rate)
+    }
+
+    fn queue(&mut self, samples: &[f32]) {
+        if self.error.is_none() {
+            if let Err(err) = self.write_samples(samples) {
+                self.error = Some(err);
+            }
+        }
+    }
+}
+
+// A sample in the range of 16-bit PCM
+pub fn to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16
+}
+
+// RIFF header with a single fmt and data chunk for mono 16-bit samples
+fn write_wav_header<W: Write>(out: &mut W, sample_rate: u32, samples: u32) -> io::Result<()> {
+    let data_len = samples * 2;
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_len).to_le_bytes())?;
+    out.write_all(b"WAVEfmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    // PCM, 1 channel
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&sample_rate.to_le_bytes())?;
+    // Bytes per second, bytes per sample and bits per sample
+    out.write_all(&(sample_rate * 2).to_le_bytes())?;
+    out.write_all(&2u16.to_le_bytes())?;
+    out.write_all(&16u16.to_le_bytes())?;
+    out.write_all(b"data")?;
+    out.write_all(&data_len.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quirks::Quirks;
+    use std::io::Cursor;
+
+    #[test]
+    fn beeper_fades_in_and_out() {
+        let mut beeper = Beeper::new(Tone::default(), 48000);
+        let mut samples = Vec::new();
+        beeper.render_frame(false, &mut samples);
+        assert_eq!(samples, vec![0.0; 800]);
+
+        samples.clear();
+        beeper.render_frame(true, &mut samples);
+        // 5 ms are 240 samples to reach the full volume
+        assert!(samples[0].abs() < 0.01);
+        assert!(samples[100].abs() < samples[200].abs());
+        // A 440 Hz square wave flips its sign every 54.5 samples
+        assert_eq!(samples[300], -0.25);
+        assert_eq!(samples[330], 0.25);
+
+        samples.clear();
+        beeper.render_frame(false, &mut samples);
+        assert!(samples[0].abs() > 0.24);
+        assert!(samples[239].abs() < 0.01);
+        assert!(samples[240..].iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn frames_split_odd_sample_rates_evenly() {
+        let mut beeper = Beeper::new(Tone::default(), 22050);
+        let mut samples = Vec::new();
+        let lens = (0..60)
+            .map(|_| {
+                let len = beeper.frame_len();
+                beeper.render_frame(true, &mut samples);
+                len
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(&lens[..2], &[367, 368]);
+        assert_eq!(samples.len(), 22050);
+    }
+
+    #[test]
+    fn patterns_loop_at_their_playback_rate() {
+        // 4000 samples per second is 8 pattern bits per sample at 500 Hz
+        let mut beeper = Beeper::new(Tone::default(), 500);
+        let mut bits = [0; 16];
+        bits[0] = 0xFF;
+        beeper.set_pattern(Some(Pattern { bits, rate: 4000.0 }));
+        let mut samples = Vec::new();
+        for _ in 0..3 {
+            beeper.render_frame(true, &mut samples);
+        }
+        // A loop takes 16 samples, the first of which has the set bits
+        let lit = samples
+            .iter()
+            .map(|&sample| sample > 0.0)
+            .collect::<Vec<_>>();
+        assert_eq!(samples.len(), 25);
+        assert_eq!(lit.iter().filter(|&&lit| lit).count(), 2);
+        assert!(lit[0] && lit[16]);
+        assert_eq!(samples[15], -0.25);
+    }
+
+    #[test]
+    fn loaded_silent_patterns_replace_the_tone() {
+        // I := 0x300, which holds zeros, then F002
+        let mut cpu = CPU::new(Quirks::xo_chip());
+        cpu.load_rom(&[0xA3, 0x00, 0xF0, 0x02]).unwrap();
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(Pattern::from_cpu(&cpu), None);
+        cpu.emulate_cycle().unwrap();
+        let pattern = Pattern::from_cpu(&cpu).unwrap();
+        assert_eq!(pattern.bits, [0; 16]);
+    }
+
+    #[test]
+    fn wav_sink_writes_pcm() {
+        let mut sink = WavSink::new(Cursor::new(Vec::new()), 8000).unwrap();
+        sink.queue(&[0.0, 1.0, -1.0, 0.5]);
+        let wav = sink.finish().unwrap().into_inner();
+        assert_eq!(wav.len(), 44 + 8);
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(&wav[4..8], &44u32.to_le_bytes());
+        assert_eq!(&wav[24..28], &8000u32.to_le_bytes());
+        assert_eq!(&wav[40..44], &8u32.to_le_bytes());
+        let samples = wav[44..]
+            .chunks(2)
+            .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]))
+            .collect::<Vec<_>>();
+        assert_eq!(samples, [0, 32767, -32767, 16384]);
+    }
+}
@@ -0,0 +1,603 @@
+// The command line: subcommands, their options and help texts
+// Parsing only checks the arguments, main reads the files they name
+
+use rusty_chip::audio::{Tone, Waveform};
+use rusty_chip::disasm::Mode;
+use rusty_chip::palette::Palette;
+use rusty_chip::quirks::Quirks;
+use rusty_chip::trace::{OpcodeClass, TraceFilter, TraceFormat};
+use std::str::FromStr;
+use std::vec;
+
+// The port GDB's documentation uses in its examples
+pub const DEFAULT_GDB_PORT: u16 = 1234;
+
+// 10 seconds of emulated time
+pub const DEFAULT_BENCH_FRAMES: usize = 600;
+
+// Size of a low resolution pixel in the window
+pub const DEFAULT_SCALE: usize = 8;
+
+const HELP: &str = "\
+RustyChip, a CHIP-8, SUPER-CHIP and XO-CHIP emulator
+
+Usage: rusty_chip [run] <rom> [options]
+       rusty_chip <command> [arguments]
+
+Commands:
+  run      Run a ROM or Octo source (.8o) in a window or the terminal, the default
+  disasm   Print a listing of a ROM
+  asm      Assemble mnemonics or compile Octo source into a ROM
+  info     Show the size, SHA-1 and database entry of a ROM
+  bench    Measure how fast a ROM is emulated
+  gdb      Let GDB debug a ROM over its remote protocol
+  trace    Print a binary trace as text
+  help     Show this or the help of a command
+
+Run rusty_chip help <command> for the options of a command.
+Exits with 0 on success, 1 when something failed and 2 for invalid arguments.
+";
+
+const RUN_HELP: &str = "\
+Usage: rusty_chip [run] <rom> [options]
+
+Runs the ROM, ROMs in the database get its quirks, speed, colours and keys.
+
+Emulation:
+  --speed <n>            Instructions per frame, 60 frames per second
+  --quirks <name>        default, vip, chip48, superchip, octo or xo-chip
+  --seed <n>             Seed of the random number generator
+  --debug                Start stopped in the debugger, commands are read from the terminal
+
+Frontends:
+  --tui                  Run in the terminal instead of a window
+  --headless             Run without a window or terminal and print the last screen,
+  --frames <n>           for this many frames
+  --scale <n>            Size of a pixel in the window, 8 by default
+  --palette <colours>    default, amber, green, lcd or up to 4 hex colours like 000000,FFB000
+  --keymap <keymap>      qwerty, qwertz, azerty, numpad or a keymap file
+
+Sound:
+  --mute                 Don't play the beeper
+  --tone <waveform>      square or sine
+  --frequency <Hz>       Pitch of the beeper, 440 by default
+  --volume <0-100>       Loudness of the beeper, 25 by default
+
+Recording:
+  --record <file>        Save the keys of the run as a movie
+  --play <file>          Play a movie back
+  --video <path>         Record a GIF, a Y4M stream or a directory of PNGs
+  --wav <file>           Record the sound as a WAV file
+  --trace <file>         Write every executed instruction to the file
+  --trace-binary         Write the trace in the compact binary format
+  --trace-pc <from>-<to> Only trace instructions in the address range, in hex
+  --trace-class <list>   Only trace flow, arithmetic, memory, display, timer, input or
+                         audio instructions, separated by commas
+";
+
+const DISASM_HELP: &str = "\
+Usage: rusty_chip disasm [--linear] [--source] <rom>
+
+Prints a listing of the ROM, only what is reachable from the entry point is code.
+  --linear    Decode every word as an instruction
+  --source    Only print labels and mnemonics, ready for rusty_chip asm
+";
+
+const ASM_HELP: &str = "\
+Usage: rusty_chip asm <source> [-o <rom>]
+
+Writes the ROM next to the source with the extension .ch8 unless -o is given.
+Sources ending in .8o are compiled as Octo, everything else is assembled as mnemonics.
+";
+
+const INFO_HELP: &str = "\
+Usage: rusty_chip info <rom>
+
+Shows the size and SHA-1 of the ROM and what the ROM database knows about it.
+";
+
+const BENCH_HELP: &str = "\
+Usage: rusty_chip bench <rom> [--frames <n>] [--speed <n>] [--quirks <name>]
+
+Emulates the ROM without any frontend as fast as possible, 600 frames by default,
+and reports how much faster than real time that is.
+";
+
+const GDB_HELP: &str = "\
+Usage: rusty_chip gdb <rom> [port]
+
+Waits for a GDB remote connection on localhost, port 1234 by default.
+";
+
+const TRACE_HELP: &str = "\
+Usage: rusty_chip trace <file>
+
+Prints a trace written with --trace-binary in the text format.
+";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Run(Box<RunOptions>),
+    Disasm {
+        rom: String,
+        mode: Mode,
+        source: bool,
+    },
+    Asm {
+        source: String,
+        output: Option<String>,
+    },
+    Info {
+        rom: String,
+    },
+    Bench {
+        rom: String,
+        frames: usize,
+        speed: Option<usize>,
+        quirks: Option<Quirks>,
+    },
+    Gdb {
+        rom: String,
+        port: u16,
+    },
+    Trace {
+        path: String,
+    },
+    // The help of a command, or the overview
+    Help(Option<String>),
+}
+
+// Settings left out are taken from the ROM database or the defaults
+#[derive(Debug, PartialEq)]
+pub struct RunOptions {
+    pub rom: String,
+    pub debug: bool,
+    pub tui: bool,
+    // Frames to run without a frontend
+    pub headless: Option<usize>,
+    pub speed: Option<usize>,
+    pub quirks: Option<Quirks>,
+    pub seed: Option<u64>,
+    pub scale: usize,
+    pub palette: Option<Palette>,
+    pub keymap: Option<String>,
+    // None when muted
+    pub tone: Option<Tone>,
+    pub record_path: Option<String>,
+    pub play_path: Option<String>,
+    pub video_path: Option<String>,
+    pub wav_path: Option<String>,
+    pub trace_path: Option<String>,
+    pub trace_format: TraceFormat,
+    pub trace_filter: TraceFilter,
+}
+
+impl RunOptions {
+    pub fn new(rom: String) -> RunOptions {
+        RunOptions {
+            rom,
+            debug: false,
+            tui: false,
+            headless: None,
+            speed: None,
+            quirks: None,
+            seed: None,
+            scale: DEFAULT_SCALE,
+            palette: None,
+            keymap: None,
+            tone: Some(Tone::default()),
+            record_path: None,
+            play_path: None,
+            video_path: None,
+            wav_path: None,
+            trace_path: None,
+            trace_format: TraceFormat::Text,
+            trace_filter: TraceFilter::default(),
+        }
+    }
+}
+
+// The help text for the command, None for commands that don't exist
+pub fn help(command: Option<&str>) -> Option<&'static str> {
+    match command {
+        None | Some("help") => Some(HELP),
+        Some("run") => Some(RUN_HELP),
+        Some("disasm") => Some(DISASM_HELP),
+        Some("asm") => Some(ASM_HELP),
+        Some("info") => Some(INFO_HELP),
+        Some("bench") => Some(BENCH_HELP),
+        Some("gdb") => Some(GDB_HELP),
+        Some("trace") => Some(TRACE_HELP),
+        Some(_) => None,
+    }
+}
+
+// Parses the arguments after the program name, errors explain what's wrong with them
+pub fn parse(mut args: Vec<String>) -> Result<Command, String> {
+    let first = args.first().cloned().unwrap_or_else(|| "help".to_string());
+    if first == "--help" || first == "-h" || first == "help" {
+        let topic = args.get(1).cloned();
+        if help(topic.as_deref()).is_none() {
+            return Err(format!("Unknown command {}", topic.unwrap_or_default()));
+        }
+        return Ok(Command::Help(topic));
+    }
+    // Without a command the arguments are those of run
+    let command = match help(Some(&first)) {
+        Some(_) => args.remove(0),
+        None => "run".to_string(),
+    };
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        return Ok(Command::Help(Some(command)));
+    }
+    let mut args = Args {
+        command,
+        args: args.into_iter(),
+        positional: Vec::new(),
+    };
+    match args.command.as_str() {
+        "disasm" => parse_disasm(args),
+        "asm" => parse_asm(args),
+        "info" => {
+            let rom = args.only_positional("<rom>")?;
+            Ok(Command::Info { rom })
+        }
+        "bench" => parse_bench(args),
+        "gdb" => {
+            args.finish_options()?;
+            let port = match args.positional.get(1) {
+                Some(port) => parse_number("port", port)?,
+                None => DEFAULT_GDB_PORT,
+            };
+            if args.positional.len() > 2 {
+                return Err(format!("Unexpected argument {}", args.positional[2]));
+            }
+            let rom = args.positional.first().cloned();
+            let rom = rom.ok_or("gdb needs a ROM")?;
+            Ok(Command::Gdb { rom, port })
+        }
+        "trace" => {
+            let path = args.only_positional("<file>")?;
+            Ok(Command::Trace { path })
+        }
+        _ => parse_run(args).map(|options| Command::Run(Box::new(options))),
+    }
+}
+
+fn parse_run(mut args: Args) -> Result<RunOptions, String> {
+    let mut options = RunOptions::new(String::new());
+    let mut frames = None;
+    let mut headless = false;
+    let mut tone = Tone::default();
+    let mut muted = false;
+    while let Some(option) = args.next_option() {
+        match option.as_str() {
+            "--debug" => options.debug = true,
+            "--tui" => options.tui = true,
+            "--headless" => headless = true,
+            "--frames" => frames = Some(args.number(&option)?),
+            "--speed" => options.speed = Some(args.positive(&option)?),
+            "--quirks" => {
+                let name = args.value(&option)?;
+                options.quirks = Some(Quirks::from_name(&name).ok_or_else(|| {
+                    format!(
+                        "Unknown quirks {}, use default, vip, chip48, superchip, octo or xo-chip",
+                        name
+                    )
+                })?);
+            }
+            "--seed" => options.seed = Some(args.number(&option)?),
+            "--scale" => {
+                options.scale = args.positive(&option)?;
+                if options.scale > 32 {
+                    return Err("--scale can be at most 32".to_string());
+                }
+            }
+            "--palette" => {
+                let palette = args.value(&option)?;
+                options.palette = Some(Palette::parse(&palette).ok_or_else(|| {
+                    format!("Invalid palette {}, use a name or hex colours", palette)
+                })?);
+            }
+            "--keymap" => options.keymap = Some(args.value(&option)?),
+            "--mute" => muted = true,
+            "--tone" => {
+                let name = args.value(&option)?;
+                tone.waveform = Waveform::from_name(&name)
+                    .ok_or_else(|| format!("Unknown waveform {}, use square or sine", name))?;
+            }
+            "--frequency" => {
+                tone.frequency = args.number(&option)?;
+                if !(tone.frequency > 0.0 && tone.frequency < 20000.0) {
+                    return Err("--frequency has to be between 0 and 20000 Hz".to_string());
+                }
+            }
+            "--volume" => {
+                let percent: u8 = args.number(&option)?;
+                if percent > 100 {
+                    return Err("--volume has to be between 0 and 100".to_string());
+                }
+                tone.volume = percent as f32 / 100.0;
+            }
+            "--record" => options.record_path = Some(args.value(&option)?),
+            "--play" => options.play_path = Some(args.value(&option)?),
+            "--video" => options.video_path = Some(args.value(&option)?),
+            "--wav" => options.wav_path = Some(args.value(&option)?),
+            "--trace" => options.trace_path = Some(args.value(&option)?),
+            "--trace-binary" => options.trace_format = TraceFormat::Binary,
+            "--trace-pc" => {
+                let range = args.value(&option)?;
+                options.trace_filter.pc_range = Some(parse_pc_range(&range).ok_or_else(|| {
+                    format!("--trace-pc needs a range like 200-2FF, not \"{}\"", range)
+                })?);
+            }
+            "--trace-class" => {
+                for name in args.value(&option)?.split(',') {
+                    let class = OpcodeClass::from_name(name).ok_or_else(|| {
+                        format!(
+                            "Unknown instruction class \"{}\", expected flow, arithmetic, \
+                             memory, display, timer, input or audio",
+                            name
+                        )
+                    })?;
+                    options.trace_filter.classes.push(class);
+                }
+            }
+            _ => return Err(args.unknown(&option)),
+        }
+    }
+    options.rom = args.only_positional("<rom>")?;
+    options.tone = if muted { None } else { Some(tone) };
+    options.headless = match (headless, frames) {
+        (true, Some(frames)) => Some(frames),
+        (true, None) => return Err("--headless needs --frames".to_string()),
+        (false, Some(_)) => return Err("--frames only works with --headless".to_string()),
+        (false, None) => None,
+    };
+
+    let movie = options.record_path.is_some() || options.play_path.is_some();
+    if options.record_path.is_some() && options.play_path.is_some() {
+        return Err("--record and --play can't be combined".to_string());
+    }
+    if options.play_path.is_some()
+        && (options.seed.is_some() || options.quirks.is_some() || options.speed.is_some())
+    {
+        return Err("Movies play with the seed, quirks and speed they were recorded with".into());
+    }
+    if options.debug {
+        let conflict = if movie {
+            Some("Movies can't be recorded or played in the debugger")
+        } else if options.video_path.is_some() {
+            Some("Videos can't be recorded in the debugger")
+        } else if options.wav_path.is_some() {
+            Some("Audio can't be recorded in the debugger")
+        } else if options.tui {
+            Some("The debugger needs the terminal, it can't run with --tui")
+        } else if headless {
+            Some("The debugger can't run with --headless")
+        } else {
+            None
+        };
+        if let Some(conflict) = conflict {
+            return Err(conflict.to_string());
+        }
+    }
+    if headless && options.tui {
+        return Err("--headless and --tui can't be combined".to_string());
+    }
+    Ok(options)
+}
+
+fn parse_disasm(mut args: Args) -> Result<Command, String> {
+    let mut mode = Mode::Recursive;
+    let mut source = false;
+    while let Some(option) = args.next_option() {
+        match option.as_str() {
+            "--linear" => mode = Mode::Linear,
+            "--source" => source = true,
+            _ => return Err(args.unknown(&option)),
+        }
+    }
+    let rom = args.only_positional("<rom>")?;
+    Ok(Command::Disasm { rom, mode, source })
+}
+
+fn parse_asm(mut args: Args) -> Result<Command, String> {
+    let mut output = None;
+    while let Some(option) = args.next_option() {
+        match option.as_str() {
+            "-o" | "--output" => output = Some(args.value(&option)?),
+            _ => return Err(args.unknown(&option)),
+        }
+    }
+    let source = args.only_positional("<source>")?;
+    Ok(Command::Asm { source, output })
+}
+
+fn parse_bench(mut args: Args) -> Result<Command, String> {
+    let mut frames = DEFAULT_BENCH_FRAMES;
+    let mut speed = None;
+    let mut quirks = None;
+    while let Some(option) = args.next_option() {
+        match option.as_str() {
+            "--frames" => frames = args.positive(&option)?,
+            "--speed" => speed = Some(args.positive(&option)?),
+            "--quirks" => {
+                let name = args.value(&option)?;
+                quirks = Some(Quirks::from_name(&name).ok_or(format!("Unknown quirks {}", name))?);
+            }
+            _ => return Err(args.unknown(&option)),
+        }
+    }
+    let rom = args.only_positional("<rom>")?;
+    Ok(Command::Bench {
+        rom,
+        frames,
+        speed,
+        quirks,
+    })
+}
+
+// The arguments of a command, options are taken one by one while everything else is
+// collected as positional arguments
+struct Args {
+    command: String,
+    args: vec::IntoIter<String>,
+    positional: Vec<String>,
+}
+
+impl Args {
+    // The next argument starting with -, a lone - is positional
+    fn next_option(&mut self) -> Option<String> {
+        for arg in self.args.by_ref() {
+            if arg.starts_with('-') && arg.len() > 1 {
+                return Some(arg);
+            }
+            self.positional.push(arg);
+        }
+        None
+    }
+
+    fn finish_options(&mut self) -> Result<(), String> {
+        match self.next_option() {
+            Some(option) => Err(self.unknown(&option)),
+            None => Ok(()),
+        }
+    }
+
+    // The only positional argument, named like in the usage
+    fn only_positional(&mut self, name: &str) -> Result<String, String> {
+        self.finish_options()?;
+        match self.positional.len() {
+            0 => Err(format!("{} needs {}", self.command, name)),
+            1 => Ok(self.positional.remove(0)),
+            _ => Err(format!("Unexpected argument {}", self.positional[1])),
+        }
+    }
+
+    fn value(&mut self, option: &str) -> Result<String, String> {
+        self.args
+            .next()
+            .ok_or_else(|| format!("{} needs a value", option))
+    }
+
+    fn number<T: FromStr>(&mut self, option: &str) -> Result<T, String> {
+        let value = self.value(option)?;
+        parse_number(option, &value)
+    }
+
+    fn positive(&mut self, option: &str) -> Result<usize, String> {
+        match self.number(option)? {
+            0 => Err(format!("{} has to be at least 1", option)),
+            number => Ok(number),
+        }
+    }
+
+    fn unknown(&self, option: &str) -> String {
+        format!("Unknown option {} for {}", option, self.command)
+    }
+}
+
+fn parse_number<T: FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value \"{}\" for {}", value, name))
+}
+
+fn parse_pc_range(range: &str) -> Option<(usize, usize)> {
+    let mut bounds = range.splitn(2, '-').map(|bound| {
+        let bound = bound.trim_start_matches("0x");
+        usize::from_str_radix(bound, 16).ok()
+    });
+    Some((bounds.next()??, bounds.next()??))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_line(line: &str) -> Result<Command, String> {
+        parse(line.split_whitespace().map(String::from).collect())
+    }
+
+    #[test]
+    fn run_is_the_default_command() {
+        let options = match parse_line("--tui game.ch8 --speed 20 --quirks vip --mute") {
+            Ok(Command::Run(options)) => options,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(options.rom, "game.ch8");
+        assert!(options.tui);
+        assert_eq!(options.speed, Some(20));
+        assert_eq!(options.quirks, Some(Quirks::cosmac_vip()));
+        assert_eq!(options.tone, None);
+        assert_eq!(options.scale, DEFAULT_SCALE);
+
+        let options = match parse_line("run game.ch8 --headless --frames 60 --seed 7") {
+            Ok(Command::Run(options)) => options,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!((options.headless, options.seed), (Some(60), Some(7)));
+
+        assert_eq!(
+            parse_line("game.ch8 --headless"),
+            Err("--headless needs --frames".to_string())
+        );
+        assert_eq!(
+            parse_line("game.ch8 other.ch8"),
+            Err("Unexpected argument other.ch8".to_string())
+        );
+        assert_eq!(
+            parse_line("game.ch8 --turbo"),
+            Err("Unknown option --turbo for run".to_string())
+        );
+        assert_eq!(
+            parse_line("game.ch8 --speed fast"),
+            Err("Invalid value \"fast\" for --speed".to_string())
+        );
+        assert_eq!(parse_line("--tui"), Err("run needs <rom>".to_string()));
+    }
+
+    #[test]
+    fn subcommands_and_help() {
+        assert_eq!(
+            parse_line("disasm --linear game.ch8"),
+            Ok(Command::Disasm {
+                rom: "game.ch8".to_string(),
+                mode: Mode::Linear,
+                source: false,
+            })
+        );
+        assert_eq!(
+            parse_line("bench game.ch8 --frames 60"),
+            Ok(Command::Bench {
+                rom: "game.ch8".to_string(),
+                frames: 60,
+                speed: None,
+                quirks: None,
+            })
+        );
+        assert_eq!(
+            parse_line("gdb game.ch8"),
+            Ok(Command::Gdb {
+                rom: "game.ch8".to_string(),
+                port: DEFAULT_GDB_PORT,
+            })
+        );
+        assert_eq!(parse_line(""), Ok(Command::Help(None)));
+        assert_eq!(parse_line("--help"), Ok(Command::Help(None)));
+        assert_eq!(
+            parse_line("help asm"),
+            Ok(Command::Help(Some("asm".into())))
+        );
+        assert_eq!(
+            parse_line("info -h"),
+            Ok(Command::Help(Some("info".into())))
+        );
+        assert_eq!(
+            parse_line("game.ch8 -h"),
+            Ok(Command::Help(Some("run".into())))
+        );
+        assert!(parse_line("help fly").is_err());
+    }
+}
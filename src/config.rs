@@ -0,0 +1,327 @@
+// Reads the small part of TOML the configuration files use:
+// [tables] and [dotted."quoted".tables], key = value pairs with bare or quoted keys, and
+// strings, integers, booleans and arrays of them on a single line
+// # starts a comment anywhere outside of a string
+
+use error::ConfigError;
+use std::env;
+use std::fs;
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::str::Chars;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::String(ref string) => Some(string),
+            _ => None,
+        }
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match *self {
+            Value::Integer(integer) => Some(integer),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Value::Boolean(boolean) => Some(boolean),
+            _ => None,
+        }
+    }
+
+    // A string or an array of strings, for settings that take one or more
+    pub fn as_strings(&self) -> Option<Vec<&str>> {
+        match *self {
+            Value::String(ref string) => Some(vec![string]),
+            Value::Array(ref values) => values.iter().map(Value::as_str).collect(),
+            _ => None,
+        }
+    }
+}
+
+// The key = value pairs below a [header], the pairs before the first header are in a
+// table without a name
+#[derive(Debug, Clone, PartialEq)]
+pub struct Table {
+    // The dotted parts of the header, [rom."a.ch8"] is ["rom", "a.ch8"]
+    pub name: Vec<String>,
+    // Where the header is, 0 for the table without a name
+    pub line: usize,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub key: String,
+    pub value: Value,
+    pub line: usize,
+}
+
+impl Table {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.entries
+            .iter()
+            .find(|entry| entry.key == key)
+            .map(|entry| &entry.value)
+    }
+}
+
+// Where the user's configuration files go, $XDG_CONFIG_HOME/rusty_chip or
+// ~/.config/rusty_chip
+pub fn user_dir() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("rusty_chip"))
+}
+
+// Reads and parses the file, errors name it as given
+pub fn read<P: AsRef<Path>>(path: P) -> Result<Vec<Table>, ConfigError> {
+    let path = path.as_ref();
+    let file = path.display().to_string();
+    match fs::read_to_string(path) {
+        Ok(text) => parse(&file, &text),
+        Err(err) => Err(ConfigError {
+            file,
+            line: 0,
+            message: err.to_string(),
+        }),
+    }
+}
+
+// Parses the text of a file, the first table is always the one without a name
+pub fn parse(file: &str, text: &str) -> Result<Vec<Table>, ConfigError> {
+    let mut tables = vec![Table {
+        name: Vec::new(),
+        line: 0,
+        entries: Vec::new(),
+    }];
+    for (index, text) in text.lines().enumerate() {
+        let line = index + 1;
+        let error = |message: String| ConfigError {
+            file: file.to_string(),
+            line,
+            message,
+        };
+        let mut chars = text.chars().peekable();
+        skip_blanks(&mut chars);
+        match chars.peek() {
+            None | Some('#') => continue,
+            Some('[') => {
+                chars.next();
+                let name = parse_header(&mut chars).map_err(&error)?;
+                if tables.iter().any(|table| table.name == name) {
+                    return Err(error(format!("[{}] is defined twice", name.join("."))));
+                }
+                tables.push(Table {
+                    name,
+                    line,
+                    entries: Vec::new(),
+                });
+            }
+            Some(_) => {
+                let (key, value) = parse_entry(&mut chars).map_err(&error)?;
+                let table = tables.last_mut().unwrap();
+                if table.get(&key).is_some() {
+                    return Err(error(format!("{} is set twice", key)));
+                }
+                table.entries.push(Entry { key, value, line });
+            }
+        }
+    }
+    Ok(tables)
+}
+
+fn parse_header(chars: &mut Peekable<Chars>) -> Result<Vec<String>, String> {
+    let mut name = Vec::new();
+    loop {
+        skip_blanks(chars);
+        name.push(parse_key(chars)?);
+        skip_blanks(chars);
+        match chars.next() {
+            Some('.') => (),
+            Some(']') => break,
+            _ => return Err("Expected . or ] in the table header".to_string()),
+        }
+    }
+    end_of_line(chars)?;
+    Ok(name)
+}
+
+fn parse_entry(chars: &mut Peekable<Chars>) -> Result<(String, Value), String> {
+    let key = parse_key(chars)?;
+    skip_blanks(chars);
+    if chars.next() != Some('=') {
+        return Err(format!("Expected = after {}", key));
+    }
+    skip_blanks(chars);
+    let value = parse_value(chars)?;
+    end_of_line(chars)?;
+    Ok((key, value))
+}
+
+// A bare key of letters, digits, _ and -, or a quoted one
+fn parse_key(chars: &mut Peekable<Chars>) -> Result<String, String> {
+    if chars.peek() == Some(&'"') {
+        chars.next();
+        return parse_string(chars);
+    }
+    let mut key = String::new();
+    while let Some(&c) = chars.peek() {
+        if !(c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            break;
+        }
+        key.push(c);
+        chars.next();
+    }
+    if key.is_empty() {
+        return Err("Expected a key".to_string());
+    }
+    Ok(key)
+}
+
+fn parse_value(chars: &mut Peekable<Chars>) -> Result<Value, String> {
+    match chars.peek() {
+        Some('"') => {
+            chars.next();
+            parse_string(chars).map(Value::String)
+        }
+        Some('[') => {
+            chars.next();
+            let mut values = Vec::new();
+            loop {
+                skip_blanks(chars);
+                if chars.peek() == Some(&']') {
+                    chars.next();
+                    return Ok(Value::Array(values));
+                }
+                values.push(parse_value(chars)?);
+                skip_blanks(chars);
+                match chars.next() {
+                    Some(',') => (),
+                    Some(']') => return Ok(Value::Array(values)),
+                    _ => return Err("Expected , or ] in the array".to_string()),
+                }
+            }
+        }
+        _ => {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == ',' || c == ']' || c == '#' {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            parse_word(&word).ok_or_else(|| format!("Invalid value \"{}\"", word))
+        }
+    }
+}
+
+// Booleans and integers, which may be hexadecimal with 0x and have _ between digits
+fn parse_word(word: &str) -> Option<Value> {
+    match word {
+        "true" => return Some(Value::Boolean(true)),
+        "false" => return Some(Value::Boolean(false)),
+        _ => (),
+    }
+    let digits = word.replace('_', "");
+    let (negative, digits) = match digits.strip_prefix('-') {
+        Some(digits) => (true, digits.to_string()),
+        None => (false, digits.trim_start_matches('+').to_string()),
+    };
+    let integer = match digits.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None if digits.chars().all(|c| c.is_ascii_digit()) => digits.parse().ok()?,
+        None => return None,
+    };
+    Some(Value::Integer(if negative { -integer } else { integer }))
+}
+
+// The rest of a string after the opening quote, with the escapes \" \\ \n and \t
+fn parse_string(chars: &mut Peekable<Chars>) -> Result<String, String> {
+    let mut string = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(string),
+            Some('\\') => match chars.next() {
+                Some('"') => string.push('"'),
+                Some('\\') => string.push('\\'),
+                Some('n') => string.push('\n'),
+                Some('t') => string.push('\t'),
+                _ => return Err("Unknown escape in string".to_string()),
+            },
+            Some(c) => string.push(c),
+            None => return Err("Unterminated string".to_string()),
+        }
+    }
+}
+
+fn end_of_line(chars: &mut Peekable<Chars>) -> Result<(), String> {
+    skip_blanks(chars);
+    match chars.peek() {
+        None | Some('#') => Ok(()),
+        Some(_) => Err(format!(
+            "Unexpected \"{}\" at the end of the line",
+            chars.collect::<String>()
+        )),
+    }
+}
+
+fn skip_blanks(chars: &mut Peekable<Chars>) {
+    while chars.peek().is_some_and(|c| c.is_whitespace()) {
+        chars.next();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_tables_and_values() {
+        let text = r#"
+            # Comments and blank lines are skipped
+            layout = "qwerty" # after values too
+            1 = ["1", "numpad7",]
+
+            [rom."pong.ch8"]
+            speed = 0x1_0
+            hires = false
+            name = "say \"hi\""
+        "#;
+        let tables = parse("test.toml", text).unwrap();
+        assert_eq!(tables.len(), 2);
+        assert_eq!(
+            tables[0].get("layout"),
+            Some(&Value::String("qwerty".into()))
+        );
+        let keys = tables[0].get("1").and_then(Value::as_strings);
+        assert_eq!(keys, Some(vec!["1", "numpad7"]));
+
+        assert_eq!(tables[1].name, ["rom", "pong.ch8"]);
+        assert_eq!(tables[1].line, 6);
+        assert_eq!(tables[1].get("speed").and_then(Value::as_integer), Some(16));
+        assert_eq!(tables[1].get("hires").and_then(Value::as_bool), Some(false));
+        assert_eq!(tables[1].get("name").unwrap().as_str(), Some("say \"hi\""));
+
+        let error = parse("test.toml", "a = 1\na = 2").unwrap_err();
+        assert_eq!(error.to_string(), "test.toml:2: a is set twice");
+        let error = parse("test.toml", "a = \"open").unwrap_err();
+        assert_eq!(error.to_string(), "test.toml:1: Unterminated string");
+        let error = parse("test.toml", "a = 1 2").unwrap_err();
+        assert_eq!(error.message, "Unexpected \"2\" at the end of the line");
+    }
+}
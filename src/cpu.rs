@@ -1,11 +1,27 @@
 use self::Opcode::*;
+use error::{CpuError, StateError};
+use quirks::{IndexIncrement, Quirks};
 use rand;
+use state::{Snapshot, StateReader, StateWriter};
 use std::fmt;
 
 // Memory Map
 // 0x000-0x1FF - Chip 8 interpreter (contains font set in emu)
-// 0x050-0x0A0 - Used for the built in 4x5 pixel font set (0-F)
+// 0x000-0x050 - Used for the built in 4x5 pixel font set (0-F)
+// 0x050-0x0F0 - Used for the SUPER-CHIP 8x10 pixel font set (0-F)
 // 0x200-0xFFF - Program ROM and work RAM
+// XO-CHIP extends the address space up to 0xFFFF
+
+const MEMORY_SIZE: usize = 0x1000;
+const XO_CHIP_MEMORY_SIZE: usize = 0x10000;
+
+// Display resolutions, SUPER-CHIP adds the high resolution mode
+const LORES_WIDTH: usize = 64;
+const LORES_HEIGHT: usize = 32;
+const HIRES_WIDTH: usize = 128;
+const HIRES_HEIGHT: usize = 64;
+
+const LARGE_FONTSET_START: usize = 0x50;
 
 // 15 1-byte general purpose registers
 // The 16th register is used for the ‘carry flag’
@@ -28,13 +44,35 @@ impl fmt::Display for Register {
             self.v[0], self.v[1], self.v[2],self.v[3], self.v[4], self.v[5],
             self.v[6], self.v[7], self.v[8],self.v[9], self.v[10], self.v[11],
             self.v[12], self.v[13], self.v[14],self.v[15]
-        );
-        Ok(())
+        )
     }
 }
 
-#[derive(Debug)]
-enum Opcode {
+// What happened in a single emulate_cycle
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepOutcome {
+    // The instruction was executed
+    Continue,
+    // The instruction was executed and changed gfx
+    Draw,
+    // The instruction is blocked on a key press or the display wait quirk, the PC did not move
+    Waiting,
+    // The ROM executed 00FD, or did so earlier
+    Exited,
+}
+
+// A range of memory an instruction reads or writes, see CPU::next_memory_access
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryAccess {
+    pub address: usize,
+    pub len: usize,
+    pub write: bool,
+}
+
+// A decoded instruction, the fields are the register numbers and immediates in the order
+// they appear in the opcode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
     Ignore,
     ClearScreen,
     Return,
@@ -70,7 +108,25 @@ enum Opcode {
     StoreBinaryCodedDecimal(u16),
     RegisterDump(u16),
     RegisterLoad(u16),
-    UNKNOWN(u16, u16, u16, u16),
+    // SUPER-CHIP
+    ScrollDown(u16),
+    ScrollRight,
+    ScrollLeft,
+    Exit,
+    LowResolution,
+    HighResolution,
+    SetIndexRegisterToLargeSpriteLocation(u16),
+    StoreFlags(u16),
+    LoadFlags(u16),
+    // XO-CHIP
+    // The address is the word after the opcode
+    SetIndexRegisterLong,
+    RegisterRangeDump(u16, u16),
+    RegisterRangeLoad(u16, u16),
+    SelectPlanes(u16),
+    LoadAudioPattern,
+    SetPitch(u16),
+    Unknown(u16),
 }
 
 pub struct CPU {
@@ -78,16 +134,37 @@ pub struct CPU {
     memory: Vec<u8>,
     // Can have values between 0x000 and 0xFFF
     index_register: u16,
-    // Can have values between 0x000 and 0xFFF
-    pub gfx: Vec<u8>,
+    // One byte per pixel, row by row, sized for the current resolution
+    // Bit 0 is the first XO-CHIP bitplane and bit 1 the second, so a pixel is one of 4 colours
+    gfx: Vec<u8>,
+    hires: bool,
+    // Bitmask of the bitplanes that drawing, clearing and scrolling affect
+    selected_planes: u8,
     program_counter: usize,
     register: Register,
     delay_timer: u8,
     sound_timer: u8,
     stack: Vec<u16>,
     stack_pointer: usize,
-    pub keypad: Vec<u8>,
+    // 1 for every pressed key, 0 otherwise
+    pub(crate) keypad: Vec<u8>,
+    // SUPER-CHIP "RPL user flags", which survive a reset on the HP-48
+    rpl_flags: Vec<u8>,
+    // XO-CHIP 1-bit audio samples, played back while the sound timer is active
+    audio_pattern: Vec<u8>,
+    // Set by F002, from then on the pattern plays instead of the tone, even if it's silent
+    audio_pattern_loaded: bool,
+    pitch: u8,
     draw_flag: bool,
+    // Set by 00FD, no further instructions are executed
+    exited: bool,
+    // Set on every 60 Hz tick, consumed by DXYN when the display wait quirk is on
+    vblank: bool,
+    // Whether the sound timer was running at the last tick, i.e. the last frame was audible
+    beeping: bool,
+    quirks: Quirks,
+    // State of the random number generator behind CXNN, see next_random
+    rng_state: u64,
     debug_current_opcode: Opcode,
 }
 
@@ -102,52 +179,403 @@ impl fmt::Display for CPU {
             self.stack_pointer,
             self.index_register,
             self.draw_flag
-        );
-        Ok(())
+        )
     }
 }
 
 impl CPU {
-    pub fn new() -> CPU {
+    // A CPU with a randomly seeded random number generator
+    pub fn new(quirks: Quirks) -> CPU {
+        CPU::with_seed(quirks, rand::random())
+    }
+    // The same seed always produces the same CXNN results, which makes runs reproducible
+    pub fn with_seed(quirks: Quirks, seed: u64) -> CPU {
         let mut cpu = CPU {
-            memory: vec![0; 4096], // 0xfff + 1 = 0x1000
+            memory: vec![
+                0;
+                if quirks.xo_chip {
+                    XO_CHIP_MEMORY_SIZE
+                } else {
+                    MEMORY_SIZE
+                }
+            ],
             keypad: vec![0; 16],
             stack: vec![0; 16],
-            gfx: vec![0; 64 * 32],
+            gfx: vec![0; LORES_WIDTH * LORES_HEIGHT],
+            hires: false,
+            selected_planes: 0b01,
             delay_timer: 0,
             sound_timer: 0,
             stack_pointer: 0,
             index_register: 0,
             program_counter: 0x200, // Start execution from this address
+            rpl_flags: vec![0; 16],
+            audio_pattern: vec![0; 16],
+            audio_pattern_loaded: false,
+            pitch: 64,
             draw_flag: false,
+            exited: false,
+            vblank: false,
+            beeping: false,
+            quirks,
+            rng_state: seed,
             register: Register::new(),
             debug_current_opcode: Ignore,
         };
         // Load the fontset into the first 512 bytes
-        for i in 0..FONTSET.len() {
-            cpu.memory[i] = FONTSET[i];
+        cpu.memory[..FONTSET.len()].copy_from_slice(&FONTSET);
+        cpu.memory[LARGE_FONTSET_START..LARGE_FONTSET_START + LARGE_FONTSET.len()]
+            .copy_from_slice(&LARGE_FONTSET);
+        cpu
+    }
+    // The framebuffer, width() * height() pixels with one byte each
+    pub fn gfx(&self) -> &[u8] {
+        &self.gfx
+    }
+    pub fn keypad(&self) -> &[u8] {
+        &self.keypad
+    }
+    pub fn set_key(&mut self, key: usize, pressed: bool) {
+        self.keypad[key & 0xF] = pressed as u8;
+    }
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+    pub fn sound_timer(&self) -> u8 {
+        self.sound_timer
+    }
+    // Width of gfx in pixels for the current resolution
+    pub fn width(&self) -> usize {
+        if self.hires {
+            HIRES_WIDTH
+        } else {
+            LORES_WIDTH
+        }
+    }
+    // Height of gfx in pixels for the current resolution
+    pub fn height(&self) -> usize {
+        if self.hires {
+            HIRES_HEIGHT
+        } else {
+            LORES_HEIGHT
         }
-        return cpu;
     }
-    pub fn load_rom(&mut self, rom: &Vec<u8>) {
-        for i in 0..rom.len() {
-            self.memory[0x200 + i] = rom[i];
+    // Whether the frame that last ticked the timers had the sound timer running
+    pub fn is_beeping(&self) -> bool {
+        self.beeping
+    }
+    // True once the ROM executed 00FD
+    pub fn has_exited(&self) -> bool {
+        self.exited
+    }
+    // The XO-CHIP audio pattern buffer, 128 samples with 1 bit each
+    pub fn audio_pattern(&self) -> &[u8] {
+        &self.audio_pattern
+    }
+    // True once the ROM loaded an audio pattern with F002
+    pub fn has_audio_pattern(&self) -> bool {
+        self.audio_pattern_loaded
+    }
+    // Playback rate of the audio pattern in samples per second
+    pub fn playback_rate(&self) -> f64 {
+        4000.0 * 2f64.powf((self.pitch as f64 - 64.0) / 48.0)
+    }
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+    pub fn program_counter(&self) -> usize {
+        self.program_counter
+    }
+    // Fails unless a whole instruction fits at the address
+    pub fn set_program_counter(&mut self, address: usize) -> Result<(), CpuError> {
+        self.check_memory(address, 2)?;
+        self.program_counter = address;
+        Ok(())
+    }
+    // V0 to VF
+    pub fn registers(&self) -> &[u8] {
+        &self.register.v
+    }
+    pub fn set_register(&mut self, x: usize, value: u8) {
+        self.register.v[x & 0xF] = value;
+    }
+    pub fn index_register(&self) -> u16 {
+        self.index_register
+    }
+    pub fn set_index_register(&mut self, value: u16) {
+        self.index_register = value;
+    }
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.delay_timer = value;
+    }
+    pub fn set_sound_timer(&mut self, value: u8) {
+        self.sound_timer = value;
+    }
+    // Return addresses of the active subroutine calls, the innermost last
+    pub fn call_stack(&self) -> &[u16] {
+        &self.stack[..self.stack_pointer]
+    }
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+    // The instruction at the program counter, without executing it
+    pub fn next_opcode(&self) -> Option<Opcode> {
+        self.word_at(self.program_counter).map(CPU::decode)
+    }
+    // The memory the instruction at the program counter will read or write through I
+    // Fetching the instruction itself doesn't count
+    pub fn next_memory_access(&self) -> Option<MemoryAccess> {
+        let i = self.index_register as usize;
+        let access = |write, len| {
+            Some(MemoryAccess {
+                address: i,
+                len,
+                write,
+            })
+        };
+        match self.next_opcode()? {
+            Display(_, _, n) => {
+                let len = if n == 0 { 32 } else { n as usize };
+                access(false, len * self.selected_planes.count_ones() as usize)
+            }
+            StoreBinaryCodedDecimal(_) => access(true, 3),
+            RegisterDump(x) => access(true, x as usize + 1),
+            RegisterLoad(x) => access(false, x as usize + 1),
+            RegisterRangeDump(x, y) => access(true, register_range(x, y).count()),
+            RegisterRangeLoad(x, y) => access(false, register_range(x, y).count()),
+            LoadAudioPattern => access(false, 16),
+            _ => None,
+        }
+    }
+    // Decrements both timers, must be called at 60 Hz independently of the instruction rate
+    // This also marks the vertical blank for the display wait quirk
+    pub fn tick_timers(&mut self) {
+        // A sound timer of N sounds for N frames, including the one it runs out in
+        self.beeping = self.sound_timer > 0;
+        if self.delay_timer > 0 {
+            self.delay_timer -= 1;
+        }
+        if self.sound_timer > 0 {
+            self.sound_timer -= 1;
         }
+        self.vblank = true;
     }
-    pub fn emulate_cycle(&mut self) -> bool {
-        let opc = self.fetch();
-        let decoded_opc = self.decode(opc);
-        self.emulate(decoded_opc);
-        return self.draw_flag;
+    // Emulates one 60 Hz frame: executes cycles_per_frame instructions, then ticks the timers once
+    // Returns whether any instruction in the frame drew to the screen
+    // Stops early without ticking the timers if the CPU fails or exits
+    pub fn run_frame(&mut self, cycles_per_frame: usize) -> Result<bool, CpuError> {
+        self.run_frame_with(cycles_per_frame, CPU::emulate_cycle)
     }
-    fn fetch(&self) -> u16 {
+    // Like run_frame, but every instruction is executed through step, e.g. to trace it
+    pub fn run_frame_with<F>(
+        &mut self,
+        cycles_per_frame: usize,
+        mut step: F,
+    ) -> Result<bool, CpuError>
+    where
+        F: FnMut(&mut CPU) -> Result<StepOutcome, CpuError>,
+    {
+        let mut drawn = false;
+        for _ in 0..cycles_per_frame {
+            match step(self)? {
+                StepOutcome::Draw => drawn = true,
+                StepOutcome::Exited => return Ok(drawn),
+                StepOutcome::Continue | StepOutcome::Waiting => (),
+            }
+        }
+        self.tick_timers();
+        Ok(drawn)
+    }
+    // Serializes the complete machine state into a versioned, checksummed save state
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        self.write_state(&mut writer);
+        writer.finish()
+    }
+    // Restores a state written by save_state, including the quirks it was saved with
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        self.read_state(StateReader::new(state)?)
+    }
+    // An in-memory copy of every field, cheaper than a save state as there is no header or checksum
+    pub fn snapshot(&self) -> Snapshot {
+        let mut writer = StateWriter::new();
+        self.write_state(&mut writer);
+        Snapshot::new(writer.into_payload())
+    }
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.read_state(StateReader::from_payload(snapshot.as_bytes()))
+            .expect("A snapshot always holds a valid state");
+    }
+    fn write_state(&self, writer: &mut StateWriter) {
+        writer.quirks(&self.quirks);
+        writer.bytes(&self.memory);
+        writer.bytes(&self.register.v);
+        writer.u16(self.index_register);
+        writer.u32(self.program_counter as u32);
+        for address in &self.stack {
+            writer.u16(*address);
+        }
+        writer.u8(self.stack_pointer as u8);
+        writer.u8(self.delay_timer);
+        writer.u8(self.sound_timer);
+        writer.bool(self.hires);
+        writer.u8(self.selected_planes);
+        writer.bytes(&self.gfx);
+        writer.bytes(&self.keypad);
+        writer.bytes(&self.rpl_flags);
+        writer.bytes(&self.audio_pattern);
+        writer.u8(self.pitch);
+        writer.bool(self.exited);
+        writer.bool(self.vblank);
+        writer.u64(self.rng_state);
+        writer.bool(self.audio_pattern_loaded);
+    }
+    fn read_state(&mut self, mut reader: StateReader) -> Result<(), StateError> {
+        let quirks = reader.quirks()?;
+        let memory_size = if quirks.xo_chip {
+            XO_CHIP_MEMORY_SIZE
+        } else {
+            MEMORY_SIZE
+        };
+        let memory = reader.exact_bytes(memory_size, "memory size")?;
+        let registers = reader.exact_bytes(16, "register count")?;
+        let index_register = reader.u16()?;
+        let program_counter = reader.u32()? as usize;
+        if program_counter >= memory_size {
+            return Err(StateError::Invalid("program counter"));
+        }
+        let mut stack = Vec::with_capacity(16);
+        for _ in 0..16 {
+            stack.push(reader.u16()?);
+        }
+        let stack_pointer = reader.u8()? as usize;
+        if stack_pointer > stack.len() {
+            return Err(StateError::Invalid("stack pointer"));
+        }
+        let delay_timer = reader.u8()?;
+        let sound_timer = reader.u8()?;
+        let hires = reader.bool()?;
+        let selected_planes = reader.u8()?;
+        if selected_planes > 0b11 {
+            return Err(StateError::Invalid("plane selection"));
+        }
+        let gfx_size = if hires {
+            HIRES_WIDTH * HIRES_HEIGHT
+        } else {
+            LORES_WIDTH * LORES_HEIGHT
+        };
+        let gfx = reader.exact_bytes(gfx_size, "framebuffer size")?;
+        let keypad = reader.exact_bytes(16, "keypad size")?;
+        let rpl_flags = reader.exact_bytes(16, "flag count")?;
+        let audio_pattern = reader.exact_bytes(16, "audio pattern size")?;
+        let pitch = reader.u8()?;
+        let exited = reader.bool()?;
+        let vblank = reader.bool()?;
+        // States from before version 2 didn't have a seeded generator, they get a fresh one
+        let rng_state = if reader.version() >= 2 {
+            reader.u64()?
+        } else {
+            rand::random()
+        };
+        // Before version 3 a pattern only counted as loaded if it had a bit set
+        let audio_pattern_loaded = if reader.version() >= 3 {
+            reader.bool()?
+        } else {
+            audio_pattern.iter().any(|&byte| byte != 0)
+        };
+        reader.finish()?;
+
+        // Only replace anything once the whole state turned out to be valid
+        *self = CPU {
+            memory,
+            index_register,
+            gfx,
+            hires,
+            selected_planes,
+            program_counter,
+            register: Register { v: registers },
+            delay_timer,
+            sound_timer,
+            stack,
+            stack_pointer,
+            keypad,
+            rpl_flags,
+            audio_pattern,
+            audio_pattern_loaded,
+            pitch,
+            draw_flag: true,
+            exited,
+            vblank,
+            beeping: false,
+            quirks,
+            rng_state,
+            debug_current_opcode: Ignore,
+        };
+        Ok(())
+    }
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), CpuError> {
+        let max = self.memory.len() - 0x200;
+        if rom.len() > max {
+            return Err(CpuError::RomTooLarge {
+                size: rom.len(),
+                max,
+            });
+        }
+        self.memory[0x200..0x200 + rom.len()].copy_from_slice(rom);
+        Ok(())
+    }
+    pub fn emulate_cycle(&mut self) -> Result<StepOutcome, CpuError> {
+        if self.exited {
+            return Ok(StepOutcome::Exited);
+        }
+        let pc = self.program_counter;
+        let opc = self.fetch()?;
+        let decoded_opc = CPU::decode(opc);
+        if let Unknown(opcode) = decoded_opc {
+            return Err(CpuError::UnknownOpcode {
+                pc: pc as u16,
+                opcode,
+            });
+        }
+        self.emulate(decoded_opc)?;
+
+        Ok(match self.debug_current_opcode {
+            _ if self.exited => StepOutcome::Exited,
+            AwaitKeyPress(_) | Display(..) if self.program_counter == pc => StepOutcome::Waiting,
+            _ if self.draw_flag => StepOutcome::Draw,
+            _ => StepOutcome::Continue,
+        })
+    }
+    fn fetch(&self) -> Result<u16, CpuError> {
+        self.word_at(self.program_counter)
+            .ok_or_else(|| self.out_of_bounds(self.program_counter + 1))
+    }
+    fn word_at(&self, address: usize) -> Option<u16> {
         // Fetch 2 bytes to get the 16 bit opcode
         // Convert the u8s to u16s, so we can safely shift them by 8 bits
-        let opcode1 = self.memory[self.program_counter] as u16;
-        let opcode2 = self.memory[self.program_counter + 1] as u16;
-        opcode1 << 8 | opcode2
+        let opcode1 = *self.memory.get(address)? as u16;
+        let opcode2 = *self.memory.get(address + 1)? as u16;
+        Some(opcode1 << 8 | opcode2)
+    }
+    fn out_of_bounds(&self, address: usize) -> CpuError {
+        CpuError::MemoryOutOfBounds {
+            pc: self.program_counter as u16,
+            address,
+        }
     }
-    fn decode(&self, opcode: u16) -> Opcode {
+    // Fails unless the len bytes starting at address are all inside memory
+    fn check_memory(&self, address: usize, len: usize) -> Result<(), CpuError> {
+        if address + len > self.memory.len() {
+            Err(self.out_of_bounds(self.memory.len().max(address)))
+        } else {
+            Ok(())
+        }
+    }
+    // Maps a word to its instruction, without looking at memory or quirks
+    pub fn decode(opcode: u16) -> Opcode {
         let nib1 = (opcode & 0xF000) >> 12;
         let nib2 = (opcode & 0x0F00) >> 8;
         let nib3 = (opcode & 0x00F0) >> 4;
@@ -159,14 +587,24 @@ impl CPU {
         // Map the u16 to the actual Opcode
         // https://en.wikipedia.org/wiki/CHIP-8#Virtual_machine_description
         match (nib1, nib2, nib3, nib4) {
-            (0x0, 0x0, 0x0, 0x0) => Ignore, // Can apparently be ignored
-            (0x0, 0x0, 0xE, 0xE) => ClearScreen,
-            (0x0, 0x0, 0xE, 0x0) => Return,
+            // 0000 is skipped, other 0NNN call machine code routines of the original hardware,
+            // which can't be run and decode as Unknown
+            (0x0, 0x0, 0x0, 0x0) => Ignore,
+            (0x0, 0x0, 0xC, n) => ScrollDown(n),
+            (0x0, 0x0, 0xE, 0x0) => ClearScreen,
+            (0x0, 0x0, 0xE, 0xE) => Return,
+            (0x0, 0x0, 0xF, 0xB) => ScrollRight,
+            (0x0, 0x0, 0xF, 0xC) => ScrollLeft,
+            (0x0, 0x0, 0xF, 0xD) => Exit,
+            (0x0, 0x0, 0xF, 0xE) => LowResolution,
+            (0x0, 0x0, 0xF, 0xF) => HighResolution,
             (0x1, _, _, _) => Jump(nnn),
             (0x2, _, _, _) => CallSubroutine(nnn),
             (0x3, n1, _, _) => SkipIfEqualAddress(n1, nn),
             (0x4, n1, _, _) => SkipIfNotEqualAddress(n1, nn),
             (0x5, n1, n2, 0x0) => SkipIfEqualRegister(n1, n2),
+            (0x5, n1, n2, 0x2) => RegisterRangeDump(n1, n2),
+            (0x5, n1, n2, 0x3) => RegisterRangeLoad(n1, n2),
             (0x6, n1, _, _) => SetRegister(n1, nn),
             (0x7, n1, _, _) => AddAddressToRegister(n1, nn),
             (0x8, n1, n2, 0x0) => Assign(n1, n2),
@@ -185,78 +623,85 @@ impl CPU {
             (0xD, n1, n2, n3) => Display(n1, n2, n3),
             (0xE, n1, 0x9, 0xE) => SkipIfKeyPressed(n1),
             (0xE, n1, 0xA, 0x1) => SkipIfNotKeyPressed(n1),
+            (0xF, 0x0, 0x0, 0x0) => SetIndexRegisterLong,
+            (0xF, n1, 0x0, 0x1) => SelectPlanes(n1),
+            (0xF, 0x0, 0x0, 0x2) => LoadAudioPattern,
             (0xF, n1, 0x0, 0x7) => GetDelayTimer(n1),
             (0xF, n1, 0x0, 0xA) => AwaitKeyPress(n1),
             (0xF, n1, 0x1, 0x5) => SetDelayTimer(n1),
             (0xF, n1, 0x1, 0x8) => SetSoundTimer(n1),
             (0xF, n1, 0x1, 0xE) => AddToIndexRegister(n1),
             (0xF, n1, 0x2, 0x9) => SetIndexRegisterToSpriteLocation(n1),
+            (0xF, n1, 0x3, 0x0) => SetIndexRegisterToLargeSpriteLocation(n1),
             (0xF, n1, 0x3, 0x3) => StoreBinaryCodedDecimal(n1),
+            (0xF, n1, 0x3, 0xA) => SetPitch(n1),
             (0xF, n1, 0x5, 0x5) => RegisterDump(n1),
             (0xF, n1, 0x6, 0x5) => RegisterLoad(n1),
-            _ => UNKNOWN(nib1, nib2, nib3, nib4),
+            (0xF, n1, 0x7, 0x5) => StoreFlags(n1),
+            (0xF, n1, 0x8, 0x5) => LoadFlags(n1),
+            _ => Unknown(opcode),
         }
     }
-    fn emulate(&mut self, opcode: Opcode) {
-        // Decrement timers
-        if self.delay_timer > 0 {
-            self.delay_timer -= 1;
-        }
-        if self.sound_timer > 0 {
-            if self.sound_timer == 1 {
-                // println!("BEEP!");
-            }
-            self.sound_timer -= 1;
-        }
-
+    fn emulate(&mut self, opcode: Opcode) -> Result<(), CpuError> {
         // Reset draw_flag
         self.draw_flag = false;
 
         match opcode {
-            Ignore => (),
+            Ignore => {
+                // Only 0000 decodes to this, see decode
+                self.program_counter += 2;
+            }
             ClearScreen => {
-                // Clear Screen
+                // Only the selected bitplanes are cleared
+                for pixel in self.gfx.iter_mut() {
+                    *pixel &= !self.selected_planes;
+                }
+                self.draw_flag = true;
                 self.program_counter += 2;
             }
             Return => {
-                self.program_counter = self.stack[self.stack_pointer] as usize;
-                if self.stack_pointer > 0 {
-                    println!("stackpointer is already 1");
-                    self.stack_pointer -= 1;
+                // Pop the return address, which points past the call instruction
+                if self.stack_pointer == 0 {
+                    return Err(CpuError::StackUnderflow {
+                        pc: self.program_counter as u16,
+                    });
                 }
+                self.stack_pointer -= 1;
+                self.program_counter = self.stack[self.stack_pointer] as usize;
             }
             Jump(nnn) => {
                 self.program_counter = nnn as usize;
             }
             CallSubroutine(nnn) => {
-                self.stack[self.stack_pointer] = self.program_counter as u16;
+                // Push the address of the next instruction, so Return continues after the call
+                if self.stack_pointer == self.stack.len() {
+                    return Err(CpuError::StackOverflow {
+                        pc: self.program_counter as u16,
+                    });
+                }
+                self.stack[self.stack_pointer] = (self.program_counter + 2) as u16;
                 self.stack_pointer += 1;
                 self.program_counter = nnn as usize;
             }
             SkipIfEqualAddress(x, nn) => {
-                self.program_counter += if self.register.v[x as usize] == nn as u8 {
-                    4
-                } else {
-                    2
-                };
+                let condition = self.register.v[x as usize] == nn as u8;
+                self.skip_if(condition);
             }
             SkipIfNotEqualAddress(x, nn) => {
-                self.program_counter += if self.register.v[x as usize] != nn as u8 {
-                    4
-                } else {
-                    2
-                };
+                let condition = self.register.v[x as usize] != nn as u8;
+                self.skip_if(condition);
             }
             SkipIfEqualRegister(x, y) => {
                 let registers_are_equal =
                     self.register.v[x as usize] == self.register.v[y as usize];
-                self.program_counter += if registers_are_equal { 4 } else { 2 };
+                self.skip_if(registers_are_equal);
             }
             SetRegister(x, nn) => {
                 self.register.v[x as usize] = nn as u8;
                 self.program_counter += 2;
             }
             AddAddressToRegister(x, nn) => {
+                // The carry flag is not changed
                 self.register.v[x as usize] = self.register.v[x as usize].wrapping_add(nn as u8);
                 self.program_counter += 2;
             }
@@ -265,89 +710,77 @@ impl CPU {
                 self.program_counter += 2;
             }
             AssignOr(x, y) => {
-                self.register.v[x as usize] =
-                    self.register.v[x as usize] | self.register.v[y as usize];
+                self.register.v[x as usize] |= self.register.v[y as usize];
+                if self.quirks.logic_resets_vf {
+                    self.register.v[0xf] = 0;
+                }
                 self.program_counter += 2;
             }
             AssignAnd(x, y) => {
-                self.register.v[x as usize] =
-                    self.register.v[x as usize] & self.register.v[y as usize];
+                self.register.v[x as usize] &= self.register.v[y as usize];
+                if self.quirks.logic_resets_vf {
+                    self.register.v[0xf] = 0;
+                }
                 self.program_counter += 2;
             }
             AssignXor(x, y) => {
-                self.register.v[x as usize] =
-                    self.register.v[x as usize] ^ self.register.v[y as usize];
+                self.register.v[x as usize] ^= self.register.v[y as usize];
+                if self.quirks.logic_resets_vf {
+                    self.register.v[0xf] = 0;
+                }
                 self.program_counter += 2;
             }
             Add(x, y) => {
                 // Opcode 0x8XY4
                 // Add VY to VX, set carry flag if overflow
-                // Set carry flag if the result will be larger than 255
-                if self.register.v[x as usize] > 0xff - self.register.v[y as usize] {
-                    self.register.v[0xf] = 1;
-                } else {
-                    self.register.v[0xf] = 0;
-                }
-
-                let result = self.register.v[x as usize].wrapping_add(self.register.v[y as usize]);
+                // VF is written last, so the flag wins if X is 0xF
+                let (result, overflow) =
+                    self.register.v[x as usize].overflowing_add(self.register.v[y as usize]);
                 self.register.v[x as usize] = result;
+                self.register.v[0xf] = overflow as u8;
                 self.program_counter += 2;
             }
             Subtract(x, y) => {
-                // TODO Unsure about this
                 // VF is set to 0 when there's a borrow, and 1 when there isn't.
                 // When VY is smaller/equal than VX, we can "safely" subtract, without underflowing
-                // If it's greater then we underflow
-                if self.register.v[y as usize] > self.register.v[x as usize] {
-                    self.register.v[0xf] = 0;
-                } else {
-                    self.register.v[0xf] = 1;
-                }
-
-                let result = self.register.v[x as usize].wrapping_sub(self.register.v[y as usize]);
+                let (result, borrow) =
+                    self.register.v[x as usize].overflowing_sub(self.register.v[y as usize]);
                 self.register.v[x as usize] = result;
+                self.register.v[0xf] = !borrow as u8;
                 self.program_counter += 2;
             }
-            LeastSigStoreAndShift(x, _) => {
+            LeastSigStoreAndShift(x, y) => {
                 // Stores the least significant bit of VX in VF and then shifts VX to the right by 1
+                // The COSMAC VIP shifts VY instead and stores the result in VX
+                let source = if self.quirks.shift_uses_vy { y } else { x };
+                let value = self.register.v[source as usize];
                 // Mask out everything but the least significant bit
-                self.register.v[0xF] = self.register.v[x as usize] & 0x1;
-                self.register.v[x as usize] >>= 1;
+                let least_significant_bit = value & 0x1;
+                self.register.v[x as usize] = value >> 1;
+                self.register.v[0xf] = least_significant_bit;
                 self.program_counter += 2;
             }
             SetSubtract(x, y) => {
                 // Sets VX to VY minus VX. VF is set to 0 when there's a borrow, and 1 when there isn't.
-                // If it's greater then we underflow
-                if self.register.v[y as usize] < self.register.v[x as usize] {
-                    // Underflow occurs
-                    self.register.v[0xf] = 0;
-                } else {
-                    self.register.v[0xf] = 1;
-                }
-
-                let result = self.register.v[y as usize].wrapping_sub(self.register.v[x as usize]);
+                let (result, borrow) =
+                    self.register.v[y as usize].overflowing_sub(self.register.v[x as usize]);
                 self.register.v[x as usize] = result;
-
+                self.register.v[0xf] = !borrow as u8;
                 self.program_counter += 2;
             }
-            MostSigStoreAndShift(x, _) => {
+            MostSigStoreAndShift(x, y) => {
                 // Stores the most significant bit of VX in VF and then shifts VX to the left by 1
-                let most_significant_bit = (self.register.v[x as usize] & 0x80) >> 7;
+                let source = if self.quirks.shift_uses_vy { y } else { x };
+                let value = self.register.v[source as usize];
+                let most_significant_bit = (value & 0x80) >> 7;
+                self.register.v[x as usize] = value << 1;
                 self.register.v[0xf] = most_significant_bit;
-                self.register.v[x as usize] <<= 1;
                 self.program_counter += 2;
             }
             SkipIfUnequalRegisters(x, y) => {
                 // Skips the next instruction if VX doesn't equal VY. (Usually the next instruction is a jump to skip a code block)
-                self.program_counter +=
-                    if self.register.v[x as usize] == self.register.v[y as usize] {
-                        4
-                    } else {
-                        2
-                    }
-                // if self.register.v[x as usize] == self.register.v[y as usize] {
-
-                // }
+                let condition = self.register.v[x as usize] != self.register.v[y as usize];
+                self.skip_if(condition);
             }
             SetIndexRegister(nnn) => {
                 // Set I to nnn
@@ -356,69 +789,44 @@ impl CPU {
             }
             Flow(nnn) => {
                 // Jumps to the address NNN plus V0.
-                self.index_register = (self.memory[nnn as usize] + self.register.v[0x0]) as u16;
-                self.program_counter += 2;
+                // CHIP-48 and SUPER-CHIP read this as BXNN and add VX instead
+                let offset_register = if self.quirks.jump_uses_vx {
+                    (nnn & 0x0F00) >> 8
+                } else {
+                    0x0
+                };
+                self.program_counter =
+                    nnn as usize + self.register.v[offset_register as usize] as usize;
             }
             Rand(x, nn) => {
                 // Sets VX to the result of a bitwise and operation on a random number (Typically: 0 to 255) and NN.
-                let random: u8 = rand::random();
-                self.register.v[x as usize] = random & self.memory[nn as usize];
+                let random = self.next_random() as u8;
+                self.register.v[x as usize] = random & nn as u8;
                 self.program_counter += 2;
             }
             Display(x, y, n) => {
-                // Coordinates at which the sprite is drawn
-                let vx = self.register.v[x as usize] as u16;
-                let vy = self.register.v[y as usize] as u16;
-
-                // For every row
-                for yline in 0..n {
-                    // Read 8 pixels (represented through 1 byte) from memory starting at I
-                    let byte = self.memory[(self.index_register + yline) as usize];
-                    // display at x, y
-                    // Before writing the byte, read the current value and check if pixels are flipped
-                    // If so, set VF to 1, otherwise to 0
-
-                    // Reset VF to 0
-                    self.register.v[0xf] = 0;
-
-                    // xline indicates the position in the line
-                    for xline in 0..8 {
-                        // 0x80 >> xline generates 128, 64, 32, .. which look like..
-                        // 0x40 in binary is       0100 0000
-                        // E.g. input 0x45 is      0100 0101
-                        // through masking we get  0100 0000
-                        let bit = byte & (0x80 >> xline);
-                        // and we know bit 7 is set if the value is not decimal(0)
-                        // because then the mask would've eliminated all 1s
-                        let index = (vx + xline + (vy + yline) * 64) as usize;
-                        if bit != 0 {
-                            // Only if the pixel is turned off, set VF = 1
-                            if self.gfx[index] == 1 {
-                                self.register.v[0xf] = 1;
-                            }
-                            // Bit was set, so xor the current value
-                            self.gfx[index] ^= 1;
-                        }
-                        // print!("{} ", self.gfx[index]);
+                if self.quirks.display_wait {
+                    // Stall on this instruction until the next frame starts
+                    if !self.vblank {
+                        self.debug_current_opcode = opcode;
+                        return Ok(());
                     }
-                    // println!();
+                    self.vblank = false;
                 }
+
+                self.draw_sprite(x as usize, y as usize, n as usize)?;
                 self.draw_flag = true;
                 self.program_counter += 2;
             }
             SkipIfKeyPressed(x) => {
                 //  Skip next instruction if key with the _value_ of Vx is pressed.
-                if self.keypad[self.register.v[x as usize] as usize] != 0 {
-                    self.program_counter += 2;
-                }
-                self.program_counter += 2;
+                let condition = self.keypad[(self.register.v[x as usize] & 0xF) as usize] != 0;
+                self.skip_if(condition);
             }
             SkipIfNotKeyPressed(x) => {
                 //  Skip next instruction if key with the _value_ of Vx is not pressed.
-                 if self.keypad[self.register.v[x as usize] as usize] == 0 {
-                    self.program_counter += 2;
-                }
-                self.program_counter += 2;
+                let condition = self.keypad[(self.register.v[x as usize] & 0xF) as usize] == 0;
+                self.skip_if(condition);
             }
             GetDelayTimer(x) => {
                 // Sets VX to the value of the delay timer.
@@ -426,21 +834,14 @@ impl CPU {
                 self.program_counter += 2;
             }
             AwaitKeyPress(x) => {
-                let mut pressed_key = 20;
-
-                for key in &self.keypad {
-                    if *key == 1 {
-                        pressed_key = *key;
-                    }
-                }
-
-                if pressed_key != 20 {
-                    self.register.v[x as usize] = pressed_key;
+                // Blocks by not advancing the PC until a key is pressed, then stores the key in VX
+                if let Some(pressed_key) = self.keypad.iter().position(|key| *key != 0) {
+                    self.register.v[x as usize] = pressed_key as u8;
                     self.program_counter += 2;
                 }
             }
             SetDelayTimer(x) => {
-                // Sets VX to the value of the delay timer.
+                // Sets the delay timer to VX.
                 self.delay_timer = self.register.v[x as usize];
                 self.program_counter += 2;
             }
@@ -449,46 +850,277 @@ impl CPU {
                 self.program_counter += 2;
             }
             AddToIndexRegister(x) => {
-                self.index_register += self.register.v[x as usize] as u16;
+                // VF is not affected
+                self.index_register = self
+                    .index_register
+                    .wrapping_add(self.register.v[x as usize] as u16);
                 self.program_counter += 2;
             }
             SetIndexRegisterToSpriteLocation(x) => {
                 // Sets I to the location of the sprite for the character in VX.
                 // Characters 0-F (in hexadecimal) are represented by a 4x5 font.
-                self.index_register = (self.register.v[x as usize] * 5) as u16;
+                self.index_register = (self.register.v[x as usize] & 0xF) as u16 * 5;
                 self.program_counter += 2;
             }
-            StoreBinaryCodedDecimal(pc) => {
-                let x = (pc & 0x0F00) >> 8;
+            StoreBinaryCodedDecimal(x) => {
+                // Hundreds at I, tens at I+1, ones at I+2
                 let vx = self.register.v[x as usize];
-                self.memory[self.index_register as usize] = vx / 100;
-                self.memory[(self.index_register + 1) as usize] = (vx % 100) / 10;
-                self.memory[(self.index_register + 1) as usize] = vx % 10;
+                let i = self.index_register as usize;
+                self.check_memory(i, 3)?;
+                self.memory[i] = vx / 100;
+                self.memory[i + 1] = (vx % 100) / 10;
+                self.memory[i + 2] = vx % 10;
                 self.program_counter += 2;
             }
-            RegisterDump(pc) => {
-                let x = (pc & 0x0F00) >> 8;
+            RegisterDump(x) => {
                 // Read V0 to VX (including VX) and write to memory starting at I
-                for i in 0..x {
-                    self.memory[(self.index_register + i) as usize] = self.register.v[i as usize];
-                }
-                // More complicated solution :D
-                // for (i, v_reg) in self.register.v.iter().take((x + 1) as usize).enumerate() {
-                //     self.memory[self.index_register as usize + i] = *v_reg;
-                // }
+                let i = self.index_register as usize;
+                let x = x as usize;
+                self.check_memory(i, x + 1)?;
+                self.memory[i..=i + x].copy_from_slice(&self.register.v[..=x]);
+                self.increment_index_after_load_store(x);
                 self.program_counter += 2;
             }
-            RegisterLoad(pc) => {
-                let x = (pc & 0x0F00) >> 8;
+            RegisterLoad(x) => {
                 // Read memory starting at I and copy to V0 to VX (including VX)
-                for i in 0..x {
-                    self.register.v[i as usize] = self.memory[(self.index_register + i) as usize];
+                let i = self.index_register as usize;
+                let x = x as usize;
+                self.check_memory(i, x + 1)?;
+                self.register.v[..=x].copy_from_slice(&self.memory[i..=i + x]);
+                self.increment_index_after_load_store(x);
+                self.program_counter += 2;
+            }
+            ScrollDown(n) => {
+                let (width, height) = (self.width(), self.height());
+                let n = n as usize;
+                // Walk bottom-up so rows are moved before they are overwritten
+                for row in (0..height).rev() {
+                    for col in 0..width {
+                        let source = if row >= n {
+                            self.gfx[(row - n) * width + col]
+                        } else {
+                            0
+                        };
+                        self.scroll_pixel(row * width + col, source);
+                    }
                 }
+                self.draw_flag = true;
                 self.program_counter += 2;
             }
-            UNKNOWN(n1, n2, n3, n4) => println!("Unkown Instruction {} {} {} {}", n1, n2, n3, n4),
+            ScrollRight => {
+                let width = self.width();
+                for row in 0..self.height() {
+                    for col in (0..width).rev() {
+                        let source = if col >= 4 {
+                            self.gfx[row * width + col - 4]
+                        } else {
+                            0
+                        };
+                        self.scroll_pixel(row * width + col, source);
+                    }
+                }
+                self.draw_flag = true;
+                self.program_counter += 2;
+            }
+            ScrollLeft => {
+                let width = self.width();
+                for row in 0..self.height() {
+                    for col in 0..width {
+                        let source = if col + 4 < width {
+                            self.gfx[row * width + col + 4]
+                        } else {
+                            0
+                        };
+                        self.scroll_pixel(row * width + col, source);
+                    }
+                }
+                self.draw_flag = true;
+                self.program_counter += 2;
+            }
+            Exit => {
+                self.exited = true;
+            }
+            LowResolution => {
+                self.set_resolution(false);
+                self.program_counter += 2;
+            }
+            HighResolution => {
+                self.set_resolution(true);
+                self.program_counter += 2;
+            }
+            SetIndexRegisterToLargeSpriteLocation(x) => {
+                // Like FX29, but for the 8x10 font, which uses 10 bytes per character
+                self.index_register =
+                    LARGE_FONTSET_START as u16 + (self.register.v[x as usize] & 0xF) as u16 * 10;
+                self.program_counter += 2;
+            }
+            StoreFlags(x) => {
+                // Save V0 to VX (including VX) in the RPL user flags
+                let x = x as usize;
+                self.rpl_flags[..=x].copy_from_slice(&self.register.v[..=x]);
+                self.program_counter += 2;
+            }
+            LoadFlags(x) => {
+                let x = x as usize;
+                self.register.v[..=x].copy_from_slice(&self.rpl_flags[..=x]);
+                self.program_counter += 2;
+            }
+            SetIndexRegisterLong => {
+                // F000 NNNN, the address is the 16 bit word following the instruction
+                self.index_register = self
+                    .word_at(self.program_counter + 2)
+                    .ok_or_else(|| self.out_of_bounds(self.program_counter + 3))?;
+                self.program_counter += 4;
+            }
+            RegisterRangeDump(x, y) => {
+                // Write VX to VY to memory starting at I, in reverse order if X > Y. I is not changed
+                let i = self.index_register as usize;
+                self.check_memory(i, register_range(x, y).count())?;
+                for (offset, register) in register_range(x, y).enumerate() {
+                    self.memory[i + offset] = self.register.v[register];
+                }
+                self.program_counter += 2;
+            }
+            RegisterRangeLoad(x, y) => {
+                let i = self.index_register as usize;
+                self.check_memory(i, register_range(x, y).count())?;
+                for (offset, register) in register_range(x, y).enumerate() {
+                    self.register.v[register] = self.memory[i + offset];
+                }
+                self.program_counter += 2;
+            }
+            SelectPlanes(n) => {
+                self.selected_planes = n as u8 & 0b11;
+                self.program_counter += 2;
+            }
+            LoadAudioPattern => {
+                // Copy 16 bytes starting at I into the audio pattern buffer
+                let i = self.index_register as usize;
+                self.check_memory(i, 16)?;
+                self.audio_pattern.copy_from_slice(&self.memory[i..i + 16]);
+                self.audio_pattern_loaded = true;
+                self.program_counter += 2;
+            }
+            SetPitch(x) => {
+                self.pitch = self.register.v[x as usize];
+                self.program_counter += 2;
+            }
+            // Rejected by emulate_cycle before getting here
+            Unknown(_) => (),
         }
         self.debug_current_opcode = opcode;
+        Ok(())
+    }
+    // Advances past the current instruction, and past the next one as well if condition holds
+    fn skip_if(&mut self, condition: bool) {
+        self.program_counter += 2;
+        if condition {
+            // F000 NNNN is 4 bytes long and has to be skipped as a whole
+            self.program_counter += if self.word_at(self.program_counter) == Some(0xF000) {
+                4
+            } else {
+                2
+            };
+        }
+    }
+    // SplitMix64, small and fast, and its whole state fits into save states
+    fn next_random(&mut self) -> u64 {
+        self.rng_state = self.rng_state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.rng_state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+    // Replaces the selected bitplanes of a pixel with the ones from source
+    fn scroll_pixel(&mut self, index: usize, source: u8) {
+        let planes = self.selected_planes;
+        self.gfx[index] = (self.gfx[index] & !planes) | (source & planes);
+    }
+    fn set_resolution(&mut self, hires: bool) {
+        // Switching resolution clears the screen
+        self.hires = hires;
+        self.gfx = vec![0; self.width() * self.height()];
+        self.draw_flag = true;
+    }
+    // Draws the sprite at I to (VX, VY). N rows of 8 pixels, or a 16x16 sprite if N is 0
+    fn draw_sprite(&mut self, x: usize, y: usize, n: usize) -> Result<(), CpuError> {
+        let (width, height) = (self.width(), self.height());
+        let (sprite_width, rows) = if n == 0 { (16, 16) } else { (8, n) };
+        let bytes_per_row = sprite_width / 8;
+        let sprite_len = rows * bytes_per_row * self.selected_planes.count_ones() as usize;
+        self.check_memory(self.index_register as usize, sprite_len)?;
+
+        // Coordinates at which the sprite is drawn
+        // The starting position always wraps around the screen
+        let vx = self.register.v[x] as usize % width;
+        let vy = self.register.v[y] as usize % height;
+
+        // Before writing a byte, read the current value and check if pixels are flipped
+        // If so, set VF to 1, otherwise it stays 0
+        self.register.v[0xf] = 0;
+
+        // With both XO-CHIP planes selected, the sprite data for the second plane follows the first
+        let mut address = self.index_register as usize;
+        for plane in 0..2 {
+            let plane_mask = 1 << plane;
+            if self.selected_planes & plane_mask == 0 {
+                continue;
+            }
+
+            // For every row
+            for yline in 0..rows {
+                // Read 8 or 16 pixels (represented through 1 or 2 bytes) from memory starting at I
+                let mut line: u16 = 0;
+                for _ in 0..bytes_per_row {
+                    line = line << 8 | self.memory[address] as u16;
+                    address += 1;
+                }
+
+                // xline indicates the position in the line
+                for xline in 0..sprite_width {
+                    // Masking with the top bit shifted right by xline picks out one pixel, e.g.
+                    // 0x40 in binary is       0100 0000
+                    // E.g. input 0x45 is      0100 0101
+                    // through masking we get  0100 0000
+                    let bit = line & (1 << (sprite_width - 1 - xline));
+                    // and we know the bit is set if the value is not decimal(0)
+                    // because then the mask would've eliminated all 1s
+                    if bit != 0 {
+                        let (px, py) = (vx + xline, vy + yline);
+                        if self.quirks.clip_sprites && (px >= width || py >= height) {
+                            continue;
+                        }
+                        // Otherwise sprites that reach over the edge wrap around to the other side
+                        let index = px % width + (py % height) * width;
+                        // Only if the pixel is turned off, set VF = 1
+                        if self.gfx[index] & plane_mask != 0 {
+                            self.register.v[0xf] = 1;
+                        }
+                        // Bit was set, so xor the current value
+                        self.gfx[index] ^= plane_mask;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    fn increment_index_after_load_store(&mut self, x: usize) {
+        let increment = match self.quirks.load_store_index {
+            IndexIncrement::Unchanged => 0,
+            IndexIncrement::ByX => x as u16,
+            IndexIncrement::ByXPlusOne => x as u16 + 1,
+        };
+        self.index_register = self.index_register.wrapping_add(increment);
+    }
+}
+
+// The registers from X to Y (inclusive), descending if X > Y
+fn register_range(x: u16, y: u16) -> Box<dyn Iterator<Item = usize>> {
+    let (x, y) = (x as usize, y as usize);
+    if x <= y {
+        Box::new(x..=y)
+    } else {
+        Box::new((y..=x).rev())
     }
 }
 
@@ -501,3 +1133,942 @@ static FONTSET: [u8; 80] = [
     0x10, 0xF0, 0xF0, 0x90, 0xF0, 0x90, 0x90, 0xE0, 0x90, 0xE0, 0x90, 0xE0, 0xF0, 0x80, 0x80, 0x80,
     0xF0, 0xE0, 0x90, 0x90, 0x90, 0xE0, 0xF0, 0x80, 0xF0, 0x80, 0xF0, 0xF0, 0x80, 0xF0, 0x80, 0x80,
 ];
+
+// The SUPER-CHIP large font, 10 bytes per character from 0 to F
+static LARGE_FONTSET: [u8; 160] = [
+    0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+    0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+    0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+    0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Loads the given opcodes at 0x200 so the first emulate_cycle executes the first one
+    fn cpu_with(opcodes: &[u16]) -> CPU {
+        cpu_with_quirks(opcodes, Quirks::default())
+    }
+
+    fn cpu_with_quirks(opcodes: &[u16], quirks: Quirks) -> CPU {
+        let mut rom = Vec::new();
+        for opcode in opcodes {
+            rom.push((opcode >> 8) as u8);
+            rom.push(*opcode as u8);
+        }
+        let mut cpu = CPU::new(quirks);
+        cpu.load_rom(&rom).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn ignore_0000_advances_pc() {
+        let mut cpu = cpu_with(&[0x0000]);
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.program_counter, 0x202);
+
+        // Machine code routines can't be run
+        let mut cpu = cpu_with(&[0x0123]);
+        assert_eq!(
+            cpu.emulate_cycle(),
+            Err(CpuError::UnknownOpcode {
+                pc: 0x200,
+                opcode: 0x0123
+            })
+        );
+    }
+
+    #[test]
+    fn clear_screen_00e0() {
+        let mut cpu = cpu_with(&[0x00E0]);
+        for pixel in cpu.gfx.iter_mut() {
+            *pixel = 1;
+        }
+        assert_eq!(cpu.emulate_cycle(), Ok(StepOutcome::Draw));
+        assert!(cpu.gfx.iter().all(|pixel| *pixel == 0));
+        assert_eq!(cpu.program_counter, 0x202);
+    }
+
+    #[test]
+    fn call_2nnn_and_return_00ee() {
+        let mut cpu = cpu_with(&[0x2300]);
+        cpu.memory[0x300] = 0x00;
+        cpu.memory[0x301] = 0xEE;
+
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.program_counter, 0x300);
+        assert_eq!(cpu.stack_pointer, 1);
+        assert_eq!(cpu.stack[0], 0x202);
+
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.program_counter, 0x202);
+        assert_eq!(cpu.stack_pointer, 0);
+    }
+
+    #[test]
+    fn jump_1nnn() {
+        let mut cpu = cpu_with(&[0x1ABC]);
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.program_counter, 0xABC);
+    }
+
+    #[test]
+    fn skip_if_equal_3xnn() {
+        let mut cpu = cpu_with(&[0x3142]);
+        cpu.register.v[1] = 0x42;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.program_counter, 0x204);
+
+        let mut cpu = cpu_with(&[0x3142]);
+        cpu.register.v[1] = 0x41;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.program_counter, 0x202);
+    }
+
+    #[test]
+    fn skip_if_not_equal_4xnn() {
+        let mut cpu = cpu_with(&[0x4142]);
+        cpu.register.v[1] = 0x41;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.program_counter, 0x204);
+
+        let mut cpu = cpu_with(&[0x4142]);
+        cpu.register.v[1] = 0x42;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.program_counter, 0x202);
+    }
+
+    #[test]
+    fn skip_if_registers_equal_5xy0() {
+        let mut cpu = cpu_with(&[0x5120]);
+        cpu.register.v[1] = 7;
+        cpu.register.v[2] = 7;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.program_counter, 0x204);
+
+        let mut cpu = cpu_with(&[0x5120]);
+        cpu.register.v[2] = 7;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.program_counter, 0x202);
+    }
+
+    #[test]
+    fn set_register_6xnn() {
+        let mut cpu = cpu_with(&[0x6A2A]);
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.register.v[0xA], 0x2A);
+        assert_eq!(cpu.program_counter, 0x202);
+    }
+
+    #[test]
+    fn add_7xnn_wraps_without_carry() {
+        let mut cpu = cpu_with(&[0x7102]);
+        cpu.register.v[1] = 0xFF;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.register.v[1], 0x01);
+        assert_eq!(cpu.register.v[0xF], 0);
+    }
+
+    #[test]
+    fn assign_8xy0() {
+        let mut cpu = cpu_with(&[0x8120]);
+        cpu.register.v[2] = 9;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.register.v[1], 9);
+        assert_eq!(cpu.program_counter, 0x202);
+    }
+
+    #[test]
+    fn logic_8xy1_8xy2_8xy3() {
+        let mut cpu = cpu_with(&[0x8121, 0x8342, 0x8563]);
+        cpu.register.v[1] = 0b1100;
+        cpu.register.v[2] = 0b1010;
+        cpu.register.v[3] = 0b1100;
+        cpu.register.v[4] = 0b1010;
+        cpu.register.v[5] = 0b1100;
+        cpu.register.v[6] = 0b1010;
+        cpu.emulate_cycle().unwrap();
+        cpu.emulate_cycle().unwrap();
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.register.v[1], 0b1110);
+        assert_eq!(cpu.register.v[3], 0b1000);
+        assert_eq!(cpu.register.v[5], 0b0110);
+        assert_eq!(cpu.program_counter, 0x206);
+    }
+
+    #[test]
+    fn add_8xy4_sets_carry() {
+        let mut cpu = cpu_with(&[0x8124, 0x8124]);
+        cpu.register.v[1] = 0xF0;
+        cpu.register.v[2] = 0x0F;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.register.v[1], 0xFF);
+        assert_eq!(cpu.register.v[0xF], 0);
+
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.register.v[1], 0x0E);
+        assert_eq!(cpu.register.v[0xF], 1);
+    }
+
+    #[test]
+    fn add_8xy4_flag_overrides_vf_operand() {
+        let mut cpu = cpu_with(&[0x8F14]);
+        cpu.register.v[1] = 0x01;
+        cpu.register.v[0xF] = 0xFF;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.register.v[0xF], 1);
+    }
+
+    #[test]
+    fn subtract_8xy5_sets_not_borrow() {
+        let mut cpu = cpu_with(&[0x8125, 0x8125]);
+        cpu.register.v[1] = 5;
+        cpu.register.v[2] = 3;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.register.v[1], 2);
+        assert_eq!(cpu.register.v[0xF], 1);
+
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.register.v[1], 0xFF);
+        assert_eq!(cpu.register.v[0xF], 0);
+    }
+
+    #[test]
+    fn shift_right_8xy6() {
+        let mut cpu = cpu_with(&[0x8106]);
+        cpu.register.v[1] = 0b0000_0101;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.register.v[1], 0b0000_0010);
+        assert_eq!(cpu.register.v[0xF], 1);
+    }
+
+    #[test]
+    fn set_subtract_8xy7() {
+        let mut cpu = cpu_with(&[0x8127, 0x8347]);
+        cpu.register.v[1] = 3;
+        cpu.register.v[2] = 5;
+        cpu.register.v[3] = 5;
+        cpu.register.v[4] = 3;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.register.v[1], 2);
+        assert_eq!(cpu.register.v[0xF], 1);
+
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.register.v[3], 0xFE);
+        assert_eq!(cpu.register.v[0xF], 0);
+    }
+
+    #[test]
+    fn shift_left_8xye() {
+        let mut cpu = cpu_with(&[0x810E]);
+        cpu.register.v[1] = 0b1000_0001;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.register.v[1], 0b0000_0010);
+        assert_eq!(cpu.register.v[0xF], 1);
+    }
+
+    #[test]
+    fn skip_if_registers_unequal_9xy0() {
+        let mut cpu = cpu_with(&[0x9120]);
+        cpu.register.v[1] = 1;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.program_counter, 0x204);
+
+        let mut cpu = cpu_with(&[0x9120]);
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.program_counter, 0x202);
+    }
+
+    #[test]
+    fn set_index_annn() {
+        let mut cpu = cpu_with(&[0xA123]);
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.index_register, 0x123);
+        assert_eq!(cpu.program_counter, 0x202);
+    }
+
+    #[test]
+    fn jump_with_offset_bnnn() {
+        let mut cpu = cpu_with(&[0xB300]);
+        cpu.register.v[0] = 0x10;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.program_counter, 0x310);
+        assert_eq!(cpu.index_register, 0);
+    }
+
+    #[test]
+    fn rand_cxnn_masks_with_nn() {
+        for _ in 0..32 {
+            let mut cpu = cpu_with(&[0xC10F]);
+            cpu.emulate_cycle().unwrap();
+            assert_eq!(cpu.register.v[1] & 0xF0, 0);
+            assert_eq!(cpu.program_counter, 0x202);
+        }
+
+        let mut cpu = cpu_with(&[0xC100]);
+        cpu.register.v[1] = 0xFF;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.register.v[1], 0);
+    }
+
+    #[test]
+    fn rand_cxnn_is_reproducible_with_a_seed() {
+        let rom = [0xC0, 0xFF, 0xC1, 0xFF, 0xC2, 0xFF, 0xC3, 0xFF];
+        let run = |seed| {
+            let mut cpu = CPU::with_seed(Quirks::default(), seed);
+            cpu.load_rom(&rom).unwrap();
+            cpu.run_frame(4).unwrap();
+            cpu.register.v[..4].to_vec()
+        };
+        assert_eq!(run(42), run(42));
+        assert_ne!(run(42), run(43));
+
+        // The generator state is part of save states, so a loaded state continues the sequence
+        let mut cpu = CPU::with_seed(Quirks::default(), 7);
+        cpu.load_rom(&rom).unwrap();
+        cpu.emulate_cycle().unwrap();
+        let state = cpu.save_state();
+        cpu.run_frame(3).unwrap();
+        let mut restored = CPU::new(Quirks::default());
+        restored.load_state(&state).unwrap();
+        restored.run_frame(3).unwrap();
+        assert_eq!(restored.register.v, cpu.register.v);
+    }
+
+    #[test]
+    fn draw_dxyn_xors_and_reports_collision() {
+        let mut cpu = cpu_with(&[0xD122, 0xD122]);
+        cpu.index_register = 0x300;
+        cpu.memory[0x300] = 0b1100_0000;
+        cpu.memory[0x301] = 0b0000_0001;
+        cpu.register.v[1] = 2;
+        cpu.register.v[2] = 3;
+
+        assert_eq!(cpu.emulate_cycle(), Ok(StepOutcome::Draw));
+        assert_eq!(cpu.gfx[3 * 64 + 2], 1);
+        assert_eq!(cpu.gfx[3 * 64 + 3], 1);
+        assert_eq!(cpu.gfx[4 * 64 + 9], 1);
+        assert_eq!(cpu.gfx.iter().filter(|pixel| **pixel == 1).count(), 3);
+        assert_eq!(cpu.register.v[0xF], 0);
+
+        cpu.emulate_cycle().unwrap();
+        assert!(cpu.gfx.iter().all(|pixel| *pixel == 0));
+        assert_eq!(cpu.register.v[0xF], 1);
+        assert_eq!(cpu.program_counter, 0x204);
+    }
+
+    #[test]
+    fn draw_dxyn_collision_on_earlier_row_is_kept() {
+        let mut cpu = cpu_with(&[0xD012]);
+        cpu.index_register = 0x300;
+        cpu.memory[0x300] = 0b1000_0000;
+        cpu.memory[0x301] = 0b1000_0000;
+        cpu.gfx[0] = 1;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.register.v[0xF], 1);
+    }
+
+    #[test]
+    fn draw_dxyn_wraps_around_edges() {
+        let mut cpu = cpu_with(&[0xD012]);
+        cpu.index_register = 0x300;
+        cpu.memory[0x300] = 0b1100_0000;
+        cpu.memory[0x301] = 0b1100_0000;
+        cpu.register.v[0] = 63;
+        cpu.register.v[1] = 31;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.gfx[31 * 64 + 63], 1);
+        assert_eq!(cpu.gfx[31 * 64], 1);
+        assert_eq!(cpu.gfx[63], 1);
+        assert_eq!(cpu.gfx[0], 1);
+    }
+
+    #[test]
+    fn skip_if_key_pressed_ex9e() {
+        let mut cpu = cpu_with(&[0xE19E]);
+        cpu.register.v[1] = 0xA;
+        cpu.keypad[0xA] = 1;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.program_counter, 0x204);
+
+        let mut cpu = cpu_with(&[0xE19E]);
+        cpu.register.v[1] = 0xA;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.program_counter, 0x202);
+    }
+
+    #[test]
+    fn skip_if_key_not_pressed_exa1() {
+        let mut cpu = cpu_with(&[0xE1A1]);
+        cpu.register.v[1] = 0xA;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.program_counter, 0x204);
+
+        let mut cpu = cpu_with(&[0xE1A1]);
+        cpu.register.v[1] = 0xA;
+        cpu.keypad[0xA] = 1;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.program_counter, 0x202);
+    }
+
+    #[test]
+    fn get_delay_timer_fx07() {
+        let mut cpu = cpu_with(&[0xF107]);
+        cpu.delay_timer = 42;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.register.v[1], 42);
+        assert_eq!(cpu.program_counter, 0x202);
+    }
+
+    #[test]
+    fn await_key_press_fx0a() {
+        let mut cpu = cpu_with(&[0xF10A]);
+        assert_eq!(cpu.emulate_cycle(), Ok(StepOutcome::Waiting));
+        assert_eq!(cpu.program_counter, 0x200);
+
+        cpu.keypad[0xB] = 1;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.register.v[1], 0xB);
+        assert_eq!(cpu.program_counter, 0x202);
+    }
+
+    #[test]
+    fn set_timers_fx15_fx18() {
+        let mut cpu = cpu_with(&[0xF115, 0xF218]);
+        cpu.register.v[1] = 10;
+        cpu.register.v[2] = 20;
+        cpu.emulate_cycle().unwrap();
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.delay_timer, 10);
+        assert_eq!(cpu.sound_timer, 20);
+        assert_eq!(cpu.program_counter, 0x204);
+    }
+
+    #[test]
+    fn add_to_index_fx1e() {
+        let mut cpu = cpu_with(&[0xF11E]);
+        cpu.index_register = 0xFFF;
+        cpu.register.v[1] = 2;
+        cpu.register.v[0xF] = 0;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.index_register, 0x1001);
+        assert_eq!(cpu.register.v[0xF], 0);
+    }
+
+    #[test]
+    fn font_location_fx29() {
+        let mut cpu = cpu_with(&[0xF129]);
+        cpu.register.v[1] = 0xA;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.index_register, 50);
+        assert_eq!(cpu.memory[50], 0xF0);
+    }
+
+    #[test]
+    fn binary_coded_decimal_fx33() {
+        let mut cpu = cpu_with(&[0xF533]);
+        cpu.index_register = 0x300;
+        cpu.register.v[5] = 254;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(&cpu.memory[0x300..0x303], &[2, 5, 4]);
+        assert_eq!(cpu.program_counter, 0x202);
+    }
+
+    #[test]
+    fn register_dump_fx55_includes_vx() {
+        let mut cpu = cpu_with(&[0xF255]);
+        cpu.index_register = 0x300;
+        cpu.register.v[0] = 1;
+        cpu.register.v[1] = 2;
+        cpu.register.v[2] = 3;
+        cpu.register.v[3] = 4;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(&cpu.memory[0x300..0x304], &[1, 2, 3, 0]);
+        assert_eq!(cpu.index_register, 0x300);
+    }
+
+    #[test]
+    fn register_load_fx65_includes_vx() {
+        let mut cpu = cpu_with(&[0xF265]);
+        cpu.index_register = 0x300;
+        cpu.memory[0x300] = 1;
+        cpu.memory[0x301] = 2;
+        cpu.memory[0x302] = 3;
+        cpu.memory[0x303] = 4;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(&cpu.register.v[..4], &[1, 2, 3, 0]);
+        assert_eq!(cpu.index_register, 0x300);
+    }
+
+    #[test]
+    fn quirk_shift_uses_vy() {
+        let quirks = Quirks {
+            shift_uses_vy: true,
+            ..Quirks::default()
+        };
+        let mut cpu = cpu_with_quirks(&[0x8126, 0x834E], quirks);
+        cpu.register.v[1] = 0xFF;
+        cpu.register.v[2] = 0b0000_0110;
+        cpu.register.v[4] = 0b1000_0001;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.register.v[1], 0b0000_0011);
+        assert_eq!(cpu.register.v[0xF], 0);
+
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.register.v[3], 0b0000_0010);
+        assert_eq!(cpu.register.v[0xF], 1);
+    }
+
+    #[test]
+    fn quirk_load_store_increments_index() {
+        let mut cpu = cpu_with_quirks(&[0xF255, 0xF265], Quirks::cosmac_vip());
+        cpu.index_register = 0x300;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.index_register, 0x303);
+
+        let mut cpu = cpu_with_quirks(&[0xF255], Quirks::chip48());
+        cpu.index_register = 0x300;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.index_register, 0x302);
+    }
+
+    #[test]
+    fn quirk_jump_uses_vx() {
+        let mut cpu = cpu_with_quirks(&[0xB320], Quirks::superchip());
+        cpu.register.v[0] = 0x01;
+        cpu.register.v[3] = 0x10;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.program_counter, 0x330);
+    }
+
+    #[test]
+    fn quirk_logic_resets_vf() {
+        let mut cpu = cpu_with_quirks(&[0x8121, 0x8122, 0x8123], Quirks::cosmac_vip());
+        for _ in 0..3 {
+            cpu.register.v[0xF] = 1;
+            cpu.emulate_cycle().unwrap();
+            assert_eq!(cpu.register.v[0xF], 0);
+        }
+    }
+
+    #[test]
+    fn quirk_clip_sprites() {
+        let quirks = Quirks {
+            clip_sprites: true,
+            ..Quirks::default()
+        };
+        let mut cpu = cpu_with_quirks(&[0xD012, 0xD232], quirks);
+        cpu.index_register = 0x300;
+        cpu.memory[0x300] = 0b1100_0000;
+        cpu.memory[0x301] = 0b1100_0000;
+        cpu.register.v[0] = 63;
+        cpu.register.v[1] = 31;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.gfx[31 * 64 + 63], 1);
+        assert_eq!(cpu.gfx.iter().filter(|pixel| **pixel == 1).count(), 1);
+
+        // The starting position still wraps
+        cpu.register.v[2] = 64 + 1;
+        cpu.register.v[3] = 32 + 1;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.gfx[64 + 1], 1);
+    }
+
+    #[test]
+    fn quirk_display_wait() {
+        let quirks = Quirks {
+            display_wait: true,
+            ..Quirks::default()
+        };
+        let mut cpu = cpu_with_quirks(&[0xD011], quirks);
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.program_counter, 0x200);
+
+        cpu.tick_timers();
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.program_counter, 0x202);
+    }
+
+    #[test]
+    fn high_and_low_resolution_00ff_00fe() {
+        let mut cpu = cpu_with(&[0x00FF, 0x00FE]);
+        cpu.gfx[0] = 1;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!((cpu.width(), cpu.height()), (128, 64));
+        assert_eq!(cpu.gfx.len(), 128 * 64);
+        assert!(cpu.gfx.iter().all(|pixel| *pixel == 0));
+
+        cpu.emulate_cycle().unwrap();
+        assert_eq!((cpu.width(), cpu.height()), (64, 32));
+        assert_eq!(cpu.gfx.len(), 64 * 32);
+        assert_eq!(cpu.program_counter, 0x204);
+    }
+
+    #[test]
+    fn draw_large_sprite_dxy0() {
+        let mut cpu = cpu_with(&[0x00FF, 0xD010]);
+        cpu.index_register = 0x300;
+        for row in 0..16 {
+            cpu.memory[0x300 + row * 2] = 0x80;
+            cpu.memory[0x300 + row * 2 + 1] = 0x01;
+        }
+        cpu.register.v[0] = 100;
+        cpu.register.v[1] = 40;
+        cpu.emulate_cycle().unwrap();
+        cpu.emulate_cycle().unwrap();
+        for row in 40..56 {
+            assert_eq!(cpu.gfx[row * 128 + 100], 1);
+            assert_eq!(cpu.gfx[row * 128 + 115], 1);
+        }
+        assert_eq!(cpu.gfx.iter().filter(|pixel| **pixel == 1).count(), 32);
+        assert_eq!(cpu.register.v[0xF], 0);
+    }
+
+    #[test]
+    fn scroll_down_00cn() {
+        let mut cpu = cpu_with(&[0x00C3]);
+        cpu.gfx[5] = 1;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.gfx[5], 0);
+        assert_eq!(cpu.gfx[3 * 64 + 5], 1);
+    }
+
+    #[test]
+    fn scroll_right_00fb_and_left_00fc() {
+        let mut cpu = cpu_with(&[0x00FB, 0x00FC, 0x00FC]);
+        cpu.gfx[64 + 62] = 1;
+        cpu.gfx[64 + 10] = 1;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.gfx[64 + 14], 1);
+        assert_eq!(cpu.gfx.iter().filter(|pixel| **pixel == 1).count(), 1);
+
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.gfx[64 + 10], 1);
+
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.gfx[64 + 6], 1);
+    }
+
+    #[test]
+    fn exit_00fd_stops_execution() {
+        let mut cpu = cpu_with(&[0x00FD, 0x6001]);
+        assert_eq!(cpu.emulate_cycle(), Ok(StepOutcome::Exited));
+        assert!(cpu.has_exited());
+        assert_eq!(cpu.emulate_cycle(), Ok(StepOutcome::Exited));
+        assert_eq!(cpu.register.v[0], 0);
+        assert_eq!(cpu.program_counter, 0x200);
+    }
+
+    #[test]
+    fn large_font_location_fx30() {
+        let mut cpu = cpu_with(&[0xF130]);
+        cpu.register.v[1] = 2;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.index_register, 0x50 + 20);
+        assert_eq!(cpu.memory[0x50 + 20], 0xFF);
+    }
+
+    #[test]
+    fn rpl_flags_fx75_fx85() {
+        let mut cpu = cpu_with(&[0xF275, 0x6000, 0xF285]);
+        cpu.register.v[0] = 1;
+        cpu.register.v[1] = 2;
+        cpu.register.v[2] = 3;
+        cpu.emulate_cycle().unwrap();
+        cpu.register.v[1] = 0;
+        cpu.register.v[2] = 0;
+        cpu.emulate_cycle().unwrap();
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(&cpu.register.v[..3], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn xo_chip_memory_size() {
+        assert_eq!(CPU::new(Quirks::default()).memory.len(), 0x1000);
+        assert_eq!(CPU::new(Quirks::xo_chip()).memory.len(), 0x10000);
+    }
+
+    #[test]
+    fn long_index_f000_nnnn() {
+        let mut cpu = cpu_with_quirks(&[0xF000, 0xBEEF], Quirks::xo_chip());
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.index_register, 0xBEEF);
+        assert_eq!(cpu.program_counter, 0x204);
+    }
+
+    #[test]
+    fn skip_steps_over_long_index() {
+        let mut cpu = cpu_with(&[0x3000, 0xF000, 0x1234, 0x6001]);
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.program_counter, 0x206);
+    }
+
+    #[test]
+    fn register_range_5xy2_5xy3() {
+        let mut cpu = cpu_with(&[0x5242, 0x5423, 0x5243]);
+        cpu.index_register = 0x300;
+        cpu.register.v[2] = 1;
+        cpu.register.v[3] = 2;
+        cpu.register.v[4] = 3;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(&cpu.memory[0x300..0x303], &[1, 2, 3]);
+        assert_eq!(cpu.index_register, 0x300);
+
+        // Reversed, V4 gets the first byte
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(&cpu.register.v[2..5], &[3, 2, 1]);
+
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(&cpu.register.v[2..5], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn plane_selection_fn01() {
+        let mut cpu = cpu_with(&[0xF201, 0xD011, 0xF301, 0xD011, 0xF101, 0x00E0]);
+        cpu.index_register = 0x300;
+        cpu.memory[0x300] = 0b1000_0000;
+        cpu.memory[0x301] = 0b1100_0000;
+
+        // Second plane only
+        cpu.emulate_cycle().unwrap();
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.gfx[0], 0b10);
+
+        // Both planes, one row of data for each
+        cpu.emulate_cycle().unwrap();
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.gfx[0], 0b01);
+        assert_eq!(cpu.gfx[1], 0b10);
+        assert_eq!(cpu.register.v[0xF], 1);
+
+        // Clearing the first plane leaves the second alone
+        cpu.emulate_cycle().unwrap();
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.gfx[0], 0);
+        assert_eq!(cpu.gfx[1], 0b10);
+    }
+
+    #[test]
+    fn audio_pattern_f002_and_pitch_fx3a() {
+        let mut cpu = cpu_with(&[0xF002, 0xF13A]);
+        cpu.index_register = 0x300;
+        cpu.memory[0x300] = 0xAA;
+        cpu.memory[0x30F] = 0x55;
+        cpu.register.v[1] = 112;
+        assert!(!cpu.has_audio_pattern());
+        cpu.emulate_cycle().unwrap();
+        assert!(cpu.has_audio_pattern());
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.audio_pattern()[0], 0xAA);
+        assert_eq!(cpu.audio_pattern()[15], 0x55);
+        assert_eq!(cpu.playback_rate(), 8000.0);
+    }
+
+    #[test]
+    fn tick_timers_counts_down_to_zero() {
+        let mut cpu = cpu_with(&[]);
+        cpu.delay_timer = 2;
+        cpu.sound_timer = 1;
+        cpu.tick_timers();
+        assert_eq!((cpu.delay_timer, cpu.sound_timer), (1, 0));
+        // The frame the sound timer ran out in still sounds
+        assert!(cpu.is_beeping());
+        cpu.tick_timers();
+        assert!(!cpu.is_beeping());
+        cpu.tick_timers();
+        assert_eq!((cpu.delay_timer, cpu.sound_timer), (0, 0));
+    }
+
+    #[test]
+    fn run_frame_ticks_timers_once() {
+        // An endless loop of adding 1 to V0
+        let mut cpu = cpu_with(&[0x7001, 0x1200]);
+        cpu.delay_timer = 10;
+        assert_eq!(cpu.run_frame(20), Ok(false));
+        assert_eq!(cpu.register.v[0], 10);
+        assert_eq!(cpu.delay_timer, 9);
+    }
+
+    #[test]
+    fn unknown_opcode_is_an_error() {
+        let mut cpu = cpu_with(&[0x5121]);
+        assert_eq!(
+            cpu.emulate_cycle(),
+            Err(CpuError::UnknownOpcode {
+                pc: 0x200,
+                opcode: 0x5121
+            })
+        );
+        assert_eq!(cpu.program_counter, 0x200);
+    }
+
+    #[test]
+    fn stack_overflow_and_underflow() {
+        // Calls itself forever
+        let mut cpu = cpu_with(&[0x2200]);
+        for _ in 0..16 {
+            cpu.emulate_cycle().unwrap();
+        }
+        assert_eq!(
+            cpu.emulate_cycle(),
+            Err(CpuError::StackOverflow { pc: 0x200 })
+        );
+
+        let mut cpu = cpu_with(&[0x00EE]);
+        assert_eq!(
+            cpu.emulate_cycle(),
+            Err(CpuError::StackUnderflow { pc: 0x200 })
+        );
+    }
+
+    #[test]
+    fn memory_out_of_bounds() {
+        let mut cpu = cpu_with(&[0x1FFF]);
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(
+            cpu.emulate_cycle(),
+            Err(CpuError::MemoryOutOfBounds {
+                pc: 0xFFF,
+                address: 0x1000
+            })
+        );
+
+        let mut cpu = cpu_with(&[0xD01F, 0xF233]);
+        cpu.index_register = 0xFFE;
+        assert!(cpu.emulate_cycle().is_err());
+        assert!(cpu.gfx.iter().all(|pixel| *pixel == 0));
+        assert_eq!(cpu.program_counter, 0x200);
+
+        cpu.program_counter = 0x202;
+        assert!(cpu.emulate_cycle().is_err());
+        assert_eq!(cpu.memory[0xFFE], 0);
+    }
+
+    #[test]
+    fn rom_too_large() {
+        let mut cpu = CPU::new(Quirks::default());
+        assert_eq!(
+            cpu.load_rom(&[0; 3585]),
+            Err(CpuError::RomTooLarge {
+                size: 3585,
+                max: 3584
+            })
+        );
+        assert!(cpu.load_rom(&[0; 3584]).is_ok());
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        let mut cpu = cpu_with_quirks(&[0x00FF, 0x6A2A, 0xA300, 0x2400], Quirks::xo_chip());
+        for _ in 0..4 {
+            cpu.emulate_cycle().unwrap();
+        }
+        cpu.gfx[100] = 3;
+        cpu.keypad[5] = 1;
+        cpu.delay_timer = 7;
+        cpu.rpl_flags[2] = 9;
+        let state = cpu.save_state();
+
+        let mut restored = CPU::new(Quirks::default());
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.save_state(), state);
+        assert_eq!(restored.quirks(), Quirks::xo_chip());
+        assert_eq!(restored.memory.len(), 0x10000);
+        assert_eq!(restored.register.v[0xA], 0x2A);
+        assert_eq!(restored.index_register, 0x300);
+        assert_eq!(restored.program_counter, 0x400);
+        assert_eq!(restored.stack_pointer, 1);
+        assert_eq!(restored.stack[0], 0x208);
+        assert_eq!((restored.width(), restored.height()), (128, 64));
+        assert_eq!(restored.gfx[100], 3);
+        assert_eq!(restored.delay_timer, 7);
+    }
+
+    #[test]
+    fn load_state_rejects_corrupted_data() {
+        let cpu = cpu_with(&[0x6001]);
+        let state = cpu.save_state();
+        let mut target = cpu_with(&[0x6002]);
+
+        let mut corrupted = state.clone();
+        corrupted[40] ^= 0xFF;
+        assert_eq!(
+            target.load_state(&corrupted),
+            Err(StateError::ChecksumMismatch)
+        );
+
+        let mut wrong_magic = state.clone();
+        wrong_magic[0] = b'X';
+        assert_eq!(target.load_state(&wrong_magic), Err(StateError::BadMagic));
+
+        let mut future_version = state.clone();
+        future_version[8] = 99;
+        assert_eq!(
+            target.load_state(&future_version),
+            Err(StateError::UnsupportedVersion(99))
+        );
+
+        assert_eq!(
+            target.load_state(&state[..state.len() - 1]),
+            Err(StateError::Truncated)
+        );
+
+        // Older versions lack the fields at the end, the header describes the shorter payload
+        let old_state = |version: u8, missing: usize| {
+            let mut old = state[..state.len() - missing].to_vec();
+            old[8] = version;
+            let payload_len = (old.len() - 18) as u32;
+            old[10..14].copy_from_slice(&payload_len.to_le_bytes());
+            let checksum = ::state::crc32(&old[18..]);
+            old[14..18].copy_from_slice(&checksum.to_le_bytes());
+            old
+        };
+        // Version 1 ended before the random number generator state
+        let mut old = cpu_with(&[]);
+        assert_eq!(old.load_state(&old_state(1, 9)), Ok(()));
+        assert_eq!(old.program_counter, cpu.program_counter);
+        // Version 2 before the flag for a loaded audio pattern
+        let mut old = cpu_with(&[]);
+        assert_eq!(old.load_state(&old_state(2, 1)), Ok(()));
+        assert!(!old.has_audio_pattern());
+
+        // The target is unchanged after all the failed attempts
+        target.emulate_cycle().unwrap();
+        assert_eq!(target.register.v[0], 2);
+    }
+
+    #[test]
+    fn next_memory_access_predicts_i_ranges() {
+        let mut cpu = cpu_with(&[0xD125, 0xF233, 0xF355, 0x5313, 0x6000]);
+        cpu.index_register = 0x300;
+        let access = |address, len, write| {
+            Some(MemoryAccess {
+                address,
+                len,
+                write,
+            })
+        };
+        assert_eq!(cpu.next_memory_access(), access(0x300, 5, false));
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.next_memory_access(), access(0x300, 3, true));
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.next_memory_access(), access(0x300, 4, true));
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.next_memory_access(), access(0x300, 3, false));
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.next_memory_access(), None);
+    }
+}
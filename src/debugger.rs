@@ -0,0 +1,875 @@
+// Interactive debugger on top of the CPU, driven by text commands
+//
+// The debugger decides how many instructions run: a host feeds it commands and calls run
+// while is_running is true. Breakpoints and watchpoints are checked before an instruction
+// executes, so a stop always shows the instruction that triggered it as the next one.
+// Timers tick once every cycles_per_frame executed instructions, time stands still while
+// the debugger is stopped.
+
+use cpu::{Opcode, StepOutcome, CPU};
+use disasm;
+use std::io::{BufRead, Write};
+use trace::Tracer;
+
+// Bytes per line of the x command
+const DUMP_WIDTH: usize = 16;
+
+const HELP: &str = "\
+step [n], s       Execute n instructions, 1 by default
+next, n           Step over subroutine calls
+finish, out       Run until the current subroutine returns
+continue, c       Run until a breakpoint or watchpoint triggers
+break ADDR [if COND]
+                  Stop at ADDR, optionally only if e.g. v3 == 5 holds
+watch ADDR [LEN]  Stop before memory in the range is written
+rwatch ADDR [LEN] Stop before memory in the range is read
+awatch ADDR [LEN] Stop before memory in the range is read or written
+delete [ID]       Remove a breakpoint or watchpoint, all of them without ID
+info              List breakpoints and watchpoints
+regs              Show registers, timers and the program counter
+set REG VALUE     Change v0-vf, i, pc, dt or st
+x ADDR [LEN]      Dump memory
+poke ADDR BYTE..  Write bytes to memory
+bt                Show the call stack
+dis [ADDR] [N]    Disassemble N instructions, starting at the program counter by default
+key K [up]        Press or release key K
+quit, q           Leave the debugger
+Numbers are decimal unless prefixed with 0x, an empty line repeats the last command";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Stopped,
+    Step(usize),
+    // Runs until the call at return_address - 2 returns to the stack depth it was made at
+    StepOver { return_address: usize, depth: usize },
+    // Runs until the stack is shallower than depth
+    StepOut { depth: usize },
+    Continue,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operand {
+    V(usize),
+    I,
+    DelayTimer,
+    SoundTimer,
+    Value(u16),
+}
+
+impl Operand {
+    fn value(&self, cpu: &CPU) -> u16 {
+        match *self {
+            Operand::V(x) => cpu.registers()[x] as u16,
+            Operand::I => cpu.index_register(),
+            Operand::DelayTimer => cpu.delay_timer() as u16,
+            Operand::SoundTimer => cpu.sound_timer() as u16,
+            Operand::Value(value) => value,
+        }
+    }
+
+    fn name(&self) -> String {
+        match *self {
+            Operand::V(x) => format!("V{:X}", x),
+            Operand::I => "I".to_string(),
+            Operand::DelayTimer => "DT".to_string(),
+            Operand::SoundTimer => "ST".to_string(),
+            Operand::Value(value) => value.to_string(),
+        }
+    }
+}
+
+// Longer operators first, so <= isn't mistaken for <
+const COMPARISONS: [&str; 6] = ["==", "!=", "<=", ">=", "<", ">"];
+
+#[derive(Debug, Clone, PartialEq)]
+struct Condition {
+    left: Operand,
+    comparison: &'static str,
+    right: Operand,
+}
+
+impl Condition {
+    fn holds(&self, cpu: &CPU) -> bool {
+        let (left, right) = (self.left.value(cpu), self.right.value(cpu));
+        match self.comparison {
+            "==" => left == right,
+            "!=" => left != right,
+            "<=" => left <= right,
+            ">=" => left >= right,
+            "<" => left < right,
+            _ => left > right,
+        }
+    }
+}
+
+struct Breakpoint {
+    id: usize,
+    address: usize,
+    condition: Option<Condition>,
+}
+
+struct Watchpoint {
+    id: usize,
+    address: usize,
+    len: usize,
+    read: bool,
+    write: bool,
+}
+
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    next_id: usize,
+    mode: Mode,
+    // Set when execution resumes, so the instruction a stop happened at doesn't stop it again
+    resumed: bool,
+    cycles_per_frame: usize,
+    cycles_since_tick: usize,
+    // Stop at FX0A without a held key and at a jump to itself, see set_stop_when_idle
+    stop_when_idle: bool,
+    last_command: String,
+    tracer: Option<Tracer>,
+}
+
+impl Debugger {
+    pub fn new(cycles_per_frame: usize) -> Debugger {
+        Debugger {
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            next_id: 1,
+            mode: Mode::Stopped,
+            resumed: false,
+            cycles_per_frame: cycles_per_frame.max(1),
+            cycles_since_tick: 0,
+            stop_when_idle: true,
+            last_command: "step".to_string(),
+            tracer: None,
+        }
+    }
+
+    // Without a window nothing can press a key or break out of an endless loop, so by default
+    // the debugger stops there instead of spinning forever. Hosts with live input turn this off
+    pub fn set_stop_when_idle(&mut self, stop_when_idle: bool) {
+        self.stop_when_idle = stop_when_idle;
+    }
+
+    // Records every instruction the debugger executes
+    pub fn enable_trace(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    // True while a step or continue is in progress, see run
+    pub fn is_running(&self) -> bool {
+        self.mode != Mode::Stopped
+    }
+
+    // Executes up to max_cycles instructions of the current step or continue
+    // Returns the reason and location once execution stops, None if it is still running
+    pub fn run(&mut self, cpu: &mut CPU, max_cycles: usize) -> Option<String> {
+        for _ in 0..max_cycles {
+            if self.mode == Mode::Stopped {
+                return None;
+            }
+            if !self.resumed {
+                if let Some(reason) = self.stop_reason(cpu) {
+                    return Some(self.stop(cpu, &reason));
+                }
+            }
+            self.resumed = false;
+
+            let result = match self.tracer {
+                Some(ref mut tracer) => tracer.step(cpu),
+                None => cpu.emulate_cycle(),
+            };
+            match result {
+                Ok(StepOutcome::Exited) => return Some(self.stop(cpu, "Program exited")),
+                Ok(_) => (),
+                Err(err) => return Some(self.stop(cpu, &err.to_string())),
+            }
+            self.cycles_since_tick += 1;
+            if self.cycles_since_tick == self.cycles_per_frame {
+                self.cycles_since_tick = 0;
+                cpu.tick_timers();
+            }
+
+            let finished = match self.mode {
+                Mode::Step(1) => true,
+                Mode::Step(n) => {
+                    self.mode = Mode::Step(n - 1);
+                    false
+                }
+                Mode::StepOver {
+                    return_address,
+                    depth,
+                } => cpu.program_counter() == return_address && cpu.call_stack().len() == depth,
+                Mode::StepOut { depth } => cpu.call_stack().len() < depth,
+                Mode::Stopped | Mode::Continue => false,
+            };
+            if finished {
+                return Some(self.stop(cpu, ""));
+            }
+        }
+        None
+    }
+
+    // Runs commands read line by line from input until quit or the end of input
+    // Every step or continue runs to completion before the next line is read
+    pub fn repl<R: BufRead, W: Write>(
+        &mut self,
+        cpu: &mut CPU,
+        input: R,
+        mut output: W,
+    ) -> std::io::Result<()> {
+        writeln!(output, "{}", location(cpu))?;
+        for line in input.lines() {
+            let line = line?;
+            if line.trim() == "quit" || line.trim() == "q" {
+                break;
+            }
+            let reply = self.command(cpu, &line);
+            if !reply.is_empty() {
+                writeln!(output, "{}", reply)?;
+            }
+            while self.is_running() {
+                if let Some(report) = self.run(cpu, self.cycles_per_frame) {
+                    writeln!(output, "{}", report)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Executes one command and returns what to show the user
+    // Steps and continues only start running, the host calls run to execute them
+    pub fn command(&mut self, cpu: &mut CPU, line: &str) -> String {
+        let line = if line.trim().is_empty() {
+            self.last_command.clone()
+        } else {
+            line.trim().to_string()
+        };
+        self.last_command = line.clone();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match self.execute(cpu, &words) {
+            Ok(reply) => reply,
+            Err(err) => err,
+        }
+    }
+
+    fn execute(&mut self, cpu: &mut CPU, words: &[&str]) -> Result<String, String> {
+        match words {
+            ["step"] | ["s"] => self.resume(Mode::Step(1)),
+            ["step", n] | ["s", n] => match parse_number(n)? {
+                0 => Err("Step count must be at least 1".to_string()),
+                n => self.resume(Mode::Step(n)),
+            },
+            ["next"] | ["n"] => match cpu.next_opcode() {
+                Some(Opcode::CallSubroutine(_)) => self.resume(Mode::StepOver {
+                    return_address: cpu.program_counter() + 2,
+                    depth: cpu.call_stack().len(),
+                }),
+                _ => self.resume(Mode::Step(1)),
+            },
+            ["finish"] | ["out"] => match cpu.call_stack().len() {
+                0 => Err("Not inside a subroutine".to_string()),
+                depth => self.resume(Mode::StepOut { depth }),
+            },
+            ["continue"] | ["c"] => self.resume(Mode::Continue),
+            ["break", address] => self.add_breakpoint(cpu, address, None),
+            ["break", address, "if", condition @ ..] => {
+                let condition = parse_condition(&condition.concat())?;
+                self.add_breakpoint(cpu, address, Some(condition))
+            }
+            [kind @ "watch", range @ ..]
+            | [kind @ "rwatch", range @ ..]
+            | [kind @ "awatch", range @ ..] => self.add_watchpoint(cpu, kind, range),
+            ["delete"] => {
+                self.breakpoints.clear();
+                self.watchpoints.clear();
+                Ok("Deleted all breakpoints and watchpoints".to_string())
+            }
+            ["delete", id] => {
+                let id = parse_number(id)?;
+                let count = self.breakpoints.len() + self.watchpoints.len();
+                self.breakpoints.retain(|breakpoint| breakpoint.id != id);
+                self.watchpoints.retain(|watchpoint| watchpoint.id != id);
+                if self.breakpoints.len() + self.watchpoints.len() == count {
+                    return Err(format!("No breakpoint or watchpoint {}", id));
+                }
+                Ok(format!("Deleted {}", id))
+            }
+            ["info"] => Ok(self.info()),
+            ["regs"] => Ok(registers(cpu)),
+            ["set", name, value] => set(cpu, name, parse_number(value)?),
+            ["x", address] => dump(cpu, parse_number(address)?, DUMP_WIDTH),
+            ["x", address, len] => dump(cpu, parse_number(address)?, parse_number(len)?),
+            ["poke", address, bytes @ ..] if !bytes.is_empty() => {
+                poke(cpu, parse_number(address)?, bytes)
+            }
+            ["bt"] => Ok(backtrace(cpu)),
+            ["dis"] => Ok(disassemble(cpu, cpu.program_counter(), 8)),
+            ["dis", address] => Ok(disassemble(cpu, parse_number(address)?, 8)),
+            ["dis", address, n] => Ok(disassemble(cpu, parse_number(address)?, parse_number(n)?)),
+            ["key", key] => press(cpu, key, true),
+            ["key", key, "up"] => press(cpu, key, false),
+            ["help"] => Ok(HELP.to_string()),
+            _ => Err(format!("Can't parse \"{}\", try help", words.join(" "))),
+        }
+    }
+
+    fn resume(&mut self, mode: Mode) -> Result<String, String> {
+        self.mode = mode;
+        self.resumed = true;
+        Ok(String::new())
+    }
+
+    fn stop(&mut self, cpu: &CPU, reason: &str) -> String {
+        self.mode = Mode::Stopped;
+        if reason.is_empty() {
+            location(cpu)
+        } else {
+            format!("{}\n{}", reason, location(cpu))
+        }
+    }
+
+    // Why execution has to stop before the instruction at the program counter, if at all
+    fn stop_reason(&self, cpu: &CPU) -> Option<String> {
+        let pc = cpu.program_counter();
+        for breakpoint in &self.breakpoints {
+            let triggered = breakpoint.address == pc
+                && breakpoint
+                    .condition
+                    .as_ref()
+                    .is_none_or(|condition| condition.holds(cpu));
+            if triggered {
+                return Some(format!("Breakpoint {}", breakpoint.id));
+            }
+        }
+        if let Some(access) = cpu.next_memory_access() {
+            for watchpoint in &self.watchpoints {
+                let overlaps = access.address < watchpoint.address + watchpoint.len
+                    && watchpoint.address < access.address + access.len;
+                let kind = if access.write {
+                    watchpoint.write
+                } else {
+                    watchpoint.read
+                };
+                if overlaps && kind {
+                    return Some(format!(
+                        "Watchpoint {}: {} of 0x{:03X}-0x{:03X}",
+                        watchpoint.id,
+                        if access.write { "write" } else { "read" },
+                        access.address,
+                        access.address + access.len - 1
+                    ));
+                }
+            }
+        }
+        if self.stop_when_idle {
+            match cpu.next_opcode() {
+                Some(Opcode::AwaitKeyPress(_)) if cpu.keypad().iter().all(|key| *key == 0) => {
+                    return Some("Waiting for a key, press one with key K".to_string())
+                }
+                Some(Opcode::Jump(address)) if address as usize == pc => {
+                    return Some("Endless loop".to_string())
+                }
+                _ => (),
+            }
+        }
+        None
+    }
+
+    fn add_breakpoint(
+        &mut self,
+        cpu: &CPU,
+        address: &str,
+        condition: Option<Condition>,
+    ) -> Result<String, String> {
+        let address = parse_address(cpu, address)?;
+        let id = self.next_id;
+        self.next_id += 1;
+        let reply = format!(
+            "Breakpoint {} at {}",
+            id,
+            describe_breakpoint(address, &condition)
+        );
+        self.breakpoints.push(Breakpoint {
+            id,
+            address,
+            condition,
+        });
+        Ok(reply)
+    }
+
+    fn add_watchpoint(&mut self, cpu: &CPU, kind: &str, range: &[&str]) -> Result<String, String> {
+        let (address, len) = match range {
+            [address] => (parse_address(cpu, address)?, 1),
+            [address, len] => (parse_address(cpu, address)?, parse_number(len)?),
+            _ => return Err(format!("Usage: {} ADDR [LEN]", kind)),
+        };
+        let end = address.checked_add(len);
+        if len == 0 || end.is_none_or(|end| end > cpu.memory().len()) {
+            return Err("The range has to be inside memory".to_string());
+        }
+        let watchpoint = Watchpoint {
+            id: self.next_id,
+            address,
+            len,
+            read: kind != "watch",
+            write: kind != "rwatch",
+        };
+        self.next_id += 1;
+        let reply = format!(
+            "Watchpoint {} at {}",
+            watchpoint.id,
+            describe_watchpoint(&watchpoint)
+        );
+        self.watchpoints.push(watchpoint);
+        Ok(reply)
+    }
+
+    fn info(&self) -> String {
+        let mut lines = Vec::new();
+        for breakpoint in &self.breakpoints {
+            lines.push(format!(
+                "{}  break {}",
+                breakpoint.id,
+                describe_breakpoint(breakpoint.address, &breakpoint.condition)
+            ));
+        }
+        for watchpoint in &self.watchpoints {
+            lines.push(format!(
+                "{}  watch {}",
+                watchpoint.id,
+                describe_watchpoint(watchpoint)
+            ));
+        }
+        if lines.is_empty() {
+            "No breakpoints or watchpoints".to_string()
+        } else {
+            lines.join("\n")
+        }
+    }
+}
+
+fn describe_breakpoint(address: usize, condition: &Option<Condition>) -> String {
+    match condition {
+        Some(condition) => format!(
+            "0x{:03X} if {} {} {}",
+            address,
+            condition.left.name(),
+            condition.comparison,
+            condition.right.name()
+        ),
+        None => format!("0x{:03X}", address),
+    }
+}
+
+fn describe_watchpoint(watchpoint: &Watchpoint) -> String {
+    let kind = match (watchpoint.read, watchpoint.write) {
+        (true, true) => "read/write",
+        (true, false) => "read",
+        _ => "write",
+    };
+    format!(
+        "0x{:03X}-0x{:03X} ({})",
+        watchpoint.address,
+        watchpoint.address + watchpoint.len - 1,
+        kind
+    )
+}
+
+// The instruction at the program counter, e.g. "0x202: 6A02  LD VA, 0x02"
+fn location(cpu: &CPU) -> String {
+    instruction_at(cpu, cpu.program_counter()).0
+}
+
+// The listing line for the instruction at address and its length in bytes
+fn instruction_at(cpu: &CPU, address: usize) -> (String, usize) {
+    let memory = cpu.memory();
+    let word = |address: usize| -> Option<u16> {
+        Some((*memory.get(address)? as u16) << 8 | *memory.get(address + 1)? as u16)
+    };
+    let opcode = match word(address) {
+        Some(opcode) => opcode,
+        None => return (format!("0x{:03X}: end of memory", address), 2),
+    };
+    let decoded = CPU::decode(opcode);
+    let long = match decoded {
+        Opcode::SetIndexRegisterLong => word(address + 2),
+        _ => None,
+    };
+    let text = match (decoded, long) {
+        (Opcode::Unknown(_), _) => format!("DW 0x{:04X}", opcode),
+        (Opcode::SetIndexRegisterLong, None) => "F000 at the end of memory".to_string(),
+        _ => disasm::mnemonic(decoded, long),
+    };
+    match long {
+        Some(long) => (
+            format!("0x{:03X}: {:04X} {:04X}  {}", address, opcode, long, text),
+            4,
+        ),
+        None => (format!("0x{:03X}: {:04X}  {}", address, opcode, text), 2),
+    }
+}
+
+fn registers(cpu: &CPU) -> String {
+    let v: Vec<String> = cpu
+        .registers()
+        .iter()
+        .enumerate()
+        .map(|(x, value)| format!("V{:X}=0x{:02X}", x, value))
+        .collect();
+    format!(
+        "{}\n{}\nI=0x{:03X} PC=0x{:03X} SP={} DT={} ST={}",
+        v[..8].join(" "),
+        v[8..].join(" "),
+        cpu.index_register(),
+        cpu.program_counter(),
+        cpu.call_stack().len(),
+        cpu.delay_timer(),
+        cpu.sound_timer()
+    )
+}
+
+fn set(cpu: &mut CPU, name: &str, value: usize) -> Result<String, String> {
+    let byte = || {
+        if value > 0xFF {
+            Err(format!("{} doesn't fit into a byte", value))
+        } else {
+            Ok(value as u8)
+        }
+    };
+    match parse_operand(name) {
+        Some(Operand::V(x)) => cpu.set_register(x, byte()?),
+        Some(Operand::I) if value <= 0xFFFF => cpu.set_index_register(value as u16),
+        Some(Operand::I) => return Err(format!("{} doesn't fit into I", value)),
+        Some(Operand::DelayTimer) => cpu.set_delay_timer(byte()?),
+        Some(Operand::SoundTimer) => cpu.set_sound_timer(byte()?),
+        _ if name.eq_ignore_ascii_case("pc") => cpu
+            .set_program_counter(value)
+            .map_err(|_| format!("0x{:X} is outside of memory", value))?,
+        _ => return Err(format!("Unknown register {}", name)),
+    }
+    Ok(registers(cpu))
+}
+
+fn dump(cpu: &CPU, address: usize, len: usize) -> Result<String, String> {
+    let memory = cpu.memory();
+    if address >= memory.len() {
+        return Err(format!("0x{:X} is outside of memory", address));
+    }
+    let end = address
+        .checked_add(len)
+        .ok_or("The range has to be inside memory")?
+        .min(memory.len());
+    let lines: Vec<String> = (address..end)
+        .step_by(DUMP_WIDTH)
+        .map(|start| {
+            let bytes: Vec<String> = memory[start..(start + DUMP_WIDTH).min(end)]
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect();
+            format!("0x{:03X}: {}", start, bytes.join(" "))
+        })
+        .collect();
+    Ok(lines.join("\n"))
+}
+
+fn poke(cpu: &mut CPU, address: usize, bytes: &[&str]) -> Result<String, String> {
+    let mut values = Vec::with_capacity(bytes.len());
+    for byte in bytes {
+        match parse_number(byte)? {
+            value if value <= 0xFF => values.push(value as u8),
+            value => return Err(format!("{} doesn't fit into a byte", value)),
+        }
+    }
+    let memory = cpu.memory_mut();
+    let end = address
+        .checked_add(values.len())
+        .filter(|&end| end <= memory.len())
+        .ok_or("The bytes have to fit into memory")?;
+    memory[address..end].copy_from_slice(&values);
+    Ok(format!("Wrote {} bytes at 0x{:03X}", values.len(), address))
+}
+
+// One line per active call, innermost first
+// Every return address points past a 2NNN, so the call was made 2 bytes before it
+fn backtrace(cpu: &CPU) -> String {
+    let mut lines = vec![format!("#0  0x{:03X}", cpu.program_counter())];
+    for (depth, return_address) in cpu.call_stack().iter().rev().enumerate() {
+        lines.push(format!(
+            "#{}  0x{:03X}  called from 0x{:03X}",
+            depth + 1,
+            return_address,
+            return_address.wrapping_sub(2)
+        ));
+    }
+    lines.join("\n")
+}
+
+fn disassemble(cpu: &CPU, mut address: usize, count: usize) -> String {
+    let mut lines = Vec::new();
+    for _ in 0..count {
+        if address >= cpu.memory().len() {
+            break;
+        }
+        let (line, len) = instruction_at(cpu, address);
+        let marker = if address == cpu.program_counter() {
+            "=> "
+        } else {
+            "   "
+        };
+        lines.push(format!("{}{}", marker, line));
+        address += len;
+    }
+    lines.join("\n")
+}
+
+fn press(cpu: &mut CPU, key: &str, pressed: bool) -> Result<String, String> {
+    match parse_number(key)? {
+        key if key <= 0xF => {
+            cpu.set_key(key, pressed);
+            Ok(format!(
+                "Key {:X} {}",
+                key,
+                if pressed { "pressed" } else { "released" }
+            ))
+        }
+        key => Err(format!("There is no key {}, keys go from 0 to 15", key)),
+    }
+}
+
+fn parse_number(word: &str) -> Result<usize, String> {
+    let lower = word.to_ascii_lowercase();
+    let parsed = match lower.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => lower.parse(),
+    };
+    parsed.map_err(|_| format!("{} is not a number", word))
+}
+
+fn parse_address(cpu: &CPU, word: &str) -> Result<usize, String> {
+    match parse_number(word)? {
+        address if address < cpu.memory().len() => Ok(address),
+        address => Err(format!("0x{:X} is outside of memory", address)),
+    }
+}
+
+// v0-vf, i, dt or st
+fn parse_operand(word: &str) -> Option<Operand> {
+    let lower = word.to_ascii_lowercase();
+    match lower.as_str() {
+        "i" => Some(Operand::I),
+        "dt" => Some(Operand::DelayTimer),
+        "st" => Some(Operand::SoundTimer),
+        _ => {
+            let register = lower.strip_prefix('v')?;
+            match usize::from_str_radix(register, 16) {
+                Ok(x) if register.len() == 1 => Some(Operand::V(x)),
+                _ => None,
+            }
+        }
+    }
+}
+
+// Parses e.g. "v3==5" or "i>=0x300", spaces are already removed
+fn parse_condition(text: &str) -> Result<Condition, String> {
+    let operand = |word: &str| match parse_operand(word) {
+        Some(operand) => Ok(operand),
+        None => match parse_number(word)? {
+            value if value <= 0xFFFF => Ok(Operand::Value(value as u16)),
+            value => Err(format!("{} is too large", value)),
+        },
+    };
+    for comparison in COMPARISONS.iter() {
+        if let Some(position) = text.find(*comparison) {
+            return Ok(Condition {
+                left: operand(&text[..position])?,
+                comparison,
+                right: operand(&text[position + comparison.len()..])?,
+            });
+        }
+    }
+    Err(format!(
+        "Can't parse condition {}, expected e.g. v3 == 5",
+        text
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quirks::Quirks;
+
+    fn cpu_with(rom: &[u8]) -> CPU {
+        let mut cpu = CPU::new(Quirks::default());
+        cpu.load_rom(rom).unwrap();
+        cpu
+    }
+
+    // Runs the command and everything it started, returns the stop report
+    fn run(debugger: &mut Debugger, cpu: &mut CPU, command: &str) -> String {
+        let reply = debugger.command(cpu, command);
+        let mut report = None;
+        for _ in 0..1000 {
+            if !debugger.is_running() {
+                break;
+            }
+            report = debugger.run(cpu, 10).or(report);
+        }
+        report.unwrap_or(reply)
+    }
+
+    // Counts V0 up, calls a subroutine that adds to V1 and stores both at 0x300
+    // 200: 7001   ADD V0, 1
+    // 202: 2208   CALL 0x208
+    // 204: 1200   JP 0x200
+    // 206: 0000
+    // 208: 7102   ADD V1, 2
+    // 20A: A300   LD I, 0x300
+    // 20C: F155   LD [I], V1
+    // 20E: 00EE   RET
+    const COUNTER: [u8; 16] = [
+        0x70, 0x01, 0x22, 0x08, 0x12, 0x00, 0x00, 0x00, 0x71, 0x02, 0xA3, 0x00, 0xF1, 0x55, 0x00,
+        0xEE,
+    ];
+
+    #[test]
+    fn breakpoints_stop_before_the_instruction() {
+        let mut cpu = cpu_with(&COUNTER);
+        let mut debugger = Debugger::new(10);
+        assert_eq!(
+            debugger.command(&mut cpu, "break 0x208"),
+            "Breakpoint 1 at 0x208"
+        );
+        assert_eq!(
+            run(&mut debugger, &mut cpu, "c"),
+            "Breakpoint 1\n0x208: 7102  ADD V1, 0x02"
+        );
+        assert_eq!(cpu.registers()[1], 0);
+
+        // Continuing executes the instruction at the breakpoint, then stops there again
+        run(&mut debugger, &mut cpu, "c");
+        assert_eq!(cpu.program_counter(), 0x208);
+        assert_eq!(cpu.registers()[1], 2);
+
+        debugger.command(&mut cpu, "delete 1");
+        debugger.command(&mut cpu, "break 0x204 if v0 >= 5");
+        run(&mut debugger, &mut cpu, "c");
+        assert_eq!(cpu.program_counter(), 0x204);
+        assert_eq!(cpu.registers()[0], 5);
+    }
+
+    #[test]
+    fn watchpoints_distinguish_reads_and_writes() {
+        let mut cpu = cpu_with(&COUNTER);
+        let mut debugger = Debugger::new(10);
+        debugger.command(&mut cpu, "rwatch 0x300 2");
+        debugger.command(&mut cpu, "break 0x204 if v0 == 3");
+        assert!(run(&mut debugger, &mut cpu, "c").starts_with("Breakpoint 2"));
+
+        debugger.command(&mut cpu, "delete 2");
+        debugger.command(&mut cpu, "watch 0x301");
+        assert_eq!(
+            run(&mut debugger, &mut cpu, "c"),
+            "Watchpoint 3: write of 0x300-0x301\n0x20C: F155  LD [I], V1"
+        );
+        assert_eq!(cpu.memory()[0x301], 6);
+    }
+
+    #[test]
+    fn step_over_and_out_follow_the_call_stack() {
+        let mut cpu = cpu_with(&COUNTER);
+        let mut debugger = Debugger::new(10);
+        run(&mut debugger, &mut cpu, "step");
+        assert_eq!(
+            run(&mut debugger, &mut cpu, "next"),
+            "0x204: 1200  JP 0x200"
+        );
+        assert_eq!(cpu.registers()[1], 2);
+
+        run(&mut debugger, &mut cpu, "step 3");
+        assert_eq!(cpu.program_counter(), 0x208);
+        assert_eq!(
+            debugger.command(&mut cpu, "bt"),
+            "#0  0x208\n#1  0x204  called from 0x202"
+        );
+        run(&mut debugger, &mut cpu, "finish");
+        assert_eq!(cpu.program_counter(), 0x204);
+        assert_eq!(cpu.call_stack().len(), 0);
+        assert_eq!(
+            debugger.command(&mut cpu, "finish"),
+            "Not inside a subroutine"
+        );
+    }
+
+    #[test]
+    fn inspects_and_modifies_state() {
+        let mut cpu = cpu_with(&COUNTER);
+        let mut debugger = Debugger::new(10);
+        debugger.command(&mut cpu, "set v3 0x2A");
+        debugger.command(&mut cpu, "set i 0x300");
+        assert!(debugger
+            .command(&mut cpu, "regs")
+            .contains("V3=0x2A V4=0x00"));
+        assert_eq!(cpu.index_register(), 0x300);
+
+        debugger.command(&mut cpu, "poke 0x300 1 2 0xFF");
+        assert_eq!(
+            debugger.command(&mut cpu, "x 0x300 4"),
+            "0x300: 01 02 FF 00"
+        );
+        assert_eq!(
+            debugger.command(&mut cpu, "poke 0x300 256"),
+            "256 doesn't fit into a byte"
+        );
+        // Lengths and addresses that overflow are rejected like any range outside memory
+        assert_eq!(
+            debugger.command(&mut cpu, "x 0x200 18446744073709551615"),
+            "The range has to be inside memory"
+        );
+        assert_eq!(
+            debugger.command(&mut cpu, "watch 0x200 18446744073709551615"),
+            "The range has to be inside memory"
+        );
+        assert_eq!(
+            debugger.command(&mut cpu, "poke 0xFFFFFFFFFFFFFFFF 1"),
+            "The bytes have to fit into memory"
+        );
+        assert_eq!(
+            debugger.command(&mut cpu, "dis 0x200 2"),
+            "=> 0x200: 7001  ADD V0, 0x01\n   0x202: 2208  CALL 0x208"
+        );
+    }
+
+    #[test]
+    fn stops_when_nothing_can_happen() {
+        // Waits for a key, then loops forever
+        let mut cpu = cpu_with(&[0xF3, 0x0A, 0x12, 0x02]);
+        let mut debugger = Debugger::new(10);
+        assert!(run(&mut debugger, &mut cpu, "c").starts_with("Waiting for a key"));
+        debugger.command(&mut cpu, "key 0xA");
+        assert!(run(&mut debugger, &mut cpu, "c").starts_with("Endless loop"));
+        assert_eq!(cpu.registers()[3], 0xA);
+    }
+
+    #[test]
+    fn repl_reads_commands_until_quit() {
+        let mut cpu = cpu_with(&COUNTER);
+        let mut debugger = Debugger::new(10);
+        let input = "break 0x20E\nc\n\nstep 2\nregs\nq\nstep\n";
+        let mut output = Vec::new();
+        debugger
+            .repl(&mut cpu, input.as_bytes(), &mut output)
+            .unwrap();
+        // The empty line repeats continue, the step after quit is never executed
+        assert_eq!(cpu.program_counter(), 0x200);
+        assert_eq!(cpu.registers()[1], 4);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("0x200: 7001  ADD V0, 0x01\nBreakpoint 1 at 0x20E\n"));
+    }
+}
@@ -0,0 +1,436 @@
+// Disassembler turning ROMs back into mnemonic source
+//
+// Mnemonics follow Cowgod's technical reference, with the common extensions for SUPER-CHIP
+// (SCD, SCR, SCL, EXIT, LOW, HIGH, LD HF, LD R) and XO-CHIP (SAVE, LOAD, LD I LONG, PLANE,
+// AUDIO, PITCH). Bytes and words are written in hex, nibbles in decimal.
+
+use cpu::Opcode::*;
+use cpu::{Opcode, CPU};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+// Address a ROM is loaded to
+pub const ROM_START: usize = 0x200;
+
+// Number of data bytes per DB line
+const BYTES_PER_LINE: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    // Decodes every word from the start, data included
+    Linear,
+    // Follows jumps, calls and skips from the entry point, everything unreached is data
+    Recursive,
+}
+
+// What refers to a labelled address, decides the name of the label
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum LabelKind {
+    Data,
+    Jump,
+    Subroutine,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Line {
+    Code {
+        address: usize,
+        bytes: Vec<u8>,
+        opcode: Opcode,
+        // The address of LD I, LONG, which is stored after the opcode
+        long: Option<u16>,
+    },
+    Data {
+        address: usize,
+        bytes: Vec<u8>,
+    },
+}
+
+impl Line {
+    pub fn address(&self) -> usize {
+        match *self {
+            Line::Code { address, .. } | Line::Data { address, .. } => address,
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        match *self {
+            Line::Code { ref bytes, .. } | Line::Data { ref bytes, .. } => bytes,
+        }
+    }
+}
+
+// A disassembled ROM, Display renders it with addresses, raw bytes and labels
+pub struct Listing {
+    pub lines: Vec<Line>,
+    labels: BTreeMap<usize, LabelKind>,
+}
+
+impl Listing {
+    // The label for an address inside the ROM that some instruction refers to
+    pub fn label(&self, address: usize) -> Option<String> {
+        self.labels.get(&address).map(|kind| {
+            let prefix = match kind {
+                LabelKind::Data => "data",
+                LabelKind::Jump => "loc",
+                LabelKind::Subroutine => "sub",
+            };
+            format!("{}_{:03X}", prefix, address)
+        })
+    }
+
+    fn address_text(&self, address: u16) -> String {
+        match self.label(address as usize) {
+            Some(label) => label,
+            None => format!("0x{:03X}", address),
+        }
+    }
+
+    // The text of a line, with addresses replaced by labels where there are any
+    pub fn text(&self, line: &Line) -> String {
+        match *line {
+            Line::Code { opcode, long, .. } => {
+                format_opcode(opcode, long, &|address| self.address_text(address))
+            }
+            Line::Data { ref bytes, .. } => format_data(bytes),
+        }
+    }
+
+    // Labels and mnemonics only, which the assembler turns back into the same ROM
+    pub fn source(&self) -> String {
+        let mut source = String::new();
+        for line in &self.lines {
+            if let Some(label) = self.label(line.address()) {
+                source.push_str(&format!("{}:\n", label));
+            }
+            source.push_str(&format!("    {}\n", self.text(line)));
+        }
+        source
+    }
+}
+
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for line in &self.lines {
+            if let Some(label) = self.label(line.address()) {
+                writeln!(f, "{}:", label)?;
+            }
+            let bytes: Vec<String> = line
+                .bytes()
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect();
+            // Data lines can be long, raw bytes of code are at most 4
+            let bytes = match line {
+                Line::Code { .. } => bytes.join(" "),
+                Line::Data { .. } => String::new(),
+            };
+            writeln!(
+                f,
+                "    {:03X}  {:<11}  {}",
+                line.address(),
+                bytes,
+                self.text(line)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// The mnemonic for a single instruction with plain hex addresses
+pub fn mnemonic(opcode: Opcode, long: Option<u16>) -> String {
+    format_opcode(opcode, long, &|address| format!("0x{:03X}", address))
+}
+
+fn format_opcode(opcode: Opcode, long: Option<u16>, address: &dyn Fn(u16) -> String) -> String {
+    match opcode {
+        Ignore => "SYS 0x000".to_string(),
+        ClearScreen => "CLS".to_string(),
+        Return => "RET".to_string(),
+        Jump(nnn) => format!("JP {}", address(nnn)),
+        SkipIfEqualAddress(x, nn) => format!("SE V{:X}, 0x{:02X}", x, nn),
+        SkipIfNotEqualAddress(x, nn) => format!("SNE V{:X}, 0x{:02X}", x, nn),
+        SkipIfEqualRegister(x, y) => format!("SE V{:X}, V{:X}", x, y),
+        SetRegister(x, nn) => format!("LD V{:X}, 0x{:02X}", x, nn),
+        SetIndexRegister(nnn) => format!("LD I, {}", address(nnn)),
+        CallSubroutine(nnn) => format!("CALL {}", address(nnn)),
+        Display(x, y, n) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        Add(x, y) => format!("ADD V{:X}, V{:X}", x, y),
+        AddAddressToRegister(x, nn) => format!("ADD V{:X}, 0x{:02X}", x, nn),
+        Assign(x, y) => format!("LD V{:X}, V{:X}", x, y),
+        AssignOr(x, y) => format!("OR V{:X}, V{:X}", x, y),
+        AssignAnd(x, y) => format!("AND V{:X}, V{:X}", x, y),
+        AssignXor(x, y) => format!("XOR V{:X}, V{:X}", x, y),
+        Subtract(x, y) => format!("SUB V{:X}, V{:X}", x, y),
+        LeastSigStoreAndShift(x, y) => format!("SHR V{:X}, V{:X}", x, y),
+        SetSubtract(x, y) => format!("SUBN V{:X}, V{:X}", x, y),
+        MostSigStoreAndShift(x, y) => format!("SHL V{:X}, V{:X}", x, y),
+        SkipIfUnequalRegisters(x, y) => format!("SNE V{:X}, V{:X}", x, y),
+        Flow(nnn) => format!("JP V0, {}", address(nnn)),
+        Rand(x, nn) => format!("RND V{:X}, 0x{:02X}", x, nn),
+        SkipIfKeyPressed(x) => format!("SKP V{:X}", x),
+        SkipIfNotKeyPressed(x) => format!("SKNP V{:X}", x),
+        GetDelayTimer(x) => format!("LD V{:X}, DT", x),
+        AwaitKeyPress(x) => format!("LD V{:X}, K", x),
+        SetDelayTimer(x) => format!("LD DT, V{:X}", x),
+        SetSoundTimer(x) => format!("LD ST, V{:X}", x),
+        AddToIndexRegister(x) => format!("ADD I, V{:X}", x),
+        SetIndexRegisterToSpriteLocation(x) => format!("LD F, V{:X}", x),
+        StoreBinaryCodedDecimal(x) => format!("LD B, V{:X}", x),
+        RegisterDump(x) => format!("LD [I], V{:X}", x),
+        RegisterLoad(x) => format!("LD V{:X}, [I]", x),
+        ScrollDown(n) => format!("SCD {}", n),
+        ScrollRight => "SCR".to_string(),
+        ScrollLeft => "SCL".to_string(),
+        Exit => "EXIT".to_string(),
+        LowResolution => "LOW".to_string(),
+        HighResolution => "HIGH".to_string(),
+        SetIndexRegisterToLargeSpriteLocation(x) => format!("LD HF, V{:X}", x),
+        StoreFlags(x) => format!("LD R, V{:X}", x),
+        LoadFlags(x) => format!("LD V{:X}, R", x),
+        SetIndexRegisterLong => match long {
+            Some(nnnn) => format!("LD I, LONG {}", address(nnnn)),
+            // F000 as the very last word of a ROM
+            None => "DW 0xF000".to_string(),
+        },
+        RegisterRangeDump(x, y) => format!("SAVE V{:X} - V{:X}", x, y),
+        RegisterRangeLoad(x, y) => format!("LOAD V{:X} - V{:X}", x, y),
+        SelectPlanes(n) => format!("PLANE {}", n),
+        LoadAudioPattern => "AUDIO".to_string(),
+        SetPitch(x) => format!("PITCH V{:X}", x),
+        Unknown(opcode) => format!("DW 0x{:04X}", opcode),
+    }
+}
+
+fn format_data(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|byte| format!("0x{:02X}", byte)).collect();
+    format!("DB {}", bytes.join(", "))
+}
+
+// Disassembles a ROM loaded at ROM_START
+pub fn disassemble(rom: &[u8], mode: Mode) -> Listing {
+    let code = match mode {
+        Mode::Linear => (0..rom.len() / 2)
+            .map(|word| ROM_START + word * 2)
+            .collect(),
+        Mode::Recursive => trace_code(rom),
+    };
+
+    let mut lines = Vec::new();
+    let mut labels = BTreeMap::new();
+    let mut data = Vec::new();
+    let mut offset = 0;
+    while offset < rom.len() {
+        let address = ROM_START + offset;
+        if code.contains(&address) && offset + 1 < rom.len() {
+            flush_data(&mut lines, &mut data, address);
+            let opcode = CPU::decode(word_at(rom, offset));
+            let long = match opcode {
+                SetIndexRegisterLong if offset + 3 < rom.len() => Some(word_at(rom, offset + 2)),
+                _ => None,
+            };
+            let len = if long.is_some() { 4 } else { 2 };
+            for (target, kind) in references(opcode, long) {
+                let in_rom = target >= ROM_START && target < ROM_START + rom.len();
+                if in_rom && labels.get(&target).is_none_or(|old| kind > *old) {
+                    labels.insert(target, kind);
+                }
+            }
+            lines.push(Line::Code {
+                address,
+                bytes: rom[offset..offset + len].to_vec(),
+                opcode,
+                long,
+            });
+            offset += len;
+        } else {
+            data.push(rom[offset]);
+            if data.len() == BYTES_PER_LINE {
+                flush_data(&mut lines, &mut data, address + 1);
+            }
+            offset += 1;
+        }
+    }
+    flush_data(&mut lines, &mut data, ROM_START + rom.len());
+
+    // Labels pointing into the middle of a line can't be shown
+    let starts: BTreeSet<usize> = lines.iter().map(Line::address).collect();
+    labels.retain(|address, _| starts.contains(address));
+
+    // Data lines are split at labels, so every label gets a line of its own
+    let lines = split_at_labels(lines, &labels);
+    Listing { lines, labels }
+}
+
+fn flush_data(lines: &mut Vec<Line>, data: &mut Vec<u8>, end: usize) {
+    if !data.is_empty() {
+        lines.push(Line::Data {
+            address: end - data.len(),
+            bytes: data.split_off(0),
+        });
+    }
+}
+
+fn split_at_labels(lines: Vec<Line>, labels: &BTreeMap<usize, LabelKind>) -> Vec<Line> {
+    let mut split = Vec::new();
+    for line in lines {
+        match line {
+            Line::Data { address, bytes } => {
+                let mut start = 0;
+                for i in 1..bytes.len() {
+                    if labels.contains_key(&(address + i)) {
+                        split.push(Line::Data {
+                            address: address + start,
+                            bytes: bytes[start..i].to_vec(),
+                        });
+                        start = i;
+                    }
+                }
+                split.push(Line::Data {
+                    address: address + start,
+                    bytes: bytes[start..].to_vec(),
+                });
+            }
+            code => split.push(code),
+        }
+    }
+    split
+}
+
+fn word_at(rom: &[u8], offset: usize) -> u16 {
+    (rom[offset] as u16) << 8 | rom[offset + 1] as u16
+}
+
+// Addresses an instruction refers to
+fn references(opcode: Opcode, long: Option<u16>) -> Vec<(usize, LabelKind)> {
+    match opcode {
+        Jump(nnn) | Flow(nnn) => vec![(nnn as usize, LabelKind::Jump)],
+        CallSubroutine(nnn) => vec![(nnn as usize, LabelKind::Subroutine)],
+        SetIndexRegister(nnn) => vec![(nnn as usize, LabelKind::Data)],
+        SetIndexRegisterLong => long
+            .map(|nnnn| vec![(nnnn as usize, LabelKind::Data)])
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+// Finds the start of every instruction reachable from ROM_START
+fn trace_code(rom: &[u8]) -> BTreeSet<usize> {
+    let end = ROM_START + rom.len();
+    let mut code = BTreeSet::new();
+    let mut pending = vec![ROM_START];
+    while let Some(address) = pending.pop() {
+        // Odd addresses and overlaps are possible, but only as targets of broken code
+        if address < ROM_START || address + 1 >= end || code.contains(&address) {
+            continue;
+        }
+        let opcode = CPU::decode(word_at(rom, address - ROM_START));
+        if let Unknown(_) = opcode {
+            continue;
+        }
+        code.insert(address);
+        let len = instruction_len(rom, address);
+        match opcode {
+            Jump(nnn) => pending.push(nnn as usize),
+            CallSubroutine(nnn) => {
+                pending.push(nnn as usize);
+                pending.push(address + len);
+            }
+            SkipIfEqualAddress(..)
+            | SkipIfNotEqualAddress(..)
+            | SkipIfEqualRegister(..)
+            | SkipIfUnequalRegisters(..)
+            | SkipIfKeyPressed(_)
+            | SkipIfNotKeyPressed(_) => {
+                let next = address + len;
+                pending.push(next);
+                if next + 1 < end {
+                    pending.push(next + instruction_len(rom, next));
+                }
+            }
+            // The target of JP V0 depends on V0, which isn't known without running the ROM
+            Return | Exit | Flow(_) => (),
+            _ => pending.push(address + len),
+        }
+    }
+    code
+}
+
+fn instruction_len(rom: &[u8], address: usize) -> usize {
+    let offset = address - ROM_START;
+    if offset + 1 < rom.len() && word_at(rom, offset) == 0xF000 {
+        4
+    } else {
+        2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(words: &[u16]) -> Vec<u8> {
+        words
+            .iter()
+            .flat_map(|word| vec![(word >> 8) as u8, *word as u8])
+            .collect()
+    }
+
+    #[test]
+    fn mnemonics() {
+        assert_eq!(mnemonic(CPU::decode(0x612A), None), "LD V1, 0x2A");
+        assert_eq!(mnemonic(CPU::decode(0xD015), None), "DRW V0, V1, 5");
+        assert_eq!(mnemonic(CPU::decode(0x8AB6), None), "SHR VA, VB");
+        assert_eq!(mnemonic(CPU::decode(0xB300), None), "JP V0, 0x300");
+        assert_eq!(mnemonic(CPU::decode(0xF265), None), "LD V2, [I]");
+        assert_eq!(mnemonic(CPU::decode(0x00C4), None), "SCD 4");
+        assert_eq!(mnemonic(CPU::decode(0x5132), None), "SAVE V1 - V3");
+        assert_eq!(
+            mnemonic(SetIndexRegisterLong, Some(0x1234)),
+            "LD I, LONG 0x1234"
+        );
+        assert_eq!(mnemonic(CPU::decode(0x8008), None), "DW 0x8008");
+    }
+
+    #[test]
+    fn recursive_descent_separates_sprites_from_code() {
+        // 200: LD I, sprite; DRW; CALL sub; JP 200
+        // 208: sprite data that would decode as garbage
+        // 20A: sub: RET
+        let rom = words(&[0xA208, 0xD015, 0x220A, 0x1200, 0xFF81, 0x00EE]);
+        let listing = disassemble(&rom, Mode::Recursive);
+        let kinds: Vec<bool> = listing
+            .lines
+            .iter()
+            .map(|line| match line {
+                Line::Code { .. } => true,
+                Line::Data { .. } => false,
+            })
+            .collect();
+        assert_eq!(kinds, [true, true, true, true, false, true]);
+        assert_eq!(listing.text(&listing.lines[0]), "LD I, data_208");
+        assert_eq!(listing.text(&listing.lines[2]), "CALL sub_20A");
+        assert_eq!(listing.text(&listing.lines[3]), "JP loc_200");
+        assert_eq!(listing.text(&listing.lines[4]), "DB 0xFF, 0x81");
+    }
+
+    #[test]
+    fn skips_follow_both_paths() {
+        // A skip over the long F000 NNNN lands 4 bytes further
+        let rom = words(&[0x3000, 0xF000, 0x0300, 0x00FD, 0x00FD]);
+        let listing = disassemble(&rom, Mode::Recursive);
+        let addresses: Vec<usize> = listing.lines.iter().map(Line::address).collect();
+        assert_eq!(addresses, [0x200, 0x202, 0x206, 0x208]);
+        assert_eq!(listing.text(&listing.lines[1]), "LD I, LONG 0x300");
+    }
+
+    #[test]
+    fn listing_shows_addresses_bytes_and_labels() {
+        let rom = words(&[0x6A02, 0x1200]);
+        let listing = disassemble(&rom, Mode::Linear).to_string();
+        assert_eq!(
+            listing,
+            "loc_200:\n    200  6A 02        LD VA, 0x02\n    202  12 00        JP loc_200\n"
+        );
+    }
+}
@@ -0,0 +1,473 @@
+use audio::{Beeper, Pattern, Tone, WavSink};
+use cpu::{StepOutcome, CPU};
+use error::CpuError;
+use movie::{Movie, MoviePlayer};
+use palette::Palette;
+use rewind::RewindBuffer;
+use screenshot;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+use trace::Tracer;
+use video::VideoRecorder;
+
+// Instructions executed per 60 Hz frame, i.e. 600 instructions per second
+pub const DEFAULT_CYCLES_PER_FRAME: usize = 10;
+
+// Receives the framebuffer whenever an instruction changed it
+pub trait DisplaySink {
+    // gfx has one byte per pixel, row by row, see CPU::gfx
+    fn draw(&mut self, gfx: &[u8], width: usize, height: usize);
+}
+
+// Told once per frame whether the beeper should be sounding, sinks that play samples
+// instead ask for them with sample_rate and get the synthesized tone, see audio::Beeper
+pub trait AudioSink {
+    fn set_beeping(&mut self, _beeping: bool) {}
+
+    // The rate to synthesize samples at, None for sinks that don't take samples
+    fn sample_rate(&self) -> Option<u32> {
+        None
+    }
+
+    // Receives the samples of every frame, from -1.0 to 1.0
+    fn queue(&mut self, _samples: &[f32]) {}
+}
+
+// Asked for the state of the 16 keys before every frame
+pub trait InputSource {
+    // Writes 1 for every pressed key and 0 for every released key
+    fn poll(&mut self, keypad: &mut [u8]);
+}
+
+// Discards every frame, for running without a screen
+pub struct NullDisplay;
+
+impl DisplaySink for NullDisplay {
+    fn draw(&mut self, _gfx: &[u8], _width: usize, _height: usize) {}
+}
+
+// Stays silent
+pub struct NullAudio;
+
+impl AudioSink for NullAudio {}
+
+// Never presses a key
+pub struct NullInput;
+
+impl InputSource for NullInput {
+    fn poll(&mut self, keypad: &mut [u8]) {
+        for key in keypad.iter_mut() {
+            *key = 0;
+        }
+    }
+}
+
+// Drives the CPU at 60 frames per second and connects it to a host
+// Hosts call run_frame at 60 Hz, the emulator pulls input and pushes video and audio
+pub struct Emulator<D = NullDisplay, A = NullAudio, I = NullInput> {
+    cpu: CPU,
+    display: D,
+    audio: A,
+    input: I,
+    cycles_per_frame: usize,
+    rewind: Option<RewindBuffer>,
+    tracer: Option<Tracer>,
+    recording: Option<Movie>,
+    playback: Option<MoviePlayer>,
+    video: Option<VideoRecorder>,
+    tone: Tone,
+    // Created for the sample rate of the audio sink once it asks for samples
+    beeper: Option<Beeper>,
+    samples: Vec<f32>,
+    // The audio track being written and the beeper rendering it
+    wav: Option<(WavSink, Beeper)>,
+}
+
+impl Emulator {
+    // An emulator without any host attached, e.g. for tests
+    pub fn headless(cpu: CPU) -> Emulator {
+        Emulator::new(cpu, NullDisplay, NullAudio, NullInput)
+    }
+}
+
+impl<D: DisplaySink, A: AudioSink, I: InputSource> Emulator<D, A, I> {
+    pub fn new(cpu: CPU, display: D, audio: A, input: I) -> Emulator<D, A, I> {
+        Emulator {
+            cpu,
+            display,
+            audio,
+            input,
+            cycles_per_frame: DEFAULT_CYCLES_PER_FRAME,
+            rewind: None,
+            tracer: None,
+            recording: None,
+            playback: None,
+            video: None,
+            tone: Tone::default(),
+            beeper: None,
+            samples: Vec::new(),
+            wav: None,
+        }
+    }
+
+    pub fn set_cycles_per_frame(&mut self, cycles_per_frame: usize) {
+        self.cycles_per_frame = cycles_per_frame;
+    }
+
+    // Records a snapshot history into the buffer on every frame, see rewind_frame
+    pub fn enable_rewind(&mut self, buffer: RewindBuffer) {
+        self.rewind = Some(buffer);
+    }
+
+    pub fn rewind(&self) -> Option<&RewindBuffer> {
+        self.rewind.as_ref()
+    }
+
+    // Records every instruction run_frame and step execute
+    pub fn enable_trace(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    // Stops tracing, the caller finishes the returned tracer
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    // Appends the keypad of every frame to the movie, the CPU has to be in the state the movie
+    // was created for, see Movie::new
+    pub fn record_movie(&mut self, movie: Movie) {
+        self.recording = Some(movie);
+    }
+
+    // Stops recording and returns the movie
+    pub fn take_recording(&mut self) -> Option<Movie> {
+        self.recording.take()
+    }
+
+    // Replaces the input of every frame with the recorded one until the movie ends,
+    // the CPU has to come from Movie::start
+    pub fn play_movie(&mut self, player: MoviePlayer) {
+        self.playback = Some(player);
+    }
+
+    pub fn playback(&self) -> Option<&MoviePlayer> {
+        self.playback.as_ref()
+    }
+
+    // Changes what the beeper sounds like from the next frame on
+    pub fn set_tone(&mut self, tone: Tone) {
+        self.tone = tone;
+        if let Some(ref mut beeper) = self.beeper {
+            beeper.set_tone(tone);
+        }
+        if let Some((_, ref mut beeper)) = self.wav {
+            beeper.set_tone(tone);
+        }
+    }
+
+    // Writes the audio of every frame run_frame and rewind_frame emulate into the WAV file,
+    // whatever the audio sink does with it
+    // Every frame has exactly a 60th of a second of samples, so the track stays in step
+    // with the frames and a video of them
+    pub fn record_audio(&mut self, wav: WavSink) {
+        let beeper = Beeper::new(self.tone, wav.sample_rate());
+        self.wav = Some((wav, beeper));
+    }
+
+    pub fn audio_recording(&self) -> Option<&WavSink> {
+        self.wav.as_ref().map(|(wav, _)| wav)
+    }
+
+    // Stops recording audio, the caller finishes the returned sink
+    pub fn take_audio(&mut self) -> Option<WavSink> {
+        self.wav.take().map(|(wav, _)| wav)
+    }
+
+    // Adds the screen to the video after every frame run_frame and rewind_frame emulate
+    pub fn record_video(&mut self, recorder: VideoRecorder) {
+        self.video = Some(recorder);
+    }
+
+    pub fn video(&self) -> Option<&VideoRecorder> {
+        self.video.as_ref()
+    }
+
+    // Stops recording, the caller finishes the returned recorder
+    pub fn take_video(&mut self) -> Option<VideoRecorder> {
+        self.video.take()
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    pub fn into_cpu(self) -> CPU {
+        self.cpu
+    }
+
+    pub fn display(&self) -> &D {
+        &self.display
+    }
+
+    pub fn display_mut(&mut self) -> &mut D {
+        &mut self.display
+    }
+
+    pub fn audio_mut(&mut self) -> &mut A {
+        &mut self.audio
+    }
+
+    pub fn input_mut(&mut self) -> &mut I {
+        &mut self.input
+    }
+
+    // Copies the key states from the input source into the CPU
+    // run_frame and step do this on their own, hosts that drive the CPU directly call it
+    pub fn poll_input(&mut self) {
+        self.input.poll(&mut self.cpu.keypad);
+    }
+
+    // Emulates one 60 Hz frame, see CPU::run_frame
+    pub fn run_frame(&mut self) -> Result<bool, CpuError> {
+        self.poll_input();
+        if let Some(ref mut player) = self.playback {
+            player.next_frame(&mut self.cpu.keypad);
+        }
+        if let Some(ref mut movie) = self.recording {
+            movie.record_frame(&self.cpu.keypad);
+        }
+        let result = match self.tracer {
+            Some(ref mut tracer) => self
+                .cpu
+                .run_frame_with(self.cycles_per_frame, |cpu| tracer.step(cpu)),
+            None => self.cpu.run_frame(self.cycles_per_frame),
+        };
+        if let Ok(true) = result {
+            self.present();
+        }
+        if let Some(ref mut rewind) = self.rewind {
+            rewind.record(&self.cpu);
+        }
+        self.record_video_frame();
+        let beeping = self.cpu.is_beeping();
+        self.play_audio(beeping);
+        result
+    }
+
+    // Called instead of run_frame while rewinding, plays the recorded history backwards
+    // Returns false when rewind is disabled or there's no history left
+    pub fn rewind_frame(&mut self) -> bool {
+        let rewound = match self.rewind {
+            Some(ref mut rewind) => rewind.rewind_frame(&mut self.cpu),
+            None => false,
+        };
+        if rewound {
+            self.present();
+        }
+        self.record_video_frame();
+        self.play_audio(false);
+        rewound
+    }
+
+    // Executes a single instruction without ticking the timers
+    pub fn step(&mut self) -> Result<StepOutcome, CpuError> {
+        self.poll_input();
+        let outcome = match self.tracer {
+            Some(ref mut tracer) => tracer.step(&mut self.cpu)?,
+            None => self.cpu.emulate_cycle()?,
+        };
+        if outcome == StepOutcome::Draw {
+            self.present();
+        }
+        Ok(outcome)
+    }
+
+    // Sends the current framebuffer to the display, regardless of whether it changed
+    pub fn present(&mut self) {
+        self.display
+            .draw(self.cpu.gfx(), self.cpu.width(), self.cpu.height());
+    }
+
+    // Sends the frame's audio to the sink and the WAV file, a frame of silence keeps them in
+    // step with the video
+    fn play_audio(&mut self, beeping: bool) {
+        let pattern = Pattern::from_cpu(&self.cpu);
+        if let Some((ref mut wav, ref mut beeper)) = self.wav {
+            self.samples.clear();
+            beeper.set_pattern(pattern);
+            beeper.render_frame(beeping, &mut self.samples);
+            wav.queue(&self.samples);
+        }
+        self.audio.set_beeping(beeping);
+        let sample_rate = match self.audio.sample_rate() {
+            Some(sample_rate) => sample_rate,
+            None => return,
+        };
+        if self.beeper.as_ref().map(Beeper::sample_rate) != Some(sample_rate) {
+            self.beeper = Some(Beeper::new(self.tone, sample_rate));
+        }
+        if let Some(ref mut beeper) = self.beeper {
+            self.samples.clear();
+            beeper.set_pattern(pattern);
+            beeper.render_frame(beeping, &mut self.samples);
+            self.audio.queue(&self.samples);
+        }
+    }
+
+    fn record_video_frame(&mut self) {
+        if let Some(ref mut video) = self.video {
+            video.frame(self.cpu.gfx(), self.cpu.width(), self.cpu.height());
+        }
+    }
+
+    // Saves the current framebuffer as a PNG with every pixel scale x scale pixels large
+    pub fn screenshot<P: AsRef<Path>>(
+        &self,
+        path: P,
+        scale: usize,
+        palette: &Palette,
+    ) -> io::Result<()> {
+        let file = BufWriter::new(File::create(path)?);
+        let cpu = &self.cpu;
+        screenshot::write_png(file, cpu.gfx(), cpu.width(), cpu.height(), scale, palette)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quirks::Quirks;
+    use std::env;
+    use std::fs;
+
+    struct CountingDisplay {
+        frames: usize,
+    }
+
+    impl DisplaySink for CountingDisplay {
+        fn draw(&mut self, gfx: &[u8], width: usize, height: usize) {
+            assert_eq!(gfx.len(), width * height);
+            self.frames += 1;
+        }
+    }
+
+    struct HoldKey(usize);
+
+    impl InputSource for HoldKey {
+        fn poll(&mut self, keypad: &mut [u8]) {
+            keypad[self.0] = 1;
+        }
+    }
+
+    #[test]
+    fn run_frame_feeds_input_and_presents_frames() {
+        // Wait for a key, draw the font sprite for it, then loop forever
+        let rom = [0xF0, 0x0A, 0xF0, 0x29, 0xD1, 0x15, 0x12, 0x06];
+        let mut cpu = CPU::new(Quirks::default());
+        cpu.load_rom(&rom).unwrap();
+        let mut emulator = Emulator::new(cpu, CountingDisplay { frames: 0 }, NullAudio, HoldKey(7));
+
+        assert_eq!(emulator.run_frame(), Ok(true));
+        assert_eq!(emulator.display().frames, 1);
+        assert_eq!(emulator.cpu().keypad()[7], 1);
+
+        // Nothing is drawn anymore, so nothing is presented
+        assert_eq!(emulator.run_frame(), Ok(false));
+        assert_eq!(emulator.display().frames, 1);
+    }
+
+    #[test]
+    fn rewind_frame_plays_history_backwards() {
+        // Adds 1 to V0 and draws the digit for it, over and over
+        let rom = [0x70, 0x01, 0x00, 0xE0, 0xF0, 0x29, 0xD1, 0x15, 0x12, 0x00];
+        let mut cpu = CPU::new(Quirks::default());
+        cpu.load_rom(&rom).unwrap();
+        let mut emulator = Emulator::new(cpu, CountingDisplay { frames: 0 }, NullAudio, NullInput);
+        assert!(!emulator.rewind_frame());
+
+        emulator.enable_rewind(RewindBuffer::new(1, 1, 1 << 16));
+        let mut snapshots = Vec::new();
+        for _ in 0..10 {
+            emulator.run_frame().unwrap();
+            snapshots.push(emulator.cpu().snapshot());
+        }
+        let presented = emulator.display().frames;
+
+        for _ in 0..3 {
+            assert!(emulator.rewind_frame());
+        }
+        assert_eq!(emulator.cpu().snapshot(), snapshots[6]);
+        assert_eq!(emulator.display().frames, presented + 3);
+    }
+
+    // Keeps all samples at 6000 Hz, 100 per frame
+    struct Samples(Vec<f32>);
+
+    impl AudioSink for Samples {
+        fn sample_rate(&self) -> Option<u32> {
+            Some(6000)
+        }
+
+        fn queue(&mut self, samples: &[f32]) {
+            self.0.extend_from_slice(samples);
+        }
+    }
+
+    #[test]
+    fn sound_timer_drives_the_beeper() {
+        // Sets the sound timer to 3, then loops forever
+        let rom = [0x60, 0x03, 0xF0, 0x18, 0x12, 0x04];
+        let mut cpu = CPU::new(Quirks::default());
+        cpu.load_rom(&rom).unwrap();
+        let mut emulator = Emulator::new(cpu, NullDisplay, Samples(Vec::new()), NullInput);
+        for _ in 0..4 {
+            emulator.run_frame().unwrap();
+        }
+        let samples = &emulator.audio_mut().0;
+        assert_eq!(samples.len(), 400);
+        // The timer sounds for 3 frames while it ticks down to 0, then the beeper fades out
+        // within 5 ms, i.e. 30 samples
+        assert!(samples[50..300].iter().all(|&sample| sample.abs() == 0.25));
+        assert!(samples[300..330].iter().any(|&sample| sample != 0.0));
+        assert!(samples[330..].iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn record_audio_writes_the_pattern_in_step_with_frames() {
+        // Loads a pattern of 8 set and 8 cleared bits, sets the sound timer to 2 and loops
+        let mut rom = vec![
+            0xA2, 0x0C, 0xF0, 0x02, 0x60, 0x02, 0xF0, 0x18, 0x12, 0x08, 0, 0,
+        ];
+        rom.extend([0xFF, 0x00].repeat(8));
+        let mut cpu = CPU::new(Quirks::xo_chip());
+        cpu.load_rom(&rom).unwrap();
+        let mut emulator = Emulator::headless(cpu);
+        let path = env::temp_dir().join(format!("rusty_chip-{}.wav", std::process::id()));
+        emulator.record_audio(WavSink::create(&path, 8000).unwrap());
+        for _ in 0..3 {
+            emulator.run_frame().unwrap();
+        }
+        assert_eq!(emulator.audio_recording().unwrap().len(), 400);
+        emulator.take_audio().unwrap().finish().unwrap();
+
+        let wav = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let samples = wav[44..]
+            .chunks(2)
+            .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]))
+            .collect::<Vec<_>>();
+        assert_eq!(samples.len(), 400);
+        // 4000 bits per second at 8000 Hz are 2 samples per bit, 32 per loop, and the timer
+        // sounds for the first two frames of 133 samples each
+        assert!(samples[40..48].iter().all(|&sample| sample == 8192));
+        assert!(samples[48..64].iter().all(|&sample| sample == -8192));
+        assert!(samples[64..80].iter().all(|&sample| sample == 8192));
+        assert!(samples[128..144].iter().all(|&sample| sample == 8192));
+        assert!(samples[266 + 40..].iter().all(|&sample| sample == 0));
+    }
+}
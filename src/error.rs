@@ -0,0 +1,212 @@
+use std::error::Error;
+use std::fmt;
+
+// Everything that stops the CPU from executing an instruction
+// The CPU state is left as it was before the failing instruction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CpuError {
+    // The word at pc does not decode to any instruction
+    UnknownOpcode { pc: u16, opcode: u16 },
+    // 2NNN with all 16 stack entries in use
+    StackOverflow { pc: u16 },
+    // 00EE with an empty stack
+    StackUnderflow { pc: u16 },
+    // The instruction at pc would access memory past the end of the address space
+    MemoryOutOfBounds { pc: u16, address: usize },
+    // The ROM does not fit into the memory after 0x200
+    RomTooLarge { size: usize, max: usize },
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CpuError::UnknownOpcode { pc, opcode } => {
+                write!(f, "Unknown instruction {:04X} at {:03X}", opcode, pc)
+            }
+            CpuError::StackOverflow { pc } => write!(f, "Stack overflow at {:03X}", pc),
+            CpuError::StackUnderflow { pc } => {
+                write!(f, "Return with an empty stack at {:03X}", pc)
+            }
+            CpuError::MemoryOutOfBounds { pc, address } => write!(
+                f,
+                "Memory access to {:X} out of bounds at {:03X}",
+                address, pc
+            ),
+            CpuError::RomTooLarge { size, max } => write!(
+                f,
+                "ROM with {} bytes is larger than the {} bytes available",
+                size, max
+            ),
+        }
+    }
+}
+
+impl Error for CpuError {}
+
+// Reasons a save state can't be loaded, the CPU is left untouched in every case
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StateError {
+    // The data does not start with the save state magic
+    BadMagic,
+    // Written by a different version of the save state format
+    UnsupportedVersion(u16),
+    // The data ends before all fields were read
+    Truncated,
+    // The payload does not match the checksum in the header
+    ChecksumMismatch,
+    // A field holds a value the CPU can't be in
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::BadMagic => write!(f, "Not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported save state version {}", version)
+            }
+            StateError::Truncated => write!(f, "Save state is truncated"),
+            StateError::ChecksumMismatch => write!(f, "Save state is corrupted"),
+            StateError::Invalid(field) => write!(f, "Save state has an invalid {}", field),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+// A mistake in assembler source, line and column start at 1
+// Problems with a file as a whole, like failing to read it, have line 0
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmError {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}: {}", self.file, self.message)
+        } else {
+            write!(
+                f,
+                "{}:{}:{}: {}",
+                self.file, self.line, self.column, self.message
+            )
+        }
+    }
+}
+
+impl Error for AsmError {}
+
+// Reasons a movie can't be played back
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MovieError {
+    // The data does not start with the movie magic
+    BadMagic,
+    // Written by a different version of the movie format
+    UnsupportedVersion(u16),
+    // The data ends before all frames were read
+    Truncated,
+    // A field holds a value no recording produces
+    Invalid(&'static str),
+    // The movie was recorded with a ROM with a different CRC-32
+    RomMismatch { recorded: u32, actual: u32 },
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MovieError::BadMagic => write!(f, "Not a movie"),
+            MovieError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported movie version {}", version)
+            }
+            MovieError::Truncated => write!(f, "Movie is truncated"),
+            MovieError::Invalid(field) => write!(f, "Movie has an invalid {}", field),
+            MovieError::RomMismatch { recorded, actual } => write!(
+                f,
+                "Movie was recorded with ROM {:08X}, this is {:08X}",
+                recorded, actual
+            ),
+        }
+    }
+}
+
+impl Error for MovieError {}
+
+// Fields of a movie are read like the ones of a save state
+impl From<StateError> for MovieError {
+    fn from(err: StateError) -> MovieError {
+        match err {
+            StateError::Truncated => MovieError::Truncated,
+            StateError::Invalid(field) => MovieError::Invalid(field),
+            StateError::BadMagic | StateError::UnsupportedVersion(_) => MovieError::BadMagic,
+            StateError::ChecksumMismatch => MovieError::Invalid("checksum"),
+        }
+    }
+}
+
+// Reasons a golden frame test fails, see golden::GoldenTest
+#[derive(Debug, Clone, PartialEq)]
+pub enum GoldenError {
+    // A line of the golden file can't be understood, lines start at 1
+    Parse { line: usize, message: String },
+    // The ROM crashed before the last frame
+    Cpu(CpuError),
+    // The screen after the last frame isn't the stored one
+    Mismatch { expected: String, actual: String },
+}
+
+impl fmt::Display for GoldenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GoldenError::Parse { line, ref message } => write!(f, "line {}: {}", line, message),
+            GoldenError::Cpu(ref err) => write!(f, "The ROM failed: {}", err),
+            GoldenError::Mismatch {
+                ref expected,
+                ref actual,
+            } => {
+                if expected.lines().count() != actual.lines().count() {
+                    return write!(f, "Expected\n{}\nbut got\n{}", expected, actual);
+                }
+                // Screens of the same size only show the rows that differ
+                let rows = expected.lines().zip(actual.lines()).enumerate();
+                for (row, (expected, actual)) in rows.filter(|&(_, (e, a))| e != a) {
+                    writeln!(f, "row {:2} expected {}", row, expected)?;
+                    writeln!(f, "{:>15} {}", "got", actual)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for GoldenError {}
+
+impl From<CpuError> for GoldenError {
+    fn from(err: CpuError) -> GoldenError {
+        GoldenError::Cpu(err)
+    }
+}
+
+// A mistake in a configuration file, lines start at 1
+// Problems with a file as a whole, like failing to read it, have line 0
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}: {}", self.file, self.message)
+        } else {
+            write!(f, "{}:{}: {}", self.file, self.line, self.message)
+        }
+    }
+}
+
+impl Error for ConfigError {}
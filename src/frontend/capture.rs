@@ -0,0 +1,61 @@
+// The screenshot and video hotkeys of the frontends, both save next to the ROM
+
+use frontend;
+use rusty_chip::emulator::{AudioSink, DisplaySink, Emulator, InputSource};
+use rusty_chip::palette::Palette;
+use rusty_chip::video::VideoRecorder;
+use std::path::PathBuf;
+
+// Pixels of screenshots are this large in low resolution and half as large in high resolution
+const SCREENSHOT_SCALE: usize = 8;
+
+// Saves the screen as game.ch8.1.png, game.ch8.2.png, ... and describes what happened
+pub fn screenshot<D, A, I>(
+    emulator: &Emulator<D, A, I>,
+    rom_path: &str,
+    palette: &Palette,
+) -> String
+where
+    D: DisplaySink,
+    A: AudioSink,
+    I: InputSource,
+{
+    let path = free_path(rom_path, "png");
+    let scale = SCREENSHOT_SCALE * 64 / emulator.cpu().width();
+    match emulator.screenshot(&path, scale, palette) {
+        Ok(()) => format!("Saved {}", path.display()),
+        Err(err) => format!("Can't write {}: {}", path.display(), err),
+    }
+}
+
+// Starts recording a GIF like game.ch8.1.gif, or stops and saves the running recording
+pub fn toggle_video<D, A, I>(
+    emulator: &mut Emulator<D, A, I>,
+    rom_path: &str,
+    palette: &Palette,
+) -> String
+where
+    D: DisplaySink,
+    A: AudioSink,
+    I: InputSource,
+{
+    if let Some(video) = emulator.take_video() {
+        return frontend::finish_video(video);
+    }
+    let path = free_path(rom_path, "gif");
+    match VideoRecorder::create(&path, frontend::VIDEO_SCALE, *palette) {
+        Ok(video) => {
+            emulator.record_video(video);
+            format!("Recording {}", path.display())
+        }
+        Err(err) => format!("Can't write {}: {}", path.display(), err),
+    }
+}
+
+// The first of game.ch8.1.ext, game.ch8.2.ext, ... that doesn't exist yet
+fn free_path(rom_path: &str, extension: &str) -> PathBuf {
+    (1..)
+        .map(|n| PathBuf::from(format!("{}.{}.{}", rom_path, n, extension)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
@@ -0,0 +1,478 @@
+use frontend::capture;
+use frontend::speaker::Speaker;
+use frontend::{self, Session};
+use piston::input::{Button, Key, PressEvent, ReleaseEvent, UpdateEvent};
+use piston_window::{
+    clear, rectangle, AdvancedWindow, Event, EventLoop, OpenGL, PistonWindow, WindowSettings,
+};
+use rusty_chip::cpu::CPU;
+use rusty_chip::debugger::Debugger;
+use rusty_chip::emulator::{DisplaySink, Emulator, InputSource};
+use rusty_chip::error::CpuError;
+use rusty_chip::keymap::Keymap;
+use rusty_chip::movie::MoviePlayer;
+use rusty_chip::palette::Palette;
+use rusty_chip::rewind::RewindBuffer;
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::fs;
+use std::io::{self, BufRead};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+// How far back holding Backspace can rewind
+static REWIND_SECONDS: usize = 30;
+// Upper bound for the memory used by the rewind history
+static REWIND_MAX_BYTES: usize = 16 * 1024 * 1024;
+
+// Runs the emulator in a window until it is closed
+// In debug mode the CPU starts stopped and is controlled by debugger commands from the terminal
+// F1 to F9 load the numbered save slot next to the ROM, holding Shift saves to it instead
+// Holding Backspace plays the last seconds backwards
+// F12 saves a screenshot next to the ROM, F11 starts and stops recording a GIF
+// While a movie records or plays, loading states and rewinding are disabled as the movie
+// couldn't follow them
+pub fn run(cpu: CPU, session: Session) {
+    let Session {
+        rom_path,
+        debug: debug_enabled,
+        tracer,
+        recording,
+        playback,
+        video,
+        wav,
+        tone,
+        keymap,
+        cycles_per_frame,
+        palette,
+        scale,
+        ..
+    } = session;
+    let speaker = match tone {
+        Some(_) => Speaker::spawn(),
+        None => Speaker::muted(),
+    };
+    let mut emulator = Emulator::new(cpu, FrameBuffer::new(), speaker, Keyboard::new(keymap));
+    if let Some(tone) = tone {
+        emulator.set_tone(tone);
+    }
+    emulator.set_cycles_per_frame(cycles_per_frame);
+    emulator.enable_rewind(RewindBuffer::new(REWIND_SECONDS, 1, REWIND_MAX_BYTES));
+    emulator.present();
+
+    let movie_active = recording.is_some() || playback.is_some();
+    let movie_path = match recording {
+        Some((movie, path)) => {
+            emulator.record_movie(movie);
+            Some(path)
+        }
+        None => None,
+    };
+    if let Some(movie) = playback {
+        emulator.set_cycles_per_frame(movie.cycles_per_frame);
+        emulator.play_movie(MoviePlayer::new(movie));
+    }
+    let mut playback_reported = false;
+    if let Some(video) = video {
+        emulator.record_video(video);
+    }
+    let wav_path = match wav {
+        Some((wav, path)) => {
+            emulator.record_audio(wav);
+            Some(path)
+        }
+        None => None,
+    };
+
+    let mut debugger = Debugger::new(cycles_per_frame);
+    // The keyboard can always end a wait for a key or a loop polling it
+    debugger.set_stop_when_idle(false);
+    if let Some(tracer) = tracer {
+        if debug_enabled {
+            debugger.enable_trace(tracer);
+        } else {
+            emulator.enable_trace(tracer);
+        }
+    }
+    let commands = if debug_enabled {
+        println!("{}", debugger.command(emulator.cpu_mut(), "dis 0x200 1"));
+        Some(read_commands())
+    } else {
+        None
+    };
+
+    let mut window_wrapper = WindowWrapper::new(palette, scale);
+    window_wrapper.window.set_ups(60);
+
+    // Once the CPU fails, emulation is paused and the last frame stays on screen
+    let mut paused = false;
+
+    let mut shift_held = false;
+    let mut rewind_held = false;
+
+    while let Some(e) = window_wrapper.window.next() {
+        if let Some(b) = e.press_args() {
+            if let Button::Keyboard(key) = b {
+                match key {
+                    Key::LShift | Key::RShift => shift_held = true,
+                    Key::Backspace if movie_active => {
+                        window_wrapper.notify("Rewind is disabled during movies")
+                    }
+                    Key::Backspace => rewind_held = true,
+                    Key::F11 => {
+                        let message = capture::toggle_video(&mut emulator, &rom_path, &palette);
+                        window_wrapper.notify(message);
+                    }
+                    Key::F12 => {
+                        let message = capture::screenshot(&emulator, &rom_path, &palette);
+                        window_wrapper.notify(message);
+                    }
+                    _ => (),
+                }
+                if let Some(slot) = save_slot(key) {
+                    let path = format!("{}.state{}", rom_path, slot);
+                    if shift_held {
+                        let state = emulator.cpu().save_state();
+                        match fs::write(&path, state) {
+                            Ok(()) => window_wrapper.notify(format!("Saved slot {}", slot)),
+                            Err(err) => window_wrapper.notify(err),
+                        }
+                    } else if movie_active {
+                        window_wrapper.notify("Loading states is disabled during movies");
+                    } else {
+                        let loaded =
+                            fs::read(&path)
+                                .map_err(|err| err.to_string())
+                                .and_then(|state| {
+                                    emulator
+                                        .cpu_mut()
+                                        .load_state(&state)
+                                        .map_err(|err| err.to_string())
+                                });
+                        match loaded {
+                            Ok(()) => {
+                                emulator.present();
+                                // A loaded state might get a failed CPU going again
+                                paused = false;
+                                window_wrapper.notify(format!("Loaded slot {}", slot));
+                            }
+                            Err(err) => window_wrapper.notify(err),
+                        }
+                    }
+                }
+            }
+            emulator.input_mut().process_input(&b, 1);
+        }
+
+        if let Some(b) = e.release_args() {
+            match b {
+                Button::Keyboard(Key::LShift) | Button::Keyboard(Key::RShift) => shift_held = false,
+                Button::Keyboard(Key::Backspace) => rewind_held = false,
+                _ => (),
+            }
+            emulator.input_mut().process_input(&b, 0);
+        }
+
+        if paused && rewind_held && e.update_args().is_some() {
+            // Rewinding to before the failure gets the CPU going again
+            if emulator.rewind_frame() {
+                paused = false;
+            }
+        } else if paused {
+            // Nothing to emulate
+        } else if let Some(ref commands) = commands {
+            while let Ok(line) = commands.try_recv() {
+                if line.trim() == "quit" || line.trim() == "q" {
+                    let video = emulator.take_video();
+                    let wav = emulator.take_audio().zip(wav_path);
+                    return frontend::finish(debugger.take_tracer(), None, video, wav);
+                }
+                let reply = debugger.command(emulator.cpu_mut(), &line);
+                if !reply.is_empty() {
+                    println!("{}", reply);
+                }
+                // Commands like poke can change what's on screen
+                emulator.present();
+            }
+            // The debugger ticks the timers itself, time stands still while it is stopped
+            if e.update_args().is_some() && debugger.is_running() {
+                emulator.poll_input();
+                let report = debugger.run(emulator.cpu_mut(), cycles_per_frame);
+                if let Some(report) = report {
+                    println!("{}", report);
+                }
+                emulator.present();
+            }
+        } else if e.update_args().is_some() {
+            // Update events arrive at 60 Hz, one emulated frame each
+            if rewind_held {
+                // At the end of the history the game stays frozen until Backspace is released
+                emulator.rewind_frame();
+            } else if let Err(err) = emulator.run_frame() {
+                window_wrapper.pause(&err);
+                paused = true;
+            }
+            if let Some(player) = emulator.playback() {
+                if player.is_finished() && !playback_reported {
+                    playback_reported = true;
+                    window_wrapper.notify(format!("Movie ended after {} frames", player.frame()));
+                }
+            }
+        }
+
+        window_wrapper.render(&e, emulator.display());
+    }
+    let tracer = emulator.take_tracer().or_else(|| debugger.take_tracer());
+    let recording = emulator.take_recording().zip(movie_path);
+    let wav = emulator.take_audio().zip(wav_path);
+    frontend::finish(tracer, recording, emulator.take_video(), wav);
+}
+
+// Keeps the last frame the emulator drew, the window shows it on every render event
+pub struct FrameBuffer {
+    pixels: Vec<u8>,
+    width: usize,
+    height: usize,
+}
+
+impl FrameBuffer {
+    fn new() -> FrameBuffer {
+        FrameBuffer {
+            pixels: Vec::new(),
+            width: 0,
+            height: 0,
+        }
+    }
+}
+
+impl DisplaySink for FrameBuffer {
+    fn draw(&mut self, gfx: &[u8], width: usize, height: usize) {
+        self.pixels.clear();
+        self.pixels.extend_from_slice(gfx);
+        self.width = width;
+        self.height = height;
+    }
+}
+
+// Key states collected from window events between frames
+pub struct Keyboard {
+    keymap: Keymap,
+    keypad: Vec<u8>,
+    // The host keys held down, a CHIP-8 key bound to several stays down until all are released
+    held: HashSet<String>,
+}
+
+impl Keyboard {
+    fn new(keymap: Keymap) -> Keyboard {
+        Keyboard {
+            keymap,
+            keypad: vec![0; 16],
+            held: HashSet::new(),
+        }
+    }
+
+    fn process_input(&mut self, b: &Button, new_value: u8) {
+        if let Some(name) = host_key_name(*b) {
+            self.set_host_key(name, new_value);
+        }
+    }
+
+    fn set_host_key(&mut self, name: String, new_value: u8) {
+        let keys = self.keymap.chip8_keys(&name);
+        if new_value == 0 {
+            self.held.remove(&name);
+        } else {
+            self.held.insert(name);
+        }
+        for key in keys {
+            let still_held = self
+                .held
+                .iter()
+                .any(|host_key| self.keymap.chip8_keys(host_key).contains(&key));
+            self.keypad[key] = still_held as u8;
+        }
+    }
+}
+
+impl InputSource for Keyboard {
+    fn poll(&mut self, keypad: &mut [u8]) {
+        keypad.copy_from_slice(&self.keypad);
+    }
+}
+
+// Forwards the lines typed into the terminal, so the event loop never blocks on stdin
+fn read_commands() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines().map_while(Result::ok) {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+// The name of the key in keymaps, see rusty_chip::keymap
+fn host_key_name(button: Button) -> Option<String> {
+    let key = match button {
+        Button::Keyboard(key) => key,
+        _ => return None,
+    };
+    let name = match key {
+        Key::Space => "space",
+        Key::Return => "enter",
+        Key::Tab => "tab",
+        Key::Up => "up",
+        Key::Down => "down",
+        Key::Left => "left",
+        Key::Right => "right",
+        Key::NumPad0 => "numpad0",
+        Key::NumPad1 => "numpad1",
+        Key::NumPad2 => "numpad2",
+        Key::NumPad3 => "numpad3",
+        Key::NumPad4 => "numpad4",
+        Key::NumPad5 => "numpad5",
+        Key::NumPad6 => "numpad6",
+        Key::NumPad7 => "numpad7",
+        Key::NumPad8 => "numpad8",
+        Key::NumPad9 => "numpad9",
+        Key::NumPadDivide => "numpad/",
+        Key::NumPadMultiply => "numpad*",
+        Key::NumPadMinus => "numpad-",
+        Key::NumPadPlus => "numpad+",
+        Key::NumPadPeriod => "numpad.",
+        Key::NumPadEnter => "numpadenter",
+        // Keys that type a character have it as their code
+        _ => {
+            let c = std::char::from_u32(key.code() as u32).filter(|c| !c.is_control())?;
+            return Some(c.to_lowercase().collect());
+        }
+    };
+    Some(name.to_string())
+}
+
+// The save slot number for the function keys F1 to F9
+fn save_slot(key: Key) -> Option<u8> {
+    match key {
+        Key::F1 => Some(1),
+        Key::F2 => Some(2),
+        Key::F3 => Some(3),
+        Key::F4 => Some(4),
+        Key::F5 => Some(5),
+        Key::F6 => Some(6),
+        Key::F7 => Some(7),
+        Key::F8 => Some(8),
+        Key::F9 => Some(9),
+        _ => None,
+    }
+}
+
+pub struct WindowWrapper {
+    window: PistonWindow,
+    palette: Palette,
+    scale: usize,
+}
+
+impl WindowWrapper {
+    fn new(palette: Palette, scale: usize) -> WindowWrapper {
+        WindowWrapper {
+            window: PistonWindow::new(
+                OpenGL::V3_3,
+                0,
+                WindowSettings::new("RustyChip", [(64 * scale) as u32, (32 * scale) as u32])
+                    .opengl(OpenGL::V3_3)
+                    .srgb(false)
+                    .build()
+                    .unwrap(),
+            ),
+            palette,
+            scale,
+        }
+    }
+
+    // Shows why the emulation stopped
+    fn pause(&mut self, err: &CpuError) {
+        eprintln!("Emulation paused: {}", err);
+        self.window.set_title(format!("RustyChip - {}", err));
+    }
+
+    // Shows a short status message in the title bar
+    fn notify<M: Display>(&mut self, message: M) {
+        println!("{}", message);
+        self.window.set_title(format!("RustyChip - {}", message));
+    }
+
+    pub fn render(&mut self, e: &Event, frame: &FrameBuffer) {
+        let (width, height) = (frame.width, frame.height);
+        if width == 0 {
+            return;
+        }
+        let pixel_size = pixel_size(self.scale, width);
+        let palette = self.palette;
+
+        self.window.draw_2d(e, |context, graphics| {
+            clear([0.5, 1.0, 0.5, 1.0], graphics);
+
+            for y in 0..height {
+                for x in 0..width {
+                    let index = y * width + x;
+
+                    let [r, g, b] = palette.colour(frame.pixels[index]);
+                    let color = [r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0, 1.0];
+
+                    rectangle(
+                        color,
+                        [
+                            x as f64 * pixel_size,
+                            y as f64 * pixel_size,
+                            pixel_size,
+                            pixel_size,
+                        ],
+                        context.transform,
+                        graphics,
+                    );
+                }
+            }
+        });
+    }
+}
+
+// Size of a pixel on screen, the window is sized for 64x32 and high resolution pixels are
+// drawn at half the size, fractions included so the image fills the window at any scale
+fn pixel_size(scale: usize, width: usize) -> f64 {
+    64.0 * scale as f64 / width as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pixels_fill_the_window_at_any_scale() {
+        assert_eq!(pixel_size(8, 64), 8.0);
+        assert_eq!(pixel_size(8, 128), 4.0);
+        assert_eq!(pixel_size(1, 128), 0.5);
+        // 128 pixels of an odd scale still span the 64 * scale pixels of the window
+        assert_eq!(pixel_size(3, 128) * 128.0, 192.0);
+    }
+
+    #[test]
+    fn keys_bound_twice_stay_down_until_both_are_released() {
+        let mut keymap = Keymap::default();
+        keymap.bind(0x5, vec!["w".to_string(), "up".to_string()]);
+        let mut keyboard = Keyboard::new(keymap);
+        let mut keypad = [0; 16];
+
+        keyboard.set_host_key("w".to_string(), 1);
+        keyboard.set_host_key("up".to_string(), 1);
+        keyboard.set_host_key("w".to_string(), 0);
+        keyboard.poll(&mut keypad);
+        assert_eq!(keypad[0x5], 1);
+
+        keyboard.set_host_key("up".to_string(), 0);
+        keyboard.poll(&mut keypad);
+        assert_eq!(keypad[0x5], 0);
+    }
+}
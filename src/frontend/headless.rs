@@ -0,0 +1,65 @@
+// Runs the ROM for a number of frames without a window or terminal, as fast as it goes,
+// and prints the screen it ended with, e.g. for scripts and recording videos in batches
+
+use frontend::{self, Session};
+use rusty_chip::cpu::CPU;
+use rusty_chip::emulator::Emulator;
+use rusty_chip::golden;
+use rusty_chip::movie::MoviePlayer;
+
+// Fails if the CPU stopped with an error before the last frame
+pub fn run(cpu: CPU, session: Session, frames: usize) -> Result<(), String> {
+    let Session {
+        tracer,
+        recording,
+        playback,
+        video,
+        wav,
+        cycles_per_frame,
+        ..
+    } = session;
+    let mut emulator = Emulator::headless(cpu);
+    emulator.set_cycles_per_frame(cycles_per_frame);
+    if let Some(tracer) = tracer {
+        emulator.enable_trace(tracer);
+    }
+    let movie_path = match recording {
+        Some((movie, path)) => {
+            emulator.record_movie(movie);
+            Some(path)
+        }
+        None => None,
+    };
+    if let Some(video) = video {
+        emulator.record_video(video);
+    }
+    let wav_path = match wav {
+        Some((wav, path)) => {
+            emulator.record_audio(wav);
+            Some(path)
+        }
+        None => None,
+    };
+    if let Some(movie) = playback {
+        emulator.set_cycles_per_frame(movie.cycles_per_frame);
+        emulator.play_movie(MoviePlayer::new(movie));
+    }
+
+    let mut result = Ok(());
+    for frame in 0..frames {
+        if let Err(err) = emulator.run_frame() {
+            result = Err(format!("Stopped in frame {}: {}", frame + 1, err));
+            break;
+        }
+    }
+    print!("{}", golden::render(emulator.cpu()));
+    let recording = emulator.take_recording().zip(movie_path);
+    let wav = emulator.take_audio().zip(wav_path);
+    frontend::finish(
+        emulator.take_tracer(),
+        recording,
+        emulator.take_video(),
+        wav,
+    );
+    result
+}
@@ -0,0 +1,91 @@
+// Hosts for the emulator core, each one behind its own cargo feature
+
+#[cfg(any(feature = "piston-frontend", feature = "tui-frontend"))]
+pub mod capture;
+#[cfg(feature = "piston-frontend")]
+pub mod gui;
+pub mod headless;
+#[cfg(feature = "piston-frontend")]
+pub mod speaker;
+#[cfg(feature = "tui-frontend")]
+pub mod tui;
+
+use rusty_chip::audio::{Tone, WavSink};
+use rusty_chip::keymap::Keymap;
+use rusty_chip::movie::Movie;
+use rusty_chip::palette::Palette;
+use rusty_chip::trace::Tracer;
+use rusty_chip::video::VideoRecorder;
+use std::fs;
+use std::path::PathBuf;
+
+// Size of a high resolution pixel in videos, low resolution pixels are twice as large
+pub const VIDEO_SCALE: usize = 4;
+
+// Everything the command line asked for besides running the ROM
+// Not every frontend reads every field
+#[cfg_attr(not(feature = "piston-frontend"), allow(dead_code))]
+pub struct Session {
+    pub rom_path: String,
+    pub debug: bool,
+    // Run in the terminal instead of a window
+    pub tui: bool,
+    // Frames to run without a frontend
+    pub headless: Option<usize>,
+    pub tracer: Option<Tracer>,
+    // The movie being recorded and where to save it once the run ends
+    pub recording: Option<(Movie, PathBuf)>,
+    pub playback: Option<Movie>,
+    pub video: Option<VideoRecorder>,
+    // The audio track being recorded and where it's written to
+    pub wav: Option<(WavSink, PathBuf)>,
+    // What the beeper sounds like, None when muted
+    pub tone: Option<Tone>,
+    pub keymap: Keymap,
+    pub cycles_per_frame: usize,
+    pub palette: Palette,
+    // Size of a low resolution pixel in the window
+    pub scale: usize,
+}
+
+// Flushes the trace and saves the recorded movie, video and audio after the run, reporting
+// what failed
+pub fn finish(
+    tracer: Option<Tracer>,
+    recording: Option<(Movie, PathBuf)>,
+    video: Option<VideoRecorder>,
+    wav: Option<(WavSink, PathBuf)>,
+) {
+    if let Some(Err(err)) = tracer.map(Tracer::finish) {
+        eprintln!("Writing the trace failed: {}", err);
+    }
+    if let Some((movie, path)) = recording {
+        match fs::write(&path, movie.to_bytes()) {
+            Ok(()) => eprintln!("Saved {} frames to {}", movie.len(), path.display()),
+            Err(err) => eprintln!("Can't write {}: {}", path.display(), err),
+        }
+    }
+    if let Some(video) = video {
+        eprintln!("{}", finish_video(video));
+    }
+    if let Some((wav, path)) = wav {
+        let seconds = wav.len() as f64 / wav.sample_rate() as f64;
+        match wav.finish() {
+            Ok(_) => eprintln!(
+                "Saved {:.1} seconds of audio to {}",
+                seconds,
+                path.display()
+            ),
+            Err(err) => eprintln!("Can't write {}: {}", path.display(), err),
+        }
+    }
+}
+
+// Closes the video and describes how it went
+pub fn finish_video(video: VideoRecorder) -> String {
+    let path = video.path().to_path_buf();
+    match video.finish() {
+        Ok(frames) => format!("Saved {} frames to {}", frames, path.display()),
+        Err(err) => format!("Can't write {}: {}", path.display(), err),
+    }
+}
@@ -0,0 +1,102 @@
+// Plays the beeper through whichever command line audio player is installed, which keeps
+// the frontend free of audio libraries
+
+use rusty_chip::audio::{self, DEFAULT_SAMPLE_RATE};
+use rusty_chip::emulator::AudioSink;
+use std::io::Write;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, SyncSender};
+use std::thread;
+
+// Players that read raw mono 16-bit samples from stdin, tried in this order
+static PLAYERS: [(&str, &[&str]); 3] = [
+    (
+        "aplay",
+        &["-q", "-t", "raw", "-f", "S16_LE", "-c", "1", "-r"],
+    ),
+    (
+        "paplay",
+        &["--raw", "--format=s16le", "--channels=1", "--rate"],
+    ),
+    (
+        "pw-cat",
+        &["--playback", "--format=s16", "--channels=1", "--rate"],
+    ),
+];
+
+// Frames of samples waiting for the player, about 100 ms
+// When the player falls further behind new frames are dropped instead of adding latency
+static QUEUED_FRAMES: usize = 6;
+
+pub struct Speaker {
+    player: Option<(Child, SyncSender<Vec<u8>>)>,
+}
+
+impl Speaker {
+    // Starts the first player that works, without one the speaker stays silent
+    pub fn spawn() -> Speaker {
+        for &(name, args) in PLAYERS.iter() {
+            let mut command = Command::new(name);
+            command.args(args);
+            // paplay reads from stdin when no file is given, the others need a -
+            if name != "paplay" {
+                command.args([DEFAULT_SAMPLE_RATE.to_string().as_str(), "-"]);
+            } else {
+                command.arg(DEFAULT_SAMPLE_RATE.to_string());
+            }
+            let spawned = command
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn();
+            if let Ok(mut child) = spawned {
+                let mut stdin = child.stdin.take().unwrap();
+                let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(QUEUED_FRAMES);
+                // Writing happens on its own thread so a slow player never stalls a frame
+                thread::spawn(move || {
+                    for bytes in receiver {
+                        if stdin.write_all(&bytes).is_err() {
+                            break;
+                        }
+                    }
+                });
+                return Speaker {
+                    player: Some((child, sender)),
+                };
+            }
+        }
+        eprintln!("No audio player found, install aplay, paplay or pw-cat for sound");
+        Speaker::muted()
+    }
+
+    pub fn muted() -> Speaker {
+        Speaker { player: None }
+    }
+}
+
+impl AudioSink for Speaker {
+    fn sample_rate(&self) -> Option<u32> {
+        self.player.as_ref().map(|_| DEFAULT_SAMPLE_RATE)
+    }
+
+    fn queue(&mut self, samples: &[f32]) {
+        if let Some((_, ref sender)) = self.player {
+            let bytes = samples
+                .iter()
+                .flat_map(|&sample| audio::to_i16(sample).to_le_bytes())
+                .collect();
+            // Full while the player is stalled, that frame is skipped
+            let _ = sender.try_send(bytes);
+        }
+    }
+}
+
+impl Drop for Speaker {
+    // Stops the sound right away instead of playing what's still buffered
+    fn drop(&mut self) {
+        if let Some((ref mut child, _)) = self.player {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
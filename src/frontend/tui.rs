@@ -0,0 +1,433 @@
+use frontend::capture;
+use frontend::{self, Session};
+use rusty_chip::cpu::CPU;
+use rusty_chip::emulator::{AudioSink, DisplaySink, Emulator, InputSource};
+use rusty_chip::keymap::Keymap;
+use rusty_chip::movie::MoviePlayer;
+use rusty_chip::palette::Palette;
+use std::io::{self, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Terminals only report key presses, repeated while a key is held, so a key counts as
+// held until no byte arrived for it for a while
+// A new press is held longer than the usual delay of 250 to 600 ms before auto-repeat
+// starts, so FX0A and EXA1 don't see a held key let go and pressed again
+static PRESS_HOLD_FRAMES: u8 = 40;
+// Once repeats arrive, longer than the usual interval between them
+static REPEAT_HOLD_FRAMES: u8 = 8;
+
+const ESCAPE: u8 = 0x1B;
+const CTRL_C: u8 = 0x03;
+// What terminals send for F11 and F12
+const F11: &[u8] = b"\x1B[23~";
+const F12: &[u8] = b"\x1B[24~";
+
+// Runs the emulator in the terminal at 60 Hz until Esc or Ctrl+C is pressed
+// F12 saves a screenshot next to the ROM, F11 starts and stops recording a GIF
+// Every character cell shows two pixels on top of each other with a half block
+pub fn run(cpu: CPU, session: Session) {
+    let Session {
+        rom_path,
+        tracer,
+        recording,
+        playback,
+        video,
+        wav,
+        tone,
+        keymap,
+        cycles_per_frame,
+        palette,
+        ..
+    } = session;
+    let raw_mode = match RawMode::enable() {
+        Ok(raw_mode) => raw_mode,
+        Err(err) => {
+            eprintln!("Can't switch the terminal to raw mode: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let mut emulator = Emulator::new(
+        cpu,
+        Screen::new(palette),
+        Bell::new(tone.is_some()),
+        TerminalKeys::new(keymap),
+    );
+    emulator.set_cycles_per_frame(cycles_per_frame);
+    if let Some(tracer) = tracer {
+        emulator.enable_trace(tracer);
+    }
+    let movie_path = match recording {
+        Some((movie, path)) => {
+            emulator.record_movie(movie);
+            Some(path)
+        }
+        None => None,
+    };
+    if let Some(video) = video {
+        emulator.record_video(video);
+    }
+    let wav_path = match wav {
+        Some((wav, path)) => {
+            emulator.record_audio(wav);
+            Some(path)
+        }
+        None => None,
+    };
+    if let Some(movie) = playback {
+        emulator.set_cycles_per_frame(movie.cycles_per_frame);
+        emulator.play_movie(MoviePlayer::new(movie));
+    }
+
+    let input = read_input();
+    let frame_time = Duration::from_secs(1) / 60;
+    let mut next_frame = Instant::now();
+    let mut status = "Esc quits".to_string();
+    let mut paused = false;
+    emulator.present();
+    'frames: loop {
+        while let Ok(bytes) = input.try_recv() {
+            // A lone Esc quits, escape sequences like the arrow keys are keys
+            if bytes.contains(&CTRL_C) || bytes == [ESCAPE] {
+                break 'frames;
+            }
+            if bytes == F11 {
+                status = capture::toggle_video(&mut emulator, &rom_path, &palette);
+            } else if bytes == F12 {
+                status = capture::screenshot(&emulator, &rom_path, &palette);
+            } else {
+                emulator.input_mut().press(&bytes);
+            }
+        }
+        if !paused {
+            if let Err(err) = emulator.run_frame() {
+                status = format!("Emulation paused: {}", err);
+                paused = true;
+            }
+            emulator.input_mut().next_frame();
+        }
+        if let Some(player) = emulator.playback() {
+            if player.is_finished() && !paused {
+                status = format!("Movie ended after {} frames", player.frame());
+            }
+        }
+        emulator.display_mut().show(&status);
+
+        next_frame += frame_time;
+        let now = Instant::now();
+        if next_frame > now {
+            thread::sleep(next_frame - now);
+        } else {
+            // Too slow to keep up, don't try to catch up on the lost frames
+            next_frame = now;
+        }
+    }
+    drop(raw_mode);
+    let recording = emulator.take_recording().zip(movie_path);
+    let wav = emulator.take_audio().zip(wav_path);
+    frontend::finish(
+        emulator.take_tracer(),
+        recording,
+        emulator.take_video(),
+        wav,
+    );
+}
+
+// Switches the terminal to raw mode with stty and restores the old settings when dropped
+// Raw mode hands over every key press right away, without echoing it or waiting for Enter
+struct RawMode {
+    saved: String,
+}
+
+impl RawMode {
+    fn enable() -> io::Result<RawMode> {
+        let saved = stty(&["-g"])?;
+        stty(&["raw", "-echo"])?;
+        // The alternate screen keeps the terminal contents, the cursor would flicker
+        print!("\x1B[?1049h\x1B[?25l\x1B[2J");
+        Ok(RawMode {
+            saved: saved.trim().to_string(),
+        })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        print!("\x1B[0m\x1B[?25h\x1B[?1049l");
+        let _ = io::stdout().flush();
+        let _ = stty(&[&self.saved]);
+    }
+}
+
+// Runs stty on the terminal stdin is connected to and returns what it printed
+fn stty(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other("stdin is not a terminal"));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// Forwards the bytes typed into the terminal, a read at a time so escape sequences
+// arrive in one piece
+fn read_input() -> Receiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        let mut stdin = stdin.lock();
+        let mut buffer = [0; 64];
+        while let Ok(len) = stdin.read(&mut buffer) {
+            if len == 0 || sender.send(buffer[..len].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+// Draws the frames the emulator sends with a status line below them
+struct Screen {
+    palette: Palette,
+    frame: String,
+    dirty: bool,
+    status: String,
+}
+
+impl Screen {
+    fn new(palette: Palette) -> Screen {
+        Screen {
+            palette,
+            frame: String::new(),
+            dirty: false,
+            status: String::new(),
+        }
+    }
+
+    // Writes the frame to the terminal if it or the status changed since the last call
+    fn show(&mut self, status: &str) {
+        if !self.dirty && status == self.status {
+            return;
+        }
+        self.dirty = false;
+        self.status = status.to_string();
+        // In raw mode a line feed doesn't return the cursor to the start of the line
+        let frame = self.frame.replace('\n', "\r\n");
+        // Clearing to the end of the screen removes the rest of a larger previous frame
+        print!("\x1B[H{}\x1B[0m\x1B[K{}\x1B[J", frame, status);
+        let _ = io::stdout().flush();
+    }
+}
+
+impl DisplaySink for Screen {
+    fn draw(&mut self, gfx: &[u8], width: usize, height: usize) {
+        self.frame = render(gfx, width, height, &self.palette);
+        self.dirty = true;
+    }
+}
+
+// Draws two rows of pixels per line of text with upper half blocks, the foreground
+// colour is the upper pixel and the background colour the lower one
+// Colour codes are only written when they change, ending every line with the colours reset
+// and the rest of the line cleared, which a wider previous frame may still fill
+fn render(gfx: &[u8], width: usize, height: usize, palette: &Palette) -> String {
+    let mut text = String::with_capacity(width * height * 4);
+    for y in (0..height).step_by(2) {
+        let mut colours = None;
+        for x in 0..width {
+            let upper = gfx[y * width + x] & 0b11;
+            let lower = gfx.get((y + 1) * width + x).map_or(0, |pixel| pixel & 0b11);
+            if colours != Some((upper, lower)) {
+                let [fr, fg, fb] = palette.colour(upper);
+                let [br, bg, bb] = palette.colour(lower);
+                text.push_str(&format!(
+                    "\x1B[38;2;{};{};{};48;2;{};{};{}m",
+                    fr, fg, fb, br, bg, bb
+                ));
+                colours = Some((upper, lower));
+            }
+            text.push('▀');
+        }
+        text.push_str("\x1B[0m\x1B[K\n");
+    }
+    text
+}
+
+// Rings the terminal bell when the sound timer starts, terminals have no way to hold a tone
+struct Bell {
+    enabled: bool,
+    beeping: bool,
+}
+
+impl Bell {
+    fn new(enabled: bool) -> Bell {
+        Bell {
+            enabled,
+            beeping: false,
+        }
+    }
+}
+
+impl AudioSink for Bell {
+    fn set_beeping(&mut self, beeping: bool) {
+        if self.enabled && beeping && !self.beeping {
+            print!("\x07");
+        }
+        self.beeping = beeping;
+    }
+}
+
+// Turns the key presses from the terminal into held and released keys
+struct TerminalKeys {
+    keymap: Keymap,
+    // Frames left until each key counts as released, 0 for released keys
+    held: [u8; 16],
+}
+
+impl TerminalKeys {
+    fn new(keymap: Keymap) -> TerminalKeys {
+        TerminalKeys {
+            keymap,
+            held: [0; 16],
+        }
+    }
+
+    // Holds the keys typed since the last frame, or keeps holding them on auto-repeat
+    fn press(&mut self, bytes: &[u8]) {
+        for name in host_key_names(bytes) {
+            for key in self.keymap.chip8_keys(&name) {
+                let hold = match self.held[key] {
+                    0 => PRESS_HOLD_FRAMES,
+                    _ => REPEAT_HOLD_FRAMES,
+                };
+                // A repeat never shortens the hold of the press before it
+                self.held[key] = self.held[key].max(hold);
+            }
+        }
+    }
+
+    // Counts down the keys after every frame, releasing the ones that weren't repeated
+    fn next_frame(&mut self) {
+        for frames in self.held.iter_mut() {
+            *frames = frames.saturating_sub(1);
+        }
+    }
+}
+
+// The names of the typed keys in keymaps, see rusty_chip::keymap
+// Terminals send the same characters for the numpad as for the other keys, so they are both
+fn host_key_names(bytes: &[u8]) -> Vec<String> {
+    let text = String::from_utf8_lossy(bytes);
+    let mut chars = text.chars();
+    let mut names = Vec::new();
+    while let Some(c) = chars.next() {
+        let name = match c {
+            '\x1B' => {
+                // Arrow keys send Esc [ or Esc O and a letter, other sequences are skipped
+                chars.next();
+                match chars.by_ref().find(|c| ('@'..='~').contains(c)) {
+                    Some('A') => "up",
+                    Some('B') => "down",
+                    Some('C') => "right",
+                    Some('D') => "left",
+                    _ => continue,
+                }
+            }
+            ' ' => "space",
+            '\t' => "tab",
+            '\r' | '\n' => {
+                names.push("numpadenter".to_string());
+                "enter"
+            }
+            _ => {
+                let c = c.to_lowercase().collect::<String>();
+                if c.len() == 1 && "0123456789/*-+.".contains(&c) {
+                    names.push(format!("numpad{}", c));
+                }
+                names.push(c);
+                continue;
+            }
+        };
+        names.push(name.to_string());
+    }
+    names
+}
+
+impl InputSource for TerminalKeys {
+    fn poll(&mut self, keypad: &mut [u8]) {
+        for (state, &frames) in keypad.iter_mut().zip(self.held.iter()) {
+            *state = (frames > 0) as u8;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_uses_half_blocks_per_two_rows() {
+        // 2x4 pixels: lit above unlit, unlit above lit, then both lit twice
+        let gfx = [1, 0, 0, 1, 1, 1, 1, 1];
+        let text = render(&gfx, 2, 4, &Palette::default());
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].matches('▀').count(), 2);
+        assert!(lines[0].starts_with("\x1B[38;2;255;255;255;48;2;0;0;0m▀"));
+        // The second line has the same colours for both cells, so only one colour code
+        assert_eq!(lines[1].matches("\x1B[38").count(), 1);
+        assert!(lines[1].ends_with("\x1B[0m\x1B[K"));
+    }
+
+    #[test]
+    fn keys_are_released_without_repeats() {
+        let mut keys = TerminalKeys::new(Keymap::default());
+        let mut keypad = [0; 16];
+        keys.press(b"1V");
+        // A press is held until auto-repeat starts
+        for _ in 0..PRESS_HOLD_FRAMES {
+            keys.poll(&mut keypad);
+            assert_eq!((keypad[1], keypad[0xF]), (1, 1));
+            keys.next_frame();
+            // Auto-repeat of 1 keeps it held
+            keys.press(b"1");
+        }
+        keys.poll(&mut keypad);
+        assert_eq!((keypad[1], keypad[0xF]), (1, 0));
+
+        // After the last repeat it's released sooner
+        for _ in 0..REPEAT_HOLD_FRAMES {
+            keys.next_frame();
+        }
+        keys.poll(&mut keypad);
+        assert_eq!(keypad[1], 0);
+    }
+
+    #[test]
+    fn a_second_tap_keeps_the_press_hold() {
+        let mut keys = TerminalKeys::new(Keymap::default());
+        let mut keypad = [0; 16];
+        keys.press(b"1");
+        keys.next_frame();
+        keys.press(b"1");
+        for _ in 1..PRESS_HOLD_FRAMES {
+            keys.poll(&mut keypad);
+            assert_eq!(keypad[1], 1);
+            keys.next_frame();
+        }
+        keys.poll(&mut keypad);
+        assert_eq!(keypad[1], 0);
+    }
+
+    #[test]
+    fn escape_sequences_and_the_numpad_have_names() {
+        let names = host_key_names(b"\x1B[A\x1B[1;5C\x1B[15~Q5");
+        assert_eq!(names, ["up", "right", "q", "numpad5", "5"]);
+        assert_eq!(host_key_names(b" \r"), ["space", "numpadenter", "enter"]);
+    }
+}
@@ -0,0 +1,512 @@
+// GDB remote serial protocol stub, lets GDB and compatible clients debug a ROM over TCP
+//
+// Packets look like $data#checksum, where the checksum is the sum of the data bytes modulo
+// 256 as two hex digits, and every packet is acknowledged with + or -. A 0x03 byte outside
+// of a packet interrupts a running continue.
+//
+// Registers, in this order and with the bit sizes from target.xml:
+// 0-15 V0-VF (8), 16 I (16), 17 PC (16), 18 SP (8), 19 DT (8), 20 ST (8)
+// 16 bit registers are sent little endian, like GDB expects from a target without endianness
+
+use cpu::{StepOutcome, CPU};
+use error::CpuError;
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+const REGISTER_COUNT: usize = 21;
+const I_REGISTER: usize = 16;
+const PC_REGISTER: usize = 17;
+const SP_REGISTER: usize = 18;
+const DT_REGISTER: usize = 19;
+const ST_REGISTER: usize = 20;
+
+// How many instructions a continue executes between checks for an interrupt from GDB
+const INTERRUPT_CHECK_INTERVAL: usize = 1000;
+
+// The largest packet GDB may send, announced in qSupported
+// Memory reads are limited to what fits into a reply, two hex digits per byte
+const PACKET_SIZE: usize = 0x4000;
+
+// Signals reported in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+pub struct GdbStub {
+    // Addresses of software breakpoints, the stub checks them instead of patching memory
+    breakpoints: BTreeSet<usize>,
+    cycles_per_frame: usize,
+    cycles_since_tick: usize,
+}
+
+impl GdbStub {
+    // Timers tick once every cycles_per_frame executed instructions
+    pub fn new(cycles_per_frame: usize) -> GdbStub {
+        GdbStub {
+            breakpoints: BTreeSet::new(),
+            cycles_per_frame: cycles_per_frame.max(1),
+            cycles_since_tick: 0,
+        }
+    }
+
+    // Waits for a single client and serves it until it detaches, kills or disconnects
+    pub fn serve(&mut self, cpu: &mut CPU, listener: &TcpListener) -> io::Result<()> {
+        let (stream, _) = listener.accept()?;
+        self.session(cpu, stream)
+    }
+
+    pub fn session(&mut self, cpu: &mut CPU, stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        let mut connection = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        };
+        while let Some(packet) = connection.read_packet()? {
+            let reply = match packet.as_str() {
+                "k" => return Ok(()),
+                "D" => {
+                    connection.write_packet("OK")?;
+                    return Ok(());
+                }
+                "s" => self.resume(cpu, &mut connection, true)?,
+                "c" => self.resume(cpu, &mut connection, false)?,
+                _ => self.handle(cpu, &packet),
+            };
+            connection.write_packet(&reply)?;
+        }
+        Ok(())
+    }
+
+    // Answers every packet that doesn't run the CPU
+    // Unsupported packets get an empty reply, which tells GDB to fall back to something else
+    fn handle(&mut self, cpu: &mut CPU, packet: &str) -> String {
+        if packet.is_empty() || !packet.is_char_boundary(1) {
+            return String::new();
+        }
+        let (command, arguments) = packet.split_at(1);
+        let reply = match command {
+            "?" => Some(stop_reply(SIGTRAP)),
+            "g" => Some(hex(&read_registers(cpu))),
+            "G" => from_hex(arguments)
+                .filter(|bytes| bytes.len() == register_bytes())
+                .and_then(|bytes| write_registers(cpu, &bytes))
+                .map(|_| "OK".to_string()),
+            "p" => usize::from_str_radix(arguments, 16)
+                .ok()
+                .and_then(|number| read_register(cpu, number))
+                .map(|bytes| hex(&bytes)),
+            "P" => split_pair(arguments, '=')
+                .and_then(|(number, value)| {
+                    let number = usize::from_str_radix(number, 16).ok()?;
+                    write_register(cpu, number, &from_hex(value)?)
+                })
+                .map(|_| "OK".to_string()),
+            "m" => parse_range(arguments).and_then(|(address, len)| {
+                let memory = cpu.memory();
+                let end = address.checked_add(len)?;
+                if address >= memory.len() || len > PACKET_SIZE / 2 {
+                    return None;
+                }
+                // Reads past the end return what is there, GDB then asks for the rest
+                Some(hex(&memory[address..end.min(memory.len())]))
+            }),
+            "M" => split_pair(arguments, ':')
+                .and_then(|(range, data)| {
+                    let (address, len) = parse_range(range)?;
+                    let bytes = from_hex(data).filter(|bytes| bytes.len() == len)?;
+                    let memory = cpu.memory_mut();
+                    let end = address
+                        .checked_add(len)
+                        .filter(|&end| end <= memory.len())?;
+                    memory[address..end].copy_from_slice(&bytes);
+                    Some(())
+                })
+                .map(|_| "OK".to_string()),
+            "Z" | "z" => parse_breakpoint(arguments).map(|breakpoint| match breakpoint {
+                Some(address) if command == "Z" => {
+                    self.breakpoints.insert(address);
+                    "OK".to_string()
+                }
+                Some(address) => {
+                    self.breakpoints.remove(&address);
+                    "OK".to_string()
+                }
+                // Hardware breakpoints and watchpoints
+                None => String::new(),
+            }),
+            "H" => Some("OK".to_string()),
+            "q" => return query(arguments),
+            _ => return String::new(),
+        };
+        reply.unwrap_or_else(|| "E01".to_string())
+    }
+
+    // Executes a single instruction or runs until a breakpoint, an error, an exit or an
+    // interrupt from GDB, and returns the stop reply
+    fn resume(
+        &mut self,
+        cpu: &mut CPU,
+        connection: &mut Connection,
+        single_step: bool,
+    ) -> io::Result<String> {
+        let mut executed = 0;
+        loop {
+            // The instruction the last stop happened at runs even if it has a breakpoint
+            if executed > 0 && self.breakpoints.contains(&cpu.program_counter()) {
+                return Ok(stop_reply(SIGTRAP));
+            }
+            match cpu.emulate_cycle() {
+                Ok(StepOutcome::Exited) => return Ok("W00".to_string()),
+                Ok(_) => (),
+                Err(CpuError::UnknownOpcode { .. }) => return Ok(stop_reply(SIGILL)),
+                Err(_) => return Ok(stop_reply(SIGSEGV)),
+            }
+            executed += 1;
+            self.cycles_since_tick += 1;
+            if self.cycles_since_tick == self.cycles_per_frame {
+                self.cycles_since_tick = 0;
+                cpu.tick_timers();
+            }
+            if single_step {
+                return Ok(stop_reply(SIGTRAP));
+            }
+            if executed % INTERRUPT_CHECK_INTERVAL == 0 && connection.interrupted()? {
+                return Ok(stop_reply(SIGINT));
+            }
+        }
+    }
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    // Returns the data of the next packet with a valid checksum, None once the client is gone
+    // Acknowledgements and interrupts between packets are skipped
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            let mut skipped = Vec::new();
+            if self.reader.read_until(b'$', &mut skipped)? == 0 || skipped.last() != Some(&b'$') {
+                return Ok(None);
+            }
+            let mut data = Vec::new();
+            self.reader.read_until(b'#', &mut data)?;
+            let mut checksum = [0; 2];
+            if data.pop() != Some(b'#') || self.reader.read_exact(&mut checksum).is_err() {
+                return Ok(None);
+            }
+            let expected = from_hex(&String::from_utf8_lossy(&checksum));
+            if expected == Some(vec![checksum_of(&data)]) {
+                self.writer.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            // Asks GDB to send the packet again
+            self.writer.write_all(b"-")?;
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.writer.write_all(packet.as_bytes())
+    }
+
+    // True if GDB sent a 0x03 byte, doesn't block
+    fn interrupted(&mut self) -> io::Result<bool> {
+        if self.reader.buffer().is_empty() {
+            self.reader.get_ref().set_nonblocking(true)?;
+            let filled = self.reader.fill_buf().map(|buffer| buffer.len());
+            self.reader.get_ref().set_nonblocking(false)?;
+            match filled {
+                Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "GDB disconnected")),
+                Ok(_) => (),
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(err) => return Err(err),
+            }
+        }
+        if self.reader.buffer().first() == Some(&0x03) {
+            self.reader.consume(1);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
+
+// Answers general queries, the arguments start after the q
+fn query(arguments: &str) -> String {
+    if arguments.starts_with("Supported") {
+        return format!("PacketSize={:x};qXfer:features:read+", PACKET_SIZE);
+    }
+    if let Some(range) = arguments.strip_prefix("Xfer:features:read:target.xml:") {
+        return match parse_range(range) {
+            Some((offset, len)) => {
+                let xml = target_xml();
+                let start = offset.min(xml.len());
+                let end = offset.saturating_add(len).min(xml.len());
+                let more = if end < xml.len() { "m" } else { "l" };
+                format!("{}{}", more, &xml[start..end])
+            }
+            None => "E01".to_string(),
+        };
+    }
+    match arguments {
+        // There's a single thread, and GDB attached to an existing process rather than
+        // starting one, so it won't kill it when quitting
+        "Attached" => "1".to_string(),
+        "C" => "QC1".to_string(),
+        "fThreadInfo" => "m1".to_string(),
+        "sThreadInfo" => "l".to_string(),
+        _ => String::new(),
+    }
+}
+
+// Describes the registers to GDB, see the layout at the top of the file
+fn target_xml() -> String {
+    let mut registers = String::new();
+    for x in 0..16 {
+        registers.push_str(&format!(
+            "<reg name=\"v{:x}\" bitsize=\"8\" type=\"uint8\"/>",
+            x
+        ));
+    }
+    registers.push_str("<reg name=\"i\" bitsize=\"16\" type=\"data_ptr\"/>");
+    registers.push_str("<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>");
+    for name in &["sp", "dt", "st"] {
+        registers.push_str(&format!(
+            "<reg name=\"{}\" bitsize=\"8\" type=\"uint8\"/>",
+            name
+        ));
+    }
+    format!(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\"><feature name=\"org.rusty_chip.chip8\">{}</feature></target>",
+        registers
+    )
+}
+
+fn stop_reply(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+fn register_size(number: usize) -> usize {
+    match number {
+        I_REGISTER | PC_REGISTER => 2,
+        _ => 1,
+    }
+}
+
+fn register_bytes() -> usize {
+    (0..REGISTER_COUNT).map(register_size).sum()
+}
+
+fn read_register(cpu: &CPU, number: usize) -> Option<Vec<u8>> {
+    let value = match number {
+        0..=15 => cpu.registers()[number] as u16,
+        I_REGISTER => cpu.index_register(),
+        PC_REGISTER => cpu.program_counter() as u16,
+        SP_REGISTER => cpu.call_stack().len() as u16,
+        DT_REGISTER => cpu.delay_timer() as u16,
+        ST_REGISTER => cpu.sound_timer() as u16,
+        _ => return None,
+    };
+    Some(value.to_le_bytes()[..register_size(number)].to_vec())
+}
+
+fn read_registers(cpu: &CPU) -> Vec<u8> {
+    (0..REGISTER_COUNT)
+        .flat_map(|number| read_register(cpu, number).unwrap_or_default())
+        .collect()
+}
+
+// Fails for unknown registers, values of the wrong size and values the CPU can't take
+// The stack pointer can't be moved, only written with its current value
+fn write_register(cpu: &mut CPU, number: usize, bytes: &[u8]) -> Option<()> {
+    if number >= REGISTER_COUNT || bytes.len() != register_size(number) {
+        return None;
+    }
+    let value = bytes
+        .iter()
+        .rev()
+        .fold(0u16, |value, byte| value << 8 | *byte as u16);
+    match number {
+        0..=15 => cpu.set_register(number, value as u8),
+        I_REGISTER => cpu.set_index_register(value),
+        PC_REGISTER => cpu.set_program_counter(value as usize).ok()?,
+        SP_REGISTER if value as usize == cpu.call_stack().len() => (),
+        SP_REGISTER => return None,
+        DT_REGISTER => cpu.set_delay_timer(value as u8),
+        _ => cpu.set_sound_timer(value as u8),
+    }
+    Some(())
+}
+
+fn write_registers(cpu: &mut CPU, bytes: &[u8]) -> Option<()> {
+    let mut offset = 0;
+    for number in 0..REGISTER_COUNT {
+        let size = register_size(number);
+        write_register(cpu, number, &bytes[offset..offset + size])?;
+        offset += size;
+    }
+    Some(())
+}
+
+// Parses "addr,len" with both numbers in hex
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    let (address, len) = split_pair(text, ',')?;
+    Some((
+        usize::from_str_radix(address, 16).ok()?,
+        usize::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+// Parses "type,addr,kind" of Z and z packets, the address is None for anything but
+// software breakpoints
+fn parse_breakpoint(text: &str) -> Option<Option<usize>> {
+    let mut fields = text.split(',');
+    let kind = fields.next()?;
+    let address = usize::from_str_radix(fields.next()?, 16).ok()?;
+    Some(if kind == "0" { Some(address) } else { None })
+}
+
+fn split_pair(text: &str, separator: char) -> Option<(&str, &str)> {
+    let position = text.find(separator)?;
+    Some((&text[..position], &text[position + 1..]))
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|start| u8::from_str_radix(text.get(start..start + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quirks::Quirks;
+    use std::thread;
+
+    // Serves the ROM on a free local port and returns the connected client
+    fn connect(rom: &[u8]) -> (TcpStream, thread::JoinHandle<CPU>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut cpu = CPU::new(Quirks::default());
+        cpu.load_rom(rom).unwrap();
+        let server = thread::spawn(move || {
+            GdbStub::new(10).serve(&mut cpu, &listener).unwrap();
+            cpu
+        });
+        let client = TcpStream::connect(address).unwrap();
+        client.set_nodelay(true).unwrap();
+        (client, server)
+    }
+
+    // Sends a packet and returns the data of the reply
+    fn send(client: &mut TcpStream, data: &str) -> String {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        client.write_all(packet.as_bytes()).unwrap();
+        let mut reply = Vec::new();
+        let mut byte = [0];
+        loop {
+            client.read_exact(&mut byte).unwrap();
+            match byte[0] {
+                b'+' if reply.is_empty() => (),
+                b'#' => break,
+                _ => reply.push(byte[0]),
+            }
+        }
+        let mut checksum = [0; 2];
+        client.read_exact(&mut checksum).unwrap();
+        assert_eq!(reply.remove(0), b'$');
+        client.write_all(b"+").unwrap();
+        String::from_utf8(reply).unwrap()
+    }
+
+    #[test]
+    fn reads_and_writes_registers_and_memory() {
+        let (mut client, server) = connect(&[0x60, 0x2A, 0xA3, 0x00]);
+        assert!(send(&mut client, "qSupported:swbreak+").contains("qXfer:features:read+"));
+        assert_eq!(send(&mut client, "s"), "S05");
+        // V0 is 0x2A, PC is 0x202 in little endian
+        let registers = send(&mut client, "g");
+        assert_eq!(registers.len(), 46);
+        assert!(registers.starts_with("2a00"));
+        assert_eq!(&registers[36..40], "0202");
+
+        assert_eq!(send(&mut client, "m200,4"), "602aa300");
+        assert_eq!(send(&mut client, "M300,2:beef"), "OK");
+        assert_eq!(send(&mut client, "m300,2"), "beef");
+        assert_eq!(send(&mut client, "m10000,2"), "E01");
+        // Ranges that overflow the address space
+        assert_eq!(send(&mut client, "m0,ffffffffffffffff"), "E01");
+        assert_eq!(send(&mut client, "Mffffffffffffffff,1:00"), "E01");
+
+        assert_eq!(send(&mut client, "P10=0003"), "OK");
+        assert_eq!(send(&mut client, "p10"), "0003");
+        assert_eq!(send(&mut client, "P12=05"), "E01");
+        assert_eq!(send(&mut client, "vMustReplyEmpty"), "");
+        assert_eq!(send(&mut client, "D"), "OK");
+
+        let cpu = server.join().unwrap();
+        assert_eq!(cpu.index_register(), 0x300);
+        assert_eq!(&cpu.memory()[0x300..0x302], &[0xBE, 0xEF]);
+    }
+
+    #[test]
+    fn breakpoints_stop_continue() {
+        // Counts V0 up to 3, then exits
+        let rom = [0x70, 0x01, 0x30, 0x03, 0x12, 0x00, 0x00, 0xFD];
+        let (mut client, server) = connect(&rom);
+        assert_eq!(send(&mut client, "Z0,204,2"), "OK");
+        assert_eq!(send(&mut client, "Z2,300,1"), "");
+        assert_eq!(send(&mut client, "c"), "S05");
+        assert_eq!(send(&mut client, "p11"), "0402");
+        assert_eq!(send(&mut client, "c"), "S05");
+        assert_eq!(send(&mut client, "p0"), "02");
+
+        assert_eq!(send(&mut client, "z0,204,2"), "OK");
+        assert_eq!(send(&mut client, "c"), "W00");
+        client.write_all(b"$k#6b").unwrap();
+        assert_eq!(server.join().unwrap().registers()[0], 3);
+    }
+
+    #[test]
+    fn interrupt_stops_a_running_continue() {
+        let (mut client, server) = connect(&[0x12, 0x00]);
+        client.write_all(b"$c#63").unwrap();
+        client.write_all(&[0x03]).unwrap();
+        let mut reply = [0; 8];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"+$S02#b5");
+        client.write_all(b"+$k#6b").unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn serves_the_target_description() {
+        let (mut client, server) = connect(&[0x12, 0x00]);
+        let first = send(&mut client, "qXfer:features:read:target.xml:0,40");
+        assert!(first.starts_with("m<?xml"));
+        let rest = send(&mut client, "qXfer:features:read:target.xml:40,1000");
+        assert!(rest.starts_with('l'));
+        let xml = format!("{}{}", &first[1..], &rest[1..]);
+        assert_eq!(xml, target_xml());
+        assert!(xml.contains("<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>"));
+        client.write_all(b"$k#6b").unwrap();
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,263 @@
+// Golden frame tests run a ROM headlessly with scripted input and compare the final
+// screen against a stored one, catching any change in what the CPU draws
+//
+// A golden file describes the run, followed by the expected screen or its CRC-32:
+//   # comments and empty lines are ignored
+//   rom corax89.ch8     ROM or Octo source, relative to the golden file
+//   quirks superchip    preset, see Quirks::from_name, default if missing
+//   cycles 1000         instructions per frame, DEFAULT_CYCLES_PER_FRAME if missing
+//   seed 7              seed of the random number generator, 0 if missing
+//   frames 120          how many frames to run
+//   key A 10 20         hold key A from frame 10 up to but not including frame 20
+//   screen              followed by one line per row, see render
+//   crc 1A2B3C4D        instead of screen, the CRC-32 of gfx for large screens
+
+use cpu::CPU;
+use emulator::{Emulator, InputSource, NullAudio, NullDisplay, DEFAULT_CYCLES_PER_FRAME};
+use error::GoldenError;
+use quirks::Quirks;
+use state::crc32;
+
+// Characters for the 4 pixel colours, the XO-CHIP planes are bit 0 and 1
+const PIXELS: [char; 4] = ['.', '#', '+', '@'];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyPress {
+    pub key: usize,
+    pub from: usize,
+    pub until: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expected {
+    Screen(String),
+    Crc(u32),
+    // Nothing stored yet, every run fails until the file is blessed
+    Missing,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GoldenTest {
+    pub rom: String,
+    pub quirks: Quirks,
+    pub cycles_per_frame: usize,
+    pub seed: u64,
+    pub frames: usize,
+    pub keys: Vec<KeyPress>,
+    pub expected: Expected,
+    // The lines before the expected screen, kept to rewrite the file when blessing
+    header: String,
+}
+
+impl GoldenTest {
+    pub fn parse(source: &str) -> Result<GoldenTest, GoldenError> {
+        let mut test = GoldenTest {
+            rom: String::new(),
+            quirks: Quirks::default(),
+            cycles_per_frame: DEFAULT_CYCLES_PER_FRAME,
+            seed: 0,
+            frames: 0,
+            keys: Vec::new(),
+            expected: Expected::Missing,
+            header: String::new(),
+        };
+        let mut lines = source.lines().enumerate();
+        for (index, line) in lines.by_ref() {
+            let error = |message: &str| GoldenError::Parse {
+                line: index + 1,
+                message: message.to_string(),
+            };
+            let mut words = line.split_whitespace();
+            let arg = |words: &mut dyn Iterator<Item = &str>, radix| {
+                words
+                    .next()
+                    .and_then(|word| u64::from_str_radix(word, radix).ok())
+                    .ok_or_else(|| error("expected a number"))
+            };
+            match words.next() {
+                None => (),
+                Some(word) if word.starts_with('#') => (),
+                Some("rom") => test.rom = words.collect::<Vec<_>>().join(" "),
+                Some("quirks") => {
+                    test.quirks = words
+                        .next()
+                        .and_then(Quirks::from_name)
+                        .ok_or_else(|| error("unknown quirks preset"))?
+                }
+                Some("cycles") => test.cycles_per_frame = arg(&mut words, 10)? as usize,
+                Some("seed") => test.seed = arg(&mut words, 10)?,
+                Some("frames") => test.frames = arg(&mut words, 10)? as usize,
+                Some("key") => {
+                    let key = arg(&mut words, 16)? as usize;
+                    let from = arg(&mut words, 10)? as usize;
+                    let until = arg(&mut words, 10)? as usize;
+                    if key > 0xF || from >= until {
+                        return Err(error("expected a key 0-F and a non-empty frame range"));
+                    }
+                    test.keys.push(KeyPress { key, from, until });
+                }
+                Some("crc") => {
+                    test.expected = Expected::Crc(arg(&mut words, 16)? as u32);
+                    break;
+                }
+                Some("screen") => {
+                    let rows = lines.by_ref().map(|(_, row)| row.trim_end());
+                    let mut screen = rows.collect::<Vec<_>>().join("\n");
+                    screen.push('\n');
+                    test.expected = Expected::Screen(screen.trim_start().to_string());
+                    break;
+                }
+                Some(_) => return Err(error("unknown directive")),
+            }
+            test.header.push_str(line);
+            test.header.push('\n');
+        }
+        if test.rom.is_empty() {
+            return Err(GoldenError::Parse {
+                line: 0,
+                message: "no rom given".to_string(),
+            });
+        }
+        Ok(test)
+    }
+
+    // Runs the ROM and returns the CPU after the last frame or the one it exited in
+    pub fn run(&self, rom: &[u8]) -> Result<CPU, GoldenError> {
+        let mut cpu = CPU::with_seed(self.quirks, self.seed);
+        cpu.load_rom(rom)?;
+        let input = Script {
+            keys: self.keys.clone(),
+            frame: 0,
+        };
+        let mut emulator = Emulator::new(cpu, NullDisplay, NullAudio, input);
+        emulator.set_cycles_per_frame(self.cycles_per_frame);
+        for _ in 0..self.frames {
+            emulator.run_frame()?;
+            if emulator.cpu().has_exited() {
+                break;
+            }
+        }
+        Ok(emulator.into_cpu())
+    }
+
+    // Runs the ROM and compares the screen with the expected one
+    pub fn check(&self, rom: &[u8]) -> Result<CPU, GoldenError> {
+        let cpu = self.run(rom)?;
+        let (expected, actual) = match self.expected {
+            Expected::Screen(ref screen) => (screen.clone(), render(&cpu)),
+            Expected::Crc(crc) => (
+                format!("crc {:08X}", crc),
+                format!("crc {:08X}", crc32(cpu.gfx())),
+            ),
+            Expected::Missing => ("nothing".to_string(), render(&cpu)),
+        };
+        if expected == actual {
+            Ok(cpu)
+        } else {
+            Err(GoldenError::Mismatch { expected, actual })
+        }
+    }
+
+    // The golden file with the expected screen replaced by the one of cpu
+    // A stored CRC stays a CRC, everything else becomes a screen dump
+    pub fn blessed(&self, cpu: &CPU) -> String {
+        match self.expected {
+            Expected::Crc(_) => format!("{}crc {:08X}\n", self.header, crc32(cpu.gfx())),
+            _ => format!("{}screen\n{}", self.header, render(cpu)),
+        }
+    }
+}
+
+// Draws the screen as text, a line per row with . for unlit and # for lit pixels
+// With XO-CHIP's second plane + is a pixel only lit there and @ one lit in both
+pub fn render(cpu: &CPU) -> String {
+    let mut screen = String::with_capacity((cpu.width() + 1) * cpu.height());
+    for row in cpu.gfx().chunks(cpu.width()) {
+        screen.extend(row.iter().map(|&pixel| PIXELS[pixel as usize & 0b11]));
+        screen.push('\n');
+    }
+    screen
+}
+
+// Holds the keys of a golden test, counting the frames it was polled in
+struct Script {
+    keys: Vec<KeyPress>,
+    frame: usize,
+}
+
+impl InputSource for Script {
+    fn poll(&mut self, keypad: &mut [u8]) {
+        for (key, state) in keypad.iter_mut().enumerate() {
+            let held = self.keys.iter().any(|press| {
+                press.key == key && press.from <= self.frame && self.frame < press.until
+            });
+            *state = held as u8;
+        }
+        self.frame += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Draws a 0 at the top left once key 0 is pressed
+    // 200: 00E0  CLS
+    // 202: E09E  SKP V0
+    // 204: 1200  JP 0x200
+    // 206: F029  LD F, V0
+    // 208: D005  DRW V0, V0, 5
+    // 20A: 120A  JP 0x20A
+    const ROM: [u8; 12] = [
+        0x00, 0xE0, 0xE0, 0x9E, 0x12, 0x00, 0xF0, 0x29, 0xD0, 0x05, 0x12, 0x0A,
+    ];
+
+    #[test]
+    fn scripted_keys_change_the_screen() {
+        let source = "# holds 0 for a frame\nrom test.ch8\ncycles 8\nframes 3\nkey 0 1 2\n";
+        let mut test = GoldenTest::parse(source).unwrap();
+        assert_eq!(
+            test.keys,
+            vec![KeyPress {
+                key: 0,
+                from: 1,
+                until: 2
+            }]
+        );
+        assert_eq!(test.expected, Expected::Missing);
+
+        let cpu = test.run(&ROM).unwrap();
+        let screen = render(&cpu);
+        assert_eq!(screen.lines().count(), 32);
+        assert_eq!(&screen[..4], "####");
+        assert!(test.check(&ROM).is_err());
+
+        let blessed = test.blessed(&cpu);
+        assert!(blessed.starts_with(source));
+        assert!(GoldenTest::parse(&blessed).unwrap().check(&ROM).is_ok());
+
+        // Any other key leaves the screen empty
+        test.keys[0].key = 2;
+        assert!(!render(&test.run(&ROM).unwrap()).contains('#'));
+    }
+
+    #[test]
+    fn crc_and_parse_errors() {
+        let test = GoldenTest::parse("rom a.ch8\nframes 1\ncrc 0\n").unwrap();
+        let cpu = test.run(&ROM).unwrap();
+        let blessed = test.blessed(&cpu);
+        assert_eq!(
+            GoldenTest::parse(&blessed).unwrap().expected,
+            Expected::Crc(crc32(cpu.gfx()))
+        );
+
+        let line_of = |source| match GoldenTest::parse(source) {
+            Err(GoldenError::Parse { line, .. }) => line,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(line_of("rom a\nkey G 1 2\n"), 2);
+        assert_eq!(line_of("rom a\n\nkey 1 5 5\n"), 3);
+        assert_eq!(line_of("quirks chip9\n"), 1);
+        assert_eq!(line_of("frames 1\n"), 0);
+    }
+}
@@ -0,0 +1,254 @@
+// A JSON reader for the ROM database, which is kept in the community database's format
+// Objects keep their keys in file order, numbers are all f64
+
+use error::ConfigError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Boolean(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    // The value of the key if this is an object that has it
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match *self {
+            Json::Object(ref entries) => entries
+                .iter()
+                .find(|&(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::String(ref string) => Some(string),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Json::Number(number) => Some(number),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Json::Boolean(boolean) => Some(boolean),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match *self {
+            Json::Array(ref values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&[(String, Json)]> {
+        match *self {
+            Json::Object(ref entries) => Some(entries),
+            _ => None,
+        }
+    }
+}
+
+// Parses a whole document, errors name the file as given and the line of the mistake
+pub fn parse(file: &str, text: &str) -> Result<Json, ConfigError> {
+    let mut parser = Parser {
+        text: text.as_bytes(),
+        position: 0,
+    };
+    let result = parser.value().and_then(|value| {
+        parser.skip_whitespace();
+        match parser.text.get(parser.position) {
+            None => Ok(value),
+            Some(_) => Err("Unexpected text after the end".to_string()),
+        }
+    });
+    result.map_err(|message| ConfigError {
+        file: file.to_string(),
+        line: 1 + text.as_bytes()[..parser.position.min(text.len())]
+            .iter()
+            .filter(|&&byte| byte == b'\n')
+            .count(),
+        message,
+    })
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') => {
+                self.position += 1;
+                let mut entries = Vec::new();
+                if self.next_is(b'}') {
+                    return Ok(Json::Object(entries));
+                }
+                loop {
+                    self.skip_whitespace();
+                    if self.peek() != Some(b'"') {
+                        return Err("Expected a key in quotes".to_string());
+                    }
+                    let key = self.string()?;
+                    self.skip_whitespace();
+                    if !self.next_is(b':') {
+                        return Err(format!("Expected : after \"{}\"", key));
+                    }
+                    entries.push((key, self.value()?));
+                    if !self.next_is(b',') {
+                        return self.close(b'}').map(|()| Json::Object(entries));
+                    }
+                }
+            }
+            Some(b'[') => {
+                self.position += 1;
+                let mut values = Vec::new();
+                if self.next_is(b']') {
+                    return Ok(Json::Array(values));
+                }
+                loop {
+                    values.push(self.value()?);
+                    if !self.next_is(b',') {
+                        return self.close(b']').map(|()| Json::Array(values));
+                    }
+                }
+            }
+            Some(b'"') => self.string().map(Json::String),
+            Some(_) => {
+                let start = self.position;
+                while self.peek().is_some_and(|byte| {
+                    byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'+' || byte == b'.'
+                }) {
+                    self.position += 1;
+                }
+                let word = String::from_utf8_lossy(&self.text[start..self.position]);
+                match &*word {
+                    "null" => Ok(Json::Null),
+                    "true" => Ok(Json::Boolean(true)),
+                    "false" => Ok(Json::Boolean(false)),
+                    number => number
+                        .parse()
+                        .map(Json::Number)
+                        .map_err(|_| format!("Invalid value \"{}\"", word)),
+                }
+            }
+            None => Err("Unexpected end of the file".to_string()),
+        }
+    }
+
+    // The string starting at the opening quote, with its escapes resolved
+    fn string(&mut self) -> Result<String, String> {
+        self.position += 1;
+        let mut bytes = Vec::new();
+        loop {
+            let byte = self.peek().ok_or("Unterminated string")?;
+            self.position += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escaped = self.peek().ok_or("Unterminated string")?;
+                    self.position += 1;
+                    let c = match escaped {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{C}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let hex = self.text.get(self.position..self.position + 4);
+                            let code = hex
+                                .and_then(|hex| std::str::from_utf8(hex).ok())
+                                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                                .ok_or("Invalid \\u escape")?;
+                            self.position += 4;
+                            // Surrogate pairs aren't needed for anything the database contains
+                            std::char::from_u32(code).unwrap_or('\u{FFFD}')
+                        }
+                        _ => return Err("Unknown escape in string".to_string()),
+                    };
+                    let mut buffer = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+                }
+                _ => bytes.push(byte),
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| "Invalid UTF-8 in string".to_string())
+    }
+
+    // Skips whitespace and the byte if it comes next
+    fn next_is(&mut self, byte: u8) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(byte) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    fn close(&mut self, byte: u8) -> Result<(), String> {
+        if self.next_is(byte) {
+            return Ok(());
+        }
+        Err(format!("Expected , or {}", byte as char))
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.position).cloned()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(|byte| byte.is_ascii_whitespace()) {
+            self.position += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_nested_values() {
+        let text = r##"[
+            {"title": "Pong \"2\"", "tickrate": 15, "wrap": false, "logo": null,
+             "keys": {"up": 1, "down": 4}, "colors": ["#000000", "é"], "empty": {}}
+        ]"##;
+        let json = parse("programs.json", text).unwrap();
+        let program = &json.as_array().unwrap()[0];
+        assert_eq!(
+            program.get("title").and_then(Json::as_str),
+            Some("Pong \"2\"")
+        );
+        assert_eq!(program.get("tickrate").and_then(Json::as_f64), Some(15.0));
+        assert_eq!(program.get("wrap").and_then(Json::as_bool), Some(false));
+        assert_eq!(program.get("logo"), Some(&Json::Null));
+        let keys = program.get("keys").and_then(Json::as_object).unwrap();
+        assert_eq!(keys[1], ("down".to_string(), Json::Number(4.0)));
+        let colors = program.get("colors").and_then(Json::as_array).unwrap();
+        assert_eq!(colors[1].as_str(), Some("é"));
+        assert_eq!(program.get("empty"), Some(&Json::Object(Vec::new())));
+
+        let err = parse("programs.json", "{\n\"a\": 1\n\"b\": 2}").unwrap_err();
+        assert_eq!(err.to_string(), "programs.json:3: Expected , or }");
+        let err = parse("programs.json", "[1, 2] 3").unwrap_err();
+        assert_eq!(err.message, "Unexpected text after the end");
+    }
+}
@@ -0,0 +1,274 @@
+// Which keys of the host keyboard press which of the 16 CHIP-8 keys
+//
+// Host keys are named by what they type: lowercase letters, digits and punctuation, plus
+// space, enter, tab, up, down, left, right, numpad0 to numpad9, numpad/, numpad*, numpad-,
+// numpad+, numpad. and numpadenter
+//
+// A keymap file picks a layout and rebinds CHIP-8 keys, for every ROM and for the ROMs with
+// a given file name, see config for the syntax:
+//
+//     layout = "qwerty"
+//     A = ["z", "numpad0"]
+//
+//     [rom."pong.ch8"]
+//     layout = "numpad"
+//     1 = "w"
+//
+// A binding replaces the host keys the layout gave that CHIP-8 key, and takes them away from
+// the CHIP-8 keys they pressed before
+
+use config::{self, Table, Value};
+use error::ConfigError;
+use std::path::Path;
+
+// The layouts put the keypad of the COSMAC VIP onto the left 4x4 block of the keyboard
+//
+//     1 2 3 C
+//     4 5 6 D
+//     7 8 9 E
+//     A 0 B F
+//
+// except for numpad, which uses the digits for themselves and the keys around them for A to F
+pub const LAYOUTS: [&str; 4] = ["qwerty", "qwertz", "azerty", "numpad"];
+
+// The CHIP-8 keys in the order the layouts list host keys, row by row as drawn above
+static KEYPAD_ORDER: [usize; 16] = [
+    0x1, 0x2, 0x3, 0xC, 0x4, 0x5, 0x6, 0xD, 0x7, 0x8, 0x9, 0xE, 0xA, 0x0, 0xB, 0xF,
+];
+
+static NAMED_KEYS: [&str; 7] = ["space", "enter", "tab", "up", "down", "left", "right"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Keymap {
+    // The host keys of each CHIP-8 key
+    bindings: Vec<Vec<String>>,
+}
+
+// QWERTZ, the keyboard the window was made for
+impl Default for Keymap {
+    fn default() -> Keymap {
+        Keymap::layout("qwertz").unwrap()
+    }
+}
+
+impl Keymap {
+    // One of LAYOUTS
+    pub fn layout(name: &str) -> Option<Keymap> {
+        let rows = match name {
+            "qwerty" => ["1234", "qwer", "asdf", "zxcv"],
+            "qwertz" => ["1234", "qwer", "asdf", "yxcv"],
+            // The digit row types these without Shift
+            "azerty" => ["&é\"'", "azer", "qsdf", "wxcv"],
+            "numpad" => {
+                let mut bindings = (0..10)
+                    .map(|digit| vec![format!("numpad{}", digit)])
+                    .collect::<Vec<_>>();
+                for name in [
+                    "numpad/",
+                    "numpad*",
+                    "numpad-",
+                    "numpad+",
+                    "numpadenter",
+                    "numpad.",
+                ] {
+                    bindings.push(vec![name.to_string()]);
+                }
+                return Some(Keymap { bindings });
+            }
+            _ => return None,
+        };
+        let mut bindings = vec![Vec::new(); 16];
+        let host_keys = rows.iter().flat_map(|row| row.chars());
+        for (&key, host_key) in KEYPAD_ORDER.iter().zip(host_keys) {
+            bindings[key].push(host_key.to_string());
+        }
+        Some(Keymap { bindings })
+    }
+
+    // Reads a keymap file, applying the [rom."<rom_name>"] table after the general settings
+    pub fn load<P: AsRef<Path>>(path: P, rom_name: Option<&str>) -> Result<Keymap, ConfigError> {
+        let file = path.as_ref().display().to_string();
+        Keymap::from_tables(&file, &config::read(path)?, rom_name)
+    }
+
+    // See load, file names the file in errors
+    pub fn from_tables(
+        file: &str,
+        tables: &[Table],
+        rom_name: Option<&str>,
+    ) -> Result<Keymap, ConfigError> {
+        let mut keymap = Keymap::default();
+        for table in tables {
+            let applies = match table.name.as_slice() {
+                [] => true,
+                [rom, name] if rom == "rom" => Some(name.as_str()) == rom_name,
+                _ => {
+                    return Err(ConfigError {
+                        file: file.to_string(),
+                        line: table.line,
+                        message: format!("Unknown table [{}]", table.name.join(".")),
+                    })
+                }
+            };
+            // Tables of other ROMs are checked as well, so mistakes show up right away
+            let mut rebound = keymap.clone();
+            rebound
+                .apply(table)
+                .map_err(|(line, message)| ConfigError {
+                    file: file.to_string(),
+                    line,
+                    message,
+                })?;
+            if applies {
+                keymap = rebound;
+            }
+        }
+        Ok(keymap)
+    }
+
+    // The host keys bound to the CHIP-8 key
+    pub fn host_keys(&self, key: usize) -> &[String] {
+        &self.bindings[key]
+    }
+
+    // Replaces the host keys of the CHIP-8 key, they no longer press any other key
+    pub fn bind(&mut self, key: usize, host_keys: Vec<String>) {
+        for bound in self.bindings.iter_mut() {
+            bound.retain(|host_key| !host_keys.contains(host_key));
+        }
+        self.bindings[key] = host_keys;
+    }
+
+    // Lets the host key press the CHIP-8 key as well
+    pub fn add(&mut self, key: usize, host_key: String) {
+        self.bindings[key].push(host_key);
+    }
+
+    // The CHIP-8 keys the host key presses, usually one or none
+    pub fn chip8_keys(&self, host_key: &str) -> Vec<usize> {
+        (0..16)
+            .filter(|&key| self.bindings[key].iter().any(|bound| bound == host_key))
+            .collect()
+    }
+
+    fn apply(&mut self, table: &Table) -> Result<(), (usize, String)> {
+        if let Some(entry) = table.entries.iter().find(|entry| entry.key == "layout") {
+            let name = entry.value.as_str().unwrap_or_default();
+            *self = Keymap::layout(name).ok_or_else(|| {
+                let message = format!("Unknown layout, use one of {}", LAYOUTS.join(", "));
+                (entry.line, message)
+            })?;
+        }
+        for entry in table.entries.iter().filter(|entry| entry.key != "layout") {
+            let key = parse_chip8_key(&entry.key)
+                .ok_or_else(|| (entry.line, format!("{} is not a CHIP-8 key", entry.key)))?;
+            let host_keys = host_key_names(&entry.value).ok_or_else(|| {
+                let message = format!("CHIP-8 key {} needs a key name or a list of them", key);
+                (entry.line, message)
+            })?;
+            if let Some(name) = host_keys.iter().find(|name| !is_host_key(name)) {
+                return Err((entry.line, format!("Unknown key \"{}\"", name)));
+            }
+            self.bind(key, host_keys);
+        }
+        Ok(())
+    }
+}
+
+// A single hex digit, 0 to F
+fn parse_chip8_key(text: &str) -> Option<usize> {
+    match text.len() {
+        1 => usize::from_str_radix(text, 16).ok(),
+        _ => None,
+    }
+}
+
+// The names a binding lists, single letters in either case
+fn host_key_names(value: &Value) -> Option<Vec<String>> {
+    let names = value.as_strings()?;
+    let lowercase = |name: &&str| match name.chars().count() {
+        1 => name.to_lowercase(),
+        _ => name.to_string(),
+    };
+    Some(names.iter().map(lowercase).collect())
+}
+
+// Whether the name is one the frontends report, see the top of the file
+pub fn is_host_key(name: &str) -> bool {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return !c.is_control() && !c.is_whitespace() && !c.is_uppercase();
+    }
+    let numpad = match name.strip_prefix("numpad") {
+        Some(key) => key.len() == 1 && "0123456789/*-+.".contains(key) || key == "enter",
+        None => false,
+    };
+    numpad || NAMED_KEYS.contains(&name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layouts_follow_the_keypad() {
+        let qwerty = Keymap::layout("qwerty").unwrap();
+        assert_eq!(qwerty.chip8_keys("1"), [0x1]);
+        assert_eq!(qwerty.chip8_keys("z"), [0xA]);
+        assert_eq!(qwerty.chip8_keys("x"), [0x0]);
+        assert_eq!(qwerty.chip8_keys("v"), [0xF]);
+        assert_eq!(Keymap::default().chip8_keys("y"), [0xA]);
+        assert_eq!(Keymap::layout("azerty").unwrap().chip8_keys("é"), [0x2]);
+        let numpad = Keymap::layout("numpad").unwrap();
+        assert_eq!(numpad.chip8_keys("numpad7"), [0x7]);
+        assert_eq!(numpad.chip8_keys("numpadenter"), [0xE]);
+        assert!(numpad.chip8_keys("7").is_empty());
+        assert!(Keymap::layout("dvorak").is_none());
+    }
+
+    #[test]
+    fn bound_keys_only_press_their_new_key() {
+        // W presses 5 in QWERTY
+        let tables = config::parse("keys.toml", "layout = \"qwerty\"\n1 = \"w\"").unwrap();
+        let keymap = Keymap::from_tables("keys.toml", &tables, None).unwrap();
+        assert_eq!(keymap.chip8_keys("w"), [0x1]);
+        assert!(keymap.host_keys(0x5).is_empty());
+        assert_eq!(keymap.chip8_keys("e"), [0x6]);
+    }
+
+    #[test]
+    fn files_rebind_keys_for_all_and_single_roms() {
+        let text = r#"
+            layout = "qwerty"
+            a = ["Z", "space"]
+
+            [rom."pong.ch8"]
+            layout = "numpad"
+            1 = "w"
+
+            [rom."other.ch8"]
+            2 = "up"
+        "#;
+        let tables = config::parse("keys.toml", text).unwrap();
+        let keymap = Keymap::from_tables("keys.toml", &tables, Some("tetris.ch8")).unwrap();
+        assert_eq!(keymap.host_keys(0xA), ["z", "space"]);
+        assert_eq!(keymap.chip8_keys("q"), [0x4]);
+
+        let keymap = Keymap::from_tables("keys.toml", &tables, Some("pong.ch8")).unwrap();
+        assert_eq!(keymap.host_keys(0x1), ["w"]);
+        assert_eq!(keymap.chip8_keys("numpad2"), [0x2]);
+        assert!(keymap.chip8_keys("space").is_empty());
+
+        for (text, message) in [
+            ("layout = \"dvorak\"", "Unknown layout"),
+            ("G = \"g\"", "G is not a CHIP-8 key"),
+            ("1 = \"F1\"", "Unknown key \"F1\""),
+            ("[rom.\"a.ch8\"]\n1 = 2", "CHIP-8 key 1 needs a key name"),
+            ("[roms]", "Unknown table [roms]"),
+        ] {
+            let tables = config::parse("keys.toml", text).unwrap();
+            let err = Keymap::from_tables("keys.toml", &tables, None).unwrap_err();
+            assert!(err.message.starts_with(message), "{}", err);
+        }
+    }
+}
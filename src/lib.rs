@@ -1,3 +1,27 @@
+extern crate gif;
+extern crate png;
 extern crate rand;
 
-pub mod cpu;
\ No newline at end of file
+pub mod asm;
+pub mod audio;
+pub mod config;
+pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod emulator;
+pub mod error;
+pub mod gdb;
+pub mod golden;
+pub mod json;
+pub mod keymap;
+pub mod movie;
+pub mod octo;
+pub mod palette;
+pub mod quirks;
+pub mod rewind;
+pub mod romdb;
+pub mod screenshot;
+pub mod sha1;
+pub mod state;
+pub mod trace;
+pub mod video;
@@ -1,149 +1,456 @@
+#[cfg(feature = "piston-frontend")]
 extern crate piston;
+#[cfg(feature = "piston-frontend")]
 extern crate piston_window;
 extern crate rand;
 extern crate rusty_chip;
 
-use piston::input::{Button, Key, PressEvent, ReleaseEvent};
-use piston_window::{clear, rectangle, Event, OpenGL, PistonWindow, WindowSettings};
-use rusty_chip::cpu;
-use std::env;
-use std::fs::File;
-use std::io::Read;
+mod cli;
+mod frontend;
 
-static SCALE: usize = 8;
+use cli::{Command, RunOptions};
+use frontend::{Session, VIDEO_SCALE};
+use rusty_chip::asm;
+use rusty_chip::audio::{WavSink, DEFAULT_SAMPLE_RATE};
+use rusty_chip::config;
+use rusty_chip::cpu::CPU;
+#[cfg(not(feature = "piston-frontend"))]
+use rusty_chip::debugger::Debugger;
+use rusty_chip::disasm::{self, Mode};
+use rusty_chip::emulator::{Emulator, DEFAULT_CYCLES_PER_FRAME};
+use rusty_chip::gdb::GdbStub;
+use rusty_chip::keymap::Keymap;
+use rusty_chip::movie::Movie;
+use rusty_chip::octo;
+use rusty_chip::quirks::{IndexIncrement, Quirks};
+use rusty_chip::romdb::{RomDatabase, RomInfo};
+use rusty_chip::sha1;
+use rusty_chip::trace::{TraceReader, Tracer};
+use rusty_chip::video::VideoRecorder;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Instant;
 
 fn main() {
-    let rom_path = env::args()
-        .nth(1)
-        .expect("Please specify the path to a ROM as the 1st arg");
-    // Specify anything as the 2nd arg to enable debug mode
-    let debug_enabled = if let Some(_) = env::args().nth(2) {
-        true
-    } else {
-        false
+    let command = match cli::parse(env::args().skip(1).collect()) {
+        Ok(command) => command,
+        Err(err) => {
+            eprintln!("{}", err);
+            eprintln!("Run rusty_chip --help for the usage");
+            process::exit(2);
+        }
     };
+    let result = match command {
+        Command::Help(topic) => {
+            print!("{}", cli::help(topic.as_deref()).unwrap_or_default());
+            Ok(())
+        }
+        Command::Run(options) => run(*options),
+        Command::Disasm { rom, mode, source } => disassemble(&rom, mode, source),
+        Command::Asm { source, output } => assemble(&source, output),
+        Command::Info { rom } => print_info(&rom),
+        Command::Bench {
+            rom,
+            frames,
+            speed,
+            quirks,
+        } => bench(&rom, frames, speed, quirks),
+        Command::Gdb { rom, port } => serve_gdb(&rom, port),
+        Command::Trace { path } => print_trace(&path),
+    };
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
 
-    let mut allow_next_step = !debug_enabled;
-
-    let rom_bytes = read_rom(&rom_path);
-
-    let mut cpu = cpu::CPU::new();
-    cpu.load_rom(&rom_bytes);
+fn run(options: RunOptions) -> Result<(), String> {
+    let rom = read_rom_or_source(&options.rom)?;
+    let (cpu, session) = start_session(options, &rom)?;
+    run_frontend(cpu, session)
+}
 
-    // Might as well free the memory now that it's been copied,
-    // otherwise this would be alive until the end of the game
-    // Same thing should be possible by just using a local scope { ... }
-    std::mem::drop(rom_bytes);
+// Creates the CPU and everything else the options ask for
+// Settings the options leave out come from the ROM database, then the defaults
+fn start_session(options: RunOptions, rom: &[u8]) -> Result<(CPU, Session), String> {
+    let RunOptions {
+        rom: rom_path,
+        debug,
+        tui,
+        headless,
+        speed,
+        quirks,
+        seed,
+        scale,
+        palette,
+        keymap,
+        tone,
+        record_path,
+        play_path,
+        video_path,
+        wav_path,
+        trace_path,
+        trace_format,
+        trace_filter,
+    } = options;
+    let tracer = match trace_path {
+        Some(path) => {
+            let file =
+                File::create(&path).map_err(|err| format!("Can't write {}: {}", path, err))?;
+            Some(Tracer::new(Box::new(file), trace_format, trace_filter))
+        }
+        None => None,
+    };
 
-    let mut window_wrapper = WindowWrapper::new();
+    // Known ROMs get the quirks, speed, colours and keys the ROM database has for them
+    let info = lookup_rom(rom)?;
+    match info {
+        Some(ref info) => eprintln!("{} ({})", info.title, info.platform),
+        None => eprintln!("ROM {} is not in the database", sha1::sha1_hex(rom)),
+    }
+    let quirks = quirks.unwrap_or_else(|| {
+        info.as_ref()
+            .map_or_else(Quirks::default, |info| info.quirks)
+    });
+    let cycles_per_frame = speed
+        .or_else(|| info.as_ref().and_then(|info| info.cycles_per_frame))
+        .unwrap_or(DEFAULT_CYCLES_PER_FRAME);
+    let palette = palette
+        .or_else(|| info.as_ref().and_then(|info| info.palette))
+        .unwrap_or_default();
 
-    while let Some(e) = window_wrapper.window.next() {
-        if let Some(b) = e.press_args() {
-            if let Button::Keyboard(key) = b {
-                if let Key::Return = key {
-                    allow_next_step = true;
-                }
-            }
-            WindowWrapper::process_input(&b, &mut cpu.keypad, 1);
+    let video = match video_path {
+        Some(path) => Some(
+            VideoRecorder::create(&path, VIDEO_SCALE, palette)
+                .map_err(|err| format!("Can't write {}: {}", path, err))?,
+        ),
+        None => None,
+    };
+    let wav = match wav_path {
+        Some(path) => {
+            let wav = WavSink::create(&path, DEFAULT_SAMPLE_RATE)
+                .map_err(|err| format!("Can't write {}: {}", path, err))?;
+            Some((wav, PathBuf::from(path)))
         }
+        None => None,
+    };
 
-        if let Some(b) = e.release_args() {
-            WindowWrapper::process_input(&b, &mut cpu.keypad, 0);
+    let mut keymap = load_keymap(keymap, &rom_path)?;
+    if let Some(ref info) = info {
+        add_key_hints(&mut keymap, &info.keys);
+    }
+
+    // Movies replay the run with the seed and quirks they were recorded with
+    let playback = match play_path {
+        Some(path) => Some(
+            fs::read(&path)
+                .map_err(|err| err.to_string())
+                .and_then(|movie| Movie::from_bytes(&movie).map_err(|err| err.to_string()))
+                .map_err(|err| format!("{}: {}", path, err))?,
+        ),
+        None => None,
+    };
+    let seed = seed.unwrap_or_else(rand::random);
+    let cpu = match playback {
+        Some(ref movie) => movie.start(rom).map_err(|err| err.to_string())?,
+        None => {
+            let mut cpu = CPU::with_seed(quirks, seed);
+            cpu.load_rom(rom).map_err(|err| err.to_string())?;
+            cpu
         }
+    };
+    let recording = record_path.map(|path| {
+        let movie = Movie::new(rom, quirks, seed, cycles_per_frame);
+        (movie, PathBuf::from(path))
+    });
+    let session = Session {
+        rom_path,
+        debug,
+        tui,
+        headless,
+        tracer,
+        recording,
+        playback,
+        video,
+        wav,
+        tone,
+        keymap,
+        cycles_per_frame,
+        palette,
+        scale,
+    };
+    Ok((cpu, session))
+}
 
-        if allow_next_step {
-            if debug_enabled {
-                cpu.emulate_cycle();
-                println!("{}", cpu);
-            } else {
-                for _ in 0..5 {
-                    cpu.emulate_cycle();
-                }
-            }
+// Looks the ROM up in the user's programs.json in the config directory, then in the
+// bundled database
+fn lookup_rom(rom: &[u8]) -> Result<Option<RomInfo>, String> {
+    let mut database = RomDatabase::bundled();
+    if let Some(path) = config::user_dir().map(|dir| dir.join("programs.json")) {
+        if path.exists() {
+            database.extend(RomDatabase::load(&path).map_err(|err| err.to_string())?);
         }
+    }
+    Ok(database.lookup(rom).cloned())
+}
 
-        if debug_enabled {
-            allow_next_step = false;
+// Binds the arrow keys, Space and Enter to the controls the database names, unless the
+// keymap already uses them
+fn add_key_hints(keymap: &mut Keymap, keys: &[(String, usize)]) {
+    for &(ref control, key) in keys {
+        let host_key = match control.as_str() {
+            "up" | "down" | "left" | "right" => control.as_str(),
+            "a" => "space",
+            "b" => "enter",
+            _ => continue,
+        };
+        if keymap.chip8_keys(host_key).is_empty() {
+            keymap.add(key, host_key.to_string());
         }
+    }
+}
 
-        window_wrapper.render(&e, &cpu.gfx);
+// A layout by name or a keymap file, applying its settings for the ROM's file name
+// Without either the user's keymap.toml is read if there is one
+fn load_keymap(arg: Option<String>, rom_path: &str) -> Result<Keymap, String> {
+    if let Some(keymap) = arg.as_deref().and_then(Keymap::layout) {
+        return Ok(keymap);
     }
+    let path = match arg {
+        Some(path) => PathBuf::from(path),
+        None => match config::user_dir().map(|dir| dir.join("keymap.toml")) {
+            Some(path) if path.exists() => path,
+            _ => return Ok(Keymap::default()),
+        },
+    };
+    let rom_name = Path::new(rom_path)
+        .file_name()
+        .and_then(|name| name.to_str());
+    Keymap::load(&path, rom_name).map_err(|err| err.to_string())
 }
 
-pub struct WindowWrapper {
-    window: PistonWindow,
+// Prints a trace written with --trace-binary in the text format
+fn print_trace(path: &str) -> Result<(), String> {
+    File::open(path)
+        .and_then(|file| TraceReader::new(BufReader::new(file)))
+        .and_then(|reader| {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            for record in reader {
+                writeln!(stdout, "{}", record?)?;
+            }
+            Ok(())
+        })
+        .map_err(|err| format!("{}: {}", path, err))
 }
 
-impl WindowWrapper {
-    fn new() -> WindowWrapper {
-        WindowWrapper {
-            window: PistonWindow::new(
-                OpenGL::V3_3,
-                0,
-                WindowSettings::new("RustyChip", [(64 * SCALE) as u32, (32 * SCALE) as u32])
-                    .opengl(OpenGL::V3_3)
-                    .srgb(false)
-                    .build()
-                    .unwrap(),
-            ),
-        }
+fn disassemble(rom_path: &str, mode: Mode, source: bool) -> Result<(), String> {
+    let listing = disasm::disassemble(&read_rom(rom_path)?, mode);
+    if source {
+        print!("{}", listing.source());
+    } else {
+        print!("{}", listing);
     }
+    Ok(())
+}
+
+fn assemble(source_path: &str, rom_path: Option<String>) -> Result<(), String> {
+    let source_path = Path::new(source_path);
+    let rom_path = rom_path.map_or_else(|| source_path.with_extension("ch8"), PathBuf::from);
+    let rom = compile(source_path)?;
+    fs::write(&rom_path, &rom)
+        .map_err(|err| format!("Can't write {}: {}", rom_path.display(), err))?;
+    eprintln!("Wrote {} bytes to {}", rom.len(), rom_path.display());
+    Ok(())
+}
 
-    fn process_input(b: &Button, keypad: &mut Vec<u8>, new_value: u8) {
-        if let &Button::Keyboard(key) = b {
-            match key {
-                Key::D0 => keypad[0] = new_value,
-                Key::D1 => keypad[1] = new_value,
-                Key::D2 => keypad[2] = new_value,
-                Key::D3 => keypad[3] = new_value,
-                Key::Q => keypad[4] = new_value,
-                Key::W => keypad[5] = new_value,
-                Key::E => keypad[6] = new_value,
-                Key::R => keypad[7] = new_value,
-                Key::A => keypad[8] = new_value,
-                Key::S => keypad[9] = new_value,
-                Key::D => keypad[10] = new_value,
-                Key::F => keypad[11] = new_value,
-                Key::Y => keypad[12] = new_value,
-                Key::X => keypad[13] = new_value,
-                Key::C => keypad[14] = new_value,
-                Key::V => keypad[15] = new_value,
-                _ => (),
+// Shows what identifies the ROM and the settings it runs with
+fn print_info(rom_path: &str) -> Result<(), String> {
+    let rom = read_rom_or_source(rom_path)?;
+    println!("File      {}", rom_path);
+    println!("Size      {} bytes", rom.len());
+    println!("SHA-1     {}", sha1::sha1_hex(&rom));
+    match lookup_rom(&rom)? {
+        Some(info) => {
+            let speed = info.cycles_per_frame.unwrap_or(DEFAULT_CYCLES_PER_FRAME);
+            println!("Title     {}", info.title);
+            println!("Platform  {}", info.platform);
+            println!("Speed     {} instructions per frame", speed);
+            println!("Quirks    {}", describe_quirks(&info.quirks));
+            if !info.keys.is_empty() {
+                let keys = info
+                    .keys
+                    .iter()
+                    .map(|&(ref control, key)| format!("{} {:X}", control, key));
+                println!("Keys      {}", keys.collect::<Vec<_>>().join(", "));
             }
         }
+        None => println!("Not in the ROM database"),
     }
+    Ok(())
+}
 
-    pub fn render(&mut self, e: &Event, pixel_buffer: &Vec<u8>) {
-        self.window.draw_2d(e, |context, graphics| {
-            clear([0.5, 1.0, 0.5, 1.0], graphics);
-
-            for y in 0..32 {
-                for x in 0..64 {
-                    let index = (y * 64 + x) as usize;
-
-                    let color = pixel_buffer[index];
-
-                    rectangle(
-                        [color as f32, color as f32, color as f32, 1.0],
-                        [
-                            (x * SCALE) as f64,
-                            (y * SCALE) as f64,
-                            SCALE as f64,
-                            SCALE as f64,
-                        ],
-                        context.transform,
-                        graphics,
-                    );
-                }
-            }
-        });
+// The quirks that differ from the default, which has all of them off
+fn describe_quirks(quirks: &Quirks) -> String {
+    let index = match quirks.load_store_index {
+        IndexIncrement::Unchanged => None,
+        IndexIncrement::ByX => Some("I += X"),
+        IndexIncrement::ByXPlusOne => Some("I += X + 1"),
+    };
+    let enabled = [
+        (quirks.shift_uses_vy, "shift VY"),
+        (index.is_some(), index.unwrap_or_default()),
+        (quirks.jump_uses_vx, "jump VX"),
+        (quirks.logic_resets_vf, "logic resets VF"),
+        (quirks.clip_sprites, "clip sprites"),
+        (quirks.display_wait, "display wait"),
+        (quirks.xo_chip, "XO-CHIP"),
+    ];
+    let names = enabled
+        .iter()
+        .filter(|&&(on, _)| on)
+        .map(|&(_, name)| name)
+        .collect::<Vec<_>>();
+    if names.is_empty() {
+        "none".to_string()
+    } else {
+        names.join(", ")
+    }
+}
+
+// Emulates the frames without any frontend and reports how long that took
+fn bench(
+    rom_path: &str,
+    frames: usize,
+    speed: Option<usize>,
+    quirks: Option<Quirks>,
+) -> Result<(), String> {
+    let rom = read_rom_or_source(rom_path)?;
+    let info = lookup_rom(&rom)?;
+    let quirks = quirks.unwrap_or_else(|| {
+        info.as_ref()
+            .map_or_else(Quirks::default, |info| info.quirks)
+    });
+    let cycles_per_frame = speed
+        .or_else(|| info.as_ref().and_then(|info| info.cycles_per_frame))
+        .unwrap_or(DEFAULT_CYCLES_PER_FRAME);
+    let mut cpu = CPU::with_seed(quirks, 0);
+    cpu.load_rom(&rom).map_err(|err| err.to_string())?;
+    let mut emulator = Emulator::headless(cpu);
+    emulator.set_cycles_per_frame(cycles_per_frame);
+
+    let start = Instant::now();
+    for frame in 0..frames {
+        emulator
+            .run_frame()
+            .map_err(|err| format!("Stopped in frame {}: {}", frame + 1, err))?;
+    }
+    let seconds = start.elapsed().as_secs_f64().max(1e-9);
+    let fps = frames as f64 / seconds;
+    println!(
+        "{} frames of {} instructions in {:.3} s",
+        frames, cycles_per_frame, seconds
+    );
+    println!(
+        "{:.0} frames per second, {:.1} times real time",
+        fps,
+        fps / 60.0
+    );
+    Ok(())
+}
+
+// Waits for a GDB remote connection on localhost and lets it drive the CPU
+fn serve_gdb(rom_path: &str, port: u16) -> Result<(), String> {
+    let rom = read_rom_or_source(rom_path)?;
+    let info = lookup_rom(&rom)?;
+    let quirks = info
+        .as_ref()
+        .map_or_else(Quirks::default, |info| info.quirks);
+    let cycles_per_frame = info
+        .and_then(|info| info.cycles_per_frame)
+        .unwrap_or(DEFAULT_CYCLES_PER_FRAME);
+    let mut cpu = CPU::new(quirks);
+    cpu.load_rom(&rom).map_err(|err| err.to_string())?;
+    TcpListener::bind(("127.0.0.1", port))
+        .and_then(|listener| {
+            eprintln!("Waiting for GDB on 127.0.0.1:{}", port);
+            GdbStub::new(cycles_per_frame).serve(&mut cpu, &listener)
+        })
+        .map_err(|err| err.to_string())
+}
+
+// Reads the ROM, compiling Octo sources on the fly
+fn read_rom_or_source(rom_path: &str) -> Result<Vec<u8>, String> {
+    if is_octo(Path::new(rom_path)) {
+        compile(Path::new(rom_path))
+    } else {
+        read_rom(rom_path)
+    }
+}
+
+fn is_octo(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "8o")
+}
+
+// Sources ending in .8o are compiled as Octo, everything else is assembled as mnemonics
+fn compile(source_path: &Path) -> Result<Vec<u8>, String> {
+    let rom = if is_octo(source_path) {
+        octo::compile_file(source_path)
+    } else {
+        asm::assemble_file(source_path)
+    };
+    rom.map_err(|err| err.to_string())
+}
+
+fn run_frontend(cpu: CPU, session: Session) -> Result<(), String> {
+    if let Some(frames) = session.headless {
+        frontend::headless::run(cpu, session, frames)
+    } else if session.tui {
+        run_terminal(cpu, session)
+    } else {
+        run_window(cpu, session)
+    }
+}
+
+#[cfg(feature = "tui-frontend")]
+fn run_terminal(cpu: CPU, session: Session) -> Result<(), String> {
+    frontend::tui::run(cpu, session);
+    Ok(())
+}
+
+#[cfg(not(feature = "tui-frontend"))]
+fn run_terminal(_cpu: CPU, _session: Session) -> Result<(), String> {
+    Err("Built without the terminal frontend, enable the tui-frontend feature".to_string())
+}
+
+#[cfg(feature = "piston-frontend")]
+fn run_window(cpu: CPU, session: Session) -> Result<(), String> {
+    frontend::gui::run(cpu, session);
+    Ok(())
+}
+
+// Without a window only the debugger can run, reading commands from the terminal
+#[cfg(not(feature = "piston-frontend"))]
+fn run_window(mut cpu: CPU, session: Session) -> Result<(), String> {
+    if !session.debug {
+        return Err(
+            "Built without a window, enable the piston-frontend feature or use --tui".to_string(),
+        );
+    }
+    let stdin = io::stdin();
+    let mut debugger = Debugger::new(session.cycles_per_frame);
+    if let Some(tracer) = session.tracer {
+        debugger.enable_trace(tracer);
     }
+    let result = debugger.repl(&mut cpu, stdin.lock(), io::stdout());
+    frontend::finish(debugger.take_tracer(), None, None, None);
+    result.map_err(|err| err.to_string())
 }
 
-fn read_rom(path: &str) -> Vec<u8> {
-    let mut file = File::open(path).unwrap();
-    let mut file_buf = Vec::new();
-    let bytes_read = file.read_to_end(&mut file_buf).unwrap();
-    println!("Read ROM with {} bytes", bytes_read);
-    file_buf
+fn read_rom(path: &str) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|err| format!("Can't read {}: {}", path, err))
 }
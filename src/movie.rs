@@ -0,0 +1,272 @@
+// Movies record everything a run depends on, so playing one back reproduces the run exactly
+//
+// Layout, all numbers little endian
+// 0x00 - 8 byte magic "RCHIPMOV"
+// 0x08 - u16 format version
+// 0x0A - Quirks, like in save states
+//      - u64 seed of the random number generator
+//      - u32 CRC-32 of the ROM
+//      - u32 instructions per frame
+//      - u32 frame count
+//      - Runs of identical frames until the frame count is reached, each a u16 keypad
+//        bitmask with bit N set while key N is held, followed by the u32 number of frames
+
+use cpu::CPU;
+use emulator::Emulator;
+use error::MovieError;
+use quirks::Quirks;
+use state::{crc32, StateReader, StateWriter};
+
+const MAGIC: &[u8; 8] = b"RCHIPMOV";
+pub const MOVIE_VERSION: u16 = 1;
+const HEADER_LEN: usize = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Movie {
+    pub quirks: Quirks,
+    pub seed: u64,
+    pub rom_checksum: u32,
+    pub cycles_per_frame: usize,
+    // The keypad bitmask of every frame
+    frames: Vec<u16>,
+}
+
+impl Movie {
+    // An empty recording for a run of the ROM on a CPU created with CPU::with_seed
+    pub fn new(rom: &[u8], quirks: Quirks, seed: u64, cycles_per_frame: usize) -> Movie {
+        Movie {
+            quirks,
+            seed,
+            rom_checksum: crc32(rom),
+            cycles_per_frame,
+            frames: Vec::new(),
+        }
+    }
+
+    // Number of recorded frames
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    // Appends the keypad as the input of the next frame
+    pub fn record_frame(&mut self, keypad: &[u8]) {
+        let mask = keypad
+            .iter()
+            .enumerate()
+            .filter(|&(_, pressed)| *pressed != 0)
+            .fold(0u16, |mask, (key, _)| mask | 1 << key);
+        self.frames.push(mask);
+    }
+
+    // A CPU in the state the recording started in, fails for any ROM but the recorded one
+    pub fn start(&self, rom: &[u8]) -> Result<CPU, MovieError> {
+        let actual = crc32(rom);
+        if actual != self.rom_checksum {
+            return Err(MovieError::RomMismatch {
+                recorded: self.rom_checksum,
+                actual,
+            });
+        }
+        let mut cpu = CPU::with_seed(self.quirks, self.seed);
+        cpu.load_rom(rom)
+            .map_err(|_| MovieError::Invalid("ROM size"))?;
+        Ok(cpu)
+    }
+
+    // Plays the whole movie without a frontend and returns the CPU after the last frame
+    // A CPU error ends the playback early, just like it ended the recorded run
+    pub fn replay(&self, rom: &[u8]) -> Result<CPU, MovieError> {
+        let mut emulator = Emulator::headless(self.start(rom)?);
+        emulator.set_cycles_per_frame(self.cycles_per_frame);
+        emulator.play_movie(MoviePlayer::new(self.clone()));
+        for _ in 0..self.len() {
+            if emulator.run_frame().is_err() {
+                break;
+            }
+        }
+        Ok(emulator.into_cpu())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        writer.quirks(&self.quirks);
+        writer.u64(self.seed);
+        writer.u32(self.rom_checksum);
+        writer.u32(self.cycles_per_frame as u32);
+        writer.u32(self.frames.len() as u32);
+        let mut frames = self.frames.iter().peekable();
+        while let Some(&mask) = frames.next() {
+            let mut count = 1u32;
+            while frames.peek() == Some(&&mask) {
+                frames.next();
+                count += 1;
+            }
+            writer.u16(mask);
+            writer.u32(count);
+        }
+
+        let mut movie = MAGIC.to_vec();
+        movie.extend_from_slice(&MOVIE_VERSION.to_le_bytes());
+        movie.extend_from_slice(&writer.into_payload());
+        movie
+    }
+
+    pub fn from_bytes(movie: &[u8]) -> Result<Movie, MovieError> {
+        if movie.len() < HEADER_LEN {
+            return Err(MovieError::Truncated);
+        }
+        if &movie[..8] != MAGIC {
+            return Err(MovieError::BadMagic);
+        }
+        let version = u16::from_le_bytes([movie[8], movie[9]]);
+        if version != MOVIE_VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+
+        let mut reader = StateReader::from_payload(&movie[HEADER_LEN..]);
+        let quirks = reader.quirks()?;
+        let seed = reader.u64()?;
+        let rom_checksum = reader.u32()?;
+        let cycles_per_frame = reader.u32()? as usize;
+        let frame_count = reader.u32()? as usize;
+        let mut frames = Vec::new();
+        while frames.len() < frame_count {
+            let mask = reader.u16()?;
+            let count = reader.u32()? as usize;
+            if count == 0 || frames.len() + count > frame_count {
+                return Err(MovieError::Invalid("frame count"));
+            }
+            frames.extend(std::iter::repeat_n(mask, count));
+        }
+        reader.finish()?;
+        Ok(Movie {
+            quirks,
+            seed,
+            rom_checksum,
+            cycles_per_frame,
+            frames,
+        })
+    }
+}
+
+// Feeds the recorded inputs to the CPU frame by frame, see Emulator::play_movie
+pub struct MoviePlayer {
+    movie: Movie,
+    frame: usize,
+}
+
+impl MoviePlayer {
+    pub fn new(movie: Movie) -> MoviePlayer {
+        MoviePlayer { movie, frame: 0 }
+    }
+
+    // Number of frames played so far
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frame >= self.movie.len()
+    }
+
+    // Overwrites the keypad with the next recorded frame
+    // Returns false and leaves the keypad alone once every frame was played
+    pub fn next_frame(&mut self, keypad: &mut [u8]) -> bool {
+        let mask = match self.movie.frames.get(self.frame) {
+            Some(mask) => *mask,
+            None => return false,
+        };
+        for (key, state) in keypad.iter_mut().enumerate() {
+            *state = (mask >> key & 1) as u8;
+        }
+        self.frame += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emulator::{InputSource, NullAudio, NullDisplay};
+
+    // Adds a random number to the held key and stores the sum as BCD at 0x300, forever
+    // 200: F10A  LD V1, K
+    // 202: C2FF  RND V2, 0xFF
+    // 204: 8214  ADD V2, V1
+    // 206: A300  LD I, 0x300
+    // 208: F233  LD B, V2
+    // 20A: 1200  JP 0x200
+    const ROM: [u8; 12] = [
+        0xF1, 0x0A, 0xC2, 0xFF, 0x82, 0x14, 0xA3, 0x00, 0xF2, 0x33, 0x12, 0x00,
+    ];
+
+    // Holds a different key every frame
+    struct Script(usize);
+
+    impl InputSource for Script {
+        fn poll(&mut self, keypad: &mut [u8]) {
+            self.0 += 1;
+            for (key, state) in keypad.iter_mut().enumerate() {
+                *state = (key == self.0 * 7 % 16) as u8;
+            }
+        }
+    }
+
+    #[test]
+    fn playback_reproduces_the_recorded_run() {
+        let seed = 0x1234_5678;
+        let cpu = CPU::with_seed(Quirks::default(), seed);
+        let mut emulator = Emulator::new(cpu, NullDisplay, NullAudio, Script(0));
+        emulator.cpu_mut().load_rom(&ROM).unwrap();
+        emulator.record_movie(Movie::new(&ROM, Quirks::default(), seed, 10));
+        for _ in 0..100 {
+            emulator.run_frame().unwrap();
+        }
+        let movie = emulator.take_recording().unwrap();
+        assert_eq!(movie.len(), 100);
+
+        let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        let replayed = movie.replay(&ROM).unwrap();
+        assert_eq!(replayed.snapshot(), emulator.cpu().snapshot());
+
+        let mut other_rom = ROM;
+        other_rom[3] = 0x0F;
+        assert_eq!(
+            movie.replay(&other_rom).err(),
+            Some(MovieError::RomMismatch {
+                recorded: crc32(&ROM),
+                actual: crc32(&other_rom),
+            })
+        );
+    }
+
+    #[test]
+    fn frames_are_run_length_encoded() {
+        let mut movie = Movie::new(&ROM, Quirks::cosmac_vip(), 1, 15);
+        let mut keypad = [0; 16];
+        for frame in 0..1000 {
+            keypad[5] = (frame >= 500) as u8;
+            movie.record_frame(&keypad);
+        }
+        let bytes = movie.to_bytes();
+        assert!(bytes.len() < 60);
+        assert_eq!(Movie::from_bytes(&bytes), Ok(movie.clone()));
+
+        let mut player = MoviePlayer::new(movie);
+        for _ in 0..501 {
+            assert!(player.next_frame(&mut keypad));
+        }
+        assert_eq!(keypad[5], 1);
+        assert_eq!(player.frame(), 501);
+
+        assert_eq!(
+            Movie::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(MovieError::Truncated)
+        );
+        assert_eq!(Movie::from_bytes(b"RCHIPSAV.."), Err(MovieError::BadMagic));
+    }
+}
@@ -0,0 +1,1151 @@
+// Compiler for Octo, the structured assembly language most modern CHIP-8 games are written in
+// https://github.com/JohnEarnest/Octo/blob/gh-pages/docs/Manual.md
+//
+// Supported are labels (: name, :next), :alias, :const, :calc, :byte, :pointer, :org,
+// :macro, :unpack, loop/while/again, if/then and if/begin/else/end, and the CHIP-8,
+// SUPER-CHIP and XO-CHIP statements. { } calc expressions work wherever a number does.
+// :breakpoint, :monitor and :proto are accepted and ignored.
+//
+// Like in Octo, 0x200 holds a jump to the label main unless main is the first thing defined.
+
+use asm::encode;
+use cpu::Opcode;
+use cpu::Opcode::*;
+use disasm::ROM_START;
+use error::AsmError;
+use std::collections::HashMap;
+use std::f64::consts::{E, PI};
+use std::fs;
+use std::path::Path;
+
+// Guards against macros that expand into themselves forever
+const MAX_MACRO_EXPANSIONS: usize = 100_000;
+
+pub fn compile(source: &str) -> Result<Vec<u8>, AsmError> {
+    Compiler::new(source, "<source>").compile()
+}
+
+pub fn compile_file(path: &Path) -> Result<Vec<u8>, AsmError> {
+    let file = path.display().to_string();
+    let source = fs::read_to_string(path).map_err(|err| AsmError {
+        file: file.clone(),
+        line: 0,
+        column: 0,
+        message: format!("Can't read the file: {}", err),
+    })?;
+    Compiler::new(&source, &file).compile()
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+}
+
+// How a forward reference is patched in once the label is defined
+#[derive(Clone, Copy)]
+enum Fixup {
+    // The low 12 bits of an instruction like jump or i :=
+    Address,
+    // A whole word, i := long and :pointer
+    Long,
+}
+
+// A comparison of an if or while
+struct Condition {
+    register: u16,
+    operator: String,
+    // Register or immediate on the right hand side, neither for key and -key
+    rhs: Operand,
+}
+
+enum Operand {
+    None,
+    Register(u16),
+    Byte(u16),
+}
+
+struct Compiler {
+    file: String,
+    // Upcoming tokens, the next one last
+    tokens: Vec<Token>,
+    // Position reported when the source ends unexpectedly
+    end: Token,
+    rom: Vec<u8>,
+    here: usize,
+    // Labels, constants and calc results
+    values: HashMap<String, f64>,
+    // Names defined with :, which are called when used as a statement
+    labels: Vec<String>,
+    aliases: HashMap<String, u16>,
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+    fixups: Vec<(usize, Fixup, Token)>,
+    // Start of every open loop and the jumps of its whiles
+    loops: Vec<(usize, Vec<usize>)>,
+    // Jumps of every open begin or else, patched by else and end
+    branches: Vec<(usize, Token)>,
+    // Whether 0x200 still holds the jump to main
+    main_jump: bool,
+}
+
+impl Compiler {
+    fn new(source: &str, file: &str) -> Compiler {
+        let mut tokens = lex(source);
+        let end = match tokens.last() {
+            Some(last) => Token {
+                text: String::new(),
+                line: last.line,
+                column: last.column + last.text.chars().count(),
+            },
+            None => Token {
+                text: String::new(),
+                line: 1,
+                column: 1,
+            },
+        };
+        tokens.reverse();
+        Compiler {
+            file: file.to_string(),
+            tokens,
+            end,
+            // Room for the jump to main
+            rom: vec![0, 0],
+            here: ROM_START + 2,
+            values: HashMap::new(),
+            labels: Vec::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            expansions: 0,
+            fixups: Vec::new(),
+            loops: Vec::new(),
+            branches: Vec::new(),
+            main_jump: true,
+        }
+    }
+
+    fn error<M: Into<String>>(&self, token: &Token, message: M) -> AsmError {
+        AsmError {
+            file: self.file.clone(),
+            line: token.line,
+            column: token.column,
+            message: message.into(),
+        }
+    }
+
+    fn compile(mut self) -> Result<Vec<u8>, AsmError> {
+        while let Some(token) = self.tokens.pop() {
+            self.statement(token)?;
+        }
+        if let Some((_, ref token)) = self.branches.last() {
+            return Err(self.error(token, "This begin is missing its end"));
+        }
+        if !self.loops.is_empty() {
+            return Err(self.error(&self.end, "A loop is missing its again"));
+        }
+
+        for (address, fixup, token) in self.fixups.clone() {
+            let value = match self.values.get(&token.text) {
+                Some(value) => *value as i64,
+                None => return Err(self.error(&token, format!("Undefined name '{}'", token.text))),
+            };
+            let offset = address - ROM_START;
+            match fixup {
+                Fixup::Address => {
+                    let nnn = self.check(&token, value, 0, 0xFFF, "a 12-bit address")?;
+                    self.rom[offset] = (self.rom[offset] & 0xF0) | (nnn >> 8) as u8;
+                    self.rom[offset + 1] = nnn as u8;
+                }
+                Fixup::Long => {
+                    let nnnn = self.check(&token, value, 0, 0xFFFF, "a 16-bit address")?;
+                    self.rom[offset] = (nnnn >> 8) as u8;
+                    self.rom[offset + 1] = nnnn as u8;
+                }
+            }
+        }
+
+        if self.main_jump {
+            let main = match self.values.get("main") {
+                Some(main) => *main as u16,
+                None => return Err(self.error(&self.end, "This program is missing a main label")),
+            };
+            self.rom[..2].copy_from_slice(&encode(Jump(main & 0xFFF)).to_be_bytes());
+        }
+        Ok(self.rom)
+    }
+
+    fn next(&mut self) -> Result<Token, AsmError> {
+        match self.tokens.pop() {
+            Some(token) => Ok(token),
+            None => Err(self.error(&self.end, "Unexpected end of file")),
+        }
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.last().map(|token| token.text.as_str())
+    }
+
+    fn expect(&mut self, text: &str) -> Result<Token, AsmError> {
+        let token = self.next()?;
+        if token.text != text {
+            return Err(self.error(&token, format!("Expected {} but got {}", text, token.text)));
+        }
+        Ok(token)
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), AsmError> {
+        match token.text.as_str() {
+            ":" => {
+                let name = self.name()?;
+                if name.text == "main" && self.main_jump && self.here == ROM_START + 2 {
+                    // Nothing before main, so there is no need to jump to it
+                    self.main_jump = false;
+                    self.rom.clear();
+                    self.here = ROM_START;
+                }
+                let here = self.here as f64;
+                self.define(&name, here)?;
+                self.labels.push(name.text);
+            }
+            ":next" => {
+                // The second byte of the next instruction, for self-modifying code
+                let name = self.name()?;
+                let address = (self.here + 1) as f64;
+                self.define(&name, address)?;
+            }
+            ":alias" => {
+                let name = self.name()?;
+                let register = self.register()?;
+                if self.values.contains_key(&name.text) {
+                    return Err(self.error(&name, format!("'{}' is already defined", name.text)));
+                }
+                self.aliases.insert(name.text, register);
+            }
+            ":const" => {
+                let name = self.name()?;
+                let value_token = self.next()?;
+                let value = self.value(&value_token)?;
+                self.define(&name, value)?;
+            }
+            ":calc" => {
+                let name = self.name()?;
+                let open = self.expect("{")?;
+                let value = self.calc(&open)?;
+                self.define(&name, value)?;
+            }
+            ":byte" => {
+                let value = self.next()?;
+                let byte = self.byte(&value)?;
+                self.emit(&[byte as u8]);
+            }
+            ":pointer" => {
+                let value = self.next()?;
+                let word = self.address(&value, Fixup::Long)?;
+                self.emit(&word.to_be_bytes());
+            }
+            ":org" => {
+                let value = self.next()?;
+                let address = self.integer(&value)?;
+                self.here =
+                    self.check(&value, address, ROM_START as i64, 0xFFFF, "memory")? as usize;
+            }
+            ":macro" => self.define_macro()?,
+            ":unpack" => {
+                // v0 gets the nibble and the high 4 bits of the address, v1 the low byte
+                let nibble_token = self.next()?;
+                let nibble = self.nibble(&nibble_token)?;
+                let address_token = self.next()?;
+                let address = self.integer(&address_token)?;
+                let address = self.check(&address_token, address, 0, 0xFFF, "a 12-bit address")?;
+                self.op(SetRegister(0, nibble << 4 | address >> 8));
+                self.op(SetRegister(1, address & 0xFF));
+            }
+            ":breakpoint" | ":proto" => {
+                self.next()?;
+            }
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+            }
+            "return" | ";" => self.op(Return),
+            "clear" => self.op(ClearScreen),
+            "hires" => self.op(HighResolution),
+            "lores" => self.op(LowResolution),
+            "exit" => self.op(Exit),
+            "scroll-left" => self.op(ScrollLeft),
+            "scroll-right" => self.op(ScrollRight),
+            "audio" => self.op(LoadAudioPattern),
+            "scroll-down" => {
+                let n = self.next()?;
+                let n = self.nibble(&n)?;
+                self.op(ScrollDown(n));
+            }
+            "scroll-up" => {
+                // XO-CHIP 00DN, there is no Opcode for it as the CPU doesn't implement it yet
+                let n = self.next()?;
+                let n = self.nibble(&n)?;
+                self.emit(&(0x00D0 | n).to_be_bytes());
+            }
+            "plane" => {
+                let n = self.next()?;
+                let n = self.nibble(&n)?;
+                self.op(SelectPlanes(n));
+            }
+            "bcd" => {
+                let x = self.register()?;
+                self.op(StoreBinaryCodedDecimal(x));
+            }
+            "saveflags" => {
+                let x = self.register()?;
+                self.op(StoreFlags(x));
+            }
+            "loadflags" => {
+                let x = self.register()?;
+                self.op(LoadFlags(x));
+            }
+            "save" | "load" => {
+                let x = self.register()?;
+                let save = token.text == "save";
+                let opcode = if self.peek() == Some("-") {
+                    self.next()?;
+                    let y = self.register()?;
+                    if save {
+                        RegisterRangeDump(x, y)
+                    } else {
+                        RegisterRangeLoad(x, y)
+                    }
+                } else if save {
+                    RegisterDump(x)
+                } else {
+                    RegisterLoad(x)
+                };
+                self.op(opcode);
+            }
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.next()?;
+                let n = self.nibble(&n)?;
+                self.op(Display(x, y, n));
+            }
+            "jump" => {
+                let target = self.next()?;
+                self.op(Jump(0));
+                let nnn = self.address(&target, Fixup::Address)?;
+                self.patch_address(self.here - 2, nnn);
+            }
+            "jump0" => {
+                let target = self.next()?;
+                self.op(Flow(0));
+                let nnn = self.address(&target, Fixup::Address)?;
+                self.patch_address(self.here - 2, nnn);
+            }
+            "native" => {
+                let target = self.next()?;
+                self.emit(&[0, 0]);
+                let nnn = self.address(&target, Fixup::Address)?;
+                self.patch_address(self.here - 2, nnn);
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                self.op(match token.text.as_str() {
+                    "delay" => SetDelayTimer(x),
+                    "buzzer" => SetSoundTimer(x),
+                    _ => SetPitch(x),
+                });
+            }
+            "i" => self.index_statement()?,
+            "if" => {
+                let condition = self.condition()?;
+                let keyword = self.next()?;
+                match keyword.text.as_str() {
+                    "then" => self.skip(&condition, false),
+                    "begin" => {
+                        self.skip(&condition, true);
+                        self.op(Jump(0));
+                        self.branches.push((self.here - 2, keyword));
+                    }
+                    _ => {
+                        return Err(self.error(
+                            &keyword,
+                            format!("Expected then or begin but got {}", keyword.text),
+                        ))
+                    }
+                }
+            }
+            "else" => {
+                let (jump, _) = match self.branches.pop() {
+                    Some(branch) => branch,
+                    None => return Err(self.error(&token, "else without a begin")),
+                };
+                self.op(Jump(0));
+                self.branches.push((self.here - 2, token));
+                let here = self.here as u16;
+                self.patch_address(jump, here);
+            }
+            "end" => {
+                let (jump, _) = match self.branches.pop() {
+                    Some(branch) => branch,
+                    None => return Err(self.error(&token, "end without a begin")),
+                };
+                let here = self.here as u16;
+                self.patch_address(jump, here);
+            }
+            "loop" => self.loops.push((self.here, Vec::new())),
+            "while" => {
+                let condition = self.condition()?;
+                if self.loops.is_empty() {
+                    return Err(self.error(&token, "while outside of a loop"));
+                }
+                self.skip(&condition, true);
+                self.op(Jump(0));
+                let jump = self.here - 2;
+                if let Some((_, ref mut whiles)) = self.loops.last_mut() {
+                    whiles.push(jump);
+                }
+            }
+            "again" => {
+                let (start, whiles) = match self.loops.pop() {
+                    Some(open) => open,
+                    None => return Err(self.error(&token, "again without a loop")),
+                };
+                self.op(Jump(start as u16));
+                let here = self.here as u16;
+                for jump in whiles {
+                    self.patch_address(jump, here);
+                }
+            }
+            _ => {
+                if let Some(x) = self.register_named(&token.text) {
+                    return self.register_statement(x);
+                }
+                if self.macros.contains_key(&token.text) {
+                    return self.expand_macro(&token);
+                }
+                if self.labels.contains(&token.text)
+                    || (is_name(&token.text) && !self.values.contains_key(&token.text))
+                {
+                    // A label called like a subroutine, possibly defined further down
+                    self.op(CallSubroutine(0));
+                    let nnn = self.address(&token, Fixup::Address)?;
+                    self.patch_address(self.here - 2, nnn);
+                    return Ok(());
+                }
+                // Numbers and constants on their own are data
+                let byte = self.byte(&token)?;
+                self.emit(&[byte as u8]);
+            }
+        }
+        Ok(())
+    }
+
+    // i := nnn, i := long nnnn, i := hex vx, i := bighex vx, i += vx
+    fn index_statement(&mut self) -> Result<(), AsmError> {
+        let operator = self.next()?;
+        match operator.text.as_str() {
+            ":=" => {
+                let source = self.next()?;
+                match source.text.as_str() {
+                    "hex" => {
+                        let x = self.register()?;
+                        self.op(SetIndexRegisterToSpriteLocation(x));
+                    }
+                    "bighex" => {
+                        let x = self.register()?;
+                        self.op(SetIndexRegisterToLargeSpriteLocation(x));
+                    }
+                    "long" => {
+                        let target = self.next()?;
+                        self.op(SetIndexRegisterLong);
+                        let nnnn = self.address(&target, Fixup::Long)?;
+                        self.emit(&nnnn.to_be_bytes());
+                    }
+                    _ => {
+                        self.op(SetIndexRegister(0));
+                        let nnn = self.address(&source, Fixup::Address)?;
+                        self.patch_address(self.here - 2, nnn);
+                    }
+                }
+            }
+            "+=" => {
+                let x = self.register()?;
+                self.op(AddToIndexRegister(x));
+            }
+            _ => {
+                return Err(self.error(
+                    &operator,
+                    format!("Expected := or += but got {}", operator.text),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    // vx := ..., vx += ..., and the other register operators
+    fn register_statement(&mut self, x: u16) -> Result<(), AsmError> {
+        let operator = self.next()?;
+        let source = self.next()?;
+        let y = self.register_named(&source.text);
+        let opcode = match (operator.text.as_str(), y) {
+            (":=", Some(y)) => Assign(x, y),
+            (":=", None) if source.text == "key" => AwaitKeyPress(x),
+            (":=", None) if source.text == "delay" => GetDelayTimer(x),
+            (":=", None) if source.text == "random" => {
+                let mask = self.next()?;
+                Rand(x, self.byte(&mask)?)
+            }
+            (":=", None) => SetRegister(x, self.byte(&source)?),
+            ("+=", Some(y)) => Add(x, y),
+            ("+=", None) => AddAddressToRegister(x, self.byte(&source)?),
+            ("-=", Some(y)) => Subtract(x, y),
+            // There is no subtract immediate, adding the two's complement does the same
+            ("-=", None) => AddAddressToRegister(x, self.byte(&source)?.wrapping_neg() & 0xFF),
+            ("=-", Some(y)) => SetSubtract(x, y),
+            ("|=", Some(y)) => AssignOr(x, y),
+            ("&=", Some(y)) => AssignAnd(x, y),
+            ("^=", Some(y)) => AssignXor(x, y),
+            (">>=", Some(y)) => LeastSigStoreAndShift(x, y),
+            ("<<=", Some(y)) => MostSigStoreAndShift(x, y),
+            ("=-", None)
+            | ("|=", None)
+            | ("&=", None)
+            | ("^=", None)
+            | (">>=", None)
+            | ("<<=", None) => {
+                return Err(self.error(
+                    &source,
+                    format!("{} needs a register but got {}", operator.text, source.text),
+                ))
+            }
+            _ => return Err(self.error(&operator, format!("Unknown operator {}", operator.text))),
+        };
+        self.op(opcode);
+        Ok(())
+    }
+
+    fn condition(&mut self) -> Result<Condition, AsmError> {
+        let register = self.register()?;
+        let operator = self.next()?;
+        let rhs = match operator.text.as_str() {
+            "key" | "-key" => Operand::None,
+            "==" | "!=" | "<" | ">" | "<=" | ">=" => {
+                let rhs = self.next()?;
+                match self.register_named(&rhs.text) {
+                    Some(y) => Operand::Register(y),
+                    None => Operand::Byte(self.byte(&rhs)?),
+                }
+            }
+            _ => return Err(self.error(&operator, format!("Unknown comparison {}", operator.text))),
+        };
+        Ok(Condition {
+            register,
+            operator: operator.text,
+            rhs,
+        })
+    }
+
+    // Emits instructions that skip the next one if the condition is when_true
+    fn skip(&mut self, condition: &Condition, when_true: bool) {
+        let x = condition.register;
+        let (operator, x, rhs) = match condition.operator.as_str() {
+            "<" | ">" | "<=" | ">=" => {
+                // VF gets the carry of a - b for < and >=, of b - a for > and <=
+                // a < b and a > b hold when it's 0, a >= b and a <= b when it's 1
+                let a_minus_b = condition.operator == "<" || condition.operator == ">=";
+                match condition.rhs {
+                    Operand::Register(y) => {
+                        self.op(Assign(0xF, x));
+                        self.op(if a_minus_b {
+                            Subtract(0xF, y)
+                        } else {
+                            SetSubtract(0xF, y)
+                        });
+                    }
+                    Operand::Byte(nn) => {
+                        self.op(SetRegister(0xF, nn));
+                        self.op(if a_minus_b {
+                            SetSubtract(0xF, x)
+                        } else {
+                            Subtract(0xF, x)
+                        });
+                    }
+                    Operand::None => unreachable!(),
+                }
+                let operator = if condition.operator.len() == 1 {
+                    "=="
+                } else {
+                    "!="
+                };
+                (operator, 0xF, Operand::Byte(0))
+            }
+            _ => (
+                condition.operator.as_str(),
+                x,
+                match condition.rhs {
+                    Operand::Register(y) => Operand::Register(y),
+                    Operand::Byte(nn) => Operand::Byte(nn),
+                    Operand::None => Operand::None,
+                },
+            ),
+        };
+
+        // Skip instructions skip when their own comparison holds
+        let equal = match operator {
+            "==" | "key" => when_true,
+            _ => !when_true,
+        };
+        let opcode = match (rhs, equal) {
+            (Operand::Register(y), true) => SkipIfEqualRegister(x, y),
+            (Operand::Register(y), false) => SkipIfUnequalRegisters(x, y),
+            (Operand::Byte(nn), true) => SkipIfEqualAddress(x, nn),
+            (Operand::Byte(nn), false) => SkipIfNotEqualAddress(x, nn),
+            (Operand::None, true) => SkipIfKeyPressed(x),
+            (Operand::None, false) => SkipIfNotKeyPressed(x),
+        };
+        self.op(opcode);
+    }
+
+    fn define_macro(&mut self) -> Result<(), AsmError> {
+        let name = self.name()?;
+        let mut args = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token.text == "{" {
+                break;
+            }
+            args.push(token.text);
+        }
+        // Calc expressions in the body have braces of their own
+        let mut depth = 1;
+        let mut body = Vec::new();
+        loop {
+            let token = self.next()?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                _ => (),
+            }
+            body.push(token);
+        }
+        if self.macros.contains_key(&name.text) {
+            return Err(self.error(&name, format!("'{}' is already defined", name.text)));
+        }
+        self.macros.insert(name.text, Macro { args, body });
+        Ok(())
+    }
+
+    // Replaces the macro and its arguments with the body
+    fn expand_macro(&mut self, name: &Token) -> Result<(), AsmError> {
+        self.expansions += 1;
+        if self.expansions > MAX_MACRO_EXPANSIONS {
+            return Err(self.error(name, "Macro expansion doesn't end"));
+        }
+        let arg_count = self.macros[&name.text].args.len();
+        let mut values = HashMap::new();
+        for i in 0..arg_count {
+            let value = self.next()?;
+            values.insert(self.macros[&name.text].args[i].clone(), value);
+        }
+        let body: Vec<Token> = self.macros[&name.text]
+            .body
+            .iter()
+            .map(|token| values.get(&token.text).unwrap_or(token).clone())
+            .collect();
+        self.tokens.extend(body.into_iter().rev());
+        Ok(())
+    }
+
+    // A name for a new label, constant, alias or macro
+    fn name(&mut self) -> Result<Token, AsmError> {
+        let token = self.next()?;
+        if !is_name(&token.text) || self.register_named(&token.text).is_some() {
+            return Err(self.error(&token, format!("'{}' can't be used as a name", token.text)));
+        }
+        Ok(token)
+    }
+
+    fn define(&mut self, name: &Token, value: f64) -> Result<(), AsmError> {
+        if self.values.contains_key(&name.text) || self.macros.contains_key(&name.text) {
+            return Err(self.error(name, format!("'{}' is already defined", name.text)));
+        }
+        self.values.insert(name.text.clone(), value);
+        Ok(())
+    }
+
+    fn register(&mut self) -> Result<u16, AsmError> {
+        let token = self.next()?;
+        match self.register_named(&token.text) {
+            Some(x) => Ok(x),
+            None => Err(self.error(
+                &token,
+                format!("Expected a register but got {}", token.text),
+            )),
+        }
+    }
+
+    // v0 to vf, or an alias for one
+    fn register_named(&self, text: &str) -> Option<u16> {
+        if let Some(x) = self.aliases.get(text) {
+            return Some(*x);
+        }
+        let mut chars = text.chars();
+        match (chars.next(), chars.next(), chars.next()) {
+            (Some('v'), Some(digit), None) | (Some('V'), Some(digit), None) => {
+                digit.to_digit(16).map(|x| x as u16)
+            }
+            _ => None,
+        }
+    }
+
+    // A number, a name with a value or a calc expression
+    fn value(&mut self, token: &Token) -> Result<f64, AsmError> {
+        if token.text == "{" {
+            return self.calc(token);
+        }
+        if let Some(number) = parse_number(&token.text) {
+            return Ok(number as f64);
+        }
+        match self.values.get(&token.text) {
+            Some(value) => Ok(*value),
+            None => Err(self.error(token, format!("Undefined name '{}'", token.text))),
+        }
+    }
+
+    fn integer(&mut self, token: &Token) -> Result<i64, AsmError> {
+        Ok(self.value(token)?.floor() as i64)
+    }
+
+    fn check(
+        &self,
+        token: &Token,
+        value: i64,
+        min: i64,
+        max: i64,
+        what: &str,
+    ) -> Result<u16, AsmError> {
+        if value < min || value > max {
+            return Err(self.error(token, format!("{} doesn't fit into {}", value, what)));
+        }
+        Ok(value as u16)
+    }
+
+    fn byte(&mut self, token: &Token) -> Result<u16, AsmError> {
+        let value = self.integer(token)?;
+        // Negative bytes are stored as two's complement
+        Ok(self.check(token, value, -128, 255, "a byte")? & 0xFF)
+    }
+
+    fn nibble(&mut self, token: &Token) -> Result<u16, AsmError> {
+        let value = self.integer(token)?;
+        self.check(token, value, 0, 15, "a nibble")
+    }
+
+    // An address, which may be a label defined further down
+    fn address(&mut self, token: &Token, fixup: Fixup) -> Result<u16, AsmError> {
+        let defined = token.text == "{"
+            || parse_number(&token.text).is_some()
+            || self.values.contains_key(&token.text);
+        if !defined && is_name(&token.text) {
+            let address = match fixup {
+                // Called right after the opcode was emitted
+                Fixup::Address => self.here - 2,
+                Fixup::Long => self.here,
+            };
+            self.fixups.push((address, fixup, token.clone()));
+            return Ok(0);
+        }
+        let value = self.integer(token)?;
+        match fixup {
+            Fixup::Address => self.check(token, value, 0, 0xFFF, "a 12-bit address"),
+            Fixup::Long => self.check(token, value, 0, 0xFFFF, "a 16-bit address"),
+        }
+    }
+
+    // Evaluates a calc expression up to the closing brace
+    // Like in Octo all binary operators have the same precedence and group to the right
+    fn calc(&mut self, open: &Token) -> Result<f64, AsmError> {
+        let mut tokens = Vec::new();
+        loop {
+            match self.tokens.pop() {
+                Some(ref token) if token.text == "}" => break,
+                Some(token) => tokens.push(token),
+                None => return Err(self.error(open, "This { is never closed")),
+            }
+        }
+        let mut position = 0;
+        let value = self.calc_expr(&tokens, &mut position, open)?;
+        if let Some(token) = tokens.get(position) {
+            return Err(self.error(token, format!("Unexpected {}", token.text)));
+        }
+        Ok(value)
+    }
+
+    fn calc_expr(
+        &self,
+        tokens: &[Token],
+        position: &mut usize,
+        open: &Token,
+    ) -> Result<f64, AsmError> {
+        let left = self.calc_term(tokens, position, open)?;
+        let operator = match tokens.get(*position) {
+            Some(token) if token.text != ")" => token,
+            _ => return Ok(left),
+        };
+        *position += 1;
+        let right = self.calc_expr(tokens, position, open)?;
+        let (a, b) = (left.floor() as i64, right.floor() as i64);
+        Ok(match operator.text.as_str() {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" => left / right,
+            "%" => left % right,
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "&" => (a & b) as f64,
+            "|" => (a | b) as f64,
+            "^" => (a ^ b) as f64,
+            "<<" => a.wrapping_shl(b as u32) as f64,
+            ">>" => a.wrapping_shr(b as u32) as f64,
+            "<" => (left < right) as i64 as f64,
+            ">" => (left > right) as i64 as f64,
+            "<=" => (left <= right) as i64 as f64,
+            ">=" => (left >= right) as i64 as f64,
+            "==" => (left == right) as i64 as f64,
+            "!=" => (left != right) as i64 as f64,
+            _ => return Err(self.error(operator, format!("Unknown operator {}", operator.text))),
+        })
+    }
+
+    fn calc_term(
+        &self,
+        tokens: &[Token],
+        position: &mut usize,
+        open: &Token,
+    ) -> Result<f64, AsmError> {
+        let token = match tokens.get(*position) {
+            Some(token) => token,
+            None => return Err(self.error(open, "Expected a value")),
+        };
+        *position += 1;
+        let unary: Option<fn(f64) -> f64> = match token.text.as_str() {
+            "-" => Some(|value| -value),
+            "~" => Some(|value| !(value.floor() as i64) as f64),
+            "!" => Some(|value| (value == 0.0) as i64 as f64),
+            "sin" => Some(f64::sin),
+            "cos" => Some(f64::cos),
+            "tan" => Some(f64::tan),
+            "exp" => Some(f64::exp),
+            "log" => Some(f64::ln),
+            "abs" => Some(f64::abs),
+            "sqrt" => Some(f64::sqrt),
+            "sign" => Some(f64::signum),
+            "ceil" => Some(f64::ceil),
+            "floor" => Some(f64::floor),
+            _ => None,
+        };
+        if let Some(function) = unary {
+            return Ok(function(self.calc_term(tokens, position, open)?));
+        }
+        match token.text.as_str() {
+            "(" => {
+                let value = self.calc_expr(tokens, position, open)?;
+                match tokens.get(*position) {
+                    Some(close) if close.text == ")" => {
+                        *position += 1;
+                        Ok(value)
+                    }
+                    _ => Err(self.error(token, "This ( is never closed")),
+                }
+            }
+            "@" => {
+                // The byte compiled to an address so far
+                let address = self.calc_term(tokens, position, open)?.floor() as usize;
+                let offset = address.wrapping_sub(ROM_START);
+                Ok(self.rom.get(offset).cloned().unwrap_or(0) as f64)
+            }
+            "PI" => Ok(PI),
+            "E" => Ok(E),
+            "HERE" => Ok(self.here as f64),
+            text => match parse_number(text) {
+                Some(number) => Ok(number as f64),
+                None => match self.values.get(text) {
+                    Some(value) => Ok(*value),
+                    None => Err(self.error(token, format!("Undefined name '{}'", text))),
+                },
+            },
+        }
+    }
+
+    fn op(&mut self, opcode: Opcode) {
+        self.emit(&encode(opcode).to_be_bytes());
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        let offset = self.here - ROM_START;
+        if self.rom.len() < offset + bytes.len() {
+            self.rom.resize(offset + bytes.len(), 0);
+        }
+        self.rom[offset..offset + bytes.len()].copy_from_slice(bytes);
+        self.here += bytes.len();
+    }
+
+    // Sets the low 12 bits of the instruction at address
+    fn patch_address(&mut self, address: usize, nnn: u16) {
+        let offset = address - ROM_START;
+        self.rom[offset] = (self.rom[offset] & 0xF0) | (nnn >> 8) as u8 & 0x0F;
+        self.rom[offset + 1] = nnn as u8;
+    }
+}
+
+// Tokens are separated by whitespace, # starts a comment
+fn lex(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let mut start = None;
+        let chars: Vec<char> = line.chars().collect();
+        for i in 0..=chars.len() {
+            let boundary = i == chars.len() || chars[i].is_whitespace() || chars[i] == '#';
+            match (start, boundary) {
+                (Some(first), true) => {
+                    tokens.push(Token {
+                        text: chars[first..i].iter().collect(),
+                        line: index + 1,
+                        column: first + 1,
+                    });
+                    start = None;
+                }
+                (None, false) => start = Some(i),
+                _ => (),
+            }
+            if i < chars.len() && chars[i] == '#' {
+                break;
+            }
+        }
+    }
+    tokens
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits
+        .strip_prefix("0b")
+        .or_else(|| digits.strip_prefix("0B"))
+    {
+        i64::from_str_radix(binary, 2).ok()?
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+// Names can't look like numbers or operators
+fn is_name(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && text
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::CPU;
+    use quirks::Quirks;
+
+    fn error(source: &str) -> (usize, usize, String) {
+        let err = compile(source).unwrap_err();
+        (err.line, err.column, err.message)
+    }
+
+    // Runs the program until it exits and returns the delay timer, which tests set as result
+    fn run(source: &str) -> u8 {
+        let mut cpu = CPU::new(Quirks::default());
+        cpu.load_rom(&compile(source).unwrap()).unwrap();
+        for _ in 0..1000 {
+            cpu.emulate_cycle().unwrap();
+            if cpu.has_exited() {
+                return cpu.delay_timer();
+            }
+        }
+        panic!("The program didn't exit");
+    }
+
+    #[test]
+    fn compiles_statements() {
+        let source = "
+            # Comments run to the end of the line
+            : main
+                clear
+                v0 := 5  v1 += 0x10  v2 := v0  v2 -= 1  v3 := random 0xF
+                i := digits  sprite v0 v1 5
+                i := long digits  save v0 - v3  load v2
+                delay := v0  buzzer := v1  v4 := key
+                hires scroll-down 4 plane 3 audio
+                return
+            : digits 0xF0 -1 :byte { 2 * 3 }
+        ";
+        assert_eq!(
+            compile(source).unwrap(),
+            [
+                0x00, 0xE0, 0x60, 0x05, 0x71, 0x10, 0x82, 0x00, 0x72, 0xFF, 0xC3, 0x0F, 0xA2, 0x28,
+                0xD0, 0x15, 0xF0, 0x00, 0x02, 0x28, 0x50, 0x32, 0xF2, 0x65, 0xF0, 0x15, 0xF1, 0x18,
+                0xF4, 0x0A, 0x00, 0xFF, 0x00, 0xC4, 0xF3, 0x01, 0xF0, 0x02, 0x00, 0xEE, 0xF0, 0xFF,
+                0x06,
+            ]
+        );
+    }
+
+    #[test]
+    fn main_after_subroutines_gets_a_jump() {
+        let source = ": double v0 += v0 ;  : main v0 := 1 double";
+        assert_eq!(
+            compile(source).unwrap(),
+            [0x12, 0x06, 0x80, 0x04, 0x00, 0xEE, 0x60, 0x01, 0x22, 0x02]
+        );
+        assert_eq!(
+            error(": other ;"),
+            (1, 10, "This program is missing a main label".to_string())
+        );
+    }
+
+    #[test]
+    fn forward_references() {
+        let source = ": main jump later :org 0x300 : later i := data sub exit : sub ; : data";
+        let rom = compile(source).unwrap();
+        assert_eq!(&rom[..2], [0x13, 0x00]);
+        assert_eq!(
+            &rom[0x100..],
+            [0xA3, 0x08, 0x23, 0x06, 0x00, 0xFD, 0x00, 0xEE]
+        );
+        assert_eq!(
+            error(": main jump nowhere"),
+            (1, 13, "Undefined name 'nowhere'".to_string())
+        );
+    }
+
+    #[test]
+    fn conditionals() {
+        assert_eq!(
+            run(": main v0 := 3 if v0 == 3 then v1 := 7 delay := v1 exit"),
+            7
+        );
+        assert_eq!(
+            run(": main v0 := 3 if v0 != 3 then v1 := 7 delay := v1 exit"),
+            0
+        );
+        let if_else = ": main v0 := 1 v1 := 2
+            if v0 == v1 begin v2 := 10 else v2 := 20 end delay := v2 exit";
+        assert_eq!(run(if_else), 20);
+    }
+
+    #[test]
+    fn comparisons() {
+        let cases = [
+            ("<", 3, 4, true),
+            ("<", 4, 4, false),
+            (">", 5, 4, true),
+            (">", 4, 4, false),
+            ("<=", 4, 4, true),
+            ("<=", 5, 4, false),
+            (">=", 4, 4, true),
+            (">=", 3, 4, false),
+        ];
+        for &(operator, a, b, expected) in cases.iter() {
+            for rhs in ["v1", "4"].iter() {
+                let rhs = if *rhs == "4" {
+                    b.to_string()
+                } else {
+                    rhs.to_string()
+                };
+                let source = format!(
+                    ": main v0 := {} v1 := {} v2 := 0 if v0 {} {} then v2 := 1 delay := v2 exit",
+                    a, b, operator, rhs
+                );
+                assert_eq!(run(&source) == 1, expected, "{}", source);
+            }
+        }
+    }
+
+    #[test]
+    fn loops() {
+        let source = ": main v0 := 0 v1 := 0
+            loop
+                v0 += 1
+                while v0 != 10
+                v1 += 2
+            again
+            delay := v1 exit";
+        assert_eq!(run(source), 18);
+    }
+
+    #[test]
+    fn aliases_constants_calc_and_macros() {
+        let source = "
+            :alias counter v3
+            :const STEP 2
+            :calc TOTAL { STEP * ( 3 + 1 ) }
+            :calc ONE { TOTAL - 7 }
+            :macro add-twice reg amount { reg += amount reg += amount }
+            : main
+                counter := 0
+                add-twice counter STEP
+                add-twice counter ONE
+                delay := counter exit
+        ";
+        assert_eq!(run(source), 6);
+    }
+
+    #[test]
+    fn calc_groups_to_the_right() {
+        assert_eq!(compile(": main :byte { 2 * 3 + 4 }").unwrap(), [14]);
+        assert_eq!(compile(": main :byte { ( 2 * 3 ) + 4 }").unwrap(), [10]);
+    }
+
+    #[test]
+    fn errors_point_at_the_problem() {
+        assert_eq!(
+            error(": main\n  v0 := 256"),
+            (2, 9, "256 doesn't fit into a byte".to_string())
+        );
+        assert_eq!(
+            error(": main v0 |= 3"),
+            (1, 14, "|= needs a register but got 3".to_string())
+        );
+        assert_eq!(
+            error(": main : main"),
+            (1, 10, "'main' is already defined".to_string())
+        );
+        assert_eq!(
+            error(": main if v0 == 1 begin"),
+            (1, 19, "This begin is missing its end".to_string())
+        );
+        assert_eq!(
+            error(": main again"),
+            (1, 8, "again without a loop".to_string())
+        );
+    }
+}
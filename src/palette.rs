@@ -0,0 +1,93 @@
+// Colours for the 4 pixel values of gfx, for frontends and exported images
+// Value 0 is unlit, 1 the first XO-CHIP bitplane, 2 the second and 3 both
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Palette {
+    pub colours: [[u8; 3]; 4],
+}
+
+// White on black with greys for the second bitplane, like the window
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette {
+            colours: [[0, 0, 0], [255, 255, 255], [170, 170, 170], [85, 85, 85]],
+        }
+    }
+}
+
+impl Palette {
+    // Looks up a preset by name: default, amber, green or lcd
+    pub fn from_name(name: &str) -> Option<Palette> {
+        let colours = match name {
+            "default" => return Some(Palette::default()),
+            // Monochrome monitors
+            "amber" => [
+                [0x1A, 0x0E, 0x00],
+                [0xFF, 0xB0, 0x00],
+                [0xB3, 0x6B, 0x00],
+                [0x66, 0x3D, 0x00],
+            ],
+            "green" => [
+                [0x00, 0x14, 0x00],
+                [0x33, 0xFF, 0x33],
+                [0x22, 0xAA, 0x22],
+                [0x11, 0x55, 0x11],
+            ],
+            // The greenish greys of an early handheld LCD
+            "lcd" => [
+                [0x9B, 0xBC, 0x0F],
+                [0x0F, 0x38, 0x0F],
+                [0x8B, 0xAC, 0x0F],
+                [0x30, 0x62, 0x30],
+            ],
+            _ => return None,
+        };
+        Some(Palette { colours })
+    }
+
+    // Reads a preset name or 2 to 4 comma separated hex colours like 000000,FFB000
+    // Colours that aren't given keep the ones of the default palette
+    pub fn parse(text: &str) -> Option<Palette> {
+        if let Some(palette) = Palette::from_name(text) {
+            return Some(palette);
+        }
+        let mut palette = Palette::default();
+        let colours = text.split(',').collect::<Vec<_>>();
+        if colours.len() < 2 || colours.len() > 4 {
+            return None;
+        }
+        for (colour, hex) in palette.colours.iter_mut().zip(colours) {
+            let hex = hex.trim().trim_start_matches('#');
+            let rgb = u32::from_str_radix(hex, 16)
+                .ok()
+                .filter(|_| hex.len() == 6)?;
+            *colour = [(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8];
+        }
+        Some(palette)
+    }
+
+    // The colour of a pixel value from gfx
+    pub fn colour(&self, pixel: u8) -> [u8; 3] {
+        self.colours[(pixel & 0b11) as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_presets_and_hex_colours() {
+        assert_eq!(Palette::parse("default"), Some(Palette::default()));
+        assert_eq!(Palette::parse("amber"), Palette::from_name("amber"));
+
+        let palette = Palette::parse("#102030, FFB000").unwrap();
+        assert_eq!(palette.colour(0), [0x10, 0x20, 0x30]);
+        assert_eq!(palette.colour(1), [0xFF, 0xB0, 0x00]);
+        assert_eq!(palette.colour(2), Palette::default().colour(2));
+
+        assert_eq!(Palette::parse("FFFFFF"), None);
+        assert_eq!(Palette::parse("FFF,000"), None);
+        assert_eq!(Palette::parse("chartreuse"), None);
+    }
+}
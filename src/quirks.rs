@@ -0,0 +1,109 @@
+// Behaviour differences between CHIP-8 interpreters
+// https://github.com/Timendus/chip8-test-suite#quirks-test
+
+// How FX55 and FX65 leave the index register behind
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum IndexIncrement {
+    // I is left unchanged (SUPER-CHIP)
+    #[default]
+    Unchanged,
+    // I is incremented by X (CHIP-48)
+    ByX,
+    // I points past the last register, i.e. is incremented by X + 1 (COSMAC VIP)
+    ByXPlusOne,
+}
+
+// The default has every quirk switched off, which is the behaviour most
+// documentation describes and most ROMs expect
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Quirks {
+    // 8XY6 and 8XYE shift VY and store the result in VX, instead of shifting VX in place
+    pub shift_uses_vy: bool,
+    // How FX55 and FX65 change I after the transfer
+    pub load_store_index: IndexIncrement,
+    // BNNN jumps to XNN + VX instead of NNN + V0
+    pub jump_uses_vx: bool,
+    // 8XY1, 8XY2 and 8XY3 reset VF to 0
+    pub logic_resets_vf: bool,
+    // Sprites are cut off at the screen edges instead of wrapping around
+    pub clip_sprites: bool,
+    // DXYN waits for the next vertical blank before drawing, limiting draws to 60 per second
+    pub display_wait: bool,
+    // Enables the XO-CHIP 64 KiB address space
+    pub xo_chip: bool,
+}
+
+impl Quirks {
+    // Looks up a preset by the name the command line and test files use for it
+    pub fn from_name(name: &str) -> Option<Quirks> {
+        match name {
+            "default" => Some(Quirks::default()),
+            "vip" | "cosmac-vip" => Some(Quirks::cosmac_vip()),
+            "chip48" => Some(Quirks::chip48()),
+            "superchip" | "schip" => Some(Quirks::superchip()),
+            "octo" => Some(Quirks::octo()),
+            "xo-chip" | "xochip" => Some(Quirks::xo_chip()),
+            _ => None,
+        }
+    }
+
+    // The original interpreter on the RCA COSMAC VIP
+    pub fn cosmac_vip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_index: IndexIncrement::ByXPlusOne,
+            jump_uses_vx: false,
+            logic_resets_vf: true,
+            clip_sprites: true,
+            display_wait: true,
+            xo_chip: false,
+        }
+    }
+
+    // CHIP-48 on the HP-48 calculators
+    pub fn chip48() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_index: IndexIncrement::ByX,
+            jump_uses_vx: true,
+            logic_resets_vf: false,
+            clip_sprites: true,
+            display_wait: false,
+            xo_chip: false,
+        }
+    }
+
+    // SUPER-CHIP 1.1
+    pub fn superchip() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_index: IndexIncrement::Unchanged,
+            jump_uses_vx: true,
+            logic_resets_vf: false,
+            clip_sprites: true,
+            display_wait: false,
+            xo_chip: false,
+        }
+    }
+
+    // Octo, the behaviour modern homebrew is written against
+    pub fn octo() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_index: IndexIncrement::ByXPlusOne,
+            jump_uses_vx: false,
+            logic_resets_vf: false,
+            clip_sprites: false,
+            display_wait: false,
+            xo_chip: false,
+        }
+    }
+
+    // XO-CHIP, Octo's extension with 64 KiB of memory, two bitplanes and pattern audio
+    pub fn xo_chip() -> Quirks {
+        Quirks {
+            xo_chip: true,
+            ..Quirks::octo()
+        }
+    }
+}
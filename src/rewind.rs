@@ -0,0 +1,271 @@
+// Ring buffer of CPU snapshots for stepping the game backwards
+//
+// Only the newest snapshot is kept in full. Every older one is stored as the difference
+// to its successor: the two snapshots are XORed, which leaves mostly zeros since memory
+// and gfx change little per frame, and the runs of zeros are then run-length encoded.
+// Stepping back undoes one difference at a time, so the cost doesn't grow with the depth.
+
+use cpu::CPU;
+use state::Snapshot;
+use std::collections::VecDeque;
+
+// Snapshots are taken at 60 Hz unless configured otherwise
+const FRAMES_PER_SECOND: usize = 60;
+
+enum Delta {
+    // Run-length encoded XOR against the next newer snapshot
+    Xor(Vec<u8>),
+    // The snapshot had a different size, e.g. after a resolution switch, and is stored as is
+    Full(Vec<u8>),
+}
+
+impl Delta {
+    fn len(&self) -> usize {
+        match self {
+            Delta::Xor(bytes) | Delta::Full(bytes) => bytes.len(),
+        }
+    }
+}
+
+pub struct RewindBuffer {
+    latest: Option<Snapshot>,
+    // Oldest difference at the front, the one leading back from latest at the back
+    deltas: VecDeque<Delta>,
+    // Number of frames between two snapshots
+    interval: usize,
+    frames_since_snapshot: usize,
+    frames_since_step_back: usize,
+    max_snapshots: usize,
+    max_bytes: usize,
+    delta_bytes: usize,
+}
+
+impl RewindBuffer {
+    // Keeps up to `seconds` of history, with a snapshot every `interval` frames,
+    // and never more than max_bytes of compressed differences
+    pub fn new(seconds: usize, interval: usize, max_bytes: usize) -> RewindBuffer {
+        let interval = interval.max(1);
+        RewindBuffer {
+            latest: None,
+            deltas: VecDeque::new(),
+            interval,
+            frames_since_snapshot: 0,
+            frames_since_step_back: 0,
+            max_snapshots: seconds * FRAMES_PER_SECOND / interval,
+            max_bytes,
+            delta_bytes: 0,
+        }
+    }
+
+    // Number of snapshots that can currently be stepped back to
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    // Memory used by the history, excluding the newest snapshot
+    pub fn compressed_bytes(&self) -> usize {
+        self.delta_bytes
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+        self.delta_bytes = 0;
+    }
+
+    // Called once per emulated frame, takes a snapshot every interval frames
+    pub fn record(&mut self, cpu: &CPU) {
+        self.frames_since_step_back = 0;
+        self.frames_since_snapshot += 1;
+        if self.latest.is_none() || self.frames_since_snapshot >= self.interval {
+            self.frames_since_snapshot = 0;
+            self.push(cpu.snapshot());
+        }
+    }
+
+    // Called once per frame while rewinding. Steps back one snapshot every interval frames,
+    // which plays the history backwards at the speed it was recorded.
+    // Returns false once the history is used up
+    pub fn rewind_frame(&mut self, cpu: &mut CPU) -> bool {
+        if self.frames_since_step_back.is_multiple_of(self.interval) && !self.step_back(cpu) {
+            return false;
+        }
+        self.frames_since_step_back += 1;
+        true
+    }
+
+    // Restores the snapshot before the newest one and drops the newest
+    pub fn step_back(&mut self, cpu: &mut CPU) -> bool {
+        let delta = match self.deltas.pop_back() {
+            Some(delta) => delta,
+            None => return false,
+        };
+        self.delta_bytes -= delta.len();
+        let latest = self.latest.take().expect("Deltas without a snapshot");
+        let previous = match delta {
+            Delta::Xor(encoded) => {
+                let mut bytes = latest.as_bytes().to_vec();
+                apply_xor(&mut bytes, &encoded);
+                bytes
+            }
+            Delta::Full(bytes) => bytes,
+        };
+        let previous = Snapshot::new(previous);
+        cpu.restore(&previous);
+        self.latest = Some(previous);
+        self.frames_since_snapshot = 0;
+        true
+    }
+
+    fn push(&mut self, snapshot: Snapshot) {
+        if let Some(latest) = self.latest.take() {
+            let delta = if latest.as_bytes().len() == snapshot.as_bytes().len() {
+                Delta::Xor(encode_xor(latest.as_bytes(), snapshot.as_bytes()))
+            } else {
+                Delta::Full(latest.as_bytes().to_vec())
+            };
+            self.delta_bytes += delta.len();
+            self.deltas.push_back(delta);
+        }
+        self.latest = Some(snapshot);
+
+        while self.deltas.len() > self.max_snapshots || self.delta_bytes > self.max_bytes {
+            match self.deltas.pop_front() {
+                Some(oldest) => self.delta_bytes -= oldest.len(),
+                None => break,
+            }
+        }
+    }
+}
+
+// Encodes old XOR new as pairs of (zero run length, literal length, literal bytes),
+// both lengths as LEB128 varints
+fn encode_xor(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    let mut position = 0;
+    while position < old.len() {
+        let zeros_start = position;
+        while position < old.len() && old[position] == new[position] {
+            position += 1;
+        }
+        let literal_start = position;
+        while position < old.len() && old[position] != new[position] {
+            position += 1;
+        }
+        write_varint(&mut encoded, literal_start - zeros_start);
+        write_varint(&mut encoded, position - literal_start);
+        for i in literal_start..position {
+            encoded.push(old[i] ^ new[i]);
+        }
+    }
+    encoded
+}
+
+// XORs the encoded difference back into bytes
+fn apply_xor(bytes: &mut [u8], encoded: &[u8]) {
+    let mut position = 0;
+    let mut cursor = 0;
+    while cursor < encoded.len() {
+        position += read_varint(encoded, &mut cursor);
+        let literal_len = read_varint(encoded, &mut cursor);
+        for _ in 0..literal_len {
+            bytes[position] ^= encoded[cursor];
+            position += 1;
+            cursor += 1;
+        }
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn read_varint(bytes: &[u8], cursor: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[*cursor];
+        *cursor += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quirks::Quirks;
+
+    // Adds 1 to V0 once per frame and stores it at 0x300
+    fn counter_cpu() -> CPU {
+        let rom = [0x70, 0x01, 0xA3, 0x00, 0xF0, 0x55, 0x12, 0x00];
+        let mut cpu = CPU::new(Quirks::default());
+        cpu.load_rom(&rom).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn xor_encoding_round_trip() {
+        let old = vec![1, 2, 3, 4, 5, 6, 7, 8, 0, 0, 0, 200];
+        let new = vec![1, 2, 9, 4, 5, 6, 7, 1, 2, 0, 0, 0];
+        let encoded = encode_xor(&old, &new);
+        let mut restored = new.clone();
+        apply_xor(&mut restored, &encoded);
+        assert_eq!(restored, old);
+    }
+
+    #[test]
+    fn rewinds_to_earlier_frames() {
+        let mut cpu = counter_cpu();
+        let mut buffer = RewindBuffer::new(10, 1, 1 << 20);
+        let mut snapshots = Vec::new();
+        for _ in 0..5 {
+            cpu.run_frame(4).unwrap();
+            buffer.record(&cpu);
+            snapshots.push(cpu.snapshot());
+        }
+        assert_eq!(buffer.len(), 4);
+        // A frame changes only a handful of bytes
+        assert!(buffer.compressed_bytes() < 100);
+
+        for expected in snapshots.iter().rev().skip(1) {
+            assert!(buffer.step_back(&mut cpu));
+            assert_eq!(&cpu.snapshot(), expected);
+        }
+        assert!(!buffer.step_back(&mut cpu));
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let mut cpu = counter_cpu();
+        let mut buffer = RewindBuffer::new(1, 2, 1 << 20);
+        for _ in 0..200 {
+            cpu.run_frame(4).unwrap();
+            buffer.record(&cpu);
+        }
+        assert_eq!(buffer.len(), 30);
+
+        let mut buffer = RewindBuffer::new(10, 1, 64);
+        for _ in 0..200 {
+            cpu.run_frame(4).unwrap();
+            buffer.record(&cpu);
+        }
+        assert!(buffer.compressed_bytes() <= 64);
+        assert!(!buffer.is_empty());
+    }
+}
@@ -0,0 +1,270 @@
+// Settings for known ROMs, looked up by the SHA-1 of their bytes
+//
+// The database is a programs.json in the format of the community CHIP-8 database
+// (https://github.com/chip-8/chip-8-database): an array of programs with a title and their
+// ROMs by hash, each with the platforms it runs on, the instructions per frame as tickrate,
+// quirks differing from the platform's in quirkyPlatforms, colors and the keys the game uses
+//
+//     [{"title": "Pong", "roms": {"<sha1>": {
+//         "platforms": ["superchip"], "tickrate": 30,
+//         "quirkyPlatforms": {"superchip": {"wrap": true}},
+//         "colors": {"pixels": ["#000000", "#ffb000"]},
+//         "keys": {"up": 1, "down": 4}}}}]
+
+use error::ConfigError;
+use json::{self, Json};
+use palette::Palette;
+use quirks::{IndexIncrement, Quirks};
+use sha1;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// The ROMs of this repository, see data/programs.json
+static BUNDLED: &str = include_str!("../data/programs.json");
+
+// What the database knows about a ROM
+#[derive(Debug, Clone, PartialEq)]
+pub struct RomInfo {
+    pub title: String,
+    // The first of its platforms the emulator supports, by the database's name
+    pub platform: String,
+    pub quirks: Quirks,
+    pub cycles_per_frame: Option<usize>,
+    pub palette: Option<Palette>,
+    // The CHIP-8 keys of the game's controls, by the database's names for them like up,
+    // down, left, right, a and b
+    pub keys: Vec<(String, usize)>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RomDatabase {
+    // By lowercase hex SHA-1
+    roms: HashMap<String, RomInfo>,
+}
+
+impl RomDatabase {
+    // The database that comes with the emulator
+    pub fn bundled() -> RomDatabase {
+        RomDatabase::parse("data/programs.json", BUNDLED).expect("bundled database is valid")
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<RomDatabase, ConfigError> {
+        let file = path.as_ref().display().to_string();
+        match fs::read_to_string(path) {
+            Ok(text) => RomDatabase::parse(&file, &text),
+            Err(err) => Err(ConfigError {
+                file,
+                line: 0,
+                message: err.to_string(),
+            }),
+        }
+    }
+
+    // Reads a programs.json, ROMs only for platforms the emulator doesn't support are left out
+    pub fn parse(file: &str, text: &str) -> Result<RomDatabase, ConfigError> {
+        let error = |message: String| ConfigError {
+            file: file.to_string(),
+            line: 0,
+            message,
+        };
+        let programs = json::parse(file, text)?;
+        let programs = programs
+            .as_array()
+            .ok_or_else(|| error("Expected an array of programs".to_string()))?;
+        let mut roms = HashMap::new();
+        for program in programs {
+            let title = program.get("title").and_then(Json::as_str).unwrap_or("");
+            let program_roms = program.get("roms").and_then(Json::as_object);
+            for (hash, rom) in program_roms.unwrap_or_default() {
+                let info = parse_rom(title, rom)
+                    .map_err(|message| error(format!("{} ({}): {}", title, hash, message)))?;
+                if let Some(info) = info {
+                    roms.insert(hash.to_lowercase(), info);
+                }
+            }
+        }
+        Ok(RomDatabase { roms })
+    }
+
+    // Adds the ROMs of the other database, replacing the ones both have
+    pub fn extend(&mut self, other: RomDatabase) {
+        self.roms.extend(other.roms);
+    }
+
+    pub fn len(&self) -> usize {
+        self.roms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.roms.is_empty()
+    }
+
+    // Looks the ROM up by the SHA-1 of its bytes
+    pub fn lookup(&self, rom: &[u8]) -> Option<&RomInfo> {
+        self.get(&sha1::sha1_hex(rom))
+    }
+
+    // Looks a ROM up by its SHA-1 in hex
+    pub fn get(&self, sha1: &str) -> Option<&RomInfo> {
+        self.roms.get(&sha1.to_lowercase())
+    }
+}
+
+// None if the ROM has no platform the emulator supports
+fn parse_rom(title: &str, rom: &Json) -> Result<Option<RomInfo>, String> {
+    let platforms = rom
+        .get("platforms")
+        .and_then(Json::as_array)
+        .ok_or("No platforms")?;
+    let platform = platforms
+        .iter()
+        .filter_map(Json::as_str)
+        .find_map(|name| platform_quirks(name).map(|quirks| (name, quirks)));
+    let (platform, mut quirks) = match platform {
+        Some(platform) => platform,
+        None => return Ok(None),
+    };
+    let quirky = rom
+        .get("quirkyPlatforms")
+        .and_then(|quirky| quirky.get(platform));
+    for (name, value) in quirky.and_then(Json::as_object).unwrap_or_default() {
+        let value = value.as_bool().ok_or("Quirks have to be true or false")?;
+        apply_quirk(&mut quirks, name, value);
+    }
+
+    let cycles_per_frame = match rom.get("tickrate") {
+        Some(tickrate) => Some(
+            tickrate
+                .as_f64()
+                .filter(|&tickrate| tickrate >= 1.0)
+                .ok_or("The tickrate has to be a positive number")? as usize,
+        ),
+        None => None,
+    };
+    let pixels = rom.get("colors").and_then(|colors| colors.get("pixels"));
+    let palette = match pixels.and_then(Json::as_array) {
+        Some(pixels) => {
+            let colours = pixels.iter().filter_map(Json::as_str).collect::<Vec<_>>();
+            // Palettes with more than 4 colours are for MEGA-CHIP, only the first ones are used
+            let colours = colours[..colours.len().min(4)].join(",");
+            Some(Palette::parse(&colours).ok_or("Invalid colors")?)
+        }
+        None => None,
+    };
+    let mut keys = Vec::new();
+    for (name, key) in rom
+        .get("keys")
+        .and_then(Json::as_object)
+        .unwrap_or_default()
+    {
+        let key = key
+            .as_f64()
+            .filter(|key| (0.0..16.0).contains(key))
+            .ok_or("Keys have to be between 0 and 15")?;
+        keys.push((name.clone(), key as usize));
+    }
+    Ok(Some(RomInfo {
+        title: title.to_string(),
+        platform: platform.to_string(),
+        quirks,
+        cycles_per_frame,
+        palette,
+        keys,
+    }))
+}
+
+// The quirks of the database's platforms, None for the ones the emulator doesn't support
+fn platform_quirks(platform: &str) -> Option<Quirks> {
+    match platform {
+        "originalChip8" | "hybridVIP" => Some(Quirks::cosmac_vip()),
+        "modernChip8" => Some(Quirks::default()),
+        "chip48" => Some(Quirks::chip48()),
+        "superchip1" | "superchip" => Some(Quirks::superchip()),
+        "xochip" => Some(Quirks::xo_chip()),
+        _ => None,
+    }
+}
+
+// The database names a quirk true when the platform behaves unlike the original interpreter
+// Quirks the emulator doesn't know are skipped, the format gains new ones over time
+fn apply_quirk(quirks: &mut Quirks, name: &str, value: bool) {
+    match name {
+        "shift" => quirks.shift_uses_vy = !value,
+        "memoryIncrementByX" if value => quirks.load_store_index = IndexIncrement::ByX,
+        "memoryLeaveIUnchanged" if value => {
+            quirks.load_store_index = IndexIncrement::Unchanged;
+        }
+        "memoryIncrementByX" | "memoryLeaveIUnchanged" => {
+            quirks.load_store_index = IndexIncrement::ByXPlusOne;
+        }
+        "wrap" => quirks.clip_sprites = !value,
+        "jump" => quirks.jump_uses_vx = value,
+        "vblank" => quirks.display_wait = value,
+        "logic" => quirks.logic_resets_vf = value,
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use octo;
+
+    #[test]
+    fn roms_are_found_by_hash_with_their_settings() {
+        let text = r##"[
+            {"title": "Beeps", "roms": {
+                "A9993E364706816ABA3E25717850C26C9CD0D89D": {
+                    "platforms": ["megachip8", "superchip"],
+                    "tickrate": 30,
+                    "quirkyPlatforms": {"superchip": {
+                        "wrap": true, "memoryIncrementByX": true, "futureQuirk": true
+                    }},
+                    "colors": {"pixels": ["#000000", "#ffb000"], "buzzer": "#ffffff"},
+                    "keys": {"up": 5, "a": 6}
+                },
+                "da39a3ee5e6b4b0d3255bfef95601890afd80709": {"platforms": ["megachip8"]}
+            }}
+        ]"##;
+        let database = RomDatabase::parse("programs.json", text).unwrap();
+        assert_eq!(database.len(), 1);
+        let info = database.lookup(b"abc").unwrap();
+        assert_eq!(info.title, "Beeps");
+        assert_eq!(info.platform, "superchip");
+        assert_eq!(info.cycles_per_frame, Some(30));
+        assert!(!info.quirks.clip_sprites);
+        assert_eq!(info.quirks.load_store_index, IndexIncrement::ByX);
+        assert_eq!(info.quirks.jump_uses_vx, Quirks::superchip().jump_uses_vx);
+        assert_eq!(info.palette.unwrap().colour(1), [0xFF, 0xB0, 0x00]);
+        assert_eq!(info.keys, [("up".to_string(), 5), ("a".to_string(), 6)]);
+        assert!(database.lookup(b"").is_none());
+
+        let text =
+            r#"[{"title": "Bad", "roms": {"00": {"platforms": ["xochip"], "tickrate": 0}}}]"#;
+        let err = RomDatabase::parse("programs.json", text).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "programs.json: Bad (00): The tickrate has to be a positive number"
+        );
+    }
+
+    #[test]
+    fn bundled_database_knows_the_golden_roms() {
+        let database = RomDatabase::bundled();
+        assert_eq!(database.len(), 5);
+        let planes = database
+            .get("FABA8D626762DEA18F787F19EEC7E4BAB4A27254")
+            .unwrap();
+        assert_eq!(planes.platform, "xochip");
+        // The entries have to follow the golden sources when they change
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+        for (source, title) in [
+            ("font.8o", "Golden test: font"),
+            ("planes.8o", "Golden test: bitplanes"),
+        ] {
+            let rom = octo::compile_file(&dir.join(source)).unwrap();
+            assert_eq!(database.lookup(&rom).unwrap().title, title);
+        }
+    }
+}
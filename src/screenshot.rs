@@ -0,0 +1,82 @@
+// Exports frames as PNG images, see Emulator::screenshot
+
+use palette::Palette;
+use png::{self, BitDepth, ColorType, HasParameters};
+use std::io::{self, Write};
+
+// Writes gfx as an indexed PNG, every pixel scaled up to a square of scale x scale
+pub fn write_png<W: Write>(
+    out: W,
+    gfx: &[u8],
+    width: usize,
+    height: usize,
+    scale: usize,
+    palette: &Palette,
+) -> io::Result<()> {
+    if scale == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The scale must be at least 1",
+        ));
+    }
+    let mut encoder = png::Encoder::new(out, (width * scale) as u32, (height * scale) as u32);
+    encoder.set(ColorType::Indexed).set(BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    let colours = palette.colours.concat();
+    writer.write_chunk(*b"PLTE", &colours)?;
+
+    // The palette index of a pixel is its value
+    let data = scale_pixels(gfx, width, scale);
+    writer.write_image_data(&data)?;
+    Ok(())
+}
+
+// Turns every pixel of gfx into a square of scale x scale pixels, masked to the 4 colours
+pub(crate) fn scale_pixels(gfx: &[u8], width: usize, scale: usize) -> Vec<u8> {
+    let mut scaled = Vec::with_capacity(gfx.len() * scale * scale);
+    for row in gfx.chunks(width) {
+        let start = scaled.len();
+        for &pixel in row {
+            scaled.extend(std::iter::repeat_n(pixel & 0b11, scale));
+        }
+        for _ in 1..scale {
+            scaled.extend_from_within(start..start + width * scale);
+        }
+    }
+    scaled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use png::Decoder;
+
+    #[test]
+    fn pixels_are_scaled_and_indexed() {
+        let gfx = [0, 1, 2, 3, 1, 0];
+        let palette = Palette::parse("010203,040506,070809,0A0B0C").unwrap();
+        let mut image = Vec::new();
+        write_png(&mut image, &gfx, 3, 2, 2, &palette).unwrap();
+
+        // The decoder expands the palette indices to their colours
+        let (info, mut reader) = Decoder::new(&image[..]).read_info().unwrap();
+        assert_eq!((info.width, info.height), (6, 4));
+        let mut pixels = vec![0; info.buffer_size()];
+        reader.next_frame(&mut pixels).unwrap();
+        let indices = pixels
+            .chunks(3)
+            .map(|rgb| palette.colours.iter().position(|c| c == rgb).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            indices,
+            [
+                0, 0, 1, 1, 2, 2, //
+                0, 0, 1, 1, 2, 2, //
+                3, 3, 1, 1, 0, 0, //
+                3, 3, 1, 1, 0, 0,
+            ]
+        );
+
+        assert!(write_png(Vec::new(), &gfx, 3, 2, 0, &palette).is_err());
+    }
+}
@@ -0,0 +1,240 @@
+// Binary container for save states
+//
+// Header, all numbers little endian
+// 0x00 - 8 byte magic "RCHIPSAV"
+// 0x08 - u16 format version
+// 0x0A - u32 payload length
+// 0x0E - u32 CRC-32 of the payload
+// 0x12 - Payload, written field by field by CPU::save_state
+
+use error::StateError;
+use quirks::{IndexIncrement, Quirks};
+
+const MAGIC: &[u8; 8] = b"RCHIPSAV";
+pub const STATE_VERSION: u16 = 3;
+// Version 1 lacked the random number generator state at the end, version 2 whether an
+// audio pattern was loaded
+const OLDEST_STATE_VERSION: u16 = 1;
+const HEADER_LEN: usize = 18;
+
+// The fields of a CPU at one point in time, see CPU::snapshot
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    payload: Vec<u8>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(payload: Vec<u8>) -> Snapshot {
+        Snapshot { payload }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.payload
+    }
+}
+
+pub(crate) struct StateWriter {
+    payload: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter {
+            payload: Vec::new(),
+        }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.payload.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.payload.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.payload.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.payload.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // Length prefixed, so the reader can check it against the expected size
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.payload.extend_from_slice(bytes);
+    }
+
+    pub fn quirks(&mut self, quirks: &Quirks) {
+        self.bool(quirks.shift_uses_vy);
+        self.u8(match quirks.load_store_index {
+            IndexIncrement::Unchanged => 0,
+            IndexIncrement::ByX => 1,
+            IndexIncrement::ByXPlusOne => 2,
+        });
+        self.bool(quirks.jump_uses_vx);
+        self.bool(quirks.logic_resets_vf);
+        self.bool(quirks.clip_sprites);
+        self.bool(quirks.display_wait);
+        self.bool(quirks.xo_chip);
+    }
+
+    pub fn into_payload(self) -> Vec<u8> {
+        self.payload
+    }
+
+    // Prepends the header to the payload
+    pub fn finish(self) -> Vec<u8> {
+        let mut state = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        state.extend_from_slice(MAGIC);
+        state.extend_from_slice(&STATE_VERSION.to_le_bytes());
+        state.extend_from_slice(&(self.payload.len() as u32).to_le_bytes());
+        state.extend_from_slice(&crc32(&self.payload).to_le_bytes());
+        state.extend_from_slice(&self.payload);
+        state
+    }
+}
+
+pub(crate) struct StateReader<'a> {
+    payload: &'a [u8],
+    position: usize,
+    version: u16,
+}
+
+impl<'a> StateReader<'a> {
+    // Validates the header and checksum before any field is read
+    pub fn new(state: &'a [u8]) -> Result<StateReader<'a>, StateError> {
+        if state.len() < HEADER_LEN {
+            return Err(StateError::Truncated);
+        }
+        if &state[..8] != MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        let version = u16::from_le_bytes([state[8], state[9]]);
+        if !(OLDEST_STATE_VERSION..=STATE_VERSION).contains(&version) {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        let len = u32::from_le_bytes([state[10], state[11], state[12], state[13]]) as usize;
+        let checksum = u32::from_le_bytes([state[14], state[15], state[16], state[17]]);
+        let payload = &state[HEADER_LEN..];
+        if payload.len() != len {
+            return Err(StateError::Truncated);
+        }
+        if crc32(payload) != checksum {
+            return Err(StateError::ChecksumMismatch);
+        }
+        Ok(StateReader {
+            payload,
+            position: 0,
+            version,
+        })
+    }
+
+    // Reads fields that were written without a header, e.g. by CPU::snapshot
+    pub fn from_payload(payload: &'a [u8]) -> StateReader<'a> {
+        StateReader {
+            payload,
+            position: 0,
+            version: STATE_VERSION,
+        }
+    }
+
+    // The format version the state was written with
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.position + len > self.payload.len() {
+            return Err(StateError::Truncated);
+        }
+        let bytes = &self.payload[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Invalid("boolean")),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn bytes(&mut self) -> Result<Vec<u8>, StateError> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    // Like bytes, but fails unless exactly len bytes were written
+    pub fn exact_bytes(&mut self, len: usize, field: &'static str) -> Result<Vec<u8>, StateError> {
+        let bytes = self.bytes()?;
+        if bytes.len() != len {
+            return Err(StateError::Invalid(field));
+        }
+        Ok(bytes)
+    }
+
+    pub fn quirks(&mut self) -> Result<Quirks, StateError> {
+        Ok(Quirks {
+            shift_uses_vy: self.bool()?,
+            load_store_index: match self.u8()? {
+                0 => IndexIncrement::Unchanged,
+                1 => IndexIncrement::ByX,
+                2 => IndexIncrement::ByXPlusOne,
+                _ => return Err(StateError::Invalid("load_store_index")),
+            },
+            jump_uses_vx: self.bool()?,
+            logic_resets_vf: self.bool()?,
+            clip_sprites: self.bool()?,
+            display_wait: self.bool()?,
+            xo_chip: self.bool()?,
+        })
+    }
+
+    // Fails if there are bytes left that no field accounted for
+    pub fn finish(self) -> Result<(), StateError> {
+        if self.position != self.payload.len() {
+            return Err(StateError::Invalid("trailing data"));
+        }
+        Ok(())
+    }
+}
+
+// CRC-32 as used by zip and PNG (reflected, polynomial 0xEDB88320)
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
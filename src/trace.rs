@@ -0,0 +1,527 @@
+// Instruction trace, one record per executed instruction
+//
+// A record holds the cycle number, PC, raw opcode and what the instruction changed:
+// registers including VF, I, the timers and the bytes it wrote to memory.
+//
+// The text format has one line per record. The binary format starts with the magic
+// "RCHIPTRC" and a u16 version, followed by the records, all numbers little endian:
+// - cycle as a LEB128 varint, the difference to the previous record
+// - u16 PC, u16 opcode
+// - u16 bitmask of changed V registers, then the new value of each as u8
+// - u8 flags: 1 I changed, 2 DT changed, 4 ST changed, 8 memory written
+// - the new I as u16, DT as u8, ST as u8 if flagged
+// - for a memory write the u16 address, u8 length and the bytes written
+
+use cpu::{Opcode, StepOutcome, CPU};
+use disasm;
+use error::CpuError;
+use std::fmt;
+use std::io::{self, BufWriter, ErrorKind, Read, Write};
+
+const MAGIC: &[u8; 8] = b"RCHIPTRC";
+const TRACE_VERSION: u16 = 1;
+
+const FLAG_INDEX: u8 = 1;
+const FLAG_DELAY_TIMER: u8 = 2;
+const FLAG_SOUND_TIMER: u8 = 4;
+const FLAG_MEMORY: u8 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    Text,
+    Binary,
+}
+
+// Groups of instructions a trace can be limited to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpcodeClass {
+    // Jumps, calls, returns, skips and exit
+    Flow,
+    // 6XNN, 7XNN, 8XYN and CXNN
+    Arithmetic,
+    // Everything that sets I or moves data between registers and memory
+    Memory,
+    // Clearing, drawing, scrolling, resolution and plane selection
+    Display,
+    Timer,
+    Input,
+    Audio,
+}
+
+impl OpcodeClass {
+    pub fn of(opcode: Opcode) -> OpcodeClass {
+        use cpu::Opcode::*;
+        match opcode {
+            Ignore | Return | Jump(_) | CallSubroutine(_) | Flow(_) | Exit => OpcodeClass::Flow,
+            SkipIfEqualAddress(..)
+            | SkipIfNotEqualAddress(..)
+            | SkipIfEqualRegister(..)
+            | SkipIfUnequalRegisters(..) => OpcodeClass::Flow,
+            SetRegister(..)
+            | AddAddressToRegister(..)
+            | Assign(..)
+            | AssignOr(..)
+            | AssignAnd(..)
+            | AssignXor(..)
+            | Add(..)
+            | Subtract(..)
+            | LeastSigStoreAndShift(..)
+            | SetSubtract(..)
+            | MostSigStoreAndShift(..)
+            | Rand(..) => OpcodeClass::Arithmetic,
+            ClearScreen | Display(..) | ScrollDown(_) | ScrollRight | ScrollLeft
+            | LowResolution | HighResolution | SelectPlanes(_) => OpcodeClass::Display,
+            GetDelayTimer(_) | SetDelayTimer(_) | SetSoundTimer(_) => OpcodeClass::Timer,
+            SkipIfKeyPressed(_) | SkipIfNotKeyPressed(_) | AwaitKeyPress(_) => OpcodeClass::Input,
+            LoadAudioPattern | SetPitch(_) => OpcodeClass::Audio,
+            _ => OpcodeClass::Memory,
+        }
+    }
+
+    // The lowercase name, as used on the command line
+    pub fn from_name(name: &str) -> Option<OpcodeClass> {
+        match name {
+            "flow" => Some(OpcodeClass::Flow),
+            "arithmetic" => Some(OpcodeClass::Arithmetic),
+            "memory" => Some(OpcodeClass::Memory),
+            "display" => Some(OpcodeClass::Display),
+            "timer" => Some(OpcodeClass::Timer),
+            "input" => Some(OpcodeClass::Input),
+            "audio" => Some(OpcodeClass::Audio),
+            _ => None,
+        }
+    }
+}
+
+// Which instructions get a record, everything by default
+// Instructions that are filtered out still count towards the cycle number
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TraceFilter {
+    // Inclusive range of PCs
+    pub pc_range: Option<(usize, usize)>,
+    // Only these classes if not empty
+    pub classes: Vec<OpcodeClass>,
+}
+
+impl TraceFilter {
+    fn matches(&self, pc: usize, opcode: Opcode) -> bool {
+        self.pc_range
+            .is_none_or(|(start, end)| start <= pc && pc <= end)
+            && (self.classes.is_empty() || self.classes.contains(&OpcodeClass::of(opcode)))
+    }
+}
+
+// What a single instruction did
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceRecord {
+    // Number of instructions executed before this one
+    pub cycle: u64,
+    pub pc: u16,
+    pub opcode: u16,
+    // Register number and new value of every register that changed
+    pub registers: Vec<(u8, u8)>,
+    pub index_register: Option<u16>,
+    pub delay_timer: Option<u8>,
+    pub sound_timer: Option<u8>,
+    // Address and bytes of what the instruction stored in memory
+    pub memory_write: Option<(u16, Vec<u8>)>,
+}
+
+// e.g. "      1042 20C F155 LD [I], V1       I=0302 [300]=01 04", the same mnemonics as
+// the disassembler
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The address of F000 NNNN only shows up as the new I
+        let decoded = disasm::mnemonic(CPU::decode(self.opcode), self.index_register);
+        let mut line = format!(
+            "{:>10} {:03X} {:04X} {:<17}",
+            self.cycle, self.pc, self.opcode, decoded
+        );
+        for &(x, value) in &self.registers {
+            line.push_str(&format!(" V{:X}={:02X}", x, value));
+        }
+        if let Some(index) = self.index_register {
+            line.push_str(&format!(" I={:04X}", index));
+        }
+        if let Some(delay_timer) = self.delay_timer {
+            line.push_str(&format!(" DT={:02X}", delay_timer));
+        }
+        if let Some(sound_timer) = self.sound_timer {
+            line.push_str(&format!(" ST={:02X}", sound_timer));
+        }
+        if let Some((address, ref bytes)) = self.memory_write {
+            line.push_str(&format!(" [{:03X}]=", address));
+            let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            line.push_str(&bytes.join(" "));
+        }
+        // Instructions without changes aren't padded for the columns that don't follow
+        f.write_str(line.trim_end())
+    }
+}
+
+// Executes instructions and writes a record for each one that passes the filter
+pub struct Tracer {
+    writer: BufWriter<Box<dyn Write>>,
+    format: TraceFormat,
+    filter: TraceFilter,
+    cycle: u64,
+    last_written_cycle: u64,
+    // Tracing stops at the first failed write, finish reports it
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new(writer: Box<dyn Write>, format: TraceFormat, filter: TraceFilter) -> Tracer {
+        let mut tracer = Tracer {
+            writer: BufWriter::new(writer),
+            format,
+            filter,
+            cycle: 0,
+            last_written_cycle: 0,
+            error: None,
+        };
+        if format == TraceFormat::Binary {
+            let mut header = MAGIC.to_vec();
+            header.extend_from_slice(&TRACE_VERSION.to_le_bytes());
+            tracer.write(&header);
+        }
+        tracer
+    }
+
+    // Executes one instruction like CPU::emulate_cycle and records it
+    // Instructions that fail or wait for a key aren't recorded
+    pub fn step(&mut self, cpu: &mut CPU) -> Result<StepOutcome, CpuError> {
+        let pc = cpu.program_counter();
+        let opcode = match cpu.next_opcode() {
+            Some(opcode) => opcode,
+            None => return cpu.emulate_cycle(),
+        };
+        if cpu.has_exited() || !self.filter.matches(pc, opcode) {
+            let outcome = cpu.emulate_cycle()?;
+            self.cycle += 1;
+            return Ok(outcome);
+        }
+
+        let registers = cpu.registers().to_vec();
+        let index_register = cpu.index_register();
+        let (delay_timer, sound_timer) = (cpu.delay_timer(), cpu.sound_timer());
+        let write = cpu.next_memory_access().filter(|access| access.write);
+        let raw = (cpu.memory()[pc] as u16) << 8 | cpu.memory()[pc + 1] as u16;
+
+        let outcome = cpu.emulate_cycle()?;
+        if outcome != StepOutcome::Waiting {
+            let record = TraceRecord {
+                cycle: self.cycle,
+                pc: pc as u16,
+                opcode: raw,
+                registers: (0..16)
+                    .filter(|x| registers[*x] != cpu.registers()[*x])
+                    .map(|x| (x as u8, cpu.registers()[x]))
+                    .collect(),
+                index_register: changed(index_register, cpu.index_register()),
+                delay_timer: changed(delay_timer, cpu.delay_timer()),
+                sound_timer: changed(sound_timer, cpu.sound_timer()),
+                memory_write: write.map(|access| {
+                    let bytes = &cpu.memory()[access.address..access.address + access.len];
+                    (access.address as u16, bytes.to_vec())
+                }),
+            };
+            self.record(&record);
+        }
+        self.cycle += 1;
+        Ok(outcome)
+    }
+
+    // Flushes the trace and returns the first error that happened while writing it
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        self.writer.flush()
+    }
+
+    fn record(&mut self, record: &TraceRecord) {
+        match self.format {
+            TraceFormat::Text => {
+                let line = format!("{}\n", record);
+                self.write(line.as_bytes());
+            }
+            TraceFormat::Binary => {
+                let bytes = encode(record, self.last_written_cycle);
+                self.write(&bytes);
+            }
+        }
+        self.last_written_cycle = record.cycle;
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        if self.error.is_none() {
+            if let Err(err) = self.writer.write_all(bytes) {
+                self.error = Some(err);
+            }
+        }
+    }
+}
+
+// The new value if it differs from the old one
+fn changed<T: PartialEq>(old: T, new: T) -> Option<T> {
+    if old != new {
+        Some(new)
+    } else {
+        None
+    }
+}
+
+fn encode(record: &TraceRecord, previous_cycle: u64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    write_varint(&mut bytes, record.cycle - previous_cycle);
+    bytes.extend_from_slice(&record.pc.to_le_bytes());
+    bytes.extend_from_slice(&record.opcode.to_le_bytes());
+    let mask = record
+        .registers
+        .iter()
+        .fold(0u16, |mask, &(x, _)| mask | 1 << x);
+    bytes.extend_from_slice(&mask.to_le_bytes());
+    bytes.extend(record.registers.iter().map(|&(_, value)| value));
+
+    let mut flags = 0;
+    if record.index_register.is_some() {
+        flags |= FLAG_INDEX;
+    }
+    if record.delay_timer.is_some() {
+        flags |= FLAG_DELAY_TIMER;
+    }
+    if record.sound_timer.is_some() {
+        flags |= FLAG_SOUND_TIMER;
+    }
+    if record.memory_write.is_some() {
+        flags |= FLAG_MEMORY;
+    }
+    bytes.push(flags);
+    if let Some(index) = record.index_register {
+        bytes.extend_from_slice(&index.to_le_bytes());
+    }
+    bytes.extend(record.delay_timer);
+    bytes.extend(record.sound_timer);
+    if let Some((address, ref written)) = record.memory_write {
+        bytes.extend_from_slice(&address.to_le_bytes());
+        // At most 16 registers are stored at once
+        bytes.push(written.len() as u8);
+        bytes.extend_from_slice(written);
+    }
+    bytes
+}
+
+// Reads the records of a binary trace one by one, so long traces don't have to fit in memory
+pub struct TraceReader<R: Read> {
+    reader: R,
+    cycle: u64,
+}
+
+impl<R: Read> TraceReader<R> {
+    // Fails unless the trace starts with the expected header
+    pub fn new(mut reader: R) -> io::Result<TraceReader<R>> {
+        let mut header = [0; 10];
+        reader.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Not a binary trace"));
+        }
+        let version = u16::from_le_bytes([header[8], header[9]]);
+        if version != TRACE_VERSION {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported trace version {}", version),
+            ));
+        }
+        Ok(TraceReader { reader, cycle: 0 })
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        self.reader.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let mut bytes = [0; 2];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    // None at the end of the trace
+    fn read_record(&mut self) -> io::Result<Option<TraceRecord>> {
+        let mut first = [0];
+        if self.reader.read(&mut first)? == 0 {
+            return Ok(None);
+        }
+        let mut delta = (first[0] & 0x7F) as u64;
+        let mut shift = 7;
+        let mut byte = first[0];
+        while byte & 0x80 != 0 {
+            byte = self.u8()?;
+            delta |= ((byte & 0x7F) as u64) << shift;
+            shift += 7;
+        }
+        self.cycle += delta;
+
+        let pc = self.u16()?;
+        let opcode = self.u16()?;
+        let mask = self.u16()?;
+        let mut registers = Vec::new();
+        for x in 0..16 {
+            if mask & 1 << x != 0 {
+                registers.push((x as u8, self.u8()?));
+            }
+        }
+        let flags = self.u8()?;
+        let index_register = if flags & FLAG_INDEX != 0 {
+            Some(self.u16()?)
+        } else {
+            None
+        };
+        let delay_timer = if flags & FLAG_DELAY_TIMER != 0 {
+            Some(self.u8()?)
+        } else {
+            None
+        };
+        let sound_timer = if flags & FLAG_SOUND_TIMER != 0 {
+            Some(self.u8()?)
+        } else {
+            None
+        };
+        let memory_write = if flags & FLAG_MEMORY != 0 {
+            let address = self.u16()?;
+            let mut written = vec![0; self.u8()? as usize];
+            self.reader.read_exact(&mut written)?;
+            Some((address, written))
+        } else {
+            None
+        };
+        Ok(Some(TraceRecord {
+            cycle: self.cycle,
+            pc,
+            opcode,
+            registers,
+            index_register,
+            delay_timer,
+            sound_timer,
+            memory_write,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<io::Result<TraceRecord>> {
+        self.read_record().transpose()
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quirks::Quirks;
+    use std::sync::{Arc, Mutex};
+
+    // A writer the test can still read after handing it to the tracer
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // 200: 6105   LD V1, 0x05
+    // 202: A300   LD I, 0x300
+    // 204: F133   LD B, V1
+    // 206: F115   LD DT, V1
+    // 208: 1208   JP 0x208
+    fn trace(format: TraceFormat, filter: TraceFilter) -> Vec<u8> {
+        let rom = [0x61, 0x05, 0xA3, 0x00, 0xF1, 0x33, 0xF1, 0x15, 0x12, 0x08];
+        let mut cpu = CPU::new(Quirks::default());
+        cpu.load_rom(&rom).unwrap();
+        let output = Shared::default();
+        let mut tracer = Tracer::new(Box::new(output.clone()), format, filter);
+        for _ in 0..6 {
+            tracer.step(&mut cpu).unwrap();
+        }
+        tracer.finish().unwrap();
+        let bytes = output.0.lock().unwrap().clone();
+        bytes
+    }
+
+    #[test]
+    fn text_trace_shows_changes() {
+        let text = String::from_utf8(trace(TraceFormat::Text, TraceFilter::default())).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0], "         0 200 6105 LD V1, 0x05       V1=05");
+        assert_eq!(
+            lines[2],
+            "         2 204 F133 LD B, V1          [300]=00 00 05"
+        );
+        assert!(lines[3].ends_with(" DT=05"));
+        assert_eq!(lines[5], "         5 208 1208 JP 0x208");
+    }
+
+    #[test]
+    fn filters_by_pc_and_class() {
+        let filter = TraceFilter {
+            pc_range: Some((0x202, 0x206)),
+            classes: vec![OpcodeClass::Memory, OpcodeClass::Timer],
+        };
+        let text = String::from_utf8(trace(TraceFormat::Text, filter)).unwrap();
+        let pcs: Vec<&str> = text.lines().map(|line| &line[11..14]).collect();
+        assert_eq!(pcs, vec!["202", "204", "206"]);
+
+        let filter = TraceFilter {
+            pc_range: None,
+            classes: vec![OpcodeClass::Flow],
+        };
+        let text = String::from_utf8(trace(TraceFormat::Text, filter)).unwrap();
+        assert_eq!(text.lines().count(), 2);
+        assert!(text.starts_with("         4 208"));
+    }
+
+    #[test]
+    fn binary_trace_reads_back_as_text() {
+        let text = String::from_utf8(trace(TraceFormat::Text, TraceFilter::default())).unwrap();
+        let binary = trace(TraceFormat::Binary, TraceFilter::default());
+        // Much smaller than the text, even for such a short trace
+        assert!(binary.len() * 3 < text.len());
+
+        let records: Vec<TraceRecord> = TraceReader::new(&binary[..])
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        let lines: Vec<String> = records.iter().map(|record| format!("{}", record)).collect();
+        assert_eq!(lines, text.lines().collect::<Vec<&str>>());
+
+        assert!(TraceReader::new(text.as_bytes()).is_err());
+        // A record cut off in the middle is an error rather than the end of the trace
+        let truncated: Vec<io::Result<TraceRecord>> = TraceReader::new(&binary[..binary.len() - 1])
+            .unwrap()
+            .collect();
+        assert!(truncated.last().unwrap().is_err());
+    }
+}
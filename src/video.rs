@@ -0,0 +1,310 @@
+// Records the screen once per 60 Hz frame, see Emulator::record_video
+//
+// The canvas always has room for 128x64 pixels of scale x scale, so switching between low
+// and high resolution keeps the video size, low resolution pixels are twice as large
+//
+// GIFs only store frames that differ from the previous one, cropped to the changed area,
+// and show them for as long as they stayed on screen
+// PNG sequences and Y4M streams have every frame, for encoders like
+// ffmpeg -framerate 60 -i frames/%06d.png or ffmpeg -i run.y4m
+
+use gif::{self, DisposalMethod, Repeat, SetParameter};
+use palette::Palette;
+use screenshot;
+use std::borrow::Cow;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const CANVAS_WIDTH: usize = 128;
+const CANVAS_HEIGHT: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VideoFormat {
+    Gif,
+    // A directory with a numbered PNG per frame
+    PngSequence,
+    // Uncompressed YUV 4:4:4 frames at 60 fps
+    Y4m,
+}
+
+impl VideoFormat {
+    // .gif and .y4m files are recorded in their format, anything else is a directory of PNGs
+    pub fn from_path(path: &Path) -> VideoFormat {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gif") => VideoFormat::Gif,
+            Some("y4m") => VideoFormat::Y4m,
+            _ => VideoFormat::PngSequence,
+        }
+    }
+}
+
+pub struct VideoRecorder {
+    path: PathBuf,
+    scale: usize,
+    palette: Palette,
+    output: Output,
+    // Number of 60 Hz frames recorded so far
+    frames: usize,
+    // The first write error, recording stops there and finish reports it
+    error: Option<io::Error>,
+}
+
+enum Output {
+    Gif {
+        encoder: gif::Encoder<BufWriter<File>>,
+        // The canvas shown since frame start, written once it's known how long it stayed
+        pending: Option<(gif::Frame<'static>, usize)>,
+        canvas: Vec<u8>,
+    },
+    PngSequence,
+    Y4m(BufWriter<File>),
+}
+
+impl VideoRecorder {
+    // Creates the file or directory at path, in the format it names, see VideoFormat::from_path
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        scale: usize,
+        palette: Palette,
+    ) -> io::Result<VideoRecorder> {
+        let path = path.as_ref().to_path_buf();
+        if scale == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The scale must be at least 1",
+            ));
+        }
+        let (width, height) = (CANVAS_WIDTH * scale, CANVAS_HEIGHT * scale);
+        let output = match VideoFormat::from_path(&path) {
+            VideoFormat::Gif => {
+                let file = BufWriter::new(File::create(&path)?);
+                let colours = palette.colours.concat();
+                let mut encoder = gif::Encoder::new(file, width as u16, height as u16, &colours)?;
+                encoder.set(Repeat::Infinite)?;
+                Output::Gif {
+                    encoder,
+                    pending: None,
+                    canvas: Vec::new(),
+                }
+            }
+            VideoFormat::PngSequence => {
+                fs::create_dir_all(&path)?;
+                Output::PngSequence
+            }
+            VideoFormat::Y4m => {
+                let mut file = BufWriter::new(File::create(&path)?);
+                writeln!(file, "YUV4MPEG2 W{} H{} F60:1 Ip A1:1 C444", width, height)?;
+                Output::Y4m(file)
+            }
+        };
+        Ok(VideoRecorder {
+            path,
+            scale,
+            palette,
+            output,
+            frames: 0,
+            error: None,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Number of 60 Hz frames recorded so far
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    // Adds the screen of the next frame, gfx as in CPU::gfx
+    pub fn frame(&mut self, gfx: &[u8], width: usize, height: usize) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(err) = self.write_frame(gfx, width, height) {
+            self.error = Some(err);
+        }
+        self.frames += 1;
+    }
+
+    // Writes what's left and closes the file, returns the number of frames recorded
+    pub fn finish(mut self) -> io::Result<usize> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        match self.output {
+            Output::Gif {
+                mut encoder,
+                pending,
+                ..
+            } => {
+                if let Some((mut frame, start)) = pending {
+                    frame.delay = gif_delay(start, self.frames);
+                    encoder.write_frame(&frame)?;
+                }
+                // The encoder writes the trailer when it's dropped
+            }
+            Output::PngSequence => (),
+            Output::Y4m(mut file) => file.flush()?,
+        }
+        Ok(self.frames)
+    }
+
+    fn write_frame(&mut self, gfx: &[u8], width: usize, height: usize) -> io::Result<()> {
+        let pixel_size = self.scale * CANVAS_WIDTH / width;
+        match self.output {
+            Output::Gif {
+                ref mut encoder,
+                ref mut pending,
+                ref mut canvas,
+            } => {
+                let next = screenshot::scale_pixels(gfx, width, pixel_size);
+                if next == *canvas {
+                    return Ok(());
+                }
+                if let Some((mut frame, start)) = pending.take() {
+                    frame.delay = gif_delay(start, self.frames);
+                    encoder.write_frame(&frame)?;
+                }
+                let canvas_width = CANVAS_WIDTH * self.scale;
+                *pending = Some((changed_area(canvas, &next, canvas_width), self.frames));
+                *canvas = next;
+                Ok(())
+            }
+            Output::PngSequence => {
+                let path = self.path.join(format!("{:06}.png", self.frames + 1));
+                let file = BufWriter::new(File::create(path)?);
+                screenshot::write_png(file, gfx, width, height, pixel_size, &self.palette)
+            }
+            Output::Y4m(ref mut file) => {
+                let canvas = screenshot::scale_pixels(gfx, width, pixel_size);
+                let yuv = self.palette.colours.map(to_yuv);
+                // The Y, U and V planes one after the other
+                let planes = (0..3)
+                    .flat_map(|plane| canvas.iter().map(move |&pixel| yuv[pixel as usize][plane]));
+                file.write_all(b"FRAME\n")?;
+                file.write_all(&planes.collect::<Vec<_>>())?;
+                Ok(())
+            }
+        }
+    }
+}
+
+// A GIF frame covering the pixels that differ between the canvases, or all of them for
+// the first frame, drawn on top of the previous frame
+fn changed_area(previous: &[u8], next: &[u8], width: usize) -> gif::Frame<'static> {
+    let (mut left, mut top, mut right, mut bottom) = (0, 0, width, next.len() / width);
+    if previous.len() == next.len() {
+        let changed = |index: &usize| previous[*index] != next[*index];
+        let mut indices = (0..next.len()).filter(changed);
+        let first = indices.next().unwrap_or(0);
+        let last = indices.next_back().unwrap_or(first);
+        top = first / width;
+        bottom = last / width + 1;
+        let columns = (top * width..bottom * width)
+            .filter(changed)
+            .map(|index| index % width);
+        let (min, max) = columns.fold((width, 0), |(min, max), x| (min.min(x), max.max(x)));
+        left = min;
+        right = max + 1;
+    }
+    let mut buffer = Vec::with_capacity((right - left) * (bottom - top));
+    for y in top..bottom {
+        buffer.extend_from_slice(&next[y * width + left..y * width + right]);
+    }
+    gif::Frame {
+        left: left as u16,
+        top: top as u16,
+        width: (right - left) as u16,
+        height: (bottom - top) as u16,
+        dispose: DisposalMethod::Keep,
+        buffer: Cow::Owned(buffer),
+        ..gif::Frame::default()
+    }
+}
+
+// GIF delays are in hundredths of a second, the frames are rounded to them so the
+// total duration doesn't drift
+fn gif_delay(start: usize, end: usize) -> u16 {
+    (end * 100 / 60 - start * 100 / 60).min(u16::MAX as usize) as u16
+}
+
+// BT.601 in the limited range Y4M players expect
+fn to_yuv([r, g, b]: [u8; 3]) -> [u8; 3] {
+    let (r, g, b) = (r as f64, g as f64, b as f64);
+    let y = 16.0 + (65.481 * r + 128.553 * g + 24.966 * b) / 255.0;
+    let u = 128.0 + (-37.797 * r - 74.203 * g + 112.0 * b) / 255.0;
+    let v = 128.0 + (112.0 * r - 93.786 * g - 18.214 * b) / 255.0;
+    [y.round() as u8, u.round() as u8, v.round() as u8]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("rusty_chip-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn gif_skips_repeated_frames() {
+        let path = temp_path("video.gif");
+        let mut recorder = VideoRecorder::create(&path, 1, Palette::default()).unwrap();
+        let mut gfx = vec![0; 64 * 32];
+        for _ in 0..3 {
+            recorder.frame(&gfx, 64, 32);
+        }
+        gfx[64 * 3 + 5] = 1;
+        recorder.frame(&gfx, 64, 32);
+        assert_eq!(recorder.finish().unwrap(), 4);
+
+        let mut reader = gif::Decoder::new(File::open(&path).unwrap())
+            .read_info()
+            .unwrap();
+        {
+            let first = reader.read_next_frame().unwrap().unwrap();
+            assert_eq!((first.width, first.height), (128, 64));
+            // 3 frames are 5 hundredths of a second
+            assert_eq!(first.delay, 5);
+        }
+        {
+            // Only the changed low resolution pixel, 2x2 on the canvas
+            let second = reader.read_next_frame().unwrap().unwrap();
+            assert_eq!((second.left, second.top), (10, 6));
+            assert_eq!((second.width, second.height), (2, 2));
+            assert_eq!(&second.buffer[..], &[1, 1, 1, 1]);
+        }
+        assert!(reader.read_next_frame().unwrap().is_none());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn y4m_and_png_sequences_keep_every_frame() {
+        let path = temp_path("video.y4m");
+        let mut recorder = VideoRecorder::create(&path, 1, Palette::default()).unwrap();
+        let gfx = vec![1; 128 * 64];
+        recorder.frame(&gfx, 128, 64);
+        recorder.frame(&gfx, 128, 64);
+        recorder.finish().unwrap();
+        let video = fs::read(&path).unwrap();
+        let header = b"YUV4MPEG2 W128 H64 F60:1 Ip A1:1 C444\n";
+        assert!(video.starts_with(header));
+        assert_eq!(video.len(), header.len() + 2 * (6 + 3 * 128 * 64));
+        // White is Y 235 and neutral U and V
+        assert_eq!(&video[header.len() + 6..][..1], &[235]);
+        assert_eq!(&video[video.len() - 1..], &[128]);
+        fs::remove_file(&path).unwrap();
+
+        let path = temp_path("frames");
+        let mut recorder = VideoRecorder::create(&path, 2, Palette::default()).unwrap();
+        recorder.frame(&gfx, 128, 64);
+        recorder.frame(&[0; 64 * 32], 64, 32);
+        recorder.finish().unwrap();
+        let pngs = fs::read_dir(&path).unwrap().count();
+        assert_eq!(pngs, 2);
+        assert!(path.join("000002.png").exists());
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
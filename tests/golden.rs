@@ -0,0 +1,77 @@
+// Runs every golden file in tests/golden and compares the screens, see rusty_chip::golden
+// RUSTY_CHIP_BLESS=1 cargo test --test golden stores the current screens instead
+// The corpus is the Octo sources next to the golden files, compiled by rusty_chip::octo, so
+// a compiler bug can hide a CPU bug; golden files whose ROM is missing are skipped
+// The golden files in tests/golden/suites run third-party test ROMs, which aren't part of
+// the repository: RUSTY_CHIP_TEST_ROMS names the directory they were downloaded to
+
+extern crate rusty_chip;
+
+use rusty_chip::golden::GoldenTest;
+use rusty_chip::octo;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[test]
+fn golden_frames() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("golden");
+    run_golden_files(&dir, &dir);
+}
+
+#[test]
+fn test_suite_frames() {
+    let rom_dir = match env::var_os("RUSTY_CHIP_TEST_ROMS") {
+        Some(rom_dir) => PathBuf::from(rom_dir),
+        None => {
+            eprintln!("Skipping the test suites, RUSTY_CHIP_TEST_ROMS isn't set");
+            return;
+        }
+    };
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("golden")
+        .join("suites");
+    run_golden_files(&dir, &rom_dir);
+}
+
+// Checks the golden files in dir, their ROMs are relative to rom_dir
+fn run_golden_files(dir: &Path, rom_dir: &Path) {
+    let bless = env::var_os("RUSTY_CHIP_BLESS").is_some();
+    let mut paths = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "golden"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    assert!(!paths.is_empty(), "No golden files in {}", dir.display());
+
+    let mut failures = Vec::new();
+    for path in paths {
+        let source = fs::read_to_string(&path).unwrap();
+        let test =
+            GoldenTest::parse(&source).unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+        let rom_path = rom_dir.join(&test.rom);
+        if !rom_path.exists() {
+            eprintln!("Skipping {}, {} is missing", path.display(), test.rom);
+            continue;
+        }
+        let rom = if rom_path.extension().is_some_and(|ext| ext == "8o") {
+            octo::compile_file(&rom_path).unwrap_or_else(|err| panic!("{}", err))
+        } else {
+            fs::read(&rom_path).unwrap()
+        };
+
+        if bless {
+            let cpu = test
+                .run(&rom)
+                .unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+            fs::write(&path, test.blessed(&cpu)).unwrap();
+        } else if let Err(err) = test.check(&rom) {
+            failures.push(format!("{}: {}", path.display(), err));
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n\n"));
+}